@@ -3,21 +3,35 @@ use std::sync::Mutex;
 
 use openraft::error::ForwardToLeader;
 use prometheus_client::registry::Registry;
-use tokio::sync::mpsc::Sender;
+use rustls::ClientConfig;
 use tracing::debug;
 use tracing::log::warn;
+use uuid::Uuid;
 
+use crate::authorizer::Authorizer;
+use crate::basic_auth::BasicAuth;
+use crate::certificate::cluster_client_builder;
 use crate::client::RegistryClient;
 use crate::config::Configuration;
 use crate::extractor::Extractor;
+use crate::garbage::GarbageCollector;
+use crate::keys::KeySet;
+use crate::mirror::MirrorHealth;
+use crate::scrubber::Scrubber;
+use crate::storage::ObjectKind;
+use crate::storage::Storage;
 use crate::store::RegistryRequest;
+use crate::token_server::TokenServer;
+use crate::types::AuditRequest;
 use crate::types::Blob;
 use crate::types::Digest;
 use crate::types::Manifest;
 use crate::types::RegistryAction;
 use crate::types::RepositoryName;
 use crate::utils;
+use crate::webhook::delivery::DeliveryQueue;
 use crate::webhook::Event;
+use crate::webhook::Webhooks;
 use crate::RegistryNodeId;
 use crate::RegistryStore;
 
@@ -29,13 +43,69 @@ pub struct RegistryApp {
     pub store: Arc<RegistryStore>,
     pub config: Configuration,
     pub extractor: Arc<Extractor>,
-    pub webhooks: Arc<Sender<Event>>,
+    pub webhooks: Arc<Webhooks>,
+    pub webhook_deliveries: Arc<DeliveryQueue>,
+    pub garbage: Arc<GarbageCollector>,
+    pub scrubber: Arc<Scrubber>,
+    pub mirroring: Arc<MirrorHealth>,
+    pub storage: Arc<dyn Storage>,
     pub registry: Mutex<Registry>,
+    pub client_tls: Option<ClientConfig>,
+    pub token_keys: Arc<KeySet>,
+    pub token_server: Option<Arc<TokenServer>>,
+    pub basic_auth: Option<BasicAuth>,
+    pub authorizer: Arc<dyn Authorizer>,
+}
+
+/// Sign an admin token as node `id`, for calling other members that can't tell it is a peer
+/// from its certificate.
+pub(crate) fn issue_peer_token(token_server: &TokenServer, id: RegistryNodeId) -> Option<String> {
+    match token_server.issue_admin(&format!("$node-{id}")) {
+        Ok(token) => Some(token),
+        Err(err) => {
+            warn!("Unable to issue a token for talking to other members: {err:?}");
+            None
+        }
+    }
 }
 
 impl RegistryApp {
+    /// An HTTP client builder for talking to other cluster members.
+    pub fn cluster_client_builder(&self) -> reqwest::ClientBuilder {
+        cluster_client_builder(&self.client_tls)
+    }
+
+    /// An admin token for the endpoints other members only accept from peers or admins, for
+    /// when they can't tell this node is a peer from its certificate. Only the builtin token
+    /// server can issue one.
+    pub fn peer_token(&self) -> Option<String> {
+        if self.config.raft.mutual_tls() {
+            return None;
+        }
+
+        issue_peer_token(self.token_server.as_ref()?, self.id)
+    }
+
+    fn leader_client(&self, leader_id: RegistryNodeId, leader_addr: String) -> RegistryClient {
+        let client = RegistryClient::with_client(
+            leader_id,
+            leader_addr,
+            self.config.raft.scheme(),
+            self.cluster_client_builder().build().unwrap(),
+            None,
+        );
+
+        match self.peer_token() {
+            Some(token) => client.with_token(token),
+            None => client,
+        }
+    }
+
     pub async fn submit_write(&self, actions: Vec<RegistryAction>) -> bool {
-        let req = RegistryRequest::Transaction { actions };
+        let req = RegistryRequest::Transaction {
+            actions,
+            request: None,
+        };
 
         match self.raft.client_write(req.clone()).await {
             Ok(_) => {
@@ -47,7 +117,7 @@ impl RegistryApp {
                     leader_node: Some(leader_node),
                 }) = e.forward_to_leader()
                 {
-                    let client = RegistryClient::new(*leader_id, leader_node.addr.clone(), None);
+                    let client = self.leader_client(*leader_id, leader_node.addr.clone());
                     let resp = client.write(&req).await;
                     if resp.is_ok() {
                         return true;
@@ -64,8 +134,28 @@ impl RegistryApp {
     }
 
     pub async fn consistent_write(&self, actions: Vec<RegistryAction>) -> bool {
-        let req = RegistryRequest::Transaction { actions };
+        self.write_and_wait(RegistryRequest::Transaction {
+            actions,
+            request: None,
+        })
+        .await
+    }
+
+    /// Like [`Self::consistent_write`], for actions made by a client `request`. It is kept in
+    /// their audit events, so webhooks can say what caused them.
+    pub async fn consistent_write_for(
+        &self,
+        request: AuditRequest,
+        actions: Vec<RegistryAction>,
+    ) -> bool {
+        self.write_and_wait(RegistryRequest::Transaction {
+            actions,
+            request: Some(request),
+        })
+        .await
+    }
 
+    async fn write_and_wait(&self, req: RegistryRequest) -> bool {
         let resp = match self.raft.client_write(req.clone()).await {
             Ok(resp) => resp,
             Err(e) => {
@@ -74,7 +164,7 @@ impl RegistryApp {
                     leader_node: Some(leader_node),
                 }) = e.forward_to_leader()
                 {
-                    let client = RegistryClient::new(*leader_id, leader_node.addr.clone(), None);
+                    let client = self.leader_client(*leader_id, leader_node.addr.clone());
                     if let Ok(resp) = client.write(&req).await {
                         resp
                     } else {
@@ -102,6 +192,17 @@ impl RegistryApp {
         }
     }
 
+    /// Queue a webhook event that isn't derived from the audit history, like a pull.
+    pub fn notify(&self, event: Event) {
+        if !self.webhooks.wants(event.kind.action()) {
+            return;
+        }
+
+        if let Err(err) = self.webhooks.enqueue(&Uuid::new_v4().to_string(), &event) {
+            tracing::error!("Error queueing webhook: {err:?}");
+        }
+    }
+
     pub fn get_blob(&self, digest: &Digest) -> Option<Blob> {
         self.store.get_blob(digest).unwrap()
     }
@@ -166,14 +267,6 @@ impl RegistryApp {
         self.store.get_tags(repository).unwrap()
     }
 
-    pub fn get_blob_path(&self, digest: &Digest) -> std::path::PathBuf {
-        utils::get_blob_path(&self.config.storage, digest)
-    }
-
-    pub fn get_manifest_path(&self, digest: &Digest) -> std::path::PathBuf {
-        utils::get_manifest_path(&self.config.storage, digest)
-    }
-
     pub fn get_upload_path(&self, upload_id: &str) -> std::path::PathBuf {
         utils::get_upload_path(&self.config.storage, upload_id)
     }
@@ -182,7 +275,11 @@ impl RegistryApp {
         utils::get_temp_path(&self.config.storage)
     }
 
-    pub fn get_temp_mirror_path(&self) -> std::path::PathBuf {
-        utils::get_temp_mirror_path(&self.config.storage)
+    pub fn get_partial_mirror_path(
+        &self,
+        object: ObjectKind,
+        digest: &Digest,
+    ) -> std::path::PathBuf {
+        utils::get_partial_mirror_path(&self.config.storage, object.dir(), digest)
     }
 }
@@ -0,0 +1,18 @@
+use async_trait::async_trait;
+
+use crate::authorizer::{AuthorizationRequest, Authorizer};
+use crate::utils::glob_match;
+
+/// Allows whatever the caller's token grants in its `access` claim. This is the default.
+pub struct ClaimsAuthorizer;
+
+#[async_trait]
+impl Authorizer for ClaimsAuthorizer {
+    async fn authorize(&self, request: &AuthorizationRequest<'_>) -> bool {
+        request.grants.iter().any(|access| {
+            // Scopes can be patterns like `team-a/*`, and `*` grants every action
+            glob_match(&access.repository.name, &request.repository.name)
+                && (access.permissions.contains(request.action) || access.permissions.contains("*"))
+        })
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::authorizer::{AuthorizationRequest, Authorizer};
+use crate::extractors::token::Access;
+use crate::types::RepositoryName;
+
+/// Stop the cache growing without bound if lots of different subjects show up.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+#[derive(Serialize)]
+struct Input<'a> {
+    subject: Option<&'a str>,
+    repository: &'a RepositoryName,
+    action: &'a str,
+    access: &'a [Access],
+}
+
+#[derive(Serialize)]
+struct Query<'a> {
+    input: Input<'a>,
+}
+
+#[derive(Deserialize)]
+struct Decision {
+    #[serde(default)]
+    result: bool,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct CacheKey {
+    subject: Option<String>,
+    repository: RepositoryName,
+    action: String,
+    grants: Vec<String>,
+}
+
+impl CacheKey {
+    fn new(request: &AuthorizationRequest<'_>) -> Self {
+        let mut grants = vec![];
+        for access in request.grants.iter() {
+            let mut actions = access.permissions.iter().cloned().collect::<Vec<_>>();
+            actions.sort();
+            grants.push(format!("{}:{}", access.repository, actions.join(",")));
+        }
+        grants.sort();
+
+        Self {
+            subject: request.subject.map(str::to_string),
+            repository: request.repository.clone(),
+            action: request.action.to_string(),
+            grants,
+        }
+    }
+}
+
+/// Asks a policy service over HTTP, in the style of OPA's data API. It POSTs
+/// `{"input": {"subject", "repository", "action", "access"}}` and expects `{"result": true}`
+/// to allow the request. Decisions are cached for `ttl`, and errors deny the request.
+pub struct HttpAuthorizer {
+    url: String,
+    client: reqwest::Client,
+    ttl: Duration,
+    cache: Mutex<HashMap<CacheKey, (Instant, bool)>>,
+}
+
+impl HttpAuthorizer {
+    pub fn new(url: String, ttl: Duration) -> Result<Self> {
+        Ok(Self {
+            url,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()?,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn ask(&self, request: &AuthorizationRequest<'_>) -> Result<bool> {
+        let query = Query {
+            input: Input {
+                subject: request.subject,
+                repository: request.repository,
+                action: request.action,
+                access: request.grants,
+            },
+        };
+
+        let decision: Decision = self
+            .client
+            .post(&self.url)
+            .json(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(decision.result)
+    }
+}
+
+#[async_trait]
+impl Authorizer for HttpAuthorizer {
+    async fn authorize(&self, request: &AuthorizationRequest<'_>) -> bool {
+        let key = CacheKey::new(request);
+
+        if let Some((expires, allowed)) = self.cache.lock().unwrap().get(&key) {
+            if *expires > Instant::now() {
+                return *allowed;
+            }
+        }
+
+        let allowed = match self.ask(request).await {
+            Ok(allowed) => allowed,
+            Err(err) => {
+                warn!("Authorizer: Unable to reach {}: {err:?}", self.url);
+                return false;
+            }
+        };
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, (expires, _)| *expires > now);
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (now + self.ttl, allowed));
+
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use super::*;
+
+    fn request<'a>(
+        subject: &'a str,
+        repository: &'a RepositoryName,
+        action: &'a str,
+    ) -> AuthorizationRequest<'a> {
+        AuthorizationRequest {
+            subject: Some(subject),
+            repository,
+            action,
+            grants: &[],
+        }
+    }
+
+    #[actix_web::test]
+    async fn asks_and_caches() {
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let counter = calls.clone();
+        let server = HttpServer::new(move || {
+            let counter = counter.clone();
+            App::new().route(
+                "/v1/data/distribd/allow",
+                web::post().to(move |query: web::Json<serde_json::Value>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let input = &query["input"];
+                    let allow = input["subject"] == "alice" && input["action"] == "pull";
+                    async move { HttpResponse::Ok().json(serde_json::json!({ "result": allow })) }
+                }),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let authorizer = HttpAuthorizer::new(
+            format!("http://{address}/v1/data/distribd/allow"),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+
+        let repository: RepositoryName = "team-a/app".parse().unwrap();
+
+        assert!(
+            authorizer
+                .authorize(&request("alice", &repository, "pull"))
+                .await
+        );
+        assert!(
+            !authorizer
+                .authorize(&request("alice", &repository, "push"))
+                .await
+        );
+        assert!(
+            !authorizer
+                .authorize(&request("bob", &repository, "pull"))
+                .await
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Both allows and denies are remembered
+        assert!(
+            authorizer
+                .authorize(&request("alice", &repository, "pull"))
+                .await
+        );
+        assert!(
+            !authorizer
+                .authorize(&request("bob", &repository, "pull"))
+                .await
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        handle.stop(true).await;
+    }
+
+    #[tokio::test]
+    async fn errors_deny() {
+        let authorizer =
+            HttpAuthorizer::new("http://127.0.0.1:1/".to_string(), Duration::from_secs(60))
+                .unwrap();
+
+        let repository: RepositoryName = "team-a/app".parse().unwrap();
+        assert!(
+            !authorizer
+                .authorize(&request("alice", &repository, "pull"))
+                .await
+        );
+    }
+}
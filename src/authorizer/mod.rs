@@ -0,0 +1,52 @@
+//! Access decisions for the `/v2` API.
+//!
+//! Once a caller has authenticated, handlers ask an [`Authorizer`] whether they may `pull`,
+//! `push` or `delete` in a repository. Embedders can supply their own with
+//! [`crate::start_raft_node_with_authorizer`].
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::config::{config_path, AuthorizerConfig};
+use crate::extractors::token::Access;
+use crate::types::RepositoryName;
+
+pub mod claims;
+pub mod http;
+pub mod policy;
+
+pub use claims::ClaimsAuthorizer;
+pub use http::HttpAuthorizer;
+pub use policy::PolicyAuthorizer;
+
+/// May `subject` perform `action` on `repository`?
+pub struct AuthorizationRequest<'a> {
+    /// Who the caller authenticated as, the `sub` of their token. `None` if they didn't log in.
+    pub subject: Option<&'a str>,
+    pub repository: &'a RepositoryName,
+    /// `pull`, `push` or `delete`.
+    pub action: &'a str,
+    /// The access granted by the caller's token.
+    pub grants: &'a [Access],
+}
+
+#[async_trait]
+pub trait Authorizer: Send + Sync {
+    async fn authorize(&self, request: &AuthorizationRequest<'_>) -> bool;
+}
+
+pub fn from_config(config: &AuthorizerConfig) -> Result<Arc<dyn Authorizer>> {
+    Ok(match config {
+        AuthorizerConfig::Claims => Arc::new(ClaimsAuthorizer),
+        AuthorizerConfig::Policy { path } => Arc::new(PolicyAuthorizer::new(policy::Policy::load(
+            &config_path(path),
+        )?)),
+        AuthorizerConfig::Http { url, cache_ttl } => Arc::new(HttpAuthorizer::new(
+            url.clone(),
+            Duration::from_secs(*cache_ttl),
+        )?),
+    })
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use figment::providers::{Format, Yaml};
+use figment::Figment;
+use serde::Deserialize;
+
+use crate::authorizer::{AuthorizationRequest, Authorizer};
+use crate::types::RepositoryName;
+use crate::utils::glob_match;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+}
+
+/// Grants `actions` on any repository matching `repositories` to the listed accounts.
+/// Patterns can use `*`, and repository patterns can refer to the account with `${account}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PolicyRule {
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    /// Whether the rule applies to clients that didn't log in.
+    #[serde(default)]
+    pub anonymous: bool,
+    pub repositories: Vec<String>,
+    pub actions: Vec<String>,
+}
+
+impl PolicyRule {
+    fn applies_to(&self, account: Option<&str>) -> bool {
+        match account {
+            Some(account) => self
+                .accounts
+                .iter()
+                .any(|pattern| glob_match(pattern, account)),
+            None => self.anonymous,
+        }
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let policy = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+        Figment::from(Yaml::string(&policy))
+            .extract()
+            .with_context(|| format!("Unable to load {}", path.display()))
+    }
+
+    /// The actions `account` may perform on `repository`.
+    pub fn granted(&self, account: Option<&str>, repository: &RepositoryName) -> BTreeSet<String> {
+        let mut actions = BTreeSet::new();
+
+        for rule in self.rules.iter() {
+            if !rule.applies_to(account) {
+                continue;
+            }
+
+            let matches = rule.repositories.iter().any(|pattern| {
+                let pattern = pattern.replace("${account}", account.unwrap_or_default());
+                glob_match(&pattern, &repository.to_string())
+            });
+
+            if matches {
+                actions.extend(rule.actions.iter().cloned());
+            }
+        }
+
+        actions
+    }
+}
+
+/// Decides access from a static policy file, ignoring what the token grants. This is the
+/// same format the built-in token server uses.
+pub struct PolicyAuthorizer {
+    policy: Policy,
+}
+
+impl PolicyAuthorizer {
+    pub fn new(policy: Policy) -> Self {
+        Self { policy }
+    }
+}
+
+#[async_trait]
+impl Authorizer for PolicyAuthorizer {
+    async fn authorize(&self, request: &AuthorizationRequest<'_>) -> bool {
+        let granted = self.policy.granted(request.subject, request.repository);
+        granted.contains(request.action) || granted.contains("*")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        serde_json::from_value(serde_json::json!({
+            "rules": [
+                {"accounts": ["alice"], "repositories": ["team-a/*"], "actions": ["pull", "push"]},
+                {"accounts": ["robot$ci-*"], "repositories": ["team-a/app"], "actions": ["pull"]},
+                {"accounts": ["*"], "repositories": ["${account}/*"], "actions": ["*"]},
+                {"anonymous": true, "repositories": ["public/*"], "actions": ["pull"]},
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn granted(account: Option<&str>, repository: &str) -> Vec<String> {
+        policy()
+            .granted(account, &repository.parse().unwrap())
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn policy_grants() {
+        assert_eq!(granted(Some("alice"), "team-a/app"), vec!["pull", "push"]);
+        assert_eq!(granted(Some("robot$ci-1"), "team-a/app"), vec!["pull"]);
+        assert_eq!(granted(Some("bob"), "bob/app"), vec!["*"]);
+        assert!(granted(Some("bob"), "team-a/app").is_empty());
+        assert_eq!(granted(None, "public/app"), vec!["pull"]);
+        assert!(granted(Some("alice"), "public/app").is_empty());
+        assert!(granted(None, "team-a/app").is_empty());
+    }
+
+    #[tokio::test]
+    async fn authorizes_from_policy() {
+        let authorizer = PolicyAuthorizer::new(policy());
+
+        let can =
+            |subject: Option<&'static str>, repository: &'static str, action: &'static str| {
+                let authorizer = &authorizer;
+                async move {
+                    authorizer
+                        .authorize(&AuthorizationRequest {
+                            subject,
+                            repository: &repository.parse().unwrap(),
+                            action,
+                            grants: &[],
+                        })
+                        .await
+                }
+            };
+
+        assert!(can(Some("alice"), "team-a/app", "push").await);
+        assert!(!can(Some("alice"), "team-a/app", "delete").await);
+        assert!(can(Some("bob"), "bob/app", "delete").await);
+        assert!(!can(Some("bob"), "team-a/app", "pull").await);
+        assert!(can(None, "public/app", "pull").await);
+        assert!(!can(None, "public/app", "push").await);
+        assert!(!can(None, "team-a/app", "pull").await);
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Result};
+
+use crate::app::RegistryApp;
+use crate::config::{config_path, BasicAuthConfig};
+use crate::extractors::token::Access;
+use crate::token_server::{load_htpasswd, verify_password};
+
+/// Username and password logins for the registry API, for clusters without a token server.
+/// Callers are granted whatever the replicated ACL entries for them and their groups allow.
+pub struct BasicAuth {
+    pub realm: String,
+    htpasswd: HashMap<String, String>,
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+impl BasicAuth {
+    pub fn new(config: &BasicAuthConfig) -> Result<Self> {
+        Ok(BasicAuth {
+            realm: config.realm.clone(),
+            htpasswd: load_htpasswd(&config_path(&config.htpasswd))?,
+            groups: config.groups.clone(),
+        })
+    }
+
+    /// Check a username and password, returning the account name if they are valid.
+    pub async fn authenticate(
+        &self,
+        app: &RegistryApp,
+        username: &str,
+        password: &str,
+    ) -> Option<String> {
+        verify_password(app, &self.htpasswd, username, password).await
+    }
+
+    /// The groups `account` is a member of.
+    pub fn groups(&self, account: &str) -> Vec<String> {
+        self.groups
+            .iter()
+            .filter(|(_, members)| members.iter().any(|member| member == account))
+            .map(|(group, _)| group.clone())
+            .collect()
+    }
+
+    /// What the ACL entries grant `account`. They are read from the store on every request
+    /// so changes apply as soon as this node has applied them.
+    pub fn grants(&self, app: &RegistryApp, account: &str) -> Result<Vec<Access>> {
+        let groups = self.groups(account);
+
+        app.store
+            .get_acls()?
+            .into_iter()
+            .filter(|entry| entry.applies_to(account, &groups))
+            .map(|entry| {
+                Ok(Access {
+                    repository: entry.repository.parse().map_err(|_| {
+                        anyhow!("Invalid ACL repository pattern {:?}", entry.repository)
+                    })?,
+                    permissions: entry.actions.into_iter().collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups() {
+        let auth = BasicAuth {
+            realm: "distribd".to_string(),
+            htpasswd: HashMap::new(),
+            groups: BTreeMap::from([
+                (
+                    "developers".to_string(),
+                    vec!["alice".to_string(), "bob".to_string()],
+                ),
+                ("ci".to_string(), vec!["robot$ci".to_string()]),
+            ]),
+        };
+
+        assert_eq!(auth.groups("alice"), vec!["developers"]);
+        assert_eq!(auth.groups("robot$ci"), vec!["ci"]);
+        assert!(auth.groups("carol").is_empty());
+    }
+}
@@ -1,13 +1,20 @@
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use chrono::Utc;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
+use distribd::certificate::{cluster_client_builder, get_client_config, ServerCertificate};
 use distribd::client::RegistryClient;
-use distribd::network::management::ImportBody;
+use distribd::config::Configuration;
+use distribd::config::TokenConfig;
+use distribd::fsck::{self, load_offline, ClusterFsckRequest};
+use distribd::network::management::{AclRule, ImportBody};
 use distribd::start_raft_node;
+use distribd::storage;
 use distribd::store::RegistryRequest;
-use distribd::types::RegistryAction;
-use distribd::utils::{get_blob_path, get_manifest_path};
+use distribd::token_server::TokenServer;
+use distribd::types::{AclSubject, AuditQuery, Digest};
 use reqwest_retry::policies::ExponentialBackoff;
 use serde_json::from_str;
 use tokio::signal;
@@ -20,6 +27,16 @@ pub struct Opt {
     pub config: Option<std::path::PathBuf>,
     #[clap(short, long, value_parser)]
     pub name: Option<String>,
+    /// Bearer token with the `admin` claim to use the management API with. Tokens are minted
+    /// automatically when the built-in token server is configured.
+    #[clap(long, env = "DISTRIBD_ADMIN_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// Client certificate to use the management API with, instead of this node's.
+    #[clap(long, value_parser, requires = "key")]
+    pub cert: Option<PathBuf>,
+    /// Private key for `--cert`.
+    #[clap(long, value_parser, requires = "cert")]
+    pub key: Option<PathBuf>,
 
     #[clap(subcommand)]
     pub action: Action,
@@ -45,10 +62,200 @@ pub enum Action {
     },
     Export {},
     Metrics {},
+    /// Check the objects stored on this node against the cluster's metadata
     Fsck {
-        #[clap(short, long, action)]
+        /// Submit the fixes for missing and corrupt objects
+        #[clap(short, long, action, conflicts_with = "offline")]
         repair: bool,
+        /// Read the database and storage directory of a stopped node directly
+        #[clap(long, action)]
+        offline: bool,
+        /// Check every member of the cluster and merge the results
+        #[clap(long, action, conflicts_with = "offline")]
+        cluster: bool,
+        /// With `--cluster`, how many healthy copies each object should have
+        #[clap(long, requires = "cluster")]
+        replicas: Option<usize>,
+        /// Print the report as JSON
+        #[clap(long, action)]
+        json: bool,
     },
+    Robot {
+        #[clap(subcommand)]
+        action: RobotAction,
+    },
+    Acl {
+        #[clap(subcommand)]
+        action: AclAction,
+    },
+    /// Show who pushed, tagged, deleted or imported what, and when
+    Audit {
+        /// Only show events for repositories matching this pattern
+        #[clap(long)]
+        repository: Option<String>,
+        #[clap(long)]
+        user: Option<String>,
+        /// Only show this kind of event, like `manifest-mounted` or `hash-tagged`
+        #[clap(long)]
+        action: Option<String>,
+        /// Only show events at or after this RFC 3339 time
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only show events before this RFC 3339 time
+        #[clap(long)]
+        until: Option<DateTime<Utc>>,
+        #[clap(long, default_value_t = 1000)]
+        limit: usize,
+    },
+    Webhook {
+        #[clap(subcommand)]
+        action: WebhookAction,
+    },
+    /// Show the manifests, tags and repositories that use a manifest or blob
+    References {
+        digest: String,
+    },
+    /// Reclaim manifests and blobs that nothing refers to
+    Gc {
+        #[clap(subcommand)]
+        action: GcAction,
+    },
+    /// Check the blobs and manifests stored on a node for corruption
+    Scrub {
+        #[clap(subcommand)]
+        action: ScrubAction,
+    },
+    /// See how fetching objects from other nodes is going
+    Mirror {
+        #[clap(subcommand)]
+        action: MirrorAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RobotAction {
+    /// Create a robot account, or issue a new secret for an existing one
+    Create { name: String },
+    /// Revoke a robot account
+    Revoke { name: String },
+    /// List robot accounts
+    List {},
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AclAction {
+    /// Grant actions on matching repositories to a user (`alice`) or group (`group:developers`)
+    Set {
+        subject: AclSubject,
+        repository: String,
+        #[clap(required = true)]
+        actions: Vec<String>,
+    },
+    /// Remove the entry for a subject and repository pattern
+    Remove {
+        subject: AclSubject,
+        repository: String,
+    },
+    /// List ACL entries
+    List {},
+}
+
+#[derive(Subcommand, Debug)]
+pub enum WebhookAction {
+    /// List webhook deliveries this node gave up on
+    DeadLetters {},
+    /// Try dead letters again, all of them unless ids are given
+    Retry { ids: Vec<u64> },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GcAction {
+    /// Collect garbage on this node now
+    Run {},
+    /// Show what garbage collection would reap on this node, without changing anything
+    DryRun {},
+    /// Show whether garbage collection is running and how the last run went
+    Status {},
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ScrubAction {
+    /// Check every object stored on this node now
+    Run {},
+    /// Show whether the scrubber is running and how the last pass went
+    Status {},
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MirrorAction {
+    /// Show how mirroring from each peer has gone, and the objects that keep failing
+    Status {},
+}
+
+/// How the CLI proves it may use the management API.
+struct AdminCredentials {
+    token: Option<String>,
+    certificate: Option<(String, String)>,
+}
+
+/// Build a client for the local node's raft port. If the cluster uses mutual TLS it presents
+/// `--cert` or this node's certificate, and it sends `--token` or an admin token minted by
+/// the built-in token server.
+async fn admin_client(
+    config: &Configuration,
+    node_id: u64,
+    retry_policy: Option<ExponentialBackoff>,
+    credentials: &AdminCredentials,
+) -> anyhow::Result<RegistryClient> {
+    let client_tls = match &config.raft.tls {
+        Some(tls) => match &tls.ca {
+            Some(ca) => {
+                let (key, chain) = credentials
+                    .certificate
+                    .clone()
+                    .unwrap_or_else(|| (tls.key.clone(), tls.chain.clone()));
+                let certificate = ServerCertificate::new(key, chain).await?;
+                Some(get_client_config(ca, Arc::new(certificate)).await?)
+            }
+            None => None,
+        },
+        None => None,
+    };
+
+    if credentials.certificate.is_some() && client_tls.is_none() {
+        anyhow::bail!("--cert can only be used when raft.tls.ca is configured");
+    }
+
+    let token = match (&credentials.token, &config.token_server) {
+        (Some(token), _) => Some(token.clone()),
+        (
+            None,
+            Some(
+                token_config @ TokenConfig {
+                    builtin: Some(builtin),
+                    ..
+                },
+            ),
+        ) => {
+            let server = TokenServer::new(token_config, builtin)
+                .context("Unable to mint an admin token, try --token")?;
+            Some(server.issue_admin("$cli")?)
+        }
+        _ => None,
+    };
+
+    let client = RegistryClient::with_client(
+        node_id,
+        "127.0.0.1:8080".to_string(),
+        config.raft.scheme(),
+        cluster_client_builder(&client_tls).build()?,
+        retry_policy,
+    );
+
+    Ok(match token {
+        Some(token) => client.with_token(token),
+        None => client,
+    })
 }
 
 #[actix_web::main]
@@ -71,6 +278,17 @@ async fn main() -> anyhow::Result<()> {
     }
     let node_id = config.id()?;
 
+    let credentials = AdminCredentials {
+        token: options.token,
+        certificate: match (options.key, options.cert) {
+            (Some(key), Some(cert)) => Some((
+                key.to_string_lossy().to_string(),
+                cert.to_string_lossy().to_string(),
+            )),
+            _ => None,
+        },
+    };
+
     let retry_policy = Some(
         ExponentialBackoff::builder()
             .retry_bounds(
@@ -96,139 +314,283 @@ async fn main() -> anyhow::Result<()> {
             tasks.notify_one();
         }
         Action::Init { address, port } => {
-            let client = RegistryClient::new(node_id, "127.0.0.1:8080".to_string(), retry_policy);
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
             let address = format!("{}:{}", address, port);
             client.init(address).await?;
             println!("Cluster initialized");
         }
         Action::AddLearner { id, address, port } => {
-            let client = RegistryClient::new(node_id, "127.0.0.1:8080".to_string(), retry_policy);
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
             let address = format!("{}:{}", address, port);
             client.add_learner((id, address)).await?;
             println!("Learner added");
         }
         Action::ChangeMembership { ids } => {
-            let client = RegistryClient::new(node_id, "127.0.0.1:8080".to_string(), retry_policy);
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
             let ids = ids.into_iter().collect();
             client.change_membership(&ids).await?;
             println!("Membership changed");
         }
         Action::Import { path } => {
-            let client = RegistryClient::new(node_id, "127.0.0.1:8080".to_string(), retry_policy);
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
             let payload = tokio::fs::read_to_string(path).await?;
             let body: ImportBody = from_str(&payload)?;
             client.import(&body).await?;
             println!("Data imported");
         }
         Action::Export {} => {
-            let client = RegistryClient::new(node_id, "127.0.0.1:8080".to_string(), retry_policy);
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
             let body = client.export().await?;
             println!("{}", serde_json::to_string(&body)?);
         }
         Action::Metrics {} => {
-            let client = RegistryClient::new(node_id, "127.0.0.1:8080".to_string(), retry_policy);
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
             let metrics = client.metrics().await?;
             println!("{:?}", metrics);
         }
-        Action::Fsck { repair } => {
-            let client = RegistryClient::new(node_id, "127.0.0.1:8080".to_string(), retry_policy);
-            let mut body = client.export().await?;
-
-            let mut fixes = vec![];
-
-            println!("Checking {} blobs...", body.blobs.len());
-            for (digest, blob) in body.blobs.iter_mut() {
-                if blob.locations.contains(&node_id) {
-                    let path = get_blob_path(&config.storage, digest);
-
-                    if !path.exists() {
-                        fixes.push(RegistryAction::BlobUnstored {
-                            timestamp: Utc::now(),
-                            digest: digest.clone(),
-                            location: node_id,
-                            user: "$fsck".to_string(),
-                        });
-                        blob.locations.remove(&node_id);
-                        continue;
+        Action::Robot { action } => {
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+            match action {
+                RobotAction::Create { name } => {
+                    let credentials = client.create_robot(&name).await?;
+                    println!("Username: {}", credentials.username);
+                    println!("Secret: {}", credentials.secret);
+                }
+                RobotAction::Revoke { name } => {
+                    client.revoke_robot(&name).await?;
+                    println!("Robot account revoked");
+                }
+                RobotAction::List {} => {
+                    for robot in client.robots().await? {
+                        println!(
+                            "{}\t{}\t{}",
+                            robot.username, robot.created, robot.created_by
+                        );
                     }
-
-                    // check size is correct
-                    // chck hash is correct
                 }
             }
-
-            println!("Checking {} manifests...", body.manifests.len());
-
-            let extractor = distribd::extractor::Extractor::new();
-
-            for (digest, manifest) in body.manifests.iter_mut() {
-                if manifest.locations.contains(&node_id) {
-                    let path = get_manifest_path(&config.storage, digest);
-
-                    if !path.exists() {
-                        fixes.push(RegistryAction::ManifestUnstored {
-                            timestamp: Utc::now(),
-                            digest: digest.clone(),
-                            location: node_id,
-                            user: "$fsck".to_string(),
-                        });
-                        manifest.locations.remove(&node_id);
-                        continue;
+        }
+        Action::Acl { action } => {
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+            match action {
+                AclAction::Set {
+                    subject,
+                    repository,
+                    actions,
+                } => {
+                    client
+                        .set_acl(&AclRule {
+                            subject,
+                            repository,
+                            actions: actions.into_iter().collect(),
+                        })
+                        .await?;
+                    println!("ACL entry set");
+                }
+                AclAction::Remove {
+                    subject,
+                    repository,
+                } => {
+                    client
+                        .remove_acl(&AclRule {
+                            subject,
+                            repository,
+                            actions: Default::default(),
+                        })
+                        .await?;
+                    println!("ACL entry removed");
+                }
+                AclAction::List {} => {
+                    for entry in client.acls().await? {
+                        let actions = entry.actions.into_iter().collect::<Vec<_>>().join(",");
+                        println!(
+                            "{}\t{}\t{}\t{}\t{}",
+                            entry.subject,
+                            entry.repository,
+                            actions,
+                            entry.created,
+                            entry.created_by
+                        );
                     }
+                }
+            }
+        }
+        Action::Audit {
+            repository,
+            user,
+            action,
+            since,
+            until,
+            limit,
+        } => {
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+            let query = AuditQuery {
+                repository,
+                user,
+                action,
+                since,
+                until,
+                limit,
+            };
+            for event in client.audit(&query).await? {
+                println!("{}", serde_json::to_string(&event)?);
+            }
+        }
+        Action::Webhook { action } => {
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+            match action {
+                WebhookAction::DeadLetters {} => {
+                    for delivery in client.webhook_dead_letters().await? {
+                        println!(
+                            "{}\t{}\t{}\t{}\t{}",
+                            delivery.id,
+                            delivery.url,
+                            delivery.created,
+                            delivery.attempts,
+                            delivery.last_error.unwrap_or_default()
+                        );
+                    }
+                }
+                WebhookAction::Retry { ids } => {
+                    let retried = client.retry_webhook_dead_letters(&ids).await?;
+                    println!("Requeued {retried} deliveries");
+                }
+            }
+        }
+        Action::References { digest } => {
+            let Ok(digest) = digest.parse::<Digest>() else {
+                anyhow::bail!("{digest} is not a sha256 digest");
+            };
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+            let references = client.references(&digest).await?;
+            println!("{}", serde_json::to_string_pretty(&references)?);
+        }
+        Action::Gc { action } => {
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+            match action {
+                GcAction::Run {} => {
+                    let run = client.garbage_collect().await?;
+                    println!("{}", serde_json::to_string_pretty(&run)?);
+                }
+                GcAction::DryRun {} => {
+                    let report = client.garbage_collect_dry_run().await?;
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                }
+                GcAction::Status {} => {
+                    let status = client.garbage_collect_status().await?;
+                    println!("{}", serde_json::to_string_pretty(&status)?);
+                }
+            }
+        }
+        Action::Scrub { action } => {
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+            match action {
+                ScrubAction::Run {} => {
+                    let pass = client.scrub().await?;
+                    println!("{}", serde_json::to_string_pretty(&pass)?);
+                }
+                ScrubAction::Status {} => {
+                    let status = client.scrub_status().await?;
+                    println!("{}", serde_json::to_string_pretty(&status)?);
+                }
+            }
+        }
+        Action::Mirror { action } => {
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+            match action {
+                MirrorAction::Status {} => {
+                    let status = client.mirror_status().await?;
+                    println!("{}", serde_json::to_string_pretty(&status)?);
+                }
+            }
+        }
+        Action::Fsck {
+            repair,
+            offline: _,
+            cluster: true,
+            replicas,
+            json,
+        } => {
+            let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+            let report = client
+                .fsck(&ClusterFsckRequest { repair, replicas })
+                .await?;
 
-                    match extractor
-                        .parse_manifest(path.clone(), manifest.content_type.as_ref().unwrap())
-                        .await
-                    {
-                        Ok(extractions) => {
-                            for extraction in extractions.iter() {
-                                match body.blobs.get(&extraction.digest) {
-                                    Some(blob) => {
-                                        for repo in manifest.repositories.iter() {
-                                            if !blob.repositories.contains(repo) {
-                                                println!("Manifest is invalid: {:?}: {}: Not all blobs are available for {}.", manifest.repositories, digest, repo)
-                                            }
-                                        }
-                                        if let Some(size) = extraction.size {
-                                            if blob.size != Some(size) {
-                                                println!("Manifest is invalid: {:?}: {}: Blob {} is wrong size: {:?} actual vs {:?} declared ({} / {})", manifest.repositories, digest, extraction.digest, blob.size, extraction.size, manifest.created, manifest.updated);
-                                            }
-                                        }
-                                    }
-                                    None => {
-                                        println!(
-                                            "Manifest is invalid: {:?}: {}: Blob {} is missing",
-                                            manifest.repositories, digest, extraction.digest,
-                                        );
-                                    }
-                                }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                for (id, node) in report.nodes.iter() {
+                    match (&node.report, &node.error) {
+                        (Some(node_report), _) => {
+                            println!("Node {id}: {} issues", node_report.issues.len());
+                            for issue in node_report.issues.iter() {
+                                println!("  {issue}");
                             }
                         }
-                        Err(e) => {
-                            println!("Manifest: Manifest is not valid: {}: {:?}", digest, e);
-                        }
+                        (None, error) => println!("Node {id}: Unable to check: {error:?}"),
                     }
-
-                    // check size is correct
-                    // chck hash is correct
                 }
-            }
-
-            println!("Checking tags in {} repositories...", body.tags.len());
-            for (_repository, tags) in body.tags.iter() {
-                for (_tag, _digest) in tags.iter() {
-                    // check digest is a manifest in repository
+                for copies in report.unavailable.iter() {
+                    println!(
+                        "{:?} {} has no healthy copies (corrupt on {:?}, unverified on {:?})",
+                        copies.object, copies.digest, copies.corrupt, copies.unverified
+                    );
+                }
+                for copies in report.under_replicated.iter() {
+                    println!(
+                        "{:?} {} has {} of {} healthy copies",
+                        copies.object,
+                        copies.digest,
+                        copies.healthy.len(),
+                        report.replicas
+                    );
+                }
+                if report.repaired {
+                    println!("Fixes submitted");
                 }
             }
+        }
+        Action::Fsck {
+            repair,
+            offline,
+            cluster: false,
+            replicas: _,
+            json,
+        } => {
+            let (state, client) = if offline {
+                (load_offline(&config.storage, node_id).await?, None)
+            } else {
+                let client = admin_client(&config, node_id, retry_policy, &credentials).await?;
+                (client.export().await?, Some(client))
+            };
+
+            let storage = storage::from_config(&config)?;
+            let report = fsck::check(&state, storage.as_ref(), node_id).await;
 
-            for action in fixes.iter() {
-                println!("{:?}", action);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!(
+                    "Checked {} blobs, {} manifests and {} tags",
+                    report.blobs, report.manifests, report.tags
+                );
+                for issue in report.issues.iter() {
+                    println!("{issue}");
+                }
+                for action in report.fixes.iter() {
+                    println!("{:?}", action);
+                }
             }
 
-            if repair {
-                client
-                    .write(&RegistryRequest::Transaction { actions: fixes })
-                    .await?;
+            if let (true, Some(client)) = (repair, client) {
+                if !report.fixes.is_empty() {
+                    client
+                        .write(&RegistryRequest::Transaction {
+                            actions: report.fixes,
+                            request: None,
+                        })
+                        .await?;
+                }
             }
         }
     }
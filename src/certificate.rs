@@ -1,16 +1,31 @@
+use std::any::Any;
 use std::sync::{Arc, RwLock};
 
-use anyhow::{Context, Result};
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+
+use anyhow::{bail, Context, Result};
 use futures::{
     channel::mpsc::{channel, Receiver},
     SinkExt, StreamExt,
 };
 use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
-use rustls::crypto::aws_lc_rs::sign::RsaSigningKey;
-use rustls::server::ResolvesServerCert;
-use rustls::sign::CertifiedKey;
-use rustls_pemfile::pkcs8_private_keys;
-use rustls_pki_types::PrivateKeyDer;
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use rustls::client::ResolvesClientCert;
+use rustls::crypto::aws_lc_rs::sign::{any_ecdsa_type, any_eddsa_type, RsaSigningKey};
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls::{ClientConfig, RootCertStore, ServerConfig, SignatureScheme};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use x509_parser::oid_registry::{
+    Oid, OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_NIST_EC_P384, OID_PKCS1_RSAENCRYPTION,
+    OID_SIG_ED25519,
+};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::config::TlsConfig;
 
 #[derive(Debug)]
 pub struct ServerCertificate {
@@ -20,15 +35,119 @@ pub struct ServerCertificate {
     watcher: RecommendedWatcher,
 }
 
+/// The kinds of key pair a certificate chain can be served with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyType {
+    Rsa,
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+}
+
+impl std::fmt::Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyType::Rsa => write!(f, "RSA"),
+            KeyType::EcdsaP256 => write!(f, "ECDSA P-256"),
+            KeyType::EcdsaP384 => write!(f, "ECDSA P-384"),
+            KeyType::Ed25519 => write!(f, "Ed25519"),
+        }
+    }
+}
+
+impl KeyType {
+    /// Work out what kind of key a certificate was issued for, returning it along with the
+    /// raw public key.
+    fn from_certificate(certificate: &CertificateDer) -> Result<(KeyType, Vec<u8>)> {
+        let (_, x509) = X509Certificate::from_der(certificate.as_ref())
+            .context("Certificate could not be parsed")?;
+        let spki = x509.public_key();
+
+        let key_type = if spki.algorithm.algorithm == OID_PKCS1_RSAENCRYPTION {
+            KeyType::Rsa
+        } else if spki.algorithm.algorithm == OID_SIG_ED25519 {
+            KeyType::Ed25519
+        } else if spki.algorithm.algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY {
+            let curve = spki
+                .algorithm
+                .parameters
+                .clone()
+                .and_then(|parameters| Oid::try_from(parameters).ok())
+                .context("ECDSA public key has no named curve")?;
+
+            if curve == OID_EC_P256 {
+                KeyType::EcdsaP256
+            } else if curve == OID_NIST_EC_P384 {
+                KeyType::EcdsaP384
+            } else {
+                bail!("Unsupported ECDSA curve: {curve}");
+            }
+        } else {
+            bail!("Unsupported public key type: {}", spki.algorithm.algorithm);
+        };
+
+        Ok((key_type, spki.subject_public_key.data.to_vec()))
+    }
+
+    /// The signature scheme we use to check a private key belongs to a certificate.
+    fn probe_scheme(&self) -> (SignatureScheme, &'static dyn VerificationAlgorithm) {
+        match self {
+            KeyType::Rsa => (
+                SignatureScheme::RSA_PSS_SHA256,
+                &signature::RSA_PSS_2048_8192_SHA256,
+            ),
+            KeyType::EcdsaP256 => (
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+                &signature::ECDSA_P256_SHA256_ASN1,
+            ),
+            KeyType::EcdsaP384 => (
+                SignatureScheme::ECDSA_NISTP384_SHA384,
+                &signature::ECDSA_P384_SHA384_ASN1,
+            ),
+            KeyType::Ed25519 => (SignatureScheme::ED25519, &signature::ED25519),
+        }
+    }
+
+    /// Build a signer of this type from a PKCS#1, SEC1 or PKCS#8 encoded private key.
+    fn signing_key(&self, key: &PrivateKeyDer) -> Option<Arc<dyn SigningKey>> {
+        let signing_key: Arc<dyn SigningKey> = match (self, key) {
+            (KeyType::Rsa, _) => Arc::new(RsaSigningKey::new(key).ok()?),
+            (KeyType::EcdsaP256 | KeyType::EcdsaP384, _) => any_ecdsa_type(key).ok()?,
+            (KeyType::Ed25519, PrivateKeyDer::Pkcs8(key)) => any_eddsa_type(key).ok()?,
+            (KeyType::Ed25519, _) => return None,
+        };
+
+        // any_ecdsa_type accepts any curve, so make sure it's the one the certificate uses
+        let (scheme, _) = self.probe_scheme();
+        signing_key.choose_scheme(&[scheme])?;
+
+        Some(signing_key)
+    }
+
+    /// Check a signer belongs to a public key by signing something and verifying it.
+    fn matches(&self, signing_key: &Arc<dyn SigningKey>, public_key: &[u8]) -> bool {
+        let (scheme, algorithm) = self.probe_scheme();
+        let message = b"distribd certificate key check";
+
+        let signature = match signing_key
+            .choose_scheme(&[scheme])
+            .map(|signer| signer.sign(message))
+        {
+            Some(Ok(signature)) => signature,
+            _ => return false,
+        };
+
+        UnparsedPublicKey::new(algorithm, public_key)
+            .verify(message, &signature)
+            .is_ok()
+    }
+}
+
 pub async fn get_certified_key(key_path: &String, chain_path: &String) -> Result<CertifiedKey> {
     let key_string = tokio::fs::read_to_string(&key_path).await?;
     let mut key_bytes = key_string.as_bytes();
-    let key = PrivateKeyDer::Pkcs8(
-        pkcs8_private_keys(&mut key_bytes)
-            .next()
-            .context("No private key found")??,
-    );
-    let signing_key = RsaSigningKey::new(&key)?;
+    let key = rustls_pemfile::private_key(&mut key_bytes)?
+        .with_context(|| format!("No private key found in {key_path}"))?;
 
     let chain_string = tokio::fs::read_to_string(&chain_path).await?;
     let mut chain_bytes = chain_string.as_bytes();
@@ -38,7 +157,141 @@ pub async fn get_certified_key(key_path: &String, chain_path: &String) -> Result
         chain.push(item?.into_owned());
     }
 
-    Ok(CertifiedKey::new(chain, Arc::new(signing_key)))
+    let leaf = chain
+        .first()
+        .with_context(|| format!("No certificates found in {chain_path}"))?;
+    let (key_type, public_key) = KeyType::from_certificate(leaf)
+        .with_context(|| format!("Unable to use certificate in {chain_path}"))?;
+
+    let signing_key = key_type.signing_key(&key).with_context(|| {
+        format!("Certificate in {chain_path} is for an {key_type} key but {key_path} is not one")
+    })?;
+
+    if !key_type.matches(&signing_key, &public_key) {
+        bail!("Private key in {key_path} does not match the certificate in {chain_path}");
+    }
+
+    Ok(CertifiedKey::new(chain, signing_key))
+}
+
+pub async fn get_root_store(ca_path: &String) -> Result<RootCertStore> {
+    let ca_string = tokio::fs::read_to_string(&ca_path).await?;
+    let mut ca_bytes = ca_string.as_bytes();
+
+    let mut roots = RootCertStore::empty();
+    for item in rustls_pemfile::certs(&mut ca_bytes) {
+        roots.add(item?)?;
+    }
+
+    if roots.is_empty() {
+        anyhow::bail!("No CA certificates found in {ca_path}");
+    }
+
+    Ok(roots)
+}
+
+fn crypto_provider() -> Arc<CryptoProvider> {
+    // reqwest pulls in the ring provider as well, so rustls can't pick a default by itself
+    Arc::new(rustls::crypto::aws_lc_rs::default_provider())
+}
+
+/// Build the TLS configuration for the raft port.
+///
+/// If a cluster CA is configured then callers must present a certificate signed by it.
+pub async fn get_server_config(
+    tls: &TlsConfig,
+    certificate: Arc<ServerCertificate>,
+) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &tls.ca {
+        Some(ca) => {
+            let roots = get_root_store(ca).await?;
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider())
+                    .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(builder.with_cert_resolver(certificate))
+}
+
+/// Build the TLS configuration used to connect to other cluster members.
+///
+/// Peers are verified against the cluster CA and we present our own certificate to them.
+pub async fn get_client_config(
+    ca_path: &String,
+    certificate: Arc<ServerCertificate>,
+) -> Result<ClientConfig> {
+    let roots = get_root_store(ca_path).await?;
+
+    Ok(ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_client_cert_resolver(certificate))
+}
+
+/// Prepare an HTTP client for talking to other cluster members.
+pub fn cluster_client_builder(tls: &Option<ClientConfig>) -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder();
+    match tls {
+        Some(tls) => builder.use_preconfigured_tls(tls.clone()),
+        None => builder,
+    }
+}
+
+/// The certificate a client presented when connecting to the raft port. It has already been
+/// verified against the cluster CA during the handshake.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    /// The common name and DNS names the certificate is for.
+    pub names: Vec<String>,
+}
+
+impl ClientCertificate {
+    pub fn from_der(der: &CertificateDer<'_>) -> Result<Self> {
+        let (_, certificate) =
+            X509Certificate::from_der(der).context("Unable to parse client certificate")?;
+
+        let mut names = vec![];
+
+        for cn in certificate.subject().iter_common_name() {
+            if let Ok(cn) = cn.as_str() {
+                names.push(cn.to_string());
+            }
+        }
+
+        if let Ok(Some(san)) = certificate.subject_alternative_name() {
+            for name in san.value.general_names.iter() {
+                if let GeneralName::DNSName(name) = name {
+                    names.push(name.to_string());
+                }
+            }
+        }
+
+        Ok(Self { names })
+    }
+}
+
+/// Remember which certificate a client presented for the lifetime of its connection, so
+/// handlers can authorize requests with it.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+
+    let (_, session) = stream.get_ref();
+    if let Some(certificate) = session.peer_certificates().and_then(|chain| chain.first()) {
+        match ClientCertificate::from_der(certificate) {
+            Ok(certificate) => {
+                data.insert(certificate);
+            }
+            Err(err) => tracing::warn!("Ignoring client certificate: {err:?}"),
+        }
+    }
 }
 
 impl ServerCertificate {
@@ -96,6 +349,23 @@ impl ResolvesServerCert for ServerCertificate {
     }
 }
 
+impl ResolvesClientCert for ServerCertificate {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        if let Ok(certified_key) = self.certified_key.read() {
+            return Some(certified_key.clone());
+        }
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
 fn async_watcher() -> notify::Result<(RecommendedWatcher, Receiver<notify::Result<Event>>)> {
     let (mut tx, rx) = channel(1);
 
@@ -112,3 +382,72 @@ fn async_watcher() -> notify::Result<(RecommendedWatcher, Receiver<notify::Resul
 
     Ok((watcher, rx))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn load(key: &str, chain: &str) -> Result<CertifiedKey> {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/tls");
+        get_certified_key(&format!("{fixtures}/{key}"), &format!("{fixtures}/{chain}")).await
+    }
+
+    #[tokio::test]
+    async fn rsa_pkcs1() {
+        load("rsa-pkcs1.key", "rsa.crt").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rsa_pkcs8() {
+        load("rsa-pkcs8.key", "rsa.crt").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ecdsa_p256_sec1() {
+        load("ecdsa-p256-sec1.key", "ecdsa-p256.crt").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ecdsa_p256_pkcs8() {
+        load("ecdsa-p256-pkcs8.key", "ecdsa-p256.crt")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn ecdsa_p384_sec1() {
+        load("ecdsa-p384-sec1.key", "ecdsa-p384.crt").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ed25519() {
+        load("ed25519.key", "ed25519.crt").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrong_key_type() {
+        let err = load("rsa-pkcs8.key", "ecdsa-p256.crt").await.unwrap_err();
+        assert!(err.to_string().contains("ECDSA P-256"));
+    }
+
+    #[tokio::test]
+    async fn wrong_curve() {
+        let err = load("ecdsa-p256-sec1.key", "ecdsa-p384.crt")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("ECDSA P-384"));
+    }
+
+    #[tokio::test]
+    async fn mismatched_key() {
+        let err = load("rsa-pkcs8.key", "node.crt").await.unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+    }
+
+    #[tokio::test]
+    async fn client_certificate_names() {
+        let key = load("node.key", "node.crt").await.unwrap();
+        let certificate = ClientCertificate::from_der(&key.cert[0]).unwrap();
+        assert_eq!(certificate.names, vec!["distribd test node"]);
+    }
+}
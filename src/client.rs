@@ -19,8 +19,25 @@ use serde::Deserialize;
 use serde::Serialize;
 use tokio::time::timeout;
 
+use crate::fsck::ClusterFsckReport;
+use crate::fsck::ClusterFsckRequest;
+use crate::garbage::GarbageReport;
+use crate::garbage::GarbageRun;
+use crate::garbage::GarbageStatus;
+use crate::mirror::MirrorStatus;
+use crate::network::management::AclRule;
 use crate::network::management::ImportBody;
+use crate::network::management::RobotCredentials;
+use crate::network::management::RobotSummary;
+use crate::scrubber::ScrubPass;
+use crate::scrubber::ScrubStatus;
 use crate::typ;
+use crate::types::AclEntry;
+use crate::types::AuditEvent;
+use crate::types::AuditQuery;
+use crate::types::Digest;
+use crate::types::References;
+use crate::webhook::delivery::Delivery;
 use crate::RegistryNodeId;
 use crate::RegistryRequest;
 
@@ -34,6 +51,12 @@ pub struct RegistryClient {
     pub leader: Arc<Mutex<(RegistryNodeId, String)>>,
 
     pub inner: ClientWithMiddleware,
+
+    /// Whether the cluster is contacted over `http` or `https`.
+    pub scheme: &'static str,
+
+    /// Bearer token for the management API.
+    pub token: Option<String>,
 }
 
 impl RegistryClient {
@@ -43,7 +66,25 @@ impl RegistryClient {
         leader_addr: String,
         retry_policy: Option<ExponentialBackoff>,
     ) -> Self {
-        let mut builder = ClientBuilder::new(reqwest::Client::new());
+        Self::with_client(
+            leader_id,
+            leader_addr,
+            "http",
+            reqwest::Client::new(),
+            retry_policy,
+        )
+    }
+
+    /// Create a client that uses a preconfigured HTTP client, e.g. one that presents a
+    /// client certificate to the cluster.
+    pub fn with_client(
+        leader_id: RegistryNodeId,
+        leader_addr: String,
+        scheme: &'static str,
+        client: reqwest::Client,
+        retry_policy: Option<ExponentialBackoff>,
+    ) -> Self {
+        let mut builder = ClientBuilder::new(client);
         if let Some(retry_policy) = retry_policy {
             builder = builder.with(RetryTransientMiddleware::new_with_policy(retry_policy));
         }
@@ -52,9 +93,17 @@ impl RegistryClient {
         Self {
             leader: Arc::new(Mutex::new((leader_id, leader_addr))),
             inner: client,
+            scheme,
+            token: None,
         }
     }
 
+    /// Authenticate to the management API with a bearer token that has the `admin` claim.
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
+    }
+
     // --- Application API
 
     /// Submit a write request to the raft cluster.
@@ -133,6 +182,95 @@ impl RegistryClient {
         self.send_rpc_to_leader("export", None::<&()>).await
     }
 
+    /// Create a robot account, returning the credentials it logs in to `/token` with.
+    pub async fn create_robot(&self, name: &str) -> Result<RobotCredentials, typ::RPCError> {
+        self.do_send_rpc_to_leader("create-robot", Some(&name.to_string()))
+            .await
+    }
+
+    pub async fn revoke_robot(&self, name: &str) -> Result<(), typ::RPCError> {
+        self.do_send_rpc_to_leader("revoke-robot", Some(&name.to_string()))
+            .await
+    }
+
+    pub async fn robots(&self) -> Result<Vec<RobotSummary>, typ::RPCError> {
+        self.do_send_rpc_to_leader("robots", None::<&()>).await
+    }
+
+    /// Add an ACL entry, or replace the actions of an existing one.
+    pub async fn set_acl(&self, rule: &AclRule) -> Result<(), typ::RPCError> {
+        self.do_send_rpc_to_leader("set-acl", Some(rule)).await
+    }
+
+    pub async fn remove_acl(&self, rule: &AclRule) -> Result<(), typ::RPCError> {
+        self.do_send_rpc_to_leader("remove-acl", Some(rule)).await
+    }
+
+    pub async fn acls(&self) -> Result<Vec<AclEntry>, typ::RPCError> {
+        self.do_send_rpc_to_leader("acls", None::<&()>).await
+    }
+
+    /// Search the audit history of the cluster.
+    pub async fn audit(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, typ::RPCError> {
+        self.do_send_rpc_to_leader("audit", Some(query)).await
+    }
+
+    /// Webhook deliveries the node gave up on.
+    pub async fn webhook_dead_letters(&self) -> Result<Vec<Delivery>, typ::RPCError> {
+        self.do_send_rpc_to_leader("webhook-dead-letters", None::<&()>)
+            .await
+    }
+
+    /// Queue dead letters to be delivered again, all of them if `ids` is empty.
+    pub async fn retry_webhook_dead_letters(&self, ids: &[u64]) -> Result<usize, typ::RPCError> {
+        self.do_send_rpc_to_leader("retry-webhook-dead-letters", Some(&ids.to_vec()))
+            .await
+    }
+
+    /// The manifests, tags and repositories that use a manifest or blob.
+    pub async fn references(&self, digest: &Digest) -> Result<References, typ::RPCError> {
+        self.do_send_rpc_to_leader(&format!("references/{digest}"), None::<&()>)
+            .await
+    }
+
+    /// Collect garbage on the node now.
+    pub async fn garbage_collect(&self) -> Result<GarbageRun, typ::RPCError> {
+        self.do_send_rpc_to_leader("garbage-collect", Some(&Empty {}))
+            .await
+    }
+
+    /// What garbage collection would reap on the node, without changing anything.
+    pub async fn garbage_collect_dry_run(&self) -> Result<GarbageReport, typ::RPCError> {
+        self.do_send_rpc_to_leader("garbage-collect/dry-run", None::<&()>)
+            .await
+    }
+
+    pub async fn garbage_collect_status(&self) -> Result<GarbageStatus, typ::RPCError> {
+        self.do_send_rpc_to_leader("garbage-collect/status", None::<&()>)
+            .await
+    }
+
+    /// Check every object stored on the node now.
+    pub async fn scrub(&self) -> Result<ScrubPass, typ::RPCError> {
+        self.do_send_rpc_to_leader("scrub", Some(&Empty {})).await
+    }
+
+    pub async fn scrub_status(&self) -> Result<ScrubStatus, typ::RPCError> {
+        self.do_send_rpc_to_leader("scrub/status", None::<&()>)
+            .await
+    }
+
+    /// How mirroring from each peer has gone on this node, and the objects that keep failing.
+    pub async fn mirror_status(&self) -> Result<MirrorStatus, typ::RPCError> {
+        self.do_send_rpc_to_leader("mirror/status", None::<&()>)
+            .await
+    }
+
+    /// Have every member of the cluster check its objects, and merge what they found.
+    pub async fn fsck(&self, req: &ClusterFsckRequest) -> Result<ClusterFsckReport, typ::RPCError> {
+        self.do_send_rpc_to_leader("fsck", Some(req)).await
+    }
+
     /// Get the metrics about the cluster.
     ///
     /// Metrics contains various information about the cluster, such as current leader,
@@ -162,10 +300,10 @@ impl RegistryClient {
         let (leader_id, url) = {
             let t = self.leader.lock().unwrap();
             let target_addr = &t.1;
-            (t.0, format!("http://{}/{}", target_addr, uri))
+            (t.0, format!("{}://{}/{}", self.scheme, target_addr, uri))
         };
 
-        let fu = if let Some(r) = req {
+        let mut builder = if let Some(r) = req {
             tracing::debug!(
                 ">>> client send request to {}: {}",
                 url,
@@ -175,8 +313,11 @@ impl RegistryClient {
         } else {
             tracing::debug!(">>> client send request to {}", url,);
             self.inner.get(url.clone())
+        };
+        if let Some(token) = &self.token {
+            builder = builder.bearer_auth(token);
         }
-        .send();
+        let fu = builder.send();
 
         let res = timeout(Duration::from_millis(3_000), fu).await;
         let resp = match res {
@@ -187,6 +328,16 @@ impl RegistryClient {
             }
         };
 
+        let status = resp.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            let reason = resp.text().await.unwrap_or_default();
+            let err = std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{status}: {reason}"),
+            );
+            return Err(RPCError::Network(NetworkError::new(&err)));
+        }
+
         let res: Result<Resp, typ::RaftError<Err>> = resp
             .json()
             .await
@@ -1,3 +1,4 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
 
 use anyhow::{Context, Result};
@@ -5,18 +6,26 @@ use figment::{
     providers::{Env, Format, Serialized, Yaml},
     Figment,
 };
-use jwt_simple::prelude::ES256PublicKey;
 use platform_dirs::AppDirs;
 use regex::Regex;
+use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use x509_parser::prelude::Pem;
 
+use crate::keys::VerificationKey;
+use crate::webhook::EventAction;
 use crate::RegistryNodeId;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TlsConfig {
     pub key: String,
     pub chain: String,
+    /// CA bundle that other cluster members' certificates are signed by. When set, peers
+    /// must present a client certificate signed by this CA to use the raft port.
+    pub ca: Option<String>,
+    /// Client certificates with one of these names (common name or DNS name) may use the
+    /// management API.
+    #[serde(default)]
+    pub admins: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -26,6 +35,21 @@ pub struct RaftConfig {
     pub tls: Option<TlsConfig>,
 }
 
+impl RaftConfig {
+    /// The URL scheme other cluster members should be contacted with.
+    pub fn scheme(&self) -> &'static str {
+        match self.tls {
+            Some(_) => "https",
+            None => "http",
+        }
+    }
+
+    /// Whether cluster members prove who they are with certificates signed by the cluster CA.
+    pub fn mutual_tls(&self) -> bool {
+        matches!(&self.tls, Some(tls) if tls.ca.is_some())
+    }
+}
+
 impl Default for RaftConfig {
     fn default() -> Self {
         Self {
@@ -66,10 +90,30 @@ impl Default for PrometheusConfig {
     }
 }
 
+/// Resolve a path from the config file, relative paths being relative to the config directory.
+pub fn config_path(path: &str) -> PathBuf {
+    let p = PathBuf::from(path);
+    if p.is_relative() {
+        let app_dirs = AppDirs::new(Some("distribd"), true).unwrap();
+        return app_dirs.config_dir.join(p);
+    }
+    p
+}
+
 #[derive(Clone, Debug)]
 pub struct PublicKey {
     pub path: String,
-    pub public_key: ES256PublicKey,
+    /// The `kid` header of tokens signed with this key, if the token server sets one.
+    pub kid: Option<String>,
+    pub public_key: VerificationKey,
+}
+
+/// A public key can be configured with just a path, or with a path and a `kid`.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum PublicKeySource {
+    Path(String),
+    Detailed { path: String, kid: Option<String> },
 }
 
 impl Serialize for PublicKey {
@@ -77,7 +121,14 @@ impl Serialize for PublicKey {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&self.path)
+        let source = match &self.kid {
+            None => PublicKeySource::Path(self.path.clone()),
+            Some(kid) => PublicKeySource::Detailed {
+                path: self.path.clone(),
+                kid: Some(kid.clone()),
+            },
+        };
+        source.serialize(serializer)
     }
 }
 
@@ -86,55 +137,381 @@ impl<'de> Deserialize<'de> for PublicKey {
     where
         D: Deserializer<'de>,
     {
-        let s: String = Deserialize::deserialize(deserializer)?;
-        let mut p = PathBuf::from(s.clone());
-        if p.is_relative() {
-            let app_dirs = AppDirs::new(Some("distribd"), true).unwrap();
-            let config_dir = app_dirs.config_dir;
-            p = config_dir.join(p);
-        }
-        let pem = std::fs::read_to_string(&p).unwrap();
-
-        let public_key = match ES256PublicKey::from_pem(&pem) {
-            Ok(public_key) => public_key,
-            Err(_) => {
-                let pem = Pem::iter_from_buffer(pem.as_bytes())
-                    .next()
-                    .unwrap()
-                    .unwrap();
-                let x509 = pem.parse_x509().expect("X.509: decoding DER failed");
-                let raw = &x509.public_key().subject_public_key.data;
-
-                ES256PublicKey::from_bytes(raw).unwrap()
-            }
+        let (s, kid) = match PublicKeySource::deserialize(deserializer)? {
+            PublicKeySource::Path(path) => (path, None),
+            PublicKeySource::Detailed { path, kid } => (path, kid),
         };
 
+        let p = config_path(&s);
+        let pem = std::fs::read_to_string(&p)
+            .map_err(|err| D::Error::custom(format!("{}: {err}", p.display())))?;
+
+        let public_key = VerificationKey::from_pem(&pem)
+            .map_err(|err| D::Error::custom(format!("{}: {err:#}", p.display())))?;
+
         Ok(PublicKey {
             path: s,
+            kid,
             public_key,
         })
     }
 }
 
+fn default_jwks_refresh_interval() -> u64 {
+    300
+}
+
+/// Where to load a JSON Web Key Set from. Set `url` to fetch it from the token server or
+/// `path` to read it from disk, relative to the config directory.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JwksConfig {
+    pub url: Option<String>,
+    pub path: Option<String>,
+    /// How often to reload the key set, in seconds. Must be at least 1.
+    #[serde(default = "default_jwks_refresh_interval")]
+    pub refresh_interval: u64,
+}
+
+fn default_token_lifetime() -> u64 {
+    300
+}
+
+/// Issue tokens from distribd itself rather than running a separate token server. Paths are
+/// relative to the config directory.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuiltinTokenServerConfig {
+    /// ES256 private key to sign tokens with. Tokens it signs are always trusted.
+    pub key: String,
+    /// htpasswd file of bcrypt hashed passwords.
+    pub htpasswd: Option<String>,
+    /// Rules for which repositories and actions each account is granted.
+    pub policy: String,
+    /// How long issued tokens are valid for, in seconds. Tokens older than an hour are
+    /// rejected regardless.
+    #[serde(default = "default_token_lifetime")]
+    pub lifetime: u64,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TokenConfig {
     pub issuer: String,
     pub service: String,
     pub realm: String,
-    pub public_key: PublicKey,
+    #[serde(default)]
+    pub public_key: Option<PublicKey>,
+    /// Additional keys to accept tokens from, e.g. while the token server rotates its key.
+    #[serde(default)]
+    pub public_keys: Vec<PublicKey>,
+    pub jwks: Option<JwksConfig>,
+    pub builtin: Option<BuiltinTokenServerConfig>,
+    /// Repositories (patterns can use `*`) that anyone can pull from without a token.
+    #[serde(default)]
+    pub public_repositories: Vec<String>,
+}
+
+fn default_basic_auth_realm() -> String {
+    "distribd".to_string()
+}
+
+/// Let clients log in to the registry with a username and password when there is no token
+/// server. What they can do is decided by the ACL entries managed with `distribd acl`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BasicAuthConfig {
+    /// htpasswd file of bcrypt hashed passwords, relative to the config directory. Robot
+    /// accounts can log in too.
+    pub htpasswd: String,
+    /// Members of each group, for ACL entries like `group:developers`.
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+    #[serde(default = "default_basic_auth_realm")]
+    pub realm: String,
+}
+
+fn default_authorizer_cache_ttl() -> u64 {
+    60
+}
+
+/// Who decides whether an authenticated caller may pull, push or delete.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AuthorizerConfig {
+    /// Trust the `access` claim of the caller's token.
+    #[default]
+    Claims,
+    /// Use a static policy file, in the same format as the built-in token server.
+    Policy { path: String },
+    /// Ask a policy service, caching decisions for `cache_ttl` seconds.
+    Http {
+        url: String,
+        #[serde(default = "default_authorizer_cache_ttl")]
+        cache_ttl: u64,
+    },
+}
+
+fn default_webhook_actions() -> BTreeSet<EventAction> {
+    BTreeSet::from([EventAction::Push])
+}
+
+fn default_pull_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_webhook_timeout() -> u64 {
+    10
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    10
+}
+
+fn default_webhook_retry_backoff() -> u64 {
+    5
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct WebhookConfig {
+    /// Identifies the webhook, so it keeps its place in the audit history when webhooks are
+    /// added, removed or reordered. Defaults to its URL.
+    pub id: Option<String>,
+
     pub url: String,
 
     #[serde(with = "serde_regex")]
     pub matcher: Regex,
+
+    /// Which kinds of event to send. Only pushes unless configured.
+    #[serde(default = "default_webhook_actions")]
+    pub actions: BTreeSet<EventAction>,
+
+    /// The fraction of pulls to send, as they can be very frequent.
+    #[serde(default = "default_pull_sample_rate")]
+    pub pull_sample_rate: f64,
+
+    /// Sign deliveries with an HMAC-SHA256 of the body using this secret.
+    pub secret: Option<String>,
+
+    /// Seconds to wait for the webhook to respond.
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: u64,
+
+    /// How many times to try a delivery before moving it to the dead letter list.
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: u32,
+
+    /// Seconds to wait before the first retry, doubling for each retry after it.
+    #[serde(default = "default_webhook_retry_backoff")]
+    pub retry_backoff: u64,
+}
+
+impl WebhookConfig {
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or(&self.url)
+    }
+
+    pub fn wants(&self, action: EventAction) -> bool {
+        self.actions.contains(&action)
+    }
+}
+
+fn default_garbage_collection_interval() -> u64 {
+    60
+}
+
+fn default_garbage_collection_grace_period() -> u64 {
+    60 * 60 * 12
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GarbageCollectionConfig {
+    /// Whether to collect garbage on a schedule. Runs can always be started from the
+    /// management API.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Seconds between runs. Must be at least 1.
+    #[serde(default = "default_garbage_collection_interval")]
+    pub interval: u64,
+
+    /// Seconds an orphaned manifest or blob is kept for, so objects that are still being
+    /// pushed aren't collected before they are referenced.
+    #[serde(default = "default_garbage_collection_grace_period")]
+    pub grace_period: u64,
+}
+
+impl Default for GarbageCollectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: default_garbage_collection_interval(),
+            grace_period: default_garbage_collection_grace_period(),
+        }
+    }
+}
+
+fn default_scrubber_rate() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_scrubber_interval() -> u64 {
+    60 * 60 * 24 * 7
+}
+
+/// Periodically re-hash the blobs and manifests stored on this node to find files that have
+/// rotted on disk.
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ScrubberConfig {
+    #[serde(default)]
     pub enabled: bool,
+
+    /// Bytes per second to read at most, so scrubbing doesn't starve clients of disk bandwidth.
+    /// 0 means no limit.
+    #[serde(default = "default_scrubber_rate")]
+    pub rate: u64,
+
+    /// Seconds between the start of one full pass and the next. Must be at least 1.
+    #[serde(default = "default_scrubber_interval")]
+    pub interval: u64,
+}
+
+impl Default for ScrubberConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rate: default_scrubber_rate(),
+            interval: default_scrubber_interval(),
+        }
+    }
+}
+
+fn default_mirroring_concurrency() -> usize {
+    8
+}
+
+fn default_mirroring_batch_size() -> usize {
+    64
+}
+
+fn default_mirroring_retry_backoff() -> u64 {
+    10
+}
+
+fn default_mirroring_max_retry_backoff() -> u64 {
+    60 * 60
+}
+
+fn default_mirroring_failing_after() -> u32 {
+    5
+}
+
+/// Fetching the blobs and manifests this node doesn't have yet from the nodes that do.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MirroringConfig {
+    /// Transfers to run at once.
+    #[serde(default = "default_mirroring_concurrency")]
+    pub concurrency: usize,
+
+    /// The most transfers to record as stored in a single raft write.
+    #[serde(default = "default_mirroring_batch_size")]
+    pub batch_size: usize,
+
+    /// Seconds to wait before retrying an object that failed to transfer, doubling for each
+    /// failure after it.
+    #[serde(default = "default_mirroring_retry_backoff")]
+    pub retry_backoff: u64,
+
+    /// The longest to wait between attempts at an object, in seconds.
+    #[serde(default = "default_mirroring_max_retry_backoff")]
+    pub max_retry_backoff: u64,
+
+    /// Failures in a row after which an object is reported as failing.
+    #[serde(default = "default_mirroring_failing_after")]
+    pub failing_after: u32,
+}
+
+impl Default for MirroringConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: default_mirroring_concurrency(),
+            batch_size: default_mirroring_batch_size(),
+            retry_backoff: default_mirroring_retry_backoff(),
+            max_retry_backoff: default_mirroring_max_retry_backoff(),
+            failing_after: default_mirroring_failing_after(),
+        }
+    }
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// A bucket in S3, or a service compatible with it, to keep blobs and manifests in. Nodes can
+/// share a bucket by giving each one its own `prefix`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct S3Config {
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// The URL of an S3 compatible service, if this isn't AWS.
+    pub endpoint: Option<String>,
+    /// Credentials, if they aren't in the `AWS_` environment variables.
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Keys are prefixed with this.
+    #[serde(default)]
+    pub prefix: String,
+    /// Whether `endpoint` can be plain HTTP.
+    #[serde(default)]
+    pub allow_http: bool,
+}
+
+/// One of the disks to spread blobs and manifests over.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DataDirectoryConfig {
+    pub path: String,
+    /// The share of new objects this directory takes, relative to the others. Defaults to its
+    /// free space in GiB when it was first used, so a newly added disk takes more than the full
+    /// ones. A weight of `0` stops new objects going to it.
+    pub weight: Option<f64>,
+}
+
+fn default_reserved_space() -> u64 {
+    1024 * 1024 * 1024
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FilesystemConfig {
+    /// Spread objects over these directories rather than keeping them in `storage`.
+    #[serde(default)]
+    pub directories: Vec<DataDirectoryConfig>,
+    /// Bytes to leave free on each data directory. New objects go elsewhere once a directory
+    /// would have less than this.
+    #[serde(default = "default_reserved_space")]
+    pub reserved_space: u64,
+}
+
+impl Default for FilesystemConfig {
+    fn default() -> Self {
+        Self {
+            directories: vec![],
+            reserved_space: default_reserved_space(),
+        }
+    }
+}
+
+/// Where blobs and manifests are kept. Uploads in progress and the database are always kept
+/// in `storage`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageBackendConfig {
+    /// Files in `storage`, or in data directories.
+    Filesystem(FilesystemConfig),
+    S3(S3Config),
+}
+
+impl Default for StorageBackendConfig {
+    fn default() -> Self {
+        StorageBackendConfig::Filesystem(FilesystemConfig::default())
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -149,9 +526,14 @@ pub struct Configuration {
     pub registry: RegistryConfig,
     pub prometheus: PrometheusConfig,
     pub token_server: Option<TokenConfig>,
+    pub basic_auth: Option<BasicAuthConfig>,
+    pub authorizer: AuthorizerConfig,
     pub storage: String,
+    pub storage_backend: StorageBackendConfig,
     pub webhooks: Vec<WebhookConfig>,
+    pub garbage_collection: GarbageCollectionConfig,
     pub scrubber: ScrubberConfig,
+    pub mirroring: MirroringConfig,
     pub sentry: Option<SentryConfig>,
 }
 
@@ -170,6 +552,29 @@ impl Configuration {
 
         Ok(node_id)
     }
+
+    /// Check for settings that can be parsed but can't work.
+    pub fn validate(&self) -> Result<()> {
+        if self.garbage_collection.interval == 0 {
+            anyhow::bail!("garbage_collection.interval must be at least 1 second");
+        }
+
+        if self.scrubber.interval == 0 {
+            anyhow::bail!("scrubber.interval must be at least 1 second");
+        }
+
+        let mut webhooks = BTreeSet::new();
+        for hook in &self.webhooks {
+            if !webhooks.insert(hook.id()) {
+                anyhow::bail!(
+                    "Webhook id {:?} is used more than once, give each webhook its own id",
+                    hook.id()
+                );
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Default for Configuration {
@@ -180,9 +585,14 @@ impl Default for Configuration {
             registry: RegistryConfig::default(),
             prometheus: PrometheusConfig::default(),
             token_server: None,
+            basic_auth: None,
+            authorizer: AuthorizerConfig::default(),
             storage: "var".to_string(),
+            storage_backend: StorageBackendConfig::default(),
             webhooks: vec![],
+            garbage_collection: GarbageCollectionConfig::default(),
             scrubber: ScrubberConfig::default(),
+            mirroring: MirroringConfig::default(),
             sentry: None,
         }
     }
@@ -258,8 +668,9 @@ mod test {
         assert_eq!(t.issuer, "Test Issuer");
         assert_eq!(t.realm, "testrealm");
         assert_eq!(t.service, "myservice");
-        assert_eq!(t.public_key.path, "token.pub");
-        assert_eq!(t.public_key.public_key.to_pem().unwrap(), "-----BEGIN PUBLIC KEY-----\nMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEPEUDSJJ2ThQmq1py0QUp1VHfLxOS\nGjl1uDis2P2rq3YWN96TDWgYbmk4v1Fd3sznlgTnM7cZ22NrrdKvM4TmVg==\n-----END PUBLIC KEY-----\n");
+        let public_key = t.public_key.unwrap();
+        assert_eq!(public_key.path, "token.pub");
+        assert_eq!(public_key.public_key.to_pem().unwrap(), "-----BEGIN PUBLIC KEY-----\nMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEPEUDSJJ2ThQmq1py0QUp1VHfLxOS\nGjl1uDis2P2rq3YWN96TDWgYbmk4v1Fd3sznlgTnM7cZ22NrrdKvM4TmVg==\n-----END PUBLIC KEY-----\n");
     }
 
     #[test]
@@ -285,8 +696,44 @@ mod test {
         assert_eq!(t.issuer, "Test Issuer");
         assert_eq!(t.realm, "testrealm");
         assert_eq!(t.service, "myservice");
-        assert_eq!(t.public_key.path, "token.crt");
-        assert_eq!(t.public_key.public_key.to_pem().unwrap(), "-----BEGIN PUBLIC KEY-----\nMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEPEUDSJJ2ThQmq1py0QUp1VHfLxOS\nGjl1uDis2P2rq3YWN96TDWgYbmk4v1Fd3sznlgTnM7cZ22NrrdKvM4TmVg==\n-----END PUBLIC KEY-----\n");
+        let public_key = t.public_key.unwrap();
+        assert_eq!(public_key.path, "token.crt");
+        assert_eq!(public_key.public_key.to_pem().unwrap(), "-----BEGIN PUBLIC KEY-----\nMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEPEUDSJJ2ThQmq1py0QUp1VHfLxOS\nGjl1uDis2P2rq3YWN96TDWgYbmk4v1Fd3sznlgTnM7cZ22NrrdKvM4TmVg==\n-----END PUBLIC KEY-----\n");
+    }
+
+    #[test]
+    fn token_config_jwks() {
+        std::env::set_var(
+            "XDG_CONFIG_HOME",
+            std::env::current_dir()
+                .unwrap()
+                .join("fixtures/etc")
+                .as_os_str(),
+        );
+
+        let data = r#"
+        {
+            "issuer": "Test Issuer",
+            "realm": "testrealm",
+            "service": "myservice",
+            "public_keys": ["token.pub", {"path": "token.crt", "kid": "next"}],
+            "jwks": {"url": "https://auth.example.com/jwks.json"}
+        }"#;
+
+        let t: TokenConfig = serde_json::from_str(data).unwrap();
+
+        assert!(t.public_key.is_none());
+        assert_eq!(t.public_keys.len(), 2);
+        assert_eq!(t.public_keys[0].kid, None);
+        assert_eq!(t.public_keys[1].path, "token.crt");
+        assert_eq!(t.public_keys[1].kid, Some("next".to_string()));
+
+        let jwks = t.jwks.unwrap();
+        assert_eq!(
+            jwks.url,
+            Some("https://auth.example.com/jwks.json".to_string())
+        );
+        assert_eq!(jwks.refresh_interval, 300);
     }
 
     #[test]
@@ -300,7 +747,159 @@ mod test {
         let t: WebhookConfig = serde_json::from_str(data).unwrap();
 
         assert_eq!(t.url, "http://localhost:1234");
+        assert_eq!(t.id(), "http://localhost:1234");
         assert!(!t.matcher.is_match("testrealm"));
         assert!(t.matcher.is_match("matcherZ"));
+        assert!(t.wants(EventAction::Push));
+        assert!(!t.wants(EventAction::Pull));
+
+        let data = r#"
+        {
+            "url": "http://localhost:1234",
+            "matcher": ".*",
+            "actions": ["delete", "tag"]
+        }"#;
+
+        let t: WebhookConfig = serde_json::from_str(data).unwrap();
+
+        assert!(!t.wants(EventAction::Push));
+        assert!(t.wants(EventAction::Delete));
+        assert!(t.wants(EventAction::Tag));
+        assert_eq!(t.pull_sample_rate, 1.0);
+        assert_eq!(t.secret, None);
+        assert_eq!(t.timeout, 10);
+        assert_eq!(t.max_attempts, 10);
+        assert_eq!(t.retry_backoff, 5);
+    }
+
+    #[test]
+    fn webhook_ids_are_unique() {
+        let hook = |data: &str| -> WebhookConfig { serde_json::from_str(data).unwrap() };
+
+        let mut config = Configuration::default();
+        config.webhooks = vec![
+            hook(r#"{"url": "http://localhost:1234", "matcher": ".*"}"#),
+            hook(r#"{"id": "audit", "url": "http://localhost:1234", "matcher": ".*"}"#),
+        ];
+        config.validate().unwrap();
+
+        config.webhooks.push(hook(
+            r#"{"url": "http://localhost:1234", "matcher": ".*", "actions": ["pull"]}"#,
+        ));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn garbage_collection_needs_an_interval() {
+        let mut config = Configuration::default();
+        config.validate().unwrap();
+
+        config.garbage_collection.interval = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn scrubber_needs_an_interval() {
+        let mut config = Configuration::default();
+        config.scrubber.interval = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn scrubber_config() {
+        let t: ScrubberConfig = serde_json::from_str(r#"{"enabled": true}"#).unwrap();
+
+        assert!(t.enabled);
+        assert_eq!(t.rate, 50 * 1024 * 1024);
+        assert_eq!(t.interval, 60 * 60 * 24 * 7);
+    }
+
+    #[test]
+    fn mirroring_config() {
+        let t: MirroringConfig = serde_json::from_str(r#"{"concurrency": 2}"#).unwrap();
+
+        assert_eq!(t.concurrency, 2);
+        assert_eq!(t.batch_size, 64);
+        assert_eq!(t.retry_backoff, 10);
+        assert_eq!(t.max_retry_backoff, 60 * 60);
+        assert_eq!(t.failing_after, 5);
+    }
+
+    #[test]
+    fn storage_backend_config() {
+        let defaults: Configuration = Figment::from(Serialized::defaults(Configuration::default()))
+            .extract()
+            .unwrap();
+        assert!(matches!(
+            defaults.storage_backend,
+            StorageBackendConfig::Filesystem(FilesystemConfig { ref directories, .. }) if directories.is_empty()
+        ));
+
+        let data = r#"
+        {
+            "type": "filesystem",
+            "directories": [
+                {"path": "/mnt/disk1", "weight": 2},
+                {"path": "/mnt/disk2"}
+            ]
+        }"#;
+
+        let t: StorageBackendConfig = serde_json::from_str(data).unwrap();
+
+        match t {
+            StorageBackendConfig::Filesystem(filesystem) => {
+                assert_eq!(filesystem.directories.len(), 2);
+                assert_eq!(filesystem.directories[0].path, "/mnt/disk1");
+                assert_eq!(filesystem.directories[0].weight, Some(2.0));
+                assert_eq!(filesystem.directories[1].weight, None);
+                assert_eq!(filesystem.reserved_space, 1024 * 1024 * 1024);
+            }
+            _ => panic!("Expected a filesystem storage backend"),
+        }
+
+        let data = r#"
+        {
+            "type": "s3",
+            "bucket": "registry",
+            "endpoint": "http://localhost:9000",
+            "allow_http": true
+        }"#;
+
+        let t: StorageBackendConfig = serde_json::from_str(data).unwrap();
+
+        match t {
+            StorageBackendConfig::S3(s3) => {
+                assert_eq!(s3.bucket, "registry");
+                assert_eq!(s3.region, "us-east-1");
+                assert_eq!(s3.endpoint, Some("http://localhost:9000".to_string()));
+                assert_eq!(s3.prefix, "");
+                assert!(s3.allow_http);
+            }
+            _ => panic!("Expected an s3 storage backend"),
+        }
+    }
+
+    #[test]
+    fn authorizer_config() {
+        let defaults: Configuration = Figment::from(Serialized::defaults(Configuration::default()))
+            .extract()
+            .unwrap();
+        assert!(matches!(defaults.authorizer, AuthorizerConfig::Claims));
+
+        let data = r#"
+        {
+            "type": "http",
+            "url": "http://localhost:8181/v1/data/distribd/allow"
+        }"#;
+
+        let t: AuthorizerConfig = serde_json::from_str(data).unwrap();
+
+        match t {
+            AuthorizerConfig::Http { url, cache_ttl } => {
+                assert_eq!(url, "http://localhost:8181/v1/data/distribd/allow");
+                assert_eq!(cache_ttl, 60);
+            }
+            _ => panic!("Expected an http authorizer"),
+        }
     }
 }
@@ -1,15 +1,13 @@
 use crate::{
     app::RegistryApp,
+    storage::ObjectKind,
     types::{Digest, RegistryAction, RepositoryName},
 };
 use chrono::prelude::*;
 use jsonschema::JSONSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
-};
+use std::collections::{HashMap, HashSet};
 use tracing::debug;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -53,6 +51,12 @@ enum Manifest {
     },
 }
 
+/// The OCI `subject` field of a manifest that refers to another one, like a signature or an SBOM.
+#[derive(Debug, Deserialize)]
+struct Referrer {
+    subject: Option<ManifestV2Layer>,
+}
+
 #[derive(Clone)]
 pub struct Extractor {
     schemas: HashMap<String, Value>,
@@ -145,21 +149,16 @@ impl Extractor {
         }
     }
 
-    pub async fn parse_manifest(
+    pub fn parse_manifest(
         &self,
-        path: PathBuf,
+        data: &str,
         content_type: &str,
     ) -> Result<HashSet<Extraction>, ExtractError> {
-        let data = match tokio::fs::read_to_string(&path).await {
-            Ok(data) => data,
-            _ => return Err(ExtractError::UnknownError {}),
-        };
-
-        if !self.validate(content_type, &data) {
+        if !self.validate(content_type, data) {
             return Err(ExtractError::SchemaValidationError {});
         }
 
-        self.extract_one(content_type, &data)
+        self.extract_one(content_type, data)
     }
 
     fn extract_one(
@@ -213,6 +212,11 @@ impl Extractor {
         Ok(results)
     }
 
+    fn extract_subject(&self, data: &str) -> Option<Digest> {
+        let referrer: Referrer = serde_json::from_str(data).ok()?;
+        Some(referrer.subject?.digest)
+    }
+
     pub async fn extract(
         &self,
         app: &RegistryApp,
@@ -254,6 +258,15 @@ impl Extractor {
             }
         }
 
+        // The subject doesn't have to exist yet, referrers can be pushed first
+        if let Some(subject) = self.extract_subject(&data) {
+            analysis.push(RegistryAction::ManifestSubject {
+                timestamp: Utc::now(),
+                digest: digest.clone(),
+                subject,
+            });
+        }
+
         drop(data);
 
         while !pending.is_empty() {
@@ -264,6 +277,17 @@ impl Extractor {
                     continue;
                 }
 
+                if let Some(manifest) = app.get_manifest(&extraction.digest) {
+                    // An image in an index was pushed, and analyzed, as a manifest of its own
+                    if !manifest.repositories.contains(repository) {
+                        return Err(ExtractError::UnknownError {});
+                    }
+
+                    seen.insert(extraction.digest);
+
+                    continue;
+                }
+
                 match app.get_blob(&extraction.digest) {
                     Some(blob) => {
                         if blob.content_type.is_some() {
@@ -302,10 +326,16 @@ impl Extractor {
                 }
 
                 // Lookup extraction.digest in blob store
-                let data = tokio::fs::read_to_string(app.get_blob_path(&extraction.digest)).await;
+                let data = app
+                    .storage
+                    .read(ObjectKind::Blob, &extraction.digest)
+                    .await
+                    .ok()
+                    .flatten()
+                    .and_then(|data| String::from_utf8(data).ok());
 
                 match data {
-                    Ok(data) => {
+                    Some(data) => {
                         let dependencies = self.extract_one(&extraction.content_type, &data);
                         match dependencies {
                             Ok(dependencies) => {
@@ -497,6 +527,39 @@ mod tests {
         assert!(extractor.validate(&content_type, &data));
     }
 
+    #[test]
+    fn subject() {
+        let extractor = Extractor::new();
+
+        let data = r#"
+            {
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {
+                    "mediaType": "application/vnd.oci.empty.v1+json",
+                    "size": 2,
+                    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+                },
+                "layers": [],
+                "subject": {
+                    "mediaType": "application/vnd.oci.image.index.v1+json",
+                    "size": 7682,
+                    "digest": "sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270"
+                }
+            }
+        "#;
+
+        assert_eq!(
+            extractor.extract_subject(data),
+            Some(
+                "sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270"
+                    .parse()
+                    .unwrap()
+            )
+        );
+        assert_eq!(extractor.extract_subject("{\"layers\": []}"), None);
+    }
+
     #[test]
     fn signed_v2_1_manifest() {
         let extractor = Extractor::new();
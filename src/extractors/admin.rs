@@ -0,0 +1,131 @@
+use crate::app::RegistryApp;
+use crate::certificate::ClientCertificate;
+use crate::extractors::token::verification_options;
+use actix_web::{
+    http::StatusCode, web::Data, FromRequest, HttpRequest, HttpResponse, HttpResponseBuilder,
+    ResponseError,
+};
+use futures_util::future::{ready, Ready};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, info};
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("The management API requires an admin token or client certificate")]
+    Missing,
+    #[error("The authorization token contains invalid data")]
+    Invalid,
+    #[error("These credentials can't be used for the management API")]
+    Forbidden,
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::Missing | AdminError::Invalid => StatusCode::UNAUTHORIZED,
+            AdminError::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code()).body(self.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AdminClaims {
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// A caller that may use the management API, either with a token that has an `admin` claim
+/// or a client certificate listed in `raft.tls.admins`.
+///
+/// Like [`crate::extractors::Token`], if neither a token server nor admin certificates are
+/// configured everyone is an admin.
+pub(crate) struct Admin {
+    pub subject: String,
+}
+
+impl FromRequest for Admin {
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Error = AdminError;
+
+    fn from_request(
+        req: &HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> <Self as FromRequest>::Future {
+        let app = req.app_data::<Data<RegistryApp>>().unwrap();
+
+        let admins = match &app.config.raft.tls {
+            Some(tls) => tls.admins.as_slice(),
+            None => &[],
+        };
+
+        let token_config = app.config.token_server.as_ref();
+
+        if token_config.is_none() && admins.is_empty() {
+            return ready(Ok(Admin {
+                subject: "$admin".to_string(),
+            }));
+        }
+
+        let certificate = req.conn_data::<ClientCertificate>();
+
+        if let Some(certificate) = certificate {
+            if let Some(name) = certificate.names.iter().find(|name| admins.contains(name)) {
+                debug!("Client certificate for {name} is an admin");
+                return ready(Ok(Admin {
+                    subject: name.clone(),
+                }));
+            }
+        }
+
+        let header = match req.headers().get("authorization") {
+            Some(header) => header.to_str().unwrap_or_default(),
+            None if certificate.is_some() => {
+                info!("Client certificate is not an admin");
+                return ready(Err(AdminError::Forbidden));
+            }
+            None => return ready(Err(AdminError::Missing)),
+        };
+
+        let token_bytes = match header.split_once(' ') {
+            Some((token_type, token_bytes)) if token_type.eq_ignore_ascii_case("bearer") => {
+                token_bytes
+            }
+            _ => {
+                info!("Not bearer token");
+                return ready(Err(AdminError::Invalid));
+            }
+        };
+
+        let config = match token_config {
+            Some(config) => config,
+            None => return ready(Err(AdminError::Invalid)),
+        };
+
+        let claims = match app
+            .token_keys
+            .verify::<AdminClaims>(token_bytes, verification_options(config))
+        {
+            Ok(claims) => claims,
+            Err(error) => {
+                info!("Could not verify admin token: {error}");
+                return ready(Err(AdminError::Invalid));
+            }
+        };
+
+        let subject = claims.subject.unwrap_or_else(|| "$admin".to_string());
+
+        if !claims.custom.admin {
+            info!("Token for \"{subject}\" does not have the admin claim");
+            return ready(Err(AdminError::Forbidden));
+        }
+
+        debug!("Validated admin token for subject \"{subject}\"");
+
+        ready(Ok(Admin { subject }))
+    }
+}
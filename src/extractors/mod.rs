@@ -1,3 +1,8 @@
+pub mod admin;
+pub mod peer;
 pub mod token;
 
+pub(crate) use admin::Admin;
+pub(crate) use peer::Peer;
+pub(crate) use peer::PeerOrAdmin;
 pub(crate) use token::Token;
@@ -0,0 +1,82 @@
+use crate::app::RegistryApp;
+use crate::certificate::ClientCertificate;
+use crate::extractors::admin::{Admin, AdminError};
+use actix_web::{
+    http::StatusCode, web::Data, FromRequest, HttpRequest, HttpResponse, HttpResponseBuilder,
+    ResponseError,
+};
+use futures_util::future::{ready, Ready};
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error("Only cluster members can use this endpoint")]
+    Forbidden,
+}
+
+impl ResponseError for PeerError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::FORBIDDEN
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code()).body(self.to_string())
+    }
+}
+
+/// Another member of the cluster, for mirroring.
+///
+/// Peers are identified by a client certificate signed by the cluster CA, so admin tokens
+/// don't grant access. Without mutual TLS there is nothing to check and the raft port must
+/// be protected by the network instead.
+pub(crate) struct Peer;
+
+impl FromRequest for Peer {
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Error = PeerError;
+
+    fn from_request(
+        req: &HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> <Self as FromRequest>::Future {
+        let app = req.app_data::<Data<RegistryApp>>().unwrap();
+
+        if app.config.raft.mutual_tls() && req.conn_data::<ClientCertificate>().is_none() {
+            info!("Rejecting peer request without a client certificate");
+            return ready(Err(PeerError::Forbidden));
+        }
+
+        ready(Ok(Peer))
+    }
+}
+
+/// A caller that can change the cluster's state directly, including with raft RPC: a peer with
+/// a certificate signed by the cluster CA, or an admin.
+///
+/// Unlike [`Peer`], this doesn't let everyone in when there is no mutual TLS, as that would
+/// get round every [`Admin`] check. Members then use an admin token from the builtin token
+/// server instead, see [`RegistryApp::peer_token`].
+pub(crate) struct PeerOrAdmin;
+
+impl FromRequest for PeerOrAdmin {
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Error = AdminError;
+
+    fn from_request(
+        req: &HttpRequest,
+        payload: &mut actix_web::dev::Payload,
+    ) -> <Self as FromRequest>::Future {
+        let app = req.app_data::<Data<RegistryApp>>().unwrap();
+
+        if app.config.raft.mutual_tls() && req.conn_data::<ClientCertificate>().is_some() {
+            return ready(Ok(PeerOrAdmin));
+        }
+
+        ready(
+            Admin::from_request(req, payload)
+                .into_inner()
+                .map(|_| PeerOrAdmin),
+        )
+    }
+}
@@ -1,13 +1,19 @@
 use crate::app::RegistryApp;
+use crate::authorizer::{AuthorizationRequest, Authorizer};
+use crate::config::TokenConfig;
+use crate::token_server::parse_basic_credentials;
 use crate::types::RepositoryName;
+use crate::utils::glob_match;
 use actix_web::{
     web::Data, FromRequest, HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError,
 };
-use futures_util::future::{ready, Ready};
+use chrono::{DateTime, Utc};
+use futures_util::future::{ready, FutureExt, LocalBoxFuture};
 use jwt_simple::prelude::*;
 use std::collections::HashSet;
+use std::sync::Arc;
 use thiserror::Error;
-use tracing::{debug, info};
+use tracing::{debug, error, info};
 
 #[derive(Debug, Error)]
 pub enum TokenError {
@@ -15,20 +21,32 @@ pub enum TokenError {
     Missing,
     #[error("The authorization token contains invalid data")]
     Invalid,
+    #[error("Invalid username or password")]
+    BadCredentials { challenge: String },
+    #[error("Unable to check access")]
+    Unavailable,
 }
 
 impl ResponseError for TokenError {
     fn status_code(&self) -> actix_web::http::StatusCode {
-        actix_web::http::StatusCode::FORBIDDEN
+        match self {
+            Self::BadCredentials { .. } => actix_web::http::StatusCode::UNAUTHORIZED,
+            Self::Unavailable => actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
+            _ => actix_web::http::StatusCode::FORBIDDEN,
+        }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponseBuilder::new(self.status_code()).finish()
+        let mut builder = HttpResponseBuilder::new(self.status_code());
+        if let Self::BadCredentials { challenge } = self {
+            builder.append_header(("Www-Authenticate", challenge.clone()));
+        }
+        builder.finish()
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub(crate) struct Access {
+pub struct Access {
     #[serde(rename = "name")]
     pub repository: RepositoryName,
     #[serde(rename = "actions")]
@@ -36,7 +54,7 @@ pub(crate) struct Access {
 }
 
 #[derive(Serialize, Deserialize)]
-struct AdditionalClaims {
+pub(crate) struct AdditionalClaims {
     access: Vec<Access>,
 }
 
@@ -44,13 +62,27 @@ pub(crate) struct Token {
     pub access: Vec<Access>,
     pub sub: String,
     pub validated_token: bool,
+    /// Set when the caller didn't log in, including for tokens the token server issued to
+    /// anonymous callers, which have no subject. `sub` isn't an account then.
+    anonymous: bool,
+    /// When a bearer token stops being valid. Callers that log in with a password, or don't
+    /// log in at all, have no deadline.
+    pub expires: Option<DateTime<Utc>>,
     admin: bool,
     realm: Option<String>,
     service: Option<String>,
+    /// Set when callers log in with a username and password rather than a bearer token.
+    basic_realm: Option<String>,
+    public_repositories: Vec<String>,
+    authorizer: Arc<dyn Authorizer>,
 }
 
 impl Token {
     pub fn get_challenge(&self, access: Vec<Access>) -> String {
+        if let Some(realm) = &self.basic_realm {
+            return format!("Basic realm=\"{realm}\"");
+        }
+
         let service = self
             .service
             .as_ref()
@@ -90,7 +122,18 @@ impl Token {
         }])
     }
 
+    pub fn get_delete_challenge(&self, repository: &RepositoryName) -> String {
+        self.get_challenge(vec![Access {
+            repository: repository.clone(),
+            permissions: HashSet::from(["delete".to_string()]),
+        }])
+    }
+
     pub fn get_general_challenge(&self) -> String {
+        if let Some(realm) = &self.basic_realm {
+            return format!("Basic realm=\"{realm}\"");
+        }
+
         let service = self
             .service
             .as_ref()
@@ -103,37 +146,64 @@ impl Token {
         format!("Bearer realm=\"{realm}\",service=\"{service}\"")
     }
 
-    pub fn has_permission(&self, repository: &RepositoryName, permission: &str) -> bool {
-        if !self.validated_token {
-            debug!("Not a validated token");
-            return false;
+    /// Whether anyone may pull from `repository`, even without a token.
+    pub fn is_public(&self, repository: &RepositoryName) -> bool {
+        self.public_repositories
+            .iter()
+            .any(|pattern| glob_match(pattern, &repository.name))
+    }
+
+    pub async fn has_permission(&self, repository: &RepositoryName, permission: &str) -> bool {
+        if permission == "pull" && self.is_public(repository) {
+            debug!("{repository} is public");
+            return true;
         }
 
-        if self.admin {
+        if self.validated_token && self.admin {
             debug!("Got an admin token");
             return true;
         }
 
         debug!("Need {permission} for {repository}");
 
-        for access in self.access.iter() {
-            debug!("Checking {access:?}");
+        // Callers that didn't log in are asked about too, as a policy can grant access to anyone
+        let request = AuthorizationRequest {
+            subject: (self.validated_token && !self.anonymous).then_some(self.sub.as_str()),
+            repository,
+            action: permission,
+            grants: &self.access,
+        };
 
-            if &access.repository == repository
-                && access.permissions.contains(&permission.to_string())
-            {
-                return true;
-            }
+        if self.authorizer.authorize(&request).await {
+            return true;
         }
 
-        info!("Didn't find a matching access rule");
+        info!(
+            "Authorizer denied {permission} on {repository} for {}",
+            self.sub
+        );
 
         false
     }
 }
 
+/// How tokens from the token server are checked, for both registry and admin tokens.
+pub(crate) fn verification_options(config: &TokenConfig) -> VerificationOptions {
+    VerificationOptions {
+        // accept tokens even if they have expired up to 15 minutes after the deadline
+        time_tolerance: Some(Duration::from_mins(15)),
+        // reject tokens if they were issued more than 1 hour ago
+        max_validity: Some(Duration::from_hours(1)),
+        // reject tokens if they don't include an issuer from that list
+        allowed_issuers: Some(HashSet::from_strings(&[config.issuer.clone()])),
+        // validate it is a token for us
+        allowed_audiences: Some(HashSet::from_strings(&[config.service.clone()])),
+        ..Default::default()
+    }
+}
+
 impl FromRequest for Token {
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
     type Error = TokenError;
 
     fn from_request(
@@ -143,15 +213,29 @@ impl FromRequest for Token {
         let app = req.app_data::<Data<RegistryApp>>().unwrap();
 
         let config = match &app.config.token_server {
+            None if app.basic_auth.is_some() => {
+                let header = req
+                    .headers()
+                    .get("authorization")
+                    .and_then(|header| header.to_str().ok())
+                    .map(|header| header.to_string());
+                return from_basic_auth(app.clone(), header).boxed_local();
+            }
             None => {
                 return ready(Ok(Token {
                     access: vec![],
                     sub: "anonymous".to_string(),
+                    anonymous: true,
+                    expires: None,
                     admin: true,
                     validated_token: true,
                     service: None,
                     realm: None,
-                }));
+                    basic_realm: None,
+                    public_repositories: vec![],
+                    authorizer: app.authorizer.clone(),
+                }))
+                .boxed_local();
             }
             Some(config) => config,
         };
@@ -162,65 +246,220 @@ impl FromRequest for Token {
                 return ready(Ok(Token {
                     access: vec![],
                     sub: "anonymous".to_string(),
+                    anonymous: true,
+                    expires: None,
                     admin: false,
                     validated_token: false,
                     service: Some(config.service.clone()),
                     realm: Some(config.realm.clone()),
-                }));
+                    basic_realm: None,
+                    public_repositories: config.public_repositories.clone(),
+                    authorizer: app.authorizer.clone(),
+                }))
+                .boxed_local();
             }
         };
 
         let (token_type, token_bytes) = match header.split_once(' ') {
             Some(value) => value,
-            _ => return ready(Err(TokenError::Missing)),
+            _ => return ready(Err(TokenError::Missing)).boxed_local(),
         };
 
         if token_type.to_lowercase() != "bearer" {
             info!("Not bearer token");
-            return ready(Err(TokenError::Invalid));
+            return ready(Err(TokenError::Invalid)).boxed_local();
         }
 
-        let options = VerificationOptions {
-            // accept tokens even if they have expired up to 15 minutes after the deadline
-            time_tolerance: Some(Duration::from_mins(15)),
-            // reject tokens if they were issued more than 1 hour ago
-            max_validity: Some(Duration::from_hours(1)),
-            // reject tokens if they don't include an issuer from that list
-            allowed_issuers: Some(HashSet::from_strings(&[config.issuer.clone()])),
-            // validate it is a token for us
-            allowed_audiences: Some(HashSet::from_strings(&[config.service.clone()])),
-            ..Default::default()
-        };
-
-        let claims = match config
-            .public_key
-            .public_key
-            .verify_token::<AdditionalClaims>(token_bytes, Some(options))
+        let claims = match app
+            .token_keys
+            .verify::<AdditionalClaims>(token_bytes, verification_options(config))
         {
             Ok(claims) => claims,
             Err(error) => {
                 info!("Could not verify token: {error}");
-                return ready(Err(TokenError::Invalid));
+                return ready(Err(TokenError::Invalid)).boxed_local();
             }
         };
 
-        let subject = match claims.subject {
-            Some(subject) => subject,
-            _ => {
-                info!("Could not retrieve subject from token");
-                return ready(Err(TokenError::Invalid));
-            }
-        };
+        ready(Ok(Token::bearer(claims, config, app.authorizer.clone()))).boxed_local()
+    }
+}
+
+impl Token {
+    /// The caller of a verified bearer token. Tokens the token server issues to callers that
+    /// didn't log in have no subject, so they can't be mistaken for an account.
+    pub(crate) fn bearer(
+        claims: JWTClaims<AdditionalClaims>,
+        config: &TokenConfig,
+        authorizer: Arc<dyn Authorizer>,
+    ) -> Token {
+        let subject = claims.subject.filter(|subject| !subject.is_empty());
 
-        debug!("Validated token for subject \"{subject}\"");
+        match &subject {
+            Some(subject) => debug!("Validated token for subject \"{subject}\""),
+            None => debug!("Validated anonymous token"),
+        }
 
-        ready(Ok(Token {
-            access: claims.custom.access.clone(),
-            sub: subject,
+        Token {
+            access: claims.custom.access,
+            anonymous: subject.is_none(),
+            expires: claims
+                .expires_at
+                .and_then(|expires| DateTime::from_timestamp(expires.as_secs() as i64, 0)),
+            sub: subject.unwrap_or_else(|| "anonymous".to_string()),
             admin: false,
             validated_token: true,
             service: Some(config.service.clone()),
             realm: Some(config.realm.clone()),
+            basic_realm: None,
+            public_repositories: config.public_repositories.clone(),
+            authorizer,
+        }
+    }
+}
+
+/// Log in with a username and password, for clusters that use `basic_auth` rather than a
+/// token server. The caller is granted whatever ACL entries apply to them.
+async fn from_basic_auth(
+    app: Data<RegistryApp>,
+    header: Option<String>,
+) -> Result<Token, TokenError> {
+    let auth = app.basic_auth.as_ref().unwrap();
+
+    let mut token = Token {
+        access: vec![],
+        sub: "anonymous".to_string(),
+        anonymous: true,
+        expires: None,
+        admin: false,
+        validated_token: false,
+        service: None,
+        realm: None,
+        basic_realm: Some(auth.realm.clone()),
+        public_repositories: vec![],
+        authorizer: app.authorizer.clone(),
+    };
+
+    let header = match header {
+        Some(header) => header,
+        None => return Ok(token),
+    };
+
+    let (username, password) = match parse_basic_credentials(&header) {
+        Some(credentials) => credentials,
+        None => {
+            info!("Not basic credentials");
+            return Err(TokenError::Invalid);
+        }
+    };
+
+    let account = match auth.authenticate(&app, &username, &password).await {
+        Some(account) => account,
+        None => {
+            info!("Rejected credentials for {username}");
+            return Err(TokenError::BadCredentials {
+                challenge: token.get_general_challenge(),
+            });
+        }
+    };
+
+    token.access = auth.grants(&app, &account).map_err(|err| {
+        error!("Unable to read ACL entries: {err:?}");
+        TokenError::Unavailable
+    })?;
+
+    debug!("Validated credentials for \"{account}\"");
+
+    token.sub = account;
+    token.anonymous = false;
+    token.validated_token = true;
+
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorizer::{ClaimsAuthorizer, PolicyAuthorizer};
+
+    fn token(access: &[(&str, &[&str])], validated_token: bool) -> Token {
+        Token {
+            access: access
+                .iter()
+                .map(|(repository, permissions)| Access {
+                    repository: repository.parse().unwrap(),
+                    permissions: permissions.iter().map(|p| p.to_string()).collect(),
+                })
+                .collect(),
+            sub: "test".to_string(),
+            anonymous: !validated_token,
+            expires: None,
+            validated_token,
+            admin: false,
+            realm: Some("realm".to_string()),
+            basic_realm: None,
+            service: Some("service".to_string()),
+            public_repositories: vec!["public/*".to_string()],
+            authorizer: Arc::new(ClaimsAuthorizer),
+        }
+    }
+
+    async fn can(token: &Token, repository: &str, permission: &str) -> bool {
+        token
+            .has_permission(&repository.parse().unwrap(), permission)
+            .await
+    }
+
+    #[tokio::test]
+    async fn exact_scopes() {
+        let token = token(&[("team-a/app", &["pull", "push"])], true);
+        assert!(can(&token, "team-a/app", "pull").await);
+        assert!(can(&token, "team-a/app", "push").await);
+        assert!(!can(&token, "team-a/app2", "pull").await);
+        assert!(!can(&token, "team-b/app", "pull").await);
+    }
+
+    #[tokio::test]
+    async fn wildcard_scopes() {
+        let token = token(&[("team-a/*", &["pull"]), ("team-b/app", &["*"])], true);
+        assert!(can(&token, "team-a/app", "pull").await);
+        assert!(can(&token, "team-a/nested/app", "pull").await);
+        assert!(!can(&token, "team-a/app", "push").await);
+        assert!(!can(&token, "team-ab/app", "pull").await);
+        assert!(can(&token, "team-b/app", "delete").await);
+    }
+
+    #[tokio::test]
+    async fn delete_is_separate() {
+        let token = token(&[("team-a/app", &["pull", "push"])], true);
+        assert!(!can(&token, "team-a/app", "delete").await);
+    }
+
+    #[tokio::test]
+    async fn public_repositories() {
+        let anonymous = token(&[], false);
+        assert!(anonymous.is_public(&"public/app".parse().unwrap()));
+        assert!(can(&anonymous, "public/app", "pull").await);
+        assert!(!can(&anonymous, "public/app", "push").await);
+        assert!(!can(&anonymous, "private/app", "pull").await);
+    }
+
+    #[tokio::test]
+    async fn anonymous_policy_rules() {
+        let policy = serde_json::from_value(serde_json::json!({
+            "rules": [
+                {"anonymous": true, "repositories": ["open/*"], "actions": ["pull"]},
+                {"accounts": ["test"], "repositories": ["team-a/*"], "actions": ["pull"]},
+            ]
         }))
+        .unwrap();
+
+        let mut anonymous = token(&[], false);
+        anonymous.authorizer = Arc::new(PolicyAuthorizer::new(policy));
+        assert!(can(&anonymous, "open/app", "pull").await);
+        assert!(!can(&anonymous, "open/app", "push").await);
+
+        // The subject of a token that wasn't validated isn't trusted
+        assert!(!can(&anonymous, "team-a/app", "pull").await);
     }
 }
@@ -0,0 +1,803 @@
+//! Consistency checks between the replicated metadata and the objects stored on a node.
+//!
+//! `fsck` checks that every blob and manifest this node is meant to have is in storage with
+//! the right size and digest, that manifests only refer to blobs in the same repository, that
+//! tags point at manifests mounted in their repository, and that there is nothing in storage
+//! that nothing refers to or that is stored twice. It can run against a live node's exported
+//! state, or directly against the database and storage directory of a node that is stopped.
+//!
+//! A cluster-wide `fsck` asks every member to check its own objects and merges the results,
+//! to find objects that have no healthy copies left or fewer than they should.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono::Utc;
+use futures::future::join_all;
+use openraft::error::Infallible;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::app::RegistryApp;
+use crate::extractor::Extractor;
+use crate::network::management::ImportBody;
+use crate::scrubber::{check_stored, ObjectKind, Problem};
+use crate::storage::{Storage, StoredObject};
+use crate::store::SerializableRegistryStateMachine;
+use crate::types::{Digest, RegistryAction, RepositoryName};
+use crate::RegistryNodeId;
+use crate::RegistryStore;
+
+/// Something `fsck` found wrong.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Issue {
+    /// A blob or manifest this node is meant to have is missing or corrupt.
+    Stored {
+        object: ObjectKind,
+        digest: Digest,
+        #[serde(flatten)]
+        problem: Problem,
+    },
+    /// A manifest couldn't be parsed.
+    InvalidManifest { digest: Digest },
+    /// A manifest refers to a blob or manifest that doesn't exist.
+    MissingDependency { digest: Digest, dependency: Digest },
+    /// A manifest refers to a blob that isn't in one of the manifest's repositories.
+    DependencyNotMounted {
+        digest: Digest,
+        dependency: Digest,
+        repository: RepositoryName,
+    },
+    /// A manifest declares a different size for a blob than the blob has.
+    DependencySize {
+        digest: Digest,
+        dependency: Digest,
+        declared: u64,
+        actual: Option<u64>,
+    },
+    /// A tag points at a manifest that doesn't exist.
+    DanglingTag {
+        repository: RepositoryName,
+        tag: String,
+        digest: Digest,
+    },
+    /// A tag points at a manifest that isn't in the tag's repository.
+    TagNotMounted {
+        repository: RepositoryName,
+        tag: String,
+        digest: Digest,
+    },
+    /// Something in storage that no stored blob or manifest refers to.
+    UnreferencedFile { location: String },
+    /// A second copy of an object, in another data directory. Only one of them is read, and
+    /// a copy that isn't read isn't checked.
+    DuplicateFile { location: String },
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Issue::Stored {
+                object,
+                digest,
+                problem,
+            } => write!(f, "Stored {object:?} {digest} is corrupt: {problem:?}"),
+            Issue::InvalidManifest { digest } => write!(f, "Manifest {digest} is not valid"),
+            Issue::MissingDependency { digest, dependency } => {
+                write!(
+                    f,
+                    "Manifest {digest} refers to {dependency}, which is missing"
+                )
+            }
+            Issue::DependencyNotMounted {
+                digest,
+                dependency,
+                repository,
+            } => write!(
+                f,
+                "Manifest {digest} refers to {dependency}, which is not in {repository}"
+            ),
+            Issue::DependencySize {
+                digest,
+                dependency,
+                declared,
+                actual,
+            } => write!(
+                f,
+                "Manifest {digest} declares {dependency} is {declared} bytes, but it is {actual:?}"
+            ),
+            Issue::DanglingTag {
+                repository,
+                tag,
+                digest,
+            } => write!(
+                f,
+                "Tag {repository}:{tag} points at {digest}, which is missing"
+            ),
+            Issue::TagNotMounted {
+                repository,
+                tag,
+                digest,
+            } => write!(
+                f,
+                "Tag {repository}:{tag} points at {digest}, which is not in {repository}"
+            ),
+            Issue::UnreferencedFile { location } => {
+                write!(f, "{location} is not referred to by anything")
+            }
+            Issue::DuplicateFile { location } => {
+                write!(
+                    f,
+                    "{location} is another copy of something stored elsewhere"
+                )
+            }
+        }
+    }
+}
+
+/// What `fsck` checked and found.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FsckReport {
+    pub blobs: usize,
+    pub manifests: usize,
+    pub tags: usize,
+    pub issues: Vec<Issue>,
+    /// Actions that would bring the metadata back in line with what is on disk.
+    pub fixes: Vec<RegistryAction>,
+}
+
+fn unstore(object: ObjectKind, digest: &Digest, location: RegistryNodeId) -> RegistryAction {
+    match object {
+        ObjectKind::Blob => RegistryAction::BlobUnstored {
+            timestamp: Utc::now(),
+            digest: digest.clone(),
+            location,
+            user: "$fsck".to_string(),
+        },
+        ObjectKind::Manifest => RegistryAction::ManifestUnstored {
+            timestamp: Utc::now(),
+            digest: digest.clone(),
+            location,
+            user: "$fsck".to_string(),
+        },
+    }
+}
+
+/// Check the objects `node_id` is meant to have in `storage` against `state`.
+pub async fn check(
+    state: &ImportBody,
+    storage: &dyn Storage,
+    node_id: RegistryNodeId,
+) -> FsckReport {
+    let mut report = FsckReport {
+        blobs: state.blobs.len(),
+        manifests: state.manifests.len(),
+        ..Default::default()
+    };
+
+    for (digest, blob) in state.blobs.iter() {
+        if !blob.locations.contains(&node_id) {
+            continue;
+        }
+
+        if let (Some(problem), _) = check_stored(storage, ObjectKind::Blob, digest, blob.size).await
+        {
+            // A copy that may be on an offline data directory, or that couldn't be read this
+            // time, isn't known to be bad
+            if problem.is_confirmed() {
+                report
+                    .fixes
+                    .push(unstore(ObjectKind::Blob, digest, node_id));
+            }
+            report.issues.push(Issue::Stored {
+                object: ObjectKind::Blob,
+                digest: digest.clone(),
+                problem,
+            });
+        }
+    }
+
+    let extractor = Extractor::new();
+
+    for (digest, manifest) in state.manifests.iter() {
+        if !manifest.locations.contains(&node_id) {
+            continue;
+        }
+
+        if let (Some(problem), _) =
+            check_stored(storage, ObjectKind::Manifest, digest, manifest.size).await
+        {
+            if problem.is_confirmed() {
+                report
+                    .fixes
+                    .push(unstore(ObjectKind::Manifest, digest, node_id));
+            }
+            report.issues.push(Issue::Stored {
+                object: ObjectKind::Manifest,
+                digest: digest.clone(),
+                problem,
+            });
+            continue;
+        }
+
+        let Some(content_type) = &manifest.content_type else {
+            continue;
+        };
+
+        let data = match storage.read(ObjectKind::Manifest, digest).await {
+            Ok(Some(data)) => String::from_utf8(data).ok(),
+            _ => None,
+        };
+
+        let Some(Ok(extractions)) = data.map(|data| extractor.parse_manifest(&data, content_type))
+        else {
+            report.issues.push(Issue::InvalidManifest {
+                digest: digest.clone(),
+            });
+            continue;
+        };
+
+        for extraction in extractions {
+            // An image index refers to other manifests rather than blobs
+            let (repositories, size) = match (
+                state.blobs.get(&extraction.digest),
+                state.manifests.get(&extraction.digest),
+            ) {
+                (Some(blob), _) => (&blob.repositories, blob.size),
+                (None, Some(child)) => (&child.repositories, child.size),
+                (None, None) => {
+                    report.issues.push(Issue::MissingDependency {
+                        digest: digest.clone(),
+                        dependency: extraction.digest,
+                    });
+                    continue;
+                }
+            };
+
+            let mut missing: Vec<&RepositoryName> = manifest
+                .repositories
+                .iter()
+                .filter(|repository| !repositories.contains(*repository))
+                .collect();
+            missing.sort();
+            for repository in missing {
+                report.issues.push(Issue::DependencyNotMounted {
+                    digest: digest.clone(),
+                    dependency: extraction.digest.clone(),
+                    repository: repository.clone(),
+                });
+            }
+
+            if let Some(declared) = extraction.size {
+                if size != Some(declared) {
+                    report.issues.push(Issue::DependencySize {
+                        digest: digest.clone(),
+                        dependency: extraction.digest.clone(),
+                        declared,
+                        actual: size,
+                    });
+                }
+            }
+        }
+    }
+
+    for (repository, tags) in state.tags.iter() {
+        for (tag, digest) in tags.iter() {
+            report.tags += 1;
+            match state.manifests.get(digest) {
+                None => report.issues.push(Issue::DanglingTag {
+                    repository: repository.clone(),
+                    tag: tag.clone(),
+                    digest: digest.clone(),
+                }),
+                Some(manifest) if !manifest.repositories.contains(repository) => {
+                    report.issues.push(Issue::TagNotMounted {
+                        repository: repository.clone(),
+                        tag: tag.clone(),
+                        digest: digest.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    let stored: [(ObjectKind, HashSet<&Digest>); 2] = [
+        (
+            ObjectKind::Blob,
+            state
+                .blobs
+                .iter()
+                .filter(|(_, blob)| blob.locations.contains(&node_id))
+                .map(|(digest, _)| digest)
+                .collect(),
+        ),
+        (
+            ObjectKind::Manifest,
+            state
+                .manifests
+                .iter()
+                .filter(|(_, manifest)| manifest.locations.contains(&node_id))
+                .map(|(digest, _)| digest)
+                .collect(),
+        ),
+    ];
+
+    for (object, stored) in stored {
+        match storage.list(object).await {
+            Ok(objects) => {
+                let mut seen = HashSet::new();
+                for StoredObject { location, digest } in objects {
+                    match digest {
+                        Some(digest) if stored.contains(&digest) => {
+                            if !seen.insert(digest) {
+                                report.issues.push(Issue::DuplicateFile { location });
+                            }
+                        }
+                        _ => report.issues.push(Issue::UnreferencedFile { location }),
+                    }
+                }
+            }
+            Err(err) => error!("fsck: Unable to list stored {}s: {err:?}", object.as_str()),
+        }
+    }
+
+    report
+}
+
+fn state_of(store: &RegistryStore) -> ImportBody {
+    let sm = store.state_machine.read().unwrap();
+    let state_machine = SerializableRegistryStateMachine::from(&*sm);
+
+    ImportBody {
+        blobs: state_machine.blobs,
+        manifests: state_machine.manifests,
+        tags: state_machine.tags,
+        referrers: state_machine.referrers,
+    }
+}
+
+/// Read the state of a node that isn't running from its database.
+pub async fn load_offline(storage: &str, node_id: RegistryNodeId) -> anyhow::Result<ImportBody> {
+    let path = Path::new(storage).join("db");
+    let db = sled::open(&path).context(format!(
+        "Unable to open {path:?}, is the node still running?"
+    ))?;
+
+    let mut registry = prometheus_client::registry::Registry::default();
+    let store = RegistryStore::new(Arc::new(db), node_id, &mut registry).await;
+
+    Ok(state_of(&store))
+}
+
+/// Check the objects stored on this node against its copy of the replicated metadata.
+pub async fn check_local(app: &RegistryApp) -> FsckReport {
+    let state = state_of(&app.store);
+    check(&state, app.storage.as_ref(), app.id).await
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClusterFsckRequest {
+    /// Submit every node's fixes in one transaction.
+    #[serde(default)]
+    pub repair: bool,
+    /// How many healthy copies each object should have. Defaults to one on every node.
+    pub replicas: Option<usize>,
+}
+
+/// What one node found, or why it couldn't be asked.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeFsck {
+    pub report: Option<FsckReport>,
+    pub error: Option<String>,
+}
+
+/// Where the copies of an object are, according to the nodes that are meant to have them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Copies {
+    pub object: ObjectKind,
+    pub digest: Digest,
+    pub healthy: BTreeSet<RegistryNodeId>,
+    pub corrupt: BTreeSet<RegistryNodeId>,
+    /// Nodes meant to have a copy that couldn't be asked, or whose copy is on a data
+    /// directory that is offline.
+    pub unverified: BTreeSet<RegistryNodeId>,
+}
+
+/// The results of every node's `fsck`, merged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterFsckReport {
+    pub replicas: usize,
+    pub nodes: BTreeMap<RegistryNodeId, NodeFsck>,
+    /// Objects in a repository that no node has a healthy copy of.
+    pub unavailable: Vec<Copies>,
+    /// Objects with some healthy copies, but fewer than `replicas`.
+    pub under_replicated: Vec<Copies>,
+    /// Whether the fixes were submitted.
+    pub repaired: bool,
+}
+
+/// Merge what each node found with the metadata, to find how many healthy copies of each
+/// object there are.
+pub fn merge(
+    state: &ImportBody,
+    replicas: usize,
+    nodes: BTreeMap<RegistryNodeId, NodeFsck>,
+) -> ClusterFsckReport {
+    let mut corrupt: HashSet<(ObjectKind, &Digest, RegistryNodeId)> = HashSet::new();
+    let mut unchecked: HashSet<(ObjectKind, &Digest, RegistryNodeId)> = HashSet::new();
+    for (id, node) in nodes.iter() {
+        for issue in node.report.iter().flat_map(|report| report.issues.iter()) {
+            match issue {
+                Issue::Stored {
+                    object,
+                    digest,
+                    problem,
+                } if !problem.is_confirmed() => {
+                    unchecked.insert((*object, digest, *id));
+                }
+                Issue::Stored { object, digest, .. } => {
+                    corrupt.insert((*object, digest, *id));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let objects = state
+        .manifests
+        .iter()
+        .map(|(digest, manifest)| {
+            (
+                ObjectKind::Manifest,
+                digest,
+                &manifest.locations,
+                &manifest.repositories,
+            )
+        })
+        .chain(state.blobs.iter().map(|(digest, blob)| {
+            (
+                ObjectKind::Blob,
+                digest,
+                &blob.locations,
+                &blob.repositories,
+            )
+        }));
+
+    let mut report = ClusterFsckReport {
+        replicas,
+        nodes: BTreeMap::new(),
+        unavailable: vec![],
+        under_replicated: vec![],
+        repaired: false,
+    };
+
+    for (object, digest, locations, repositories) in objects {
+        let mut copies = Copies {
+            object,
+            digest: digest.clone(),
+            healthy: BTreeSet::new(),
+            corrupt: BTreeSet::new(),
+            unverified: BTreeSet::new(),
+        };
+
+        for location in locations {
+            if !matches!(
+                nodes.get(location),
+                Some(NodeFsck {
+                    report: Some(_),
+                    ..
+                })
+            ) || unchecked.contains(&(object, digest, *location))
+            {
+                copies.unverified.insert(*location);
+            } else if corrupt.contains(&(object, digest, *location)) {
+                copies.corrupt.insert(*location);
+            } else {
+                copies.healthy.insert(*location);
+            }
+        }
+
+        if copies.healthy.is_empty() {
+            // Objects that aren't in any repository are waiting to be garbage collected
+            if !repositories.is_empty() {
+                report.unavailable.push(copies);
+            }
+        } else if copies.healthy.len() < replicas {
+            report.under_replicated.push(copies);
+        }
+    }
+
+    report.nodes = nodes;
+    report
+}
+
+/// Ask every member of the cluster to check its own objects, and merge the results. With
+/// `repair`, every node's fixes are submitted together.
+pub async fn check_cluster(
+    app: &RegistryApp,
+    request: &ClusterFsckRequest,
+) -> anyhow::Result<ClusterFsckReport> {
+    let membership = app
+        .store
+        .state_machine
+        .read()
+        .unwrap()
+        .get_last_membership()?;
+
+    let client = app.cluster_client_builder().build()?;
+    let scheme = app.config.raft.scheme();
+    let token = app.peer_token();
+
+    let checks = membership.nodes().map(|(id, node)| {
+        let client = client.clone();
+        let token = token.clone();
+        let url = format!("{scheme}://{}/fsck/local", node.addr);
+        async move {
+            if *id == app.id {
+                return (
+                    *id,
+                    NodeFsck {
+                        report: Some(check_local(app).await),
+                        error: None,
+                    },
+                );
+            }
+
+            let result: anyhow::Result<FsckReport> = async {
+                let mut req = client.post(&url);
+                if let Some(token) = &token {
+                    req = req.bearer_auth(token);
+                }
+                let resp = req.send().await?.error_for_status()?;
+                let res: Result<FsckReport, Infallible> = resp.json().await?;
+                Ok(res?)
+            }
+            .await;
+
+            let node = match result {
+                Ok(report) => NodeFsck {
+                    report: Some(report),
+                    error: None,
+                },
+                Err(err) => {
+                    error!("fsck: Unable to check node {id}: {err:?}");
+                    NodeFsck {
+                        report: None,
+                        error: Some(format!("{err:?}")),
+                    }
+                }
+            };
+
+            (*id, node)
+        }
+    });
+
+    let nodes: BTreeMap<RegistryNodeId, NodeFsck> = join_all(checks).await.into_iter().collect();
+    let replicas = request.replicas.unwrap_or(nodes.len());
+
+    let state = state_of(&app.store);
+    let mut report = merge(&state, replicas, nodes);
+
+    if request.repair {
+        let fixes: Vec<RegistryAction> = report
+            .nodes
+            .values()
+            .flat_map(|node| node.report.iter().flat_map(|report| report.fixes.clone()))
+            .collect();
+
+        if !fixes.is_empty() {
+            if !app.submit_write(fixes).await {
+                anyhow::bail!("fsck: Unable to submit fixes");
+            }
+            report.repaired = true;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::Range;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::storage::{FilesystemStorage, ObjectStream, Storage, StoredObject};
+    use crate::utils::get_blob_path;
+
+    /// Storage whose reads fail part way through, like an S3 backend whose connection is reset.
+    struct FlakyStorage;
+
+    #[async_trait]
+    impl Storage for FlakyStorage {
+        async fn put(&self, _: ObjectKind, _: &Digest, _: &std::path::Path) -> anyhow::Result<()> {
+            anyhow::bail!("not supported by FlakyStorage")
+        }
+
+        async fn open(
+            &self,
+            _: ObjectKind,
+            _: &Digest,
+            _: Option<Range<u64>>,
+        ) -> anyhow::Result<Option<ObjectStream>> {
+            let chunks: Vec<std::io::Result<bytes::Bytes>> = vec![
+                Ok(bytes::Bytes::from_static(b"FOO")),
+                Err(std::io::Error::other("connection reset")),
+            ];
+            Ok(Some(Box::pin(futures::stream::iter(chunks))))
+        }
+
+        async fn stat(&self, _: ObjectKind, _: &Digest) -> anyhow::Result<Option<u64>> {
+            Ok(Some(6))
+        }
+
+        async fn delete(&self, _: ObjectKind, _: &Digest) -> anyhow::Result<()> {
+            anyhow::bail!("not supported by FlakyStorage")
+        }
+
+        async fn quarantine(&self, _: ObjectKind, _: &Digest) -> anyhow::Result<String> {
+            anyhow::bail!("not supported by FlakyStorage")
+        }
+
+        async fn list(&self, _: ObjectKind) -> anyhow::Result<Vec<StoredObject>> {
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn offline_finds_unreferenced_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = tempdir.path().to_string_lossy().to_string();
+
+        let digest: Digest =
+            "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+                .parse()
+                .unwrap();
+        let path = get_blob_path(&storage, &digest);
+        std::fs::write(&path, "FOOBAR").unwrap();
+
+        let state = load_offline(&storage, 1).await.unwrap();
+        assert!(state.blobs.is_empty());
+
+        let storage = FilesystemStorage::new(&storage);
+        let report = check(&state, &storage, 1).await;
+        assert_eq!(
+            report.issues,
+            vec![Issue::UnreferencedFile {
+                location: path.to_string_lossy().to_string()
+            }]
+        );
+        assert!(report.fixes.is_empty());
+
+        let stored = storage.list(ObjectKind::Blob).await.unwrap();
+        assert_eq!(stored[0].digest, Some(digest));
+    }
+
+    #[tokio::test]
+    async fn read_errors_are_not_repaired() {
+        let digest: Digest =
+            "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+                .parse()
+                .unwrap();
+
+        let mut state = ImportBody {
+            blobs: BTreeMap::new(),
+            manifests: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            referrers: BTreeMap::new(),
+        };
+        state.blobs.insert(
+            digest.clone(),
+            crate::types::Blob {
+                size: Some(6),
+                content_type: None,
+                dependencies: Some(vec![]),
+                repositories: HashSet::from(["myrepo".parse().unwrap()]),
+                locations: HashSet::from([1, 2]),
+                created: Utc::now(),
+                updated: Utc::now(),
+            },
+        );
+
+        let report = check(&state, &FlakyStorage, 1).await;
+        assert_eq!(
+            report.issues,
+            vec![Issue::Stored {
+                object: ObjectKind::Blob,
+                digest: digest.clone(),
+                problem: Problem::Unreadable,
+            }]
+        );
+        assert!(report.fixes.is_empty());
+
+        // The copy isn't known to be bad, just not checked
+        let nodes = BTreeMap::from([
+            (
+                1,
+                NodeFsck {
+                    report: Some(report),
+                    error: None,
+                },
+            ),
+            (
+                2,
+                NodeFsck {
+                    report: Some(FsckReport::default()),
+                    error: None,
+                },
+            ),
+        ]);
+        let report = merge(&state, 2, nodes);
+        let copies = &report.under_replicated[0];
+        assert_eq!(copies.healthy, BTreeSet::from([2]));
+        assert!(copies.corrupt.is_empty());
+        assert_eq!(copies.unverified, BTreeSet::from([1]));
+    }
+
+    #[test]
+    fn merge_counts_healthy_copies() {
+        let digest: Digest =
+            "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+                .parse()
+                .unwrap();
+
+        let mut state = ImportBody {
+            blobs: BTreeMap::new(),
+            manifests: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            referrers: BTreeMap::new(),
+        };
+        state.blobs.insert(
+            digest.clone(),
+            crate::types::Blob {
+                size: Some(6),
+                content_type: None,
+                dependencies: Some(vec![]),
+                repositories: HashSet::from(["myrepo".parse().unwrap()]),
+                locations: HashSet::from([1, 2, 3]),
+                created: Utc::now(),
+                updated: Utc::now(),
+            },
+        );
+
+        let healthy = || NodeFsck {
+            report: Some(FsckReport::default()),
+            error: None,
+        };
+        let corrupt = NodeFsck {
+            report: Some(FsckReport {
+                issues: vec![Issue::Stored {
+                    object: ObjectKind::Blob,
+                    digest: digest.clone(),
+                    problem: Problem::Missing,
+                }],
+                ..Default::default()
+            }),
+            error: None,
+        };
+        let down = NodeFsck {
+            report: None,
+            error: Some("Connection refused".to_string()),
+        };
+
+        let nodes = BTreeMap::from([(1, healthy()), (2, corrupt), (3, down)]);
+        let report = merge(&state, 3, nodes);
+
+        assert!(report.unavailable.is_empty());
+        assert_eq!(report.under_replicated.len(), 1);
+        let copies = &report.under_replicated[0];
+        assert_eq!(copies.healthy, BTreeSet::from([1]));
+        assert_eq!(copies.corrupt, BTreeSet::from([2]));
+        assert_eq!(copies.unverified, BTreeSet::from([3]));
+
+        let nodes = BTreeMap::from([(1, healthy()), (2, healthy()), (3, healthy())]);
+        let report = merge(&state, 3, nodes);
+        assert!(report.unavailable.is_empty());
+        assert!(report.under_replicated.is_empty());
+    }
+}
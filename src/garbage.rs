@@ -1,64 +1,172 @@
 //! Garbage collection for manifests and blobs.
 //!
 //! distribd automatically garbage collects blobs and manifests that are no longer referenced by other objects in the DAG.
+//!
+//! Collection happens in two phases. In phase 1 the leader unmounts orphaned objects from
+//! their repositories once they are older than the grace period. In phase 2 every node
+//! deletes its copies of objects that are no longer in any repository.
 
-use std::io::ErrorKind;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::time::Instant;
 
+use actix_web::web::Data;
 use anyhow::Context;
-use chrono::Utc;
-use tokio::fs::remove_file;
+use chrono::{DateTime, Utc};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info};
 
 use crate::app::RegistryApp;
-use crate::types::RegistryAction;
+use crate::config::GarbageCollectionConfig;
+use crate::storage::ObjectKind;
+use crate::store::GarbageScan;
+use crate::types::{Digest, Mount, RegistryAction};
+
+/// What a garbage collection run reaped, or for a dry run what it would reap.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GarbageReport {
+    /// Phase 1: orphaned manifests that are unmounted from repositories.
+    pub manifest_mounts: Vec<Mount>,
+    /// Phase 1: orphaned blobs that are unmounted from repositories.
+    pub blob_mounts: Vec<Mount>,
+    /// Phase 2: manifests in no repository that are deleted from this node.
+    pub manifests: Vec<Digest>,
+    /// Phase 2: blobs in no repository that are deleted from this node.
+    pub blobs: Vec<Digest>,
+}
 
-const MINIMUM_GARBAGE_AGE: i64 = 60 * 60 * 12;
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GarbageRun {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub report: GarbageReport,
+    /// Why the run stopped early, if it did.
+    pub error: Option<String>,
+}
 
-async fn do_garbage_collect_phase1(app: &Arc<RegistryApp>) -> anyhow::Result<()> {
-    debug!("Garbage collection: Phase 1: Sweeping for mounted objects with no dependents");
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GarbageStatus {
+    /// Whether garbage is collected on a schedule.
+    pub enabled: bool,
+    pub running: bool,
+    pub last_run: Option<GarbageRun>,
+}
 
-    let minimum_age = chrono::Duration::try_seconds(MINIMUM_GARBAGE_AGE)
-        .context("Failed to convert i64 to duration")?;
-    let mut actions = vec![];
+#[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
+struct RunLabels {
+    result: String,
+}
 
-    for (digest, manifest) in app.store.get_orphaned_manifests()? {
-        let age = Utc::now() - manifest.created;
-        if age < minimum_age {
-            debug!(
-                "Garbage collection: Phase 1: {} is orphaned but less than 12 hours old",
-                &digest,
-            );
-            continue;
-        }
+#[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
+struct ReapedLabels {
+    phase: String,
+    object: String,
+}
 
-        for repository in manifest.repositories {
-            actions.push(RegistryAction::ManifestUnmounted {
-                timestamp: Utc::now(),
-                digest: digest.clone(),
-                repository,
-                user: "$system".to_string(),
-            })
-        }
+/// Runs garbage collection for this node and keeps track of how it went.
+pub struct GarbageCollector {
+    config: GarbageCollectionConfig,
+    running: tokio::sync::Mutex<()>,
+    last_run: std::sync::Mutex<Option<GarbageRun>>,
+    runs: Family<RunLabels, Counter>,
+    reaped: Family<ReapedLabels, Counter>,
+    duration: Histogram,
+}
+
+impl GarbageCollector {
+    pub fn new(config: &GarbageCollectionConfig, registry: &mut Registry) -> Self {
+        let collector = GarbageCollector {
+            config: config.clone(),
+            running: tokio::sync::Mutex::new(()),
+            last_run: std::sync::Mutex::new(None),
+            runs: Family::default(),
+            reaped: Family::default(),
+            duration: Histogram::new(exponential_buckets(0.01, 4.0, 10)),
+        };
+
+        registry.register(
+            "distribd_garbage_collection_runs",
+            "Number of garbage collection runs",
+            collector.runs.clone(),
+        );
+        registry.register(
+            "distribd_garbage_collection_reaped",
+            "Number of mounts and stored objects reaped by garbage collection",
+            collector.reaped.clone(),
+        );
+        registry.register(
+            "distribd_garbage_collection_duration_seconds",
+            "How long garbage collection runs take",
+            collector.duration.clone(),
+        );
+
+        collector
     }
-    for (digest, blob) in app.store.get_orphaned_blobs()? {
-        let age = Utc::now() - blob.created;
-        if age < minimum_age {
-            info!(
-                "Garbage collection: Phase 1: {} is orphaned but less than 12 hours old",
-                &digest,
-            );
-            continue;
+
+    pub fn status(&self) -> GarbageStatus {
+        GarbageStatus {
+            enabled: self.config.enabled,
+            running: self.running.try_lock().is_err(),
+            last_run: self.last_run.lock().unwrap().clone(),
         }
-        for repository in blob.repositories {
-            actions.push(RegistryAction::BlobUnmounted {
-                timestamp: Utc::now(),
-                digest: digest.clone(),
-                repository,
-                user: "$system".to_string(),
+    }
+
+    fn grace_period(&self) -> anyhow::Result<chrono::Duration> {
+        chrono::Duration::try_seconds(self.config.grace_period as i64)
+            .context("Failed to convert grace period to duration")
+    }
+
+    fn count_reaped(&self, phase: &str, object: &str, count: usize) {
+        self.reaped
+            .get_or_create(&ReapedLabels {
+                phase: phase.to_string(),
+                object: object.to_string(),
             })
-        }
+            .inc_by(count as u64);
+    }
+}
+
+/// The mounts that phase 1 removes: objects that nothing tagged in the repository refers to,
+/// and that haven't been mounted or changed within the grace period. Only the candidates the
+/// state machine has recorded since the last run, and what they lead to, are checked.
+fn plan_phase1(app: &RegistryApp, grace_period: chrono::Duration) -> anyhow::Result<GarbageScan> {
+    Ok(app.store.find_garbage(Utc::now() - grace_period)?)
+}
+
+async fn do_garbage_collect_phase1(
+    app: &RegistryApp,
+    report: &mut GarbageReport,
+) -> anyhow::Result<()> {
+    debug!(
+        "Garbage collection: Phase 1: Sweeping for mounted objects that are unreachable from tags"
+    );
+
+    let GarbageScan {
+        index,
+        manifests: manifest_mounts,
+        blobs: blob_mounts,
+        checked,
+    } = plan_phase1(app, app.garbage.grace_period()?)?;
+
+    let mut actions = vec![];
+    for mount in &manifest_mounts {
+        actions.push(RegistryAction::ManifestUnmounted {
+            timestamp: Utc::now(),
+            digest: mount.digest.clone(),
+            repository: mount.repository.clone(),
+            user: "$system".to_string(),
+        });
+    }
+    for mount in &blob_mounts {
+        actions.push(RegistryAction::BlobUnmounted {
+            timestamp: Utc::now(),
+            digest: mount.digest.clone(),
+            repository: mount.repository.clone(),
+            user: "$system".to_string(),
+        });
     }
 
     if !actions.is_empty() {
@@ -66,75 +174,66 @@ async fn do_garbage_collect_phase1(app: &Arc<RegistryApp>) -> anyhow::Result<()>
             "Garbage collection: Phase 1: Reaped {} mounts",
             actions.len()
         );
-        app.submit_write(actions).await;
     }
 
-    Ok(())
-}
-
-async fn cleanup_object(path: &PathBuf) -> anyhow::Result<()> {
-    if path.exists() {
-        remove_file(&path)
-            .await
-            .context(format!("Error while removing {path:?}"))?;
+    // Candidates that are still reachable don't need checking again until they change
+    if !checked.is_empty() {
+        actions.push(RegistryAction::GarbageChecked {
+            timestamp: Utc::now(),
+            index,
+            mounts: checked,
+        });
+    }
 
-        info!("Garbage collection: Removed file {path:?}");
+    if !actions.is_empty() && !app.submit_write(actions).await {
+        anyhow::bail!("Garbage collection: Phase 1: Unable to unmount objects");
     }
 
-    for path in path.parent().unwrap().ancestors().take(3) {
-        match path.read_dir() {
-            Ok(mut iter) => {
-                if iter.next().is_some() {
-                    // We've hit a shared directory
-                    // This counts as a win
-                    return Ok(());
-                }
+    app.garbage
+        .count_reaped("1", "manifest", manifest_mounts.len());
+    app.garbage.count_reaped("1", "blob", blob_mounts.len());
+    report.manifest_mounts = manifest_mounts;
+    report.blob_mounts = blob_mounts;
+
+    Ok(())
+}
+
+/// The objects stored on this node that phase 2 deletes, because they aren't in any repository.
+fn plan_phase2(app: &RegistryApp) -> anyhow::Result<(Vec<Digest>, Vec<Digest>)> {
+    let mut manifests = vec![];
+    for digest in app.store.get_unmounted_manifests()? {
+        if let Some(manifest) = app.store.get_manifest(&digest)? {
+            if manifest.locations.contains(&app.id) && manifest.repositories.is_empty() {
+                manifests.push(digest);
             }
-            Err(err) => match err.kind() {
-                ErrorKind::NotFound => {
-                    continue;
-                }
-                _ => {
-                    Err(err).context(format!("Error whilst reading contents of {path:?}"))?;
-                }
-            },
         }
+    }
 
-        match tokio::fs::remove_dir(path).await {
-            Ok(_) => {
-                info!("Garbage collection: Removed directory {path:?}");
+    let mut blobs = vec![];
+    for digest in app.store.get_unmounted_blobs()? {
+        if let Some(blob) = app.store.get_blob(&digest)? {
+            if blob.locations.contains(&app.id) && blob.repositories.is_empty() {
+                blobs.push(digest);
             }
-            Err(err) => match err.kind() {
-                ErrorKind::NotFound => {
-                    continue;
-                }
-                _ => {
-                    Err(err).context(format!("Error whilst removing {path:?}"))?;
-                }
-            },
         }
     }
 
-    Ok(())
+    Ok((manifests, blobs))
 }
 
-async fn do_garbage_collect_phase2(app: &Arc<RegistryApp>) -> anyhow::Result<()> {
+async fn do_garbage_collect_phase2(
+    app: &RegistryApp,
+    report: &mut GarbageReport,
+) -> anyhow::Result<()> {
     debug!("Garbage collection: Phase 2: Sweeping for unmounted objects that can be unstored");
 
-    let mut actions = vec![];
+    let (manifests, blobs) = plan_phase2(app)?;
 
-    for (digest, manifest) in app.store.get_orphaned_manifests()? {
-        if !manifest.locations.contains(&app.id) {
-            continue;
-        }
-
-        if !manifest.repositories.is_empty() {
-            continue;
-        }
+    let mut actions = vec![];
 
-        let path = app.get_manifest_path(&digest);
-        if let Err(err) = cleanup_object(&path).await {
-            error!("Unable to cleanup filesystem for: {path:?}: {err:?}");
+    for digest in manifests {
+        if let Err(err) = app.storage.delete(ObjectKind::Manifest, &digest).await {
+            error!("Unable to cleanup storage for manifest {digest}: {err:?}");
             continue;
         }
 
@@ -144,20 +243,12 @@ async fn do_garbage_collect_phase2(app: &Arc<RegistryApp>) -> anyhow::Result<()>
             location: app.id,
             user: "$system".to_string(),
         });
+        report.manifests.push(digest);
     }
 
-    for (digest, blob) in app.store.get_orphaned_blobs()? {
-        if !blob.locations.contains(&app.id) {
-            continue;
-        }
-
-        if !blob.repositories.is_empty() {
-            continue;
-        }
-
-        let path = app.get_blob_path(&digest);
-        if let Err(err) = cleanup_object(&path).await {
-            error!("Unable to cleanup filesystem for: {path:?}: {err:?}");
+    for digest in blobs {
+        if let Err(err) = app.storage.delete(ObjectKind::Blob, &digest).await {
+            error!("Unable to cleanup storage for blob {digest}: {err:?}");
             continue;
         }
 
@@ -167,6 +258,7 @@ async fn do_garbage_collect_phase2(app: &Arc<RegistryApp>) -> anyhow::Result<()>
             location: app.id,
             user: "$system".to_string(),
         });
+        report.blobs.push(digest);
     }
 
     if !actions.is_empty() {
@@ -174,33 +266,105 @@ async fn do_garbage_collect_phase2(app: &Arc<RegistryApp>) -> anyhow::Result<()>
             "Garbage collection: Phase 2: Reaped {} stores",
             actions.len()
         );
-        app.submit_write(actions).await;
+        if !app.submit_write(actions).await {
+            anyhow::bail!("Garbage collection: Phase 2: Unable to unstore objects");
+        }
     }
 
+    app.garbage
+        .count_reaped("2", "manifest", report.manifests.len());
+    app.garbage.count_reaped("2", "blob", report.blobs.len());
+
     Ok(())
 }
 
-pub async fn do_garbage_collect(app: Arc<RegistryApp>) -> anyhow::Result<()> {
-    loop {
-        let metrics = app.raft.metrics().borrow().clone();
+/// What garbage collection would reap if it ran on this node now, without changing anything.
+/// Phase 2 only lists objects that are already in no repository, not the ones phase 1 would
+/// unmount.
+pub fn dry_run(app: &RegistryApp) -> anyhow::Result<GarbageReport> {
+    let scan = plan_phase1(app, app.garbage.grace_period()?)?;
+    let (manifests, blobs) = plan_phase2(app)?;
+
+    Ok(GarbageReport {
+        manifest_mounts: scan.manifests,
+        blob_mounts: scan.blobs,
+        manifests,
+        blobs,
+    })
+}
 
-        if matches!(metrics.state, openraft::ServerState::Leader) {
-            do_garbage_collect_phase1(&app).await?;
-        }
+/// Collect garbage on this node now. Phase 1 only runs on the leader. If a run is already in
+/// progress, this waits for it to finish first.
+pub async fn collect(app: &RegistryApp) -> GarbageRun {
+    let _running = app.garbage.running.lock().await;
+
+    let state = app.raft.metrics().borrow().state;
+    let started = Utc::now();
+    let timer = Instant::now();
+    let mut report = GarbageReport::default();
 
-        if matches!(
-            metrics.state,
+    let mut result = Ok(());
+    if matches!(state, openraft::ServerState::Leader) {
+        result = do_garbage_collect_phase1(app, &mut report).await;
+    }
+    if result.is_ok()
+        && matches!(
+            state,
             openraft::ServerState::Leader | openraft::ServerState::Follower
-        ) {
-            do_garbage_collect_phase2(&app).await?;
-        }
+        )
+    {
+        result = do_garbage_collect_phase2(app, &mut report).await;
+    }
 
-        if matches!(metrics.state, openraft::ServerState::Shutdown) {
-            break;
-        }
+    app.garbage.duration.observe(timer.elapsed().as_secs_f64());
+    app.garbage
+        .runs
+        .get_or_create(&RunLabels {
+            result: match result {
+                Ok(_) => "success",
+                Err(_) => "error",
+            }
+            .to_string(),
+        })
+        .inc();
+
+    let run = GarbageRun {
+        started,
+        finished: Utc::now(),
+        report,
+        error: result.err().map(|err| format!("{err:?}")),
+    };
+
+    if let Some(err) = &run.error {
+        error!("Garbage collection failed: {err}");
+    }
+
+    *app.garbage.last_run.lock().unwrap() = Some(run.clone());
 
-        tokio::time::sleep(core::time::Duration::from_secs(60)).await;
+    run
+}
+
+/// Collect garbage every `interval` seconds, if it is enabled.
+pub fn start_garbage_collecting(app: Data<RegistryApp>) {
+    if !app.config.garbage_collection.enabled {
+        info!("Garbage collection: Not enabled, it will only run when triggered");
+        return;
     }
 
-    Ok(())
+    let interval = std::time::Duration::from_secs(app.config.garbage_collection.interval);
+
+    tokio::spawn(async move {
+        loop {
+            if matches!(
+                app.raft.metrics().borrow().state,
+                openraft::ServerState::Shutdown
+            ) {
+                break;
+            }
+
+            collect(&app).await;
+
+            tokio::time::sleep(interval).await;
+        }
+    });
 }
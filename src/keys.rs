@@ -0,0 +1,525 @@
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use anyhow::{bail, Context, Result};
+use data_encoding::BASE64URL_NOPAD;
+use jwt_simple::prelude::*;
+use jwt_simple::token::Token as Jwt;
+use serde::de::DeserializeOwned;
+use tracing::{debug, error, info, warn};
+use x509_parser::prelude::Pem;
+
+use crate::config::{config_path, JwksConfig, TokenConfig};
+
+/// A public key that tokens can be verified with.
+#[derive(Clone, Debug)]
+pub enum VerificationKey {
+    Es256(ES256PublicKey),
+    Es384(ES384PublicKey),
+    Rsa {
+        rs256: Box<RS256PublicKey>,
+        rs384: Box<RS384PublicKey>,
+    },
+    Ed25519(Ed25519PublicKey),
+}
+
+impl VerificationKey {
+    /// Load a key from a DER encoded SubjectPublicKeyInfo.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        if let Ok(key) = ES256PublicKey::from_der(der) {
+            return Ok(VerificationKey::Es256(key));
+        }
+        if let Ok(key) = ES384PublicKey::from_der(der) {
+            return Ok(VerificationKey::Es384(key));
+        }
+        if let Ok(rs256) = RS256PublicKey::from_der(der) {
+            let rs384 = RS384PublicKey::from_der(der)
+                .map_err(|err| anyhow::anyhow!("Invalid RSA public key: {err}"))?;
+            return Ok(VerificationKey::Rsa {
+                rs256: Box::new(rs256),
+                rs384: Box::new(rs384),
+            });
+        }
+        if let Ok(key) = Ed25519PublicKey::from_der(der) {
+            return Ok(VerificationKey::Ed25519(key));
+        }
+
+        bail!("Unsupported public key type, expected ECDSA P-256, ECDSA P-384, RSA or Ed25519");
+    }
+
+    /// Load a key from a PEM public key or the leaf of a PEM certificate chain.
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        let pem = Pem::iter_from_buffer(pem.as_bytes())
+            .next()
+            .context("No PEM data found")?
+            .context("Invalid PEM data")?;
+
+        if pem.label == "CERTIFICATE" {
+            let x509 = pem.parse_x509().context("X.509: decoding DER failed")?;
+            return Self::from_der(x509.public_key().raw);
+        }
+
+        Self::from_der(&pem.contents)
+    }
+
+    /// Load a key from a JSON Web Key.
+    fn from_jwk(jwk: &Jwk) -> Result<Self> {
+        let decode = |field: &Option<String>, name: &str| -> Result<Vec<u8>> {
+            let value = field
+                .as_ref()
+                .with_context(|| format!("JWK is missing \"{name}\""))?;
+            BASE64URL_NOPAD
+                .decode(value.as_bytes())
+                .with_context(|| format!("JWK has invalid \"{name}\""))
+        };
+
+        match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("RSA", _) => {
+                let n = decode(&jwk.n, "n")?;
+                let e = decode(&jwk.e, "e")?;
+                let rs256 = RS256PublicKey::from_components(&n, &e)
+                    .map_err(|err| anyhow::anyhow!("Invalid RSA JWK: {err}"))?;
+                let rs384 = RS384PublicKey::from_components(&n, &e)
+                    .map_err(|err| anyhow::anyhow!("Invalid RSA JWK: {err}"))?;
+                Ok(VerificationKey::Rsa {
+                    rs256: Box::new(rs256),
+                    rs384: Box::new(rs384),
+                })
+            }
+            ("EC", Some(crv @ ("P-256" | "P-384"))) => {
+                let mut point = vec![0x04];
+                point.extend(decode(&jwk.x, "x")?);
+                point.extend(decode(&jwk.y, "y")?);
+
+                if crv == "P-256" {
+                    ES256PublicKey::from_bytes(&point)
+                        .map(VerificationKey::Es256)
+                        .map_err(|err| anyhow::anyhow!("Invalid P-256 JWK: {err}"))
+                } else {
+                    ES384PublicKey::from_bytes(&point)
+                        .map(VerificationKey::Es384)
+                        .map_err(|err| anyhow::anyhow!("Invalid P-384 JWK: {err}"))
+                }
+            }
+            ("OKP", Some("Ed25519")) => Ed25519PublicKey::from_bytes(&decode(&jwk.x, "x")?)
+                .map(VerificationKey::Ed25519)
+                .map_err(|err| anyhow::anyhow!("Invalid Ed25519 JWK: {err}")),
+            (kty, crv) => bail!("Unsupported JWK type: {kty} {crv:?}"),
+        }
+    }
+
+    pub fn to_pem(&self) -> Result<String> {
+        let pem = match self {
+            VerificationKey::Es256(key) => key.to_pem(),
+            VerificationKey::Es384(key) => key.to_pem(),
+            VerificationKey::Rsa { rs256, .. } => rs256.to_pem(),
+            VerificationKey::Ed25519(key) => Ok(key.to_pem()),
+        };
+
+        pem.map_err(|err| anyhow::anyhow!("Could not encode public key: {err}"))
+    }
+
+    /// Whether this key can check a token signed with the JWS algorithm `alg`.
+    fn supports(&self, alg: &str) -> bool {
+        matches!(
+            (self, alg),
+            (VerificationKey::Es256(_), "ES256")
+                | (VerificationKey::Es384(_), "ES384")
+                | (VerificationKey::Rsa { .. }, "RS256" | "RS384")
+                | (VerificationKey::Ed25519(_), "EdDSA")
+        )
+    }
+
+    fn verify<C: Serialize + DeserializeOwned>(
+        &self,
+        alg: &str,
+        token: &str,
+        options: VerificationOptions,
+    ) -> Result<JWTClaims<C>> {
+        let claims = match (self, alg) {
+            (VerificationKey::Es256(key), _) => key.verify_token(token, Some(options)),
+            (VerificationKey::Es384(key), _) => key.verify_token(token, Some(options)),
+            (VerificationKey::Rsa { rs384, .. }, "RS384") => {
+                rs384.verify_token(token, Some(options))
+            }
+            (VerificationKey::Rsa { rs256, .. }, _) => rs256.verify_token(token, Some(options)),
+            (VerificationKey::Ed25519(key), _) => key.verify_token(token, Some(options)),
+        };
+
+        claims.map_err(|err| anyhow::anyhow!("{err}"))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// A key a token server might have signed a token with.
+#[derive(Clone, Debug)]
+pub struct TokenKey {
+    /// When set, only tokens with a matching `kid` header are checked with this key.
+    pub kid: Option<String>,
+    /// When set, only tokens signed with this algorithm are checked with this key.
+    pub alg: Option<String>,
+    pub key: VerificationKey,
+}
+
+impl TokenKey {
+    fn accepts(&self, kid: Option<&str>, alg: &str) -> bool {
+        if let (Some(kid), Some(ours)) = (kid, &self.kid) {
+            if kid != ours {
+                return false;
+            }
+        }
+
+        if let Some(ours) = &self.alg {
+            if ours != alg {
+                return false;
+            }
+        }
+
+        self.key.supports(alg)
+    }
+}
+
+/// Parse a JWKS document, skipping keys that can't be used to verify tokens.
+pub fn parse_jwks(data: &[u8]) -> Result<Vec<TokenKey>> {
+    let jwks: JwkSet = serde_json::from_slice(data).context("Invalid JWKS document")?;
+
+    let mut keys = vec![];
+    for jwk in jwks.keys.iter() {
+        if jwk.usage.as_deref().unwrap_or("sig") != "sig" {
+            continue;
+        }
+
+        match VerificationKey::from_jwk(jwk) {
+            Ok(key) => keys.push(TokenKey {
+                kid: jwk.kid.clone(),
+                alg: jwk.alg.clone(),
+                key,
+            }),
+            Err(err) => warn!("Skipping JWK {:?}: {err}", jwk.kid),
+        }
+    }
+
+    Ok(keys)
+}
+
+/// All the keys tokens are currently accepted from. Keys are parsed when they are loaded
+/// so that verifying a request doesn't have to.
+#[derive(Default)]
+pub struct KeySet {
+    configured: Vec<TokenKey>,
+    jwks: RwLock<Arc<Vec<TokenKey>>>,
+}
+
+impl KeySet {
+    pub fn new(config: Option<&TokenConfig>) -> Self {
+        let configured = match config {
+            Some(config) => config
+                .public_key
+                .iter()
+                .chain(config.public_keys.iter())
+                .map(|public_key| TokenKey {
+                    kid: public_key.kid.clone(),
+                    alg: None,
+                    key: public_key.public_key.clone(),
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        KeySet {
+            configured,
+            jwks: RwLock::new(Arc::new(vec![])),
+        }
+    }
+
+    /// Trust another key, e.g. the one the built-in token server signs with.
+    pub fn add(&mut self, key: TokenKey) {
+        self.configured.push(key);
+    }
+
+    /// Replace the keys that were loaded from JWKS.
+    pub fn set_jwks(&self, keys: Vec<TokenKey>) {
+        *self.jwks.write().unwrap() = Arc::new(keys);
+    }
+
+    pub fn verify<C: Serialize + DeserializeOwned>(
+        &self,
+        token: &str,
+        options: VerificationOptions,
+    ) -> Result<JWTClaims<C>> {
+        let metadata =
+            Jwt::decode_metadata(token).map_err(|err| anyhow::anyhow!("Invalid token: {err}"))?;
+        let alg = metadata.algorithm();
+        let kid = metadata.key_id();
+
+        let jwks = self.jwks.read().unwrap().clone();
+
+        let mut last_error = None;
+        for key in self.configured.iter().chain(jwks.iter()) {
+            if !key.accepts(kid, alg) {
+                continue;
+            }
+
+            match key.key.verify(alg, token, options.clone()) {
+                Ok(claims) => return Ok(claims),
+                Err(err) => {
+                    debug!("Token not verified by key {:?}: {err}", key.kid);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        match last_error {
+            Some(err) => Err(err),
+            None => bail!("No key available for {alg} token with kid {kid:?}"),
+        }
+    }
+}
+
+async fn fetch_jwks(client: &reqwest::Client, config: &JwksConfig) -> Result<Vec<TokenKey>> {
+    let data = match (&config.url, &config.path) {
+        (Some(url), _) => client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec(),
+        (None, Some(path)) => tokio::fs::read(config_path(path)).await?,
+        (None, None) => bail!("JWKS needs either a url or a path"),
+    };
+
+    parse_jwks(&data)
+}
+
+/// Load the JWKS and keep reloading it in the background so the token server can rotate keys.
+pub async fn start_refreshing_jwks(keys: Arc<KeySet>, config: JwksConfig) -> Result<()> {
+    if config.url.is_none() && config.path.is_none() {
+        bail!("JWKS needs either a url or a path");
+    }
+
+    if config.refresh_interval == 0 {
+        bail!("JWKS refresh_interval must be at least 1 second");
+    }
+
+    // Don't let a token server that stops responding hold up startup or stall refreshes
+    let client = reqwest::Client::builder()
+        .user_agent("distribd/jwks")
+        .timeout(std::time::Duration::from_secs(10))
+        .build()?;
+
+    // Load the keys up front so that tokens are accepted as soon as the server starts
+    match fetch_jwks(&client, &config).await {
+        Ok(fetched) => keys.set_jwks(fetched),
+        Err(err) => error!("Unable to load JWKS: {err:?}"),
+    }
+
+    tokio::spawn(async move {
+        let period = std::time::Duration::from_secs(config.refresh_interval);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        loop {
+            interval.tick().await;
+
+            match fetch_jwks(&client, &config).await {
+                Ok(fetched) => {
+                    info!("Loaded {} token verification keys from JWKS", fetched.len());
+                    keys.set_jwks(fetched);
+                }
+                Err(err) => {
+                    // Keep using the keys we already have until the token server is back
+                    error!("Unable to refresh JWKS: {err:?}");
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> VerificationOptions {
+        VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&["issuer"])),
+            ..Default::default()
+        }
+    }
+
+    fn claims() -> JWTClaims<NoCustomClaims> {
+        Claims::create(Duration::from_mins(5)).with_issuer("issuer")
+    }
+
+    fn jwks(keys: Vec<serde_json::Value>) -> Vec<TokenKey> {
+        parse_jwks(&serde_json::to_vec(&serde_json::json!({ "keys": keys })).unwrap()).unwrap()
+    }
+
+    fn b64(data: &[u8]) -> String {
+        BASE64URL_NOPAD.encode(data)
+    }
+
+    #[test]
+    fn rsa_jwk() {
+        let key_pair = RS256KeyPair::generate(2048).unwrap().with_key_id("rsa");
+        let components = key_pair.public_key().to_components();
+
+        let keys = KeySet::default();
+        keys.set_jwks(jwks(vec![serde_json::json!({
+            "kty": "RSA",
+            "kid": "rsa",
+            "n": b64(&components.n),
+            "e": b64(&components.e),
+        })]));
+
+        let token = key_pair.sign(claims()).unwrap();
+        keys.verify::<NoCustomClaims>(&token, options()).unwrap();
+
+        let rs384 = RS384KeyPair::from_der(&key_pair.to_der().unwrap())
+            .unwrap()
+            .with_key_id("rsa");
+        let token = rs384.sign(claims()).unwrap();
+        keys.verify::<NoCustomClaims>(&token, options()).unwrap();
+    }
+
+    #[test]
+    fn ecdsa_jwk() {
+        let p256 = ES256KeyPair::generate().with_key_id("p256");
+        let p384 = ES384KeyPair::generate().with_key_id("p384");
+
+        let point = |bytes: Vec<u8>| {
+            let (x, y) = bytes[1..].split_at((bytes.len() - 1) / 2);
+            (b64(x), b64(y))
+        };
+        let (x256, y256) = point(p256.public_key().public_key().to_bytes_uncompressed());
+        let (x384, y384) = point(p384.public_key().public_key().to_bytes_uncompressed());
+
+        let keys = KeySet::default();
+        keys.set_jwks(jwks(vec![
+            serde_json::json!({"kty": "EC", "crv": "P-256", "kid": "p256", "x": x256, "y": y256}),
+            serde_json::json!({"kty": "EC", "crv": "P-384", "kid": "p384", "x": x384, "y": y384}),
+        ]));
+
+        let token = p256.sign(claims()).unwrap();
+        keys.verify::<NoCustomClaims>(&token, options()).unwrap();
+
+        let token = p384.sign(claims()).unwrap();
+        keys.verify::<NoCustomClaims>(&token, options()).unwrap();
+    }
+
+    #[test]
+    fn eddsa_jwk() {
+        let key_pair = Ed25519KeyPair::generate().with_key_id("ed");
+
+        let keys = KeySet::default();
+        keys.set_jwks(jwks(vec![serde_json::json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "kid": "ed",
+            "x": b64(&key_pair.public_key().to_bytes()),
+        })]));
+
+        let token = key_pair.sign(claims()).unwrap();
+        keys.verify::<NoCustomClaims>(&token, options()).unwrap();
+    }
+
+    #[test]
+    fn unsupported_jwks_skipped() {
+        let keys = jwks(vec![
+            serde_json::json!({"kty": "oct", "k": "c2VjcmV0"}),
+            serde_json::json!({"kty": "EC", "crv": "P-521", "x": "AA", "y": "AA"}),
+            serde_json::json!({"kty": "OKP", "crv": "Ed25519", "use": "enc", "x": "AA"}),
+        ]);
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn select_by_kid() {
+        let current = ES256KeyPair::generate().with_key_id("current");
+        let rotated = ES256KeyPair::generate().with_key_id("rotated");
+
+        let keys = KeySet::default();
+        keys.set_jwks(vec![
+            TokenKey {
+                kid: Some("current".to_string()),
+                alg: None,
+                key: VerificationKey::Es256(current.public_key()),
+            },
+            TokenKey {
+                kid: Some("next".to_string()),
+                alg: None,
+                key: VerificationKey::Es256(rotated.public_key()),
+            },
+        ]);
+
+        let token = current.sign(claims()).unwrap();
+        keys.verify::<NoCustomClaims>(&token, options()).unwrap();
+
+        // The key is known but it is advertised under a different kid
+        let token = rotated.sign(claims()).unwrap();
+        assert!(keys.verify::<NoCustomClaims>(&token, options()).is_err());
+    }
+
+    #[test]
+    fn wrong_algorithm() {
+        let key_pair = ES256KeyPair::generate();
+
+        let keys = KeySet::default();
+        keys.set_jwks(vec![TokenKey {
+            kid: None,
+            alg: None,
+            key: VerificationKey::Es384(ES384KeyPair::generate().public_key()),
+        }]);
+
+        let token = key_pair.sign(claims()).unwrap();
+        assert!(keys.verify::<NoCustomClaims>(&token, options()).is_err());
+    }
+
+    #[test]
+    fn from_pem() {
+        let rsa = RS256KeyPair::generate(2048).unwrap();
+        let key = VerificationKey::from_pem(&rsa.public_key().to_pem().unwrap()).unwrap();
+        assert!(key.supports("RS384"));
+
+        let ed = Ed25519KeyPair::generate();
+        let key = VerificationKey::from_pem(&ed.public_key().to_pem()).unwrap();
+        assert!(key.supports("EdDSA"));
+
+        let p384 = ES384KeyPair::generate();
+        let key = VerificationKey::from_pem(&p384.public_key().to_pem().unwrap()).unwrap();
+        assert!(key.supports("ES384"));
+    }
+
+    #[tokio::test]
+    async fn jwks_needs_a_refresh_interval() {
+        let config = JwksConfig {
+            url: Some("http://localhost/jwks.json".to_string()),
+            path: None,
+            refresh_interval: 0,
+        };
+
+        assert!(start_refreshing_jwks(Arc::new(KeySet::default()), config)
+            .await
+            .is_err());
+    }
+}
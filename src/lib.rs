@@ -11,18 +11,31 @@ use actix_web::web;
 use actix_web::web::Data;
 use actix_web::App;
 use actix_web::HttpServer;
+use authorizer::Authorizer;
+use basic_auth::BasicAuth;
+use certificate::cluster_client_builder;
+use certificate::get_client_config;
+use certificate::get_server_config;
 use certificate::ServerCertificate;
 use config::Configuration;
+use config::TokenConfig;
 use extractor::Extractor;
+use garbage::GarbageCollector;
+use keys::start_refreshing_jwks;
+use keys::KeySet;
 use middleware::prometheus::Port;
 use middleware::prometheus::PrometheusHttpMetrics;
+use mirror::MirrorHealth;
 use openraft::storage::Adaptor;
 use openraft::BasicNode;
 use openraft::Config;
 use openraft::Entry;
 use openraft::Raft;
-use rustls::ServerConfig;
+use scrubber::Scrubber;
+use token_server::TokenServer;
 use tokio::sync::Notify;
+use tracing::warn;
+use webhook::delivery::DeliveryQueue;
 use webhook::start_webhook_worker;
 
 use crate::app::RegistryApp;
@@ -35,18 +48,25 @@ use crate::store::RegistryResponse;
 use crate::store::RegistryStore;
 
 pub mod app;
+pub mod authorizer;
+pub mod basic_auth;
 pub mod certificate;
 pub mod client;
 pub mod config;
 pub mod extractor;
 pub mod extractors;
+pub mod fsck;
 pub mod garbage;
+pub mod keys;
 pub mod middleware;
 pub mod mirror;
 pub mod network;
 pub mod prometheus;
 pub mod registry;
+pub mod scrubber;
+pub mod storage;
 pub mod store;
+pub mod token_server;
 pub mod types;
 pub mod utils;
 pub mod webhook;
@@ -96,6 +116,16 @@ fn create_dir(parent_dir: &str, child_dir: &str) -> std::io::Result<()> {
 }
 
 pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>> {
+    let authorizer = authorizer::from_config(&conf.authorizer)?;
+    start_raft_node_with_authorizer(conf, authorizer).await
+}
+
+/// Start a node that makes access decisions with a custom [`Authorizer`] rather than the one
+/// in the configuration.
+pub async fn start_raft_node_with_authorizer(
+    conf: Configuration,
+    authorizer: Arc<dyn Authorizer>,
+) -> anyhow::Result<Arc<Notify>> {
     let _guard = conf.sentry.as_ref().map(|config| {
         sentry::init((
             config.endpoint.clone(),
@@ -107,6 +137,7 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
     });
 
     let node_id = conf.id()?;
+    conf.validate()?;
 
     create_dir(&conf.storage, "uploads")?;
     create_dir(&conf.storage, "manifests")?;
@@ -129,12 +160,73 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
 
     let db: sled::Db = sled::open(&path).unwrap_or_else(|_| panic!("could not open: {:?}", path));
 
+    let webhook_deliveries = Arc::new(DeliveryQueue::new(&db)?);
+
     // Create a instance of where the Raft data will be stored.
     let store = RegistryStore::new(Arc::new(db), node_id, &mut registry).await;
 
+    let certificate = match &conf.raft.tls {
+        Some(tls) => Some(Arc::new(
+            ServerCertificate::new(tls.key.clone(), tls.chain.clone()).await?,
+        )),
+        None => None,
+    };
+
+    let client_tls = match (&conf.raft.tls, &certificate) {
+        (Some(tls), Some(certificate)) => match &tls.ca {
+            Some(ca) => Some(get_client_config(ca, certificate.clone()).await?),
+            None => None,
+        },
+        _ => None,
+    };
+
+    if let Some(token) = &conf.token_server {
+        if token.public_key.is_none()
+            && token.public_keys.is_empty()
+            && token.jwks.is_none()
+            && token.builtin.is_none()
+        {
+            anyhow::bail!(
+                "token_server needs a public_key, public_keys, jwks or builtin to verify tokens"
+            );
+        }
+    }
+
+    let token_server = match &conf.token_server {
+        Some(
+            token @ TokenConfig {
+                builtin: Some(builtin),
+                ..
+            },
+        ) => Some(Arc::new(TokenServer::new(token, builtin)?)),
+        _ => None,
+    };
+
+    let admins = conf
+        .raft
+        .tls
+        .as_ref()
+        .map(|tls| tls.admins.len())
+        .unwrap_or(0);
+    if conf.token_server.is_none() && admins == 0 {
+        warn!("Neither token_server nor raft.tls.admins is configured so the management API isn't authenticated");
+    } else if !conf.raft.mutual_tls() && token_server.is_none() {
+        // Members would have no way to prove they are peers, and raft RPC is only open to
+        // peers and admins
+        anyhow::bail!("The management API is authenticated, so members need raft.tls.ca or token_server.builtin to authenticate to each other");
+    }
+
     // Create the network layer that will connect and communicate the raft instances and
     // will be used in conjunction with the store created above.
-    let network = RegistryNetwork {};
+    let network = RegistryNetwork {
+        id: node_id,
+        client: cluster_client_builder(&client_tls).build()?,
+        scheme: conf.raft.scheme(),
+        token_server: match conf.raft.mutual_tls() {
+            true => None,
+            false => token_server.clone(),
+        },
+    };
 
     let (log_store, state_machine) = Adaptor::new(store.clone());
 
@@ -145,7 +237,35 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
 
     let extractor = Arc::new(Extractor::new());
 
-    let webhook_queue = start_webhook_worker(conf.webhooks.clone(), &mut registry);
+    let basic_auth = match &conf.basic_auth {
+        Some(_) if conf.token_server.is_some() => {
+            anyhow::bail!("basic_auth can't be used with token_server, configure token_server.builtin.htpasswd instead");
+        }
+        Some(config) => Some(BasicAuth::new(config)?),
+        None => None,
+    };
+
+    let mut token_keys = KeySet::new(conf.token_server.as_ref());
+    if let Some(token_server) = &token_server {
+        token_keys.add(token_server.token_key());
+    }
+    let token_keys = Arc::new(token_keys);
+    if let Some(jwks) = conf
+        .token_server
+        .as_ref()
+        .and_then(|token| token.jwks.clone())
+    {
+        start_refreshing_jwks(token_keys.clone(), jwks).await?;
+    }
+
+    let webhooks = start_webhook_worker(&conf, webhook_deliveries.clone(), &mut registry);
+    let garbage = Arc::new(GarbageCollector::new(
+        &conf.garbage_collection,
+        &mut registry,
+    ));
+    let scrubber = Arc::new(Scrubber::new(&conf.scrubber, &mut registry));
+    let mirroring = Arc::new(MirrorHealth::new(&conf.mirroring, &mut registry));
+    let storage = storage::from_config(&conf)?;
 
     // Create an application that will store all the instances created above, this will
     // be later used on the actix-web services.
@@ -155,8 +275,18 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
         store,
         config: conf.clone(),
         extractor,
-        webhooks: Arc::new(webhook_queue),
+        webhooks,
+        webhook_deliveries,
+        garbage,
+        scrubber,
+        mirroring,
+        storage,
         registry: Mutex::new(registry),
+        client_tls,
+        token_keys,
+        token_server,
+        basic_auth,
+        authorizer,
     });
 
     let app1 = app.clone();
@@ -164,6 +294,10 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
     let app3 = app.clone();
     let app4 = app.clone();
 
+    if !conf.raft.mutual_tls() {
+        warn!("The raft port is not using mutual TLS so peers fetching blobs and manifests aren't authenticated");
+    }
+
     // Start the actix-web server.
     let server = HttpServer::new(move || {
         let json_config = web::JsonConfig::default().limit(4096 * 1024 * 10);
@@ -183,6 +317,8 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
             .service(raft::vote)
             .service(raft::get_blob)
             .service(raft::get_manifest)
+            .service(raft::fsck_local)
+            .service(raft::audit_page)
             // admin API
             .service(management::init)
             .service(management::add_learner)
@@ -190,17 +326,32 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
             .service(management::metrics)
             .service(management::import)
             .service(management::export)
+            .service(management::create_robot)
+            .service(management::revoke_robot)
+            .service(management::robots)
+            .service(management::set_acl)
+            .service(management::remove_acl)
+            .service(management::acls)
+            .service(management::audit)
+            .service(management::webhook_dead_letters)
+            .service(management::retry_webhook_dead_letters)
+            .service(management::references)
+            .service(management::garbage_collect)
+            .service(management::garbage_collect_dry_run)
+            .service(management::garbage_collect_status)
+            .service(management::scrub)
+            .service(management::scrub_status)
+            .service(management::mirror_status)
+            .service(management::fsck)
             // application API
             .service(api::write)
     })
+    .on_connect(certificate::on_connect)
     .disable_signals();
 
-    let server = match conf.raft.tls.clone() {
-        Some(tls) => {
-            let certificate = ServerCertificate::new(tls.key.clone(), tls.chain.clone()).await?;
-            let config = ServerConfig::builder()
-                .with_no_client_auth()
-                .with_cert_resolver(Arc::new(certificate));
+    let server = match (&conf.raft.tls, certificate) {
+        (Some(tls), Some(certificate)) => {
+            let config = get_server_config(tls, certificate).await?;
 
             server.bind_rustls_0_23(
                 (
@@ -210,7 +361,7 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
                 config,
             )?
         }
-        None => server.bind((
+        _ => server.bind((
             app2.config.raft.address.clone().as_str(),
             app2.config.raft.port,
         ))?,
@@ -239,6 +390,8 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
             .service(registry::manifests::delete::delete_by_tag)
             // tags
             .service(registry::tags::get::get)
+            // change feed
+            .service(registry::events::get)
             // roots
             .service(registry::get::get)
             .service(registry::head::head);
@@ -253,6 +406,7 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
             // we can't use compression because it enables transfer-encoding: chunked which breaks content-length which breaks containerd
             // .wrap(middleware::Compress::default())
             .app_data(app.clone())
+            .service(token_server::token)
             .service(registry_api)
     })
     .bind((
@@ -298,6 +452,16 @@ pub async fn start_raft_node(conf: Configuration) -> anyhow::Result<Arc<Notify>>
 
     self::store::metrics::start_watching_metrics(app3.clone());
 
+    self::store::backfill::start_backfilling_audit(app3.clone());
+
+    garbage::start_garbage_collecting(app3.clone());
+
+    scrubber::start_scrubbing(app3.clone());
+
+    if !conf.webhooks.is_empty() {
+        webhook::notifier::start_notifying(app3.clone());
+    }
+
     tokio::spawn(async move {
         receiver.notified().await;
         drop(receiver);
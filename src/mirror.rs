@@ -1,18 +1,33 @@
 use crate::app::RegistryApp;
+use crate::config::MirroringConfig;
+use crate::storage::ObjectKind;
 use crate::types::{Digest, RegistryAction};
 use crate::RegistryNodeId;
 use actix_web::web::Data;
-use chrono::Utc;
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
 use rand::seq::SliceRandom;
-use std::path::PathBuf;
+use rand::Rng;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::Mutex;
 use std::time::Duration;
-use std::{collections::HashSet, sync::Arc};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::watch;
 use tokio::task::JoinSet;
-use tracing::debug;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, warn};
 
-#[derive(Hash, PartialEq, std::cmp::Eq, Debug)]
+#[derive(Clone, Hash, PartialEq, std::cmp::Eq, Debug)]
 pub enum MirrorRequest {
     Blob { digest: Digest },
     Manifest { digest: Digest },
@@ -21,6 +36,8 @@ pub enum MirrorRequest {
 pub enum MirrorResult {
     Retry {
         request: MirrorRequest,
+        /// What was downloaded before it failed, to carry on from next time.
+        partial: Option<Partial>,
     },
     Success {
         request: MirrorRequest,
@@ -52,18 +69,87 @@ impl MirrorRequest {
         }
     }
 
-    pub fn storage_path(&self, app: &Arc<RegistryApp>) -> PathBuf {
+    pub fn digest(&self) -> &Digest {
         match self {
-            MirrorRequest::Blob { digest } => app.get_blob_path(digest),
-            MirrorRequest::Manifest { digest } => app.get_manifest_path(digest),
+            MirrorRequest::Blob { digest } | MirrorRequest::Manifest { digest } => digest,
         }
     }
+
+    pub fn object(&self) -> ObjectKind {
+        match self {
+            MirrorRequest::Blob { .. } => ObjectKind::Blob,
+            MirrorRequest::Manifest { .. } => ObjectKind::Manifest,
+        }
+    }
+}
+
+/// What an earlier attempt at an object got before it was cut off: how much of it was written
+/// to its partial file, and the hash of that much, so the download can carry on from there.
+pub struct Partial {
+    hasher: ring::digest::Context,
+    length: u64,
+}
+
+impl Partial {
+    fn empty() -> Self {
+        Partial {
+            hasher: ring::digest::Context::new(&ring::digest::SHA256),
+            length: 0,
+        }
+    }
+
+    /// Where the download to `path` stands. The state of the attempt that wrote the file is
+    /// used if it is still accurate, otherwise (after a restart, say) the file is read to work
+    /// it out again.
+    async fn resume(path: &Path, partial: Option<Partial>) -> Partial {
+        let length = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Partial::empty(),
+        };
+
+        if let Some(partial) = partial {
+            if partial.length == length {
+                return partial;
+            }
+        }
+
+        match Partial::hash_file(path).await {
+            Ok(partial) => partial,
+            Err(err) => {
+                debug!(
+                    "Mirroring: Unable to read partial download {path:?}, starting again: {err}"
+                );
+                let _ = tokio::fs::remove_file(path).await;
+                Partial::empty()
+            }
+        }
+    }
+
+    async fn hash_file(path: &Path) -> std::io::Result<Partial> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut partial = Partial::empty();
+        let mut buffer = vec![0; 64 * 1024];
+
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok(partial);
+            }
+            partial.hasher.update(&buffer[..read]);
+            partial.length += read as u64;
+        }
+    }
+
+    fn digest(&self) -> Digest {
+        Digest::from_sha256(&self.hasher.clone().finish())
+    }
 }
 
 async fn do_transfer(
     app: Data<RegistryApp>,
     client: reqwest::Client,
     request: MirrorRequest,
+    partial: Option<Partial>,
 ) -> MirrorResult {
     let (digest, locations, object_type) = match request {
         MirrorRequest::Blob { ref digest } => {
@@ -116,7 +202,8 @@ async fn do_transfer(
         .get_last_membership()
         .unwrap();
 
-    let mut urls = vec![];
+    let mut sources = HashMap::new();
+    let scheme = app.config.raft.scheme();
 
     for (nid, node) in peers.nodes() {
         if !locations.contains(nid) {
@@ -124,269 +211,791 @@ async fn do_transfer(
         }
 
         let address = &node.addr;
-        let url = format!("http://{address}/{object_type}/{digest}");
-        urls.push(url);
+        let url = format!("{scheme}://{address}/{object_type}/{digest}");
+        sources.insert(*nid, url);
     }
 
-    let url = match urls.choose(&mut rand::thread_rng()) {
-        Some(url) => url,
-        None => {
-            debug!("Mirroring: {digest:?}: Failed to pick a node to mirror from");
-            return MirrorResult::None;
-        }
+    let candidates: Vec<RegistryNodeId> = sources.keys().copied().collect();
+    let Some(peer) = app.mirroring.choose(&request, &candidates) else {
+        debug!("Mirroring: {digest:?}: Failed to pick a node to mirror from");
+        return MirrorResult::None;
     };
+    let url = &sources[&peer];
 
     debug!("Mirroring: Will download: {url}");
 
-    let mut resp = match client.get(url).send().await {
-        Ok(resp) => resp,
-        Err(err) => {
-            debug!("Mirroring: Unable to fetch {url}: {err}");
-            return MirrorResult::Retry { request };
-        }
-    };
+    let file_name = app.get_partial_mirror_path(request.object(), digest);
+    let mut partial = Partial::resume(&file_name, partial).await;
+
+    if let Err(err) = download(&client, url, &file_name, digest, &mut partial).await {
+        debug!("Mirroring: Unable to fetch {url}: {err:#}");
+        app.mirroring
+            .failed(&request, Some(peer), format!("{err:#}"));
+        return MirrorResult::Retry {
+            request,
+            partial: Some(partial),
+        };
+    }
 
-    let status_code = resp.status();
+    app.mirroring.succeeded(&request, peer);
 
-    if status_code != reqwest::StatusCode::OK {
-        debug!("Mirroring: Unable to fetch {url}: {status_code}");
-        return MirrorResult::Retry { request };
+    if let Err(err) = app.storage.put(request.object(), digest, &file_name).await {
+        debug!("Mirroring: Failed to store file for {url}: {err:?}");
+        app.mirroring
+            .failed(&request, None, format!("Unable to store: {err:#}"));
+        return MirrorResult::Retry {
+            request,
+            partial: None,
+        };
+    }
+
+    debug!("Mirroring: Mirrored {digest}");
+
+    request.success(app.id)
+}
+
+/// Fetch an object from a peer into its partial file, carrying on from where `partial` got to,
+/// and check it is what was asked for.
+async fn download(
+    client: &reqwest::Client,
+    url: &str,
+    file_name: &Path,
+    digest: &Digest,
+    partial: &mut Partial,
+) -> anyhow::Result<()> {
+    let mut builder = client.get(url);
+    if partial.length > 0 {
+        debug!(
+            "Mirroring: {file_name:?}: Resuming from byte {}",
+            partial.length
+        );
+        builder = builder.header(RANGE, format!("bytes={}-", partial.length));
     }
 
-    let file_name = app.get_temp_mirror_path();
+    let mut resp = builder.send().await?;
+
+    let mut file = match resp.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT if partial.length > 0 => {
+            let expected = format!("bytes {}-", partial.length);
+            let content_range = resp
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            if !content_range.starts_with(&expected) {
+                bail!("Peer sent the wrong range: {content_range:?}");
+            }
 
-    let mut file = match tokio::fs::File::create(&file_name).await {
-        Ok(file) => {
-            debug!("Mirroring: {file_name:?}: Created new file for writing");
-            file
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(file_name)
+                .await
+                .context(format!("Failed opening output file {file_name:?}"))?
         }
-        Err(err) => {
-            debug!("Mirroring: Failed creating output file for {url}: {err}");
-            return MirrorResult::Retry { request };
+        reqwest::StatusCode::OK => {
+            // The peer is sending all of it
+            *partial = Partial::empty();
+
+            tokio::fs::File::create(file_name)
+                .await
+                .context(format!("Failed creating output file {file_name:?}"))?
         }
+        status_code => bail!("Peer responded with {status_code}"),
     };
 
-    let mut hasher = ring::digest::Context::new(&ring::digest::SHA256);
+    let streamed = async {
+        while let Some(chunk) = resp.chunk().await.context("Failed reading chunk")? {
+            file.write_all(&chunk)
+                .await
+                .context("Failed to write output chunk")?;
 
-    loop {
-        match resp.chunk().await {
-            Ok(Some(chunk)) => {
-                if let Err(err) = file.write_all(&chunk).await {
-                    debug!("Mirroring: Failed write output chunk for {url}: {err}");
-                    return MirrorResult::Retry { request };
-                }
-
-                debug!("Mirroring: Downloaded {} bytes", chunk.len());
-                hasher.update(&chunk);
-            }
-            Ok(None) => {
-                debug!("Mirroring: Finished streaming");
-                break;
-            }
-            Err(err) => {
-                debug!("Mirroring: Failed reading chunk for {url}: {err}");
-                return MirrorResult::Retry { request };
-            }
-        };
-    }
-
-    if let Err(err) = file.flush().await {
-        debug!("Mirroring: Failed to flush output file for {url}: {err}");
-        return MirrorResult::Retry { request };
-    }
+            debug!("Mirroring: Downloaded {} bytes", chunk.len());
+            partial.hasher.update(&chunk);
+            partial.length += chunk.len() as u64;
+        }
 
-    debug!("Mirroring: Output flushed");
+        Ok::<_, anyhow::Error>(())
+    };
+    let streamed = streamed.await;
 
-    if let Err(err) = file.sync_all().await {
-        debug!("Mirroring: Failed to sync_all output file for {url}: {err}");
-        return MirrorResult::Retry { request };
-    }
+    // What did arrive is kept for the next attempt, even if it was cut off
+    file.flush().await.context("Failed to flush output file")?;
+    streamed?;
 
-    debug!("Mirroring: Output synced");
+    debug!("Mirroring: Finished streaming");
 
+    file.sync_all()
+        .await
+        .context("Failed to sync_all output file")?;
     drop(file);
 
-    debug!("Mirroring: File handle dropped");
+    debug!("Mirroring: Output synced");
 
-    let download_digest = Digest::from_sha256(&hasher.finish());
+    let download_digest = partial.digest();
 
     if digest != &download_digest {
-        debug!("Mirroring: Download of {url} complete but wrong digest: {download_digest}");
-        return MirrorResult::Retry { request };
+        let _ = tokio::fs::remove_file(file_name).await;
+        *partial = Partial::empty();
+        bail!("Download complete but wrong digest: {download_digest}");
     }
 
     debug!("Mirroring: Download has correct hash ({download_digest} vs {digest})");
 
-    if !crate::registry::utils::validate_hash(&file_name, digest).await {
-        debug!("Mirroring: Downloaded file for {url} is corrupt");
-        return MirrorResult::Retry { request };
-    };
+    Ok(())
+}
 
-    let storage_path = request.storage_path(&app);
-    if let Err(err) = tokio::fs::rename(file_name, storage_path).await {
-        debug!("Mirroring: Failed to store file for {url}: {err}");
-        return MirrorResult::Retry { request };
+/// What to fetch first: manifests, then image configs, then other blobs smallest first. Images
+/// become usable on this node sooner if they aren't waiting behind large layers.
+fn priority(object: ObjectKind, content_type: Option<&str>, size: Option<u64>) -> (u8, u64) {
+    let size = size.unwrap_or(u64::MAX);
+    match (object, content_type) {
+        (ObjectKind::Manifest, _) => (0, size),
+        (
+            ObjectKind::Blob,
+            Some(
+                "application/vnd.oci.image.config.v1+json"
+                | "application/vnd.docker.container.image.v1+json",
+            ),
+        ) => (1, size),
+        (ObjectKind::Blob, _) => (2, size),
     }
+}
 
-    debug!("Mirroring: Mirrored {digest}");
+/// How long to wait before the next attempt at an object, after `attempts` failures. It doubles
+/// each time, starting at `initial`, and is then cut by up to half at random so that objects
+/// that failed together aren't all retried together.
+fn backoff(initial: Duration, maximum: Duration, attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    let backoff = initial.saturating_mul(factor).min(maximum);
+    backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
 
-    request.success(app.id)
+/// How transfers from a peer have been going.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerHealth {
+    pub successes: u64,
+    pub failures: u64,
+    /// Failures since the last success.
+    pub consecutive_failures: u32,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
 }
 
-enum MirrorState {
-    Changed,
-    Timeout,
-    Close,
+/// An object that hasn't transferred since it last failed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectAttempts {
+    pub object: ObjectKind,
+    pub digest: Digest,
+    /// Failures in a row.
+    pub attempts: u32,
+    pub first_failure: DateTime<Utc>,
+    pub last_failure: DateTime<Utc>,
+    pub last_error: String,
+    /// Peers it has failed from since every peer was last tried.
+    pub failed_peers: BTreeSet<RegistryNodeId>,
+    /// It won't be tried again before this.
+    pub next_attempt: DateTime<Utc>,
 }
 
-#[inline]
-async fn wait_for_change(mut subscriber: watch::Receiver<HashSet<Digest>>) -> MirrorState {
-    if let Err(result) = subscriber.changed().await {
-        tracing::debug!("RecvError: Closing mirror: {:?}", result);
-        return MirrorState::Close;
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorStatus {
+    pub peers: BTreeMap<RegistryNodeId, PeerHealth>,
+    /// Objects that have failed at least `failing_after` times in a row.
+    pub failing: Vec<ObjectAttempts>,
+}
 
-    MirrorState::Changed
+#[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
+struct TransferLabels {
+    peer: String,
+    result: String,
 }
 
-#[inline]
-async fn wait_for_time() -> MirrorState {
-    tokio::time::sleep(Duration::from_secs(10)).await;
+#[derive(Default)]
+struct History {
+    peers: HashMap<RegistryNodeId, PeerHealth>,
+    objects: HashMap<MirrorRequest, ObjectAttempts>,
+}
 
-    MirrorState::Timeout
+/// Remembers how mirroring from each peer, and of each object, has gone, so that sources that
+/// are failing are avoided and objects that keep failing are retried less and less often.
+pub struct MirrorHealth {
+    config: MirroringConfig,
+    history: Mutex<History>,
+    transfers: Family<TransferLabels, Counter>,
+    backing_off: Gauge,
+    failing: Gauge,
 }
 
-pub(crate) async fn do_miroring(app: Data<RegistryApp>) -> anyhow::Result<()> {
-    let client = reqwest::Client::builder()
-        .user_agent("distribd/mirror")
-        .build()
-        .unwrap();
+impl MirrorHealth {
+    pub fn new(config: &MirroringConfig, registry: &mut Registry) -> Self {
+        let health = MirrorHealth {
+            config: config.clone(),
+            history: Mutex::new(History::default()),
+            transfers: Family::default(),
+            backing_off: Gauge::default(),
+            failing: Gauge::default(),
+        };
 
-    let app1 = app.clone();
-    let client1 = client.clone();
+        registry.register(
+            "distribd_mirror_transfers",
+            "Number of objects fetched from each peer, by whether it worked",
+            health.transfers.clone(),
+        );
+        registry.register(
+            "distribd_mirror_backing_off",
+            "Objects waiting to be retried after failing to transfer",
+            health.backing_off.clone(),
+        );
+        registry.register(
+            "distribd_mirror_failing",
+            "Objects that have failed to transfer too many times in a row",
+            health.failing.clone(),
+        );
+
+        health
+    }
 
-    tokio::spawn(async move {
-        let mut subscriber = app1
-            .store
-            .state_machine
-            .read()
-            .unwrap()
-            .pending_blobs
-            .subscribe();
-        let mut pending_blobs = subscriber.borrow_and_update().clone();
-        let mut waiters = JoinSet::new();
-
-        waiters.spawn(wait_for_change(subscriber.clone()));
-        if !pending_blobs.is_empty() {
-            waiters.spawn(wait_for_time());
+    /// Pick the peer to fetch an object from. Peers it has already failed from are only tried
+    /// again once every peer has been, and otherwise the peers that have been failing least are
+    /// preferred.
+    pub fn choose(
+        &self,
+        request: &MirrorRequest,
+        candidates: &[RegistryNodeId],
+    ) -> Option<RegistryNodeId> {
+        let mut history = self.history.lock().unwrap();
+        let History { peers, objects } = &mut *history;
+
+        let mut untried: Vec<RegistryNodeId> = candidates.to_vec();
+        if let Some(attempts) = objects.get_mut(request) {
+            untried.retain(|peer| !attempts.failed_peers.contains(peer));
+            if untried.is_empty() {
+                // Every peer has failed it, so start another round
+                attempts.failed_peers.clear();
+                untried = candidates.to_vec();
+            }
+        }
+
+        let failures =
+            |peer: &RegistryNodeId| peers.get(peer).map_or(0, |peer| peer.consecutive_failures);
+
+        let fewest = untried.iter().map(failures).min()?;
+        untried.retain(|peer| failures(peer) == fewest);
+        untried.choose(&mut rand::thread_rng()).copied()
+    }
+
+    /// Whether an object's backoff has passed.
+    pub fn ready(&self, request: &MirrorRequest) -> bool {
+        match self.history.lock().unwrap().objects.get(request) {
+            Some(attempts) => attempts.next_attempt <= Utc::now(),
+            None => true,
+        }
+    }
+
+    pub fn succeeded(&self, request: &MirrorRequest, peer: RegistryNodeId) {
+        let mut history = self.history.lock().unwrap();
+
+        let health = history.peers.entry(peer).or_default();
+        health.successes += 1;
+        health.consecutive_failures = 0;
+        health.last_success = Some(Utc::now());
+
+        history.objects.remove(request);
+
+        self.transfers
+            .get_or_create(&TransferLabels {
+                peer: peer.to_string(),
+                result: "success".to_string(),
+            })
+            .inc();
+        self.update_gauges(&history);
+    }
+
+    /// Record a failed attempt at an object, and when to try it next. `peer` is the peer it was
+    /// being fetched from, if the failure was down to it.
+    pub fn failed(&self, request: &MirrorRequest, peer: Option<RegistryNodeId>, error: String) {
+        let mut history = self.history.lock().unwrap();
+        let now = Utc::now();
+
+        if let Some(peer) = peer {
+            let health = history.peers.entry(peer).or_default();
+            health.failures += 1;
+            health.consecutive_failures += 1;
+            health.last_failure = Some(now);
+            health.last_error = Some(error.clone());
+
+            self.transfers
+                .get_or_create(&TransferLabels {
+                    peer: peer.to_string(),
+                    result: "failure".to_string(),
+                })
+                .inc();
+        }
+
+        let (object, digest) = (request.object(), request.digest());
+
+        let attempts = history
+            .objects
+            .entry(request.clone())
+            .or_insert_with(|| ObjectAttempts {
+                object,
+                digest: digest.clone(),
+                attempts: 0,
+                first_failure: now,
+                last_failure: now,
+                last_error: String::new(),
+                failed_peers: BTreeSet::new(),
+                next_attempt: now,
+            });
+
+        attempts.attempts += 1;
+        attempts.last_failure = now;
+        attempts.last_error = error;
+        attempts.failed_peers.extend(peer);
+
+        let wait = backoff(
+            Duration::from_secs(self.config.retry_backoff),
+            Duration::from_secs(self.config.max_retry_backoff),
+            attempts.attempts,
+        );
+        attempts.next_attempt = now + chrono::Duration::from_std(wait).unwrap_or_default();
+
+        if attempts.attempts == self.config.failing_after {
+            warn!(
+                "Mirroring: {} {digest} has failed {} times in a row: {}",
+                object.as_str(),
+                attempts.attempts,
+                attempts.last_error
+            );
+        }
+
+        self.update_gauges(&history);
+    }
+
+    /// Forget objects that no longer need to be mirrored.
+    fn retain(&self, pending: &HashSet<MirrorRequest>) {
+        let mut history = self.history.lock().unwrap();
+        history
+            .objects
+            .retain(|request, _| pending.contains(request));
+        self.update_gauges(&history);
+    }
+
+    fn is_failing(&self, attempts: &ObjectAttempts) -> bool {
+        attempts.attempts >= self.config.failing_after
+    }
+
+    fn update_gauges(&self, history: &History) {
+        self.backing_off.set(history.objects.len() as i64);
+        self.failing.set(
+            history
+                .objects
+                .values()
+                .filter(|attempts| self.is_failing(attempts))
+                .count() as i64,
+        );
+    }
+
+    pub fn status(&self) -> MirrorStatus {
+        let history = self.history.lock().unwrap();
+
+        let mut failing: Vec<ObjectAttempts> = history
+            .objects
+            .values()
+            .filter(|attempts| self.is_failing(attempts))
+            .cloned()
+            .collect();
+        failing.sort_by_key(|attempts| std::cmp::Reverse(attempts.attempts));
+
+        MirrorStatus {
+            peers: history
+                .peers
+                .iter()
+                .map(|(peer, health)| (*peer, health.clone()))
+                .collect(),
+            failing,
         }
+    }
+}
 
-        while let Some(res) = waiters.join_next().await {
-            match res {
-                Ok(MirrorState::Timeout | MirrorState::Changed) => {
-                    pending_blobs = subscriber.borrow_and_update().clone();
-                    for digest in pending_blobs.iter() {
-                        let result = do_transfer(
-                            app1.clone(),
-                            client1.clone(),
-                            MirrorRequest::Blob {
-                                digest: digest.clone(),
-                            },
+struct Mirror {
+    app: Data<RegistryApp>,
+    client: reqwest::Client,
+    /// Waiting for a free worker, most important first.
+    queue: VecDeque<MirrorRequest>,
+    in_flight: HashSet<MirrorRequest>,
+    transfers: JoinSet<(MirrorRequest, MirrorResult)>,
+    /// Transferred, but this node might not be recorded as having them yet.
+    done: HashSet<MirrorRequest>,
+    /// Transferred, waiting to be recorded in the next batch.
+    stored: Vec<(MirrorRequest, RegistryAction)>,
+    /// Downloads that were cut off, to be resumed.
+    partials: HashMap<MirrorRequest, Partial>,
+}
+
+impl Mirror {
+    /// Queue everything that is pending, not already being dealt with and not waiting to be
+    /// retried.
+    fn refill(&mut self, blobs: HashSet<Digest>, manifests: HashSet<Digest>) {
+        let pending: HashSet<MirrorRequest> = manifests
+            .into_iter()
+            .map(|digest| MirrorRequest::Manifest { digest })
+            .chain(
+                blobs
+                    .into_iter()
+                    .map(|digest| MirrorRequest::Blob { digest }),
+            )
+            .collect();
+
+        self.done.retain(|request| pending.contains(request));
+        self.stored.retain(|(request, _)| pending.contains(request));
+
+        // Downloads that were cut off aren't needed for objects that aren't wanted any more
+        let unwanted: Vec<MirrorRequest> = self
+            .partials
+            .keys()
+            .filter(|request| !pending.contains(request))
+            .cloned()
+            .collect();
+        for request in unwanted {
+            self.partials.remove(&request);
+            let path = self
+                .app
+                .get_partial_mirror_path(request.object(), request.digest());
+            let _ = std::fs::remove_file(path);
+        }
+        self.app.mirroring.retain(&pending);
+
+        let mut queue: Vec<((u8, u64), MirrorRequest)> = pending
+            .into_iter()
+            .filter(|request| !self.in_flight.contains(request) && !self.done.contains(request))
+            .filter(|request| self.app.mirroring.ready(request))
+            .map(|request| {
+                let priority = match &request {
+                    MirrorRequest::Blob { digest } => {
+                        let blob = self.app.get_blob(digest);
+                        let blob = blob.as_ref();
+                        priority(
+                            ObjectKind::Blob,
+                            blob.and_then(|blob| blob.content_type.as_deref()),
+                            blob.and_then(|blob| blob.size),
                         )
-                        .await;
+                    }
+                    MirrorRequest::Manifest { digest } => priority(
+                        ObjectKind::Manifest,
+                        None,
+                        self.app
+                            .get_manifest(digest)
+                            .and_then(|manifest| manifest.size),
+                    ),
+                };
+                (priority, request)
+            })
+            .collect();
+
+        queue.sort_by_key(|(priority, _)| *priority);
+
+        self.queue = queue.into_iter().map(|(_, request)| request).collect();
+    }
+
+    /// Start transfers from the front of the queue until every worker is busy.
+    fn start(&mut self) {
+        let concurrency = self.app.config.mirroring.concurrency.max(1);
 
-                        if let MirrorResult::Success { action, request: _ } = result {
-                            if app1.submit_write(vec![action]).await {
-                                debug!("Mirroring: Download logged to raft");
-                            }
+        while self.in_flight.len() < concurrency {
+            let Some(request) = self.queue.pop_front() else {
+                break;
+            };
+
+            self.in_flight.insert(request.clone());
+
+            let app = self.app.clone();
+            let transfer = AssertUnwindSafe(do_transfer(
+                self.app.clone(),
+                self.client.clone(),
+                request.clone(),
+                self.partials.remove(&request),
+            ))
+            .catch_unwind();
+
+            self.transfers.spawn(async move {
+                // A transfer that panics is retried like one that failed
+                let result = match transfer.await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        app.mirroring
+                            .failed(&request, None, "Transfer panicked".to_string());
+                        MirrorResult::Retry {
+                            request: request.clone(),
+                            partial: None,
                         }
                     }
-                }
-                Ok(MirrorState::Close) => {
-                    break;
-                }
-                Err(err) => {
-                    tracing::error!("JoinError during mirror: {:?}", err);
-                    break;
-                }
-            }
+                };
+                (request, result)
+            });
+        }
+    }
 
-            match res {
-                Ok(MirrorState::Changed) => {
-                    waiters.spawn(wait_for_change(subscriber.clone()));
-                }
-                Ok(MirrorState::Timeout) => {
-                    if !pending_blobs.is_empty() {
-                        waiters.spawn(wait_for_time());
-                    }
-                }
-                _ => {}
+    fn finished(&mut self, request: MirrorRequest, result: MirrorResult) {
+        self.in_flight.remove(&request);
+
+        match result {
+            MirrorResult::Success { request, action } => {
+                self.done.insert(request.clone());
+                self.stored.push((request, action));
+            }
+            MirrorResult::Retry {
+                request,
+                partial: Some(partial),
+            } => {
+                self.partials.insert(request, partial);
             }
+            _ => {}
         }
-    });
+    }
+
+    /// Record everything that has been transferred since the last batch in one raft write.
+    async fn flush(&mut self) {
+        if self.stored.is_empty() {
+            return;
+        }
+
+        let actions: Vec<RegistryAction> = self
+            .stored
+            .iter()
+            .map(|(_, action)| action.clone())
+            .collect();
+
+        let count = actions.len();
+        if self.app.submit_write(actions).await {
+            debug!("Mirroring: {count} downloads logged to raft");
+            self.stored.clear();
+        } else {
+            // The objects are stored, so keep them for the next batch rather than downloading
+            // them again
+            debug!("Mirroring: Unable to log {count} downloads to raft, will retry");
+        }
+    }
+}
 
-    let app1 = app;
-    let client1 = client;
+fn snapshot(pending: &mut watch::Receiver<HashSet<Digest>>) -> HashSet<Digest> {
+    pending.borrow_and_update().clone()
+}
+
+pub(crate) async fn do_miroring(app: Data<RegistryApp>) -> anyhow::Result<()> {
+    let client = app
+        .cluster_client_builder()
+        .user_agent("distribd/mirror")
+        .build()
+        .unwrap();
 
     tokio::spawn(async move {
-        let mut subscriber = app1
-            .store
-            .state_machine
-            .read()
-            .unwrap()
-            .pending_manifests
-            .subscribe();
-        let mut pending_manifests = subscriber.borrow_and_update().clone();
-        let mut waiters = JoinSet::new();
-
-        waiters.spawn(wait_for_change(subscriber.clone()));
-        if !pending_manifests.is_empty() {
-            waiters.spawn(wait_for_time());
-        }
+        let (mut blobs, mut manifests) = {
+            let sm = app.store.state_machine.read().unwrap();
+            (
+                sm.pending_blobs.subscribe(),
+                sm.pending_manifests.subscribe(),
+            )
+        };
 
-        while let Some(res) = waiters.join_next().await {
-            match res {
-                Ok(MirrorState::Timeout | MirrorState::Changed) => {
-                    pending_manifests = subscriber.borrow_and_update().clone();
-                    for digest in pending_manifests.iter() {
-                        let result = do_transfer(
-                            app1.clone(),
-                            client1.clone(),
-                            MirrorRequest::Manifest {
-                                digest: digest.clone(),
-                            },
-                        )
-                        .await;
+        let batch_size = app.config.mirroring.batch_size.max(1);
+
+        let mut mirror = Mirror {
+            app,
+            client,
+            queue: VecDeque::new(),
+            in_flight: HashSet::new(),
+            transfers: JoinSet::new(),
+            done: HashSet::new(),
+            stored: vec![],
+            partials: HashMap::new(),
+        };
 
-                        if let MirrorResult::Success { action, request: _ } = result {
-                            if app1.submit_write(vec![action]).await {
-                                debug!("Mirroring: Download logged to raft");
-                            }
-                        }
+        mirror.refill(snapshot(&mut blobs), snapshot(&mut manifests));
+
+        // Failed transfers are retried when the pending objects are next looked at after their
+        // backoff
+        let mut retry = tokio::time::interval(Duration::from_secs(
+            mirror.app.config.mirroring.retry_backoff.clamp(1, 10),
+        ));
+        retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut flush = tokio::time::interval(Duration::from_secs(1));
+        flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            mirror.start();
+
+            tokio::select! {
+                changed = blobs.changed() => {
+                    if let Err(err) = changed {
+                        debug!("RecvError: Closing mirror: {err:?}");
+                        break;
                     }
+                    mirror.refill(snapshot(&mut blobs), snapshot(&mut manifests));
                 }
-                Ok(MirrorState::Close) => {
-                    break;
+                changed = manifests.changed() => {
+                    if let Err(err) = changed {
+                        debug!("RecvError: Closing mirror: {err:?}");
+                        break;
+                    }
+                    mirror.refill(snapshot(&mut blobs), snapshot(&mut manifests));
                 }
-                Err(err) => {
-                    tracing::error!("JoinError during mirror: {:?}", err);
-                    break;
+                _ = retry.tick() => {
+                    mirror.refill(snapshot(&mut blobs), snapshot(&mut manifests));
                 }
-            }
-
-            match res {
-                Ok(MirrorState::Changed) => {
-                    waiters.spawn(wait_for_change(subscriber.clone()));
+                _ = flush.tick() => {
+                    mirror.flush().await;
                 }
-                Ok(MirrorState::Timeout) => {
-                    if !pending_manifests.is_empty() {
-                        waiters.spawn(wait_for_time());
+                Some(joined) = mirror.transfers.join_next() => {
+                    match joined {
+                        Ok((request, result)) => mirror.finished(request, result),
+                        Err(err) => error!("JoinError during mirror: {err:?}"),
+                    }
+
+                    if mirror.stored.len() >= batch_size || mirror.in_flight.is_empty() {
+                        mirror.flush().await;
                     }
                 }
-                _ => {}
             }
         }
     });
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manifests_and_configs_come_first() {
+        let mut objects = vec![
+            priority(ObjectKind::Blob, Some("application/octet-stream"), Some(10)),
+            priority(ObjectKind::Blob, None, Some(1 << 30)),
+            priority(
+                ObjectKind::Blob,
+                Some("application/vnd.oci.image.config.v1+json"),
+                Some(2000),
+            ),
+            priority(ObjectKind::Blob, None, None),
+            priority(ObjectKind::Manifest, None, Some(500)),
+        ];
+        objects.sort();
+
+        assert_eq!(
+            objects,
+            vec![(0, 500), (1, 2000), (2, 10), (2, 1 << 30), (2, u64::MAX)]
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_downloads_carry_on() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("mirror-blobs-partial");
+
+        // Nothing downloaded yet
+        let partial = Partial::resume(&path, None).await;
+        assert_eq!(partial.length, 0);
+
+        // After a restart the file is all there is to go on
+        std::fs::write(&path, "FOO").unwrap();
+        let mut partial = Partial::resume(&path, None).await;
+        assert_eq!(partial.length, 3);
+
+        partial.hasher.update(b"BAR");
+        partial.length += 3;
+        let expected: Digest =
+            "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+                .parse()
+                .unwrap();
+
+        // The last attempt's state is used as is, without reading the file again
+        std::fs::write(&path, "XXXXXX").unwrap();
+        let partial = Partial::resume(&path, Some(partial)).await;
+        assert_eq!(partial.digest(), expected);
+
+        // Unless the file isn't what it says it got
+        std::fs::write(&path, "FOOBA").unwrap();
+        let partial = Partial::resume(&path, Some(partial)).await;
+        assert_eq!(partial.length, 5);
+        assert_ne!(partial.digest(), expected);
+    }
+
+    fn blob(n: u8) -> MirrorRequest {
+        MirrorRequest::Blob {
+            digest: format!("sha256:{}", format!("{n:02x}").repeat(32))
+                .parse()
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let initial = Duration::from_secs(10);
+        let maximum = Duration::from_secs(60);
+
+        for (attempts, longest) in [(1, 10), (2, 20), (3, 40), (4, 60), (100, 60)] {
+            let longest = Duration::from_secs(longest);
+            let wait = backoff(initial, maximum, attempts);
+            assert!(
+                wait >= longest / 2 && wait <= longest,
+                "{attempts}: {wait:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn failed_peers_are_tried_last() {
+        let health = MirrorHealth::new(&MirroringConfig::default(), &mut Registry::default());
+        let request = blob(1);
+
+        health.failed(&request, Some(2), "Connection refused".to_string());
+        assert!(!health.ready(&request));
+
+        // Peer 2 failed this object, so every other peer is tried before it again
+        health.failed(&request, Some(3), "Connection refused".to_string());
+        for _ in 0..20 {
+            assert_eq!(health.choose(&request, &[2, 3, 4]), Some(4));
+        }
+
+        // Once they all have, another round starts, preferring peers failing least overall
+        health.failed(&request, Some(4), "Connection refused".to_string());
+        health.succeeded(&blob(2), 3);
+        for _ in 0..20 {
+            assert_eq!(health.choose(&request, &[2, 3, 4]), Some(3));
+        }
+
+        health.succeeded(&request, 3);
+        assert!(health.ready(&request));
+        assert!(health.status().failing.is_empty());
+    }
+
+    #[test]
+    fn repeated_failures_are_reported() {
+        let config = MirroringConfig {
+            failing_after: 2,
+            ..Default::default()
+        };
+        let health = MirrorHealth::new(&config, &mut Registry::default());
+
+        health.failed(&blob(1), Some(2), "Peer responded with 404".to_string());
+        health.failed(&blob(2), Some(2), "Peer responded with 404".to_string());
+        health.failed(&blob(2), None, "Unable to store".to_string());
+
+        let status = health.status();
+        assert_eq!(status.failing.len(), 1);
+        assert_eq!(status.failing[0].digest, *blob(2).digest());
+        assert_eq!(status.failing[0].attempts, 2);
+        assert_eq!(status.failing[0].last_error, "Unable to store");
+        assert_eq!(status.peers[&2].failures, 2);
+        assert_eq!(status.peers[&2].consecutive_failures, 2);
+        assert_eq!(health.failing.get(), 1);
+        assert_eq!(health.backing_off.get(), 2);
+
+        // Objects that are no longer wanted are forgotten
+        health.retain(&HashSet::from([blob(1)]));
+        assert!(health.status().failing.is_empty());
+        assert_eq!(health.backing_off.get(), 1);
+    }
+}
@@ -5,6 +5,7 @@ use actix_web::Responder;
 use web::Json;
 
 use crate::app::RegistryApp;
+use crate::extractors::PeerOrAdmin;
 use crate::store::RegistryRequest;
 
 /**
@@ -19,6 +20,7 @@ use crate::store::RegistryRequest;
 #[post("/write")]
 pub async fn write(
     app: Data<RegistryApp>,
+    _caller: PeerOrAdmin,
     req: Json<RegistryRequest>,
 ) -> actix_web::Result<impl Responder> {
     let response = app.raft.client_write(req.0).await;
@@ -6,21 +6,44 @@ use actix_web::post;
 use actix_web::web;
 use actix_web::web::Data;
 use actix_web::Responder;
+use chrono::DateTime;
 use chrono::Utc;
 use openraft::error::Infallible;
 use openraft::BasicNode;
 use openraft::RaftMetrics;
+use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
+use tracing::error;
 use web::Json;
 
 use crate::app::RegistryApp;
+use crate::extractors::Admin;
+use crate::fsck::ClusterFsckReport;
+use crate::fsck::ClusterFsckRequest;
+use crate::garbage;
+use crate::garbage::GarbageReport;
+use crate::garbage::GarbageRun;
+use crate::garbage::GarbageStatus;
+use crate::mirror::MirrorStatus;
+use crate::scrubber;
+use crate::scrubber::ScrubPass;
+use crate::scrubber::ScrubStatus;
 use crate::store::SerializableRegistryStateMachine;
+use crate::token_server::generate_secret;
+use crate::token_server::hash_secret;
+use crate::token_server::ROBOT_PREFIX;
+use crate::types::AclEntry;
+use crate::types::AclSubject;
+use crate::types::AuditEvent;
+use crate::types::AuditQuery;
 use crate::types::Blob;
 use crate::types::Digest;
 use crate::types::Manifest;
+use crate::types::References;
 use crate::types::RegistryAction;
 use crate::types::RepositoryName;
+use crate::webhook::delivery::Delivery;
 use crate::RegistryNodeId;
 
 // --- Cluster management
@@ -33,6 +56,7 @@ use crate::RegistryNodeId;
 #[post("/add-learner")]
 pub async fn add_learner(
     app: Data<RegistryApp>,
+    _admin: Admin,
     req: Json<(RegistryNodeId, String)>,
 ) -> actix_web::Result<impl Responder> {
     let node_id = req.0 .0;
@@ -47,6 +71,7 @@ pub async fn add_learner(
 #[post("/change-membership")]
 pub async fn change_membership(
     app: Data<RegistryApp>,
+    _admin: Admin,
     req: Json<BTreeSet<RegistryNodeId>>,
 ) -> actix_web::Result<impl Responder> {
     let res = app.raft.change_membership(req.0, false).await;
@@ -55,7 +80,11 @@ pub async fn change_membership(
 
 /// Initialize a single-node cluster.
 #[post("/init")]
-pub async fn init(app: Data<RegistryApp>, req: Json<String>) -> actix_web::Result<impl Responder> {
+pub async fn init(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+    req: Json<String>,
+) -> actix_web::Result<impl Responder> {
     let node = BasicNode {
         addr: req.0.clone(),
     };
@@ -67,7 +96,7 @@ pub async fn init(app: Data<RegistryApp>, req: Json<String>) -> actix_web::Resul
 
 /// Get the latest metrics of the cluster
 #[get("/metrics")]
-pub async fn metrics(app: Data<RegistryApp>) -> actix_web::Result<impl Responder> {
+pub async fn metrics(app: Data<RegistryApp>, _admin: Admin) -> actix_web::Result<impl Responder> {
     let metrics = app.raft.metrics().borrow().clone();
 
     let res: Result<RaftMetrics<RegistryNodeId, BasicNode>, Infallible> = Ok(metrics);
@@ -79,12 +108,15 @@ pub struct ImportBody {
     pub blobs: BTreeMap<Digest, Blob>,
     pub manifests: BTreeMap<Digest, Manifest>,
     pub tags: BTreeMap<RepositoryName, BTreeMap<String, Digest>>,
+    #[serde(default)]
+    pub referrers: BTreeMap<Digest, BTreeSet<Digest>>,
 }
 
 /// Import a v2 snapshot
 #[post("/import")]
 pub async fn import(
     app: Data<RegistryApp>,
+    _admin: Admin,
     payload: web::Json<ImportBody>,
 ) -> actix_web::Result<impl Responder> {
     let mut actions = vec![];
@@ -157,6 +189,15 @@ pub async fn import(
             });
         }
     }
+    for (subject, referrers) in payload.referrers.iter() {
+        for digest in referrers.iter() {
+            actions.push(RegistryAction::ManifestSubject {
+                timestamp: Utc::now(),
+                digest: digest.clone(),
+                subject: subject.clone(),
+            });
+        }
+    }
     for (repo, tags) in payload.tags.iter() {
         for (tag, digest) in tags.iter() {
             actions.push(RegistryAction::HashTagged {
@@ -179,7 +220,7 @@ pub async fn import(
 }
 
 #[get("/export")]
-pub async fn export(app: Data<RegistryApp>) -> actix_web::Result<impl Responder> {
+pub async fn export(app: Data<RegistryApp>, _admin: Admin) -> actix_web::Result<impl Responder> {
     let sm = app.store.state_machine.read().unwrap();
     let state_machine = SerializableRegistryStateMachine::from(&*sm);
 
@@ -187,6 +228,355 @@ pub async fn export(app: Data<RegistryApp>) -> actix_web::Result<impl Responder>
         blobs: state_machine.blobs,
         manifests: state_machine.manifests,
         tags: state_machine.tags,
+        referrers: state_machine.referrers,
+    });
+    Ok(Json(res))
+}
+
+// --- Robot accounts
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RobotCredentials {
+    pub username: String,
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RobotSummary {
+    pub username: String,
+    pub created: DateTime<Utc>,
+    pub created_by: String,
+}
+
+/// Report a failed read of the registry state as a server error, rather than panicking.
+fn store_error(err: impl std::fmt::Debug) -> actix_web::Error {
+    error!("Management API: Unable to read the registry state: {err:?}");
+    actix_web::error::ErrorInternalServerError("Unable to read the registry state")
+}
+
+/// Create a robot account for the built-in token server, or replace the secret of an
+/// existing one. The secret is only ever returned here.
+#[post("/create-robot")]
+pub async fn create_robot(
+    app: Data<RegistryApp>,
+    admin: Admin,
+    req: Json<String>,
+) -> actix_web::Result<impl Responder> {
+    let name = req.0;
+
+    let valid = Regex::new(r"^[a-z0-9]+(?:[._-][a-z0-9]+)*$").unwrap();
+    if name.len() > 64 || !valid.is_match(&name) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Robot names must be lowercase alphanumerics separated by '.', '_' or '-'",
+        ));
+    }
+
+    let secret = generate_secret();
+
+    let actions = vec![RegistryAction::RobotAccountCreated {
+        timestamp: Utc::now(),
+        name: name.clone(),
+        secret_hash: hash_secret(&secret),
+        user: admin.subject.clone(),
+    }];
+
+    if !app.consistent_write(actions).await {
+        return Err(actix_web::error::ErrorInternalServerError(
+            "Unable to create robot account",
+        ));
+    }
+
+    let res: Result<RobotCredentials, Infallible> = Ok(RobotCredentials {
+        username: format!("{ROBOT_PREFIX}{name}"),
+        secret,
     });
     Ok(Json(res))
 }
+
+/// Revoke a robot account so it can't get any new tokens.
+#[post("/revoke-robot")]
+pub async fn revoke_robot(
+    app: Data<RegistryApp>,
+    admin: Admin,
+    req: Json<String>,
+) -> actix_web::Result<impl Responder> {
+    let name = req.0;
+    let name = name.strip_prefix(ROBOT_PREFIX).unwrap_or(&name).to_string();
+
+    if app.store.get_robot(&name).map_err(store_error)?.is_none() {
+        return Err(actix_web::error::ErrorNotFound("No such robot account"));
+    }
+
+    let actions = vec![RegistryAction::RobotAccountRevoked {
+        timestamp: Utc::now(),
+        name,
+        user: admin.subject.clone(),
+    }];
+
+    if !app.consistent_write(actions).await {
+        return Err(actix_web::error::ErrorInternalServerError(
+            "Unable to revoke robot account",
+        ));
+    }
+
+    let res: Result<(), Infallible> = Ok(());
+    Ok(Json(res))
+}
+
+#[get("/robots")]
+pub async fn robots(app: Data<RegistryApp>, _admin: Admin) -> actix_web::Result<impl Responder> {
+    let robots = app
+        .store
+        .get_robots()
+        .map_err(store_error)?
+        .into_iter()
+        .map(|(name, robot)| RobotSummary {
+            username: format!("{ROBOT_PREFIX}{name}"),
+            created: robot.created,
+            created_by: robot.created_by,
+        })
+        .collect::<Vec<_>>();
+
+    let res: Result<Vec<RobotSummary>, Infallible> = Ok(robots);
+    Ok(Json(res))
+}
+
+// --- Access control lists
+
+/// Actions an ACL entry can grant. `*` grants all of them.
+const ACL_ACTIONS: &[&str] = &["pull", "push", "delete", "*"];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AclRule {
+    pub subject: AclSubject,
+    pub repository: String,
+    #[serde(default)]
+    pub actions: BTreeSet<String>,
+}
+
+/// Grant `actions` on repositories matching `repository` to a user or group, replacing what
+/// an existing entry for the same subject and repository grants.
+#[post("/set-acl")]
+pub async fn set_acl(
+    app: Data<RegistryApp>,
+    admin: Admin,
+    req: Json<AclRule>,
+) -> actix_web::Result<impl Responder> {
+    let rule = req.0;
+
+    if rule.repository.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest(
+            "ACL entries need a repository pattern",
+        ));
+    }
+
+    if rule.actions.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest(
+            "ACL entries need at least one action",
+        ));
+    }
+
+    if let Some(action) = rule
+        .actions
+        .iter()
+        .find(|action| !ACL_ACTIONS.contains(&action.as_str()))
+    {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "Unknown action '{action}', expected one of pull, push, delete or *"
+        )));
+    }
+
+    let actions = vec![RegistryAction::AclEntrySet {
+        timestamp: Utc::now(),
+        subject: rule.subject,
+        repository: rule.repository,
+        actions: rule.actions,
+        user: admin.subject.clone(),
+    }];
+
+    if !app.consistent_write(actions).await {
+        return Err(actix_web::error::ErrorInternalServerError(
+            "Unable to set ACL entry",
+        ));
+    }
+
+    let res: Result<(), Infallible> = Ok(());
+    Ok(Json(res))
+}
+
+/// Remove the ACL entry for a subject and repository pattern.
+#[post("/remove-acl")]
+pub async fn remove_acl(
+    app: Data<RegistryApp>,
+    admin: Admin,
+    req: Json<AclRule>,
+) -> actix_web::Result<impl Responder> {
+    let rule = req.0;
+
+    if app
+        .store
+        .get_acl(&rule.subject, &rule.repository)
+        .map_err(store_error)?
+        .is_none()
+    {
+        return Err(actix_web::error::ErrorNotFound("No such ACL entry"));
+    }
+
+    let actions = vec![RegistryAction::AclEntryRemoved {
+        timestamp: Utc::now(),
+        subject: rule.subject,
+        repository: rule.repository,
+        user: admin.subject.clone(),
+    }];
+
+    if !app.consistent_write(actions).await {
+        return Err(actix_web::error::ErrorInternalServerError(
+            "Unable to remove ACL entry",
+        ));
+    }
+
+    let res: Result<(), Infallible> = Ok(());
+    Ok(Json(res))
+}
+
+#[get("/acls")]
+pub async fn acls(app: Data<RegistryApp>, _admin: Admin) -> actix_web::Result<impl Responder> {
+    let res: Result<Vec<AclEntry>, Infallible> = Ok(app.store.get_acls().map_err(store_error)?);
+    Ok(Json(res))
+}
+
+// --- Audit history
+
+/// Who pushed, tagged, deleted or imported what, and when, filtered by `query`.
+#[post("/audit")]
+pub async fn audit(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+    query: Json<AuditQuery>,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<Vec<AuditEvent>, Infallible> = Ok(app.store.get_audit_events(&query).unwrap());
+    Ok(Json(res))
+}
+
+// --- Webhook deliveries
+
+/// Webhook deliveries this node gave up on after running out of attempts.
+#[get("/webhook-dead-letters")]
+pub async fn webhook_dead_letters(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<Vec<Delivery>, Infallible> = Ok(app.webhook_deliveries.dead_letters().unwrap());
+    Ok(Json(res))
+}
+
+/// Try the dead letters with these ids again, or all of them if none are given. Returns how
+/// many were requeued.
+#[post("/retry-webhook-dead-letters")]
+pub async fn retry_webhook_dead_letters(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+    ids: Json<Vec<u64>>,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<usize, Infallible> =
+        Ok(app.webhook_deliveries.retry_dead_letters(&ids).unwrap());
+    Ok(Json(res))
+}
+
+// --- References
+
+/// The manifests, tags and repositories that use a manifest or blob.
+#[get("/references/{digest}")]
+pub async fn references(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+    digest: web::Path<Digest>,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<References, Infallible> = Ok(app.store.get_references(&digest).unwrap());
+    Ok(Json(res))
+}
+
+// --- Garbage collection
+
+/// Collect garbage on this node now, returning what was reaped once the run finishes.
+#[post("/garbage-collect")]
+pub async fn garbage_collect(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<GarbageRun, Infallible> = Ok(garbage::collect(&app).await);
+    Ok(Json(res))
+}
+
+/// What garbage collection would reap on this node if it ran now.
+#[get("/garbage-collect/dry-run")]
+pub async fn garbage_collect_dry_run(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<GarbageReport, Infallible> = Ok(garbage::dry_run(&app).unwrap());
+    Ok(Json(res))
+}
+
+/// Whether garbage collection is running on this node, and how the last run went.
+#[get("/garbage-collect/status")]
+pub async fn garbage_collect_status(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<GarbageStatus, Infallible> = Ok(app.garbage.status());
+    Ok(Json(res))
+}
+
+// --- Scrubbing
+
+/// Check every object stored on this node now, returning what was found once the pass finishes.
+#[post("/scrub")]
+pub async fn scrub(app: Data<RegistryApp>, _admin: Admin) -> actix_web::Result<impl Responder> {
+    let res: Result<ScrubPass, Infallible> = Ok(scrubber::scrub(&app).await);
+    Ok(Json(res))
+}
+
+/// Whether the scrubber is running on this node, and how the last pass went.
+#[get("/scrub/status")]
+pub async fn scrub_status(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<ScrubStatus, Infallible> = Ok(app.scrubber.status());
+    Ok(Json(res))
+}
+
+// --- Mirroring
+
+/// How mirroring from each peer has gone on this node, and the objects that keep failing.
+#[get("/mirror/status")]
+pub async fn mirror_status(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<MirrorStatus, Infallible> = Ok(app.mirroring.status());
+    Ok(Json(res))
+}
+
+// --- Consistency checks
+
+/// Have every member check its objects against the metadata, and merge what they found.
+#[post("/fsck")]
+pub async fn fsck(
+    app: Data<RegistryApp>,
+    _admin: Admin,
+    req: Json<ClusterFsckRequest>,
+) -> actix_web::Result<impl Responder> {
+    let report = match crate::fsck::check_cluster(&app, &req.0).await {
+        Ok(report) => report,
+        Err(err) => {
+            return Err(actix_web::error::ErrorInternalServerError(format!(
+                "{err:?}"
+            )));
+        }
+    };
+
+    let res: Result<ClusterFsckReport, Infallible> = Ok(report);
+    Ok(Json(res))
+}
@@ -1,4 +1,3 @@
-use actix_files::NamedFile;
 use actix_web::get;
 use actix_web::http::StatusCode;
 use actix_web::post;
@@ -9,6 +8,7 @@ use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::HttpResponseBuilder;
 use actix_web::Responder;
+use openraft::error::Infallible;
 use openraft::raft::AppendEntriesRequest;
 use openraft::raft::InstallSnapshotRequest;
 use openraft::raft::VoteRequest;
@@ -16,16 +16,29 @@ use serde::Deserialize;
 use web::Json;
 
 use crate::app::RegistryApp;
+use crate::extractors::Peer;
+use crate::extractors::PeerOrAdmin;
+use crate::fsck;
+use crate::fsck::FsckReport;
 use crate::registry::errors::RegistryError;
+use crate::registry::utils::range_offset;
+use crate::registry::utils::stored_body;
+use crate::registry::utils::stored_body_from;
+use crate::storage::ObjectKind;
+use crate::types::AuditPageRequest;
 use crate::types::Digest;
 use crate::RegistryNodeId;
 use crate::RegistryTypeConfig;
 
+/// The most audit events sent to a peer at once.
+const AUDIT_PAGE_LIMIT: usize = 1000;
+
 // --- Raft communication
 
 #[post("/raft-vote")]
 pub async fn vote(
     app: Data<RegistryApp>,
+    _caller: PeerOrAdmin,
     req: Json<VoteRequest<RegistryNodeId>>,
 ) -> actix_web::Result<impl Responder> {
     let res = app.raft.vote(req.0).await;
@@ -35,6 +48,7 @@ pub async fn vote(
 #[post("/raft-append")]
 pub async fn append(
     app: Data<RegistryApp>,
+    _caller: PeerOrAdmin,
     req: Json<AppendEntriesRequest<RegistryTypeConfig>>,
 ) -> actix_web::Result<impl Responder> {
     let res = app.raft.append_entries(req.0).await;
@@ -44,6 +58,7 @@ pub async fn append(
 #[post("/raft-snapshot")]
 pub async fn snapshot(
     app: Data<RegistryApp>,
+    _caller: PeerOrAdmin,
     req: Json<InstallSnapshotRequest<RegistryTypeConfig>>,
 ) -> actix_web::Result<impl Responder> {
     let res = app.raft.install_snapshot(req.0).await;
@@ -55,9 +70,12 @@ pub struct BlobRequest {
     digest: Digest,
 }
 
+/// Send a blob to a peer that is mirroring it. A peer resuming a download that was cut off
+/// asks for the rest with `Range: bytes=<offset>-`.
 #[get("/blobs/{digest}")]
 pub(crate) async fn get_blob(
     app: Data<RegistryApp>,
+    _peer: Peer,
     req: HttpRequest,
     path: Path<BlobRequest>,
 ) -> Result<impl Responder, RegistryError> {
@@ -87,21 +105,35 @@ pub(crate) async fn get_blob(
         }
     };
 
-    let blob_path = app.get_blob_path(&path.digest);
-    if !blob_path.is_file() {
-        tracing::info!("Blob was not present on disk");
-        return Err(RegistryError::BlobNotFound {});
+    if let Some(offset) = range_offset(&req) {
+        if let Some((blob, stored_size)) =
+            stored_body_from(&app, ObjectKind::Blob, &path.digest, offset).await
+        {
+            return Ok(HttpResponseBuilder::new(StatusCode::PARTIAL_CONTENT)
+                .content_type(content_type)
+                .append_header(("Docker-Content-Digest", path.digest.to_string()))
+                .append_header((
+                    "Content-Range",
+                    format!("bytes {offset}-{}/{stored_size}", stored_size - 1),
+                ))
+                .no_chunking(stored_size - offset)
+                .streaming(blob));
+        }
+
+        // Past the end, or gone: send all of it, or say it is missing
     }
 
-    let blob = NamedFile::open_async(blob_path)
-        .await
-        .unwrap()
-        .into_response(&req);
+    let Some((blob, stored_size)) = stored_body(&app, ObjectKind::Blob, &path.digest, false).await
+    else {
+        tracing::info!("Blob was not present in storage");
+        return Err(RegistryError::BlobNotFound {});
+    };
 
     Ok(HttpResponseBuilder::new(StatusCode::OK)
         .content_type(content_type)
         .append_header(("Docker-Content-Digest", path.digest.to_string()))
-        .body(blob.into_body()))
+        .no_chunking(stored_size)
+        .streaming(blob))
 }
 
 #[derive(Debug, Deserialize)]
@@ -112,7 +144,7 @@ pub struct ManifestGetRequestDigest {
 #[get("/manifests/{digest}")]
 pub(crate) async fn get_manifest(
     app: Data<RegistryApp>,
-    req: HttpRequest,
+    _peer: Peer,
     path: Path<ManifestGetRequestDigest>,
 ) -> Result<HttpResponse, RegistryError> {
     let manifest = match app.get_manifest(&path.digest) {
@@ -142,18 +174,48 @@ pub(crate) async fn get_manifest(
         }
     };
 
-    let manifest_path = app.get_manifest_path(&path.digest);
-    if !manifest_path.is_file() {
+    let Some((manifest, stored_size)) =
+        stored_body(&app, ObjectKind::Manifest, &path.digest, false).await
+    else {
         return Err(RegistryError::ManifestNotFound {});
-    }
-
-    let manifest = NamedFile::open_async(manifest_path)
-        .await
-        .unwrap()
-        .into_response(&req);
+    };
 
     Ok(HttpResponseBuilder::new(StatusCode::OK)
         .content_type(content_type)
         .append_header(("Docker-Content-Digest", path.digest.to_string()))
-        .body(manifest.into_body()))
+        .no_chunking(stored_size)
+        .streaming(manifest))
+}
+
+// --- Audit history
+
+/// A page of this node's audit history, for a peer that installed a snapshot and is filling in
+/// the events it is missing. Refused while this node is missing some of them too.
+#[post("/audit/page")]
+pub(crate) async fn audit_page(
+    app: Data<RegistryApp>,
+    _caller: PeerOrAdmin,
+    req: Json<AuditPageRequest>,
+) -> actix_web::Result<HttpResponse> {
+    let page = app
+        .store
+        .get_audit_page(req.after, req.until, req.limit.min(AUDIT_PAGE_LIMIT))
+        .map_err(|_| actix_web::error::ErrorInternalServerError("Unable to read audit history"))?;
+
+    match page {
+        Some(events) => Ok(HttpResponse::Ok().json(events)),
+        None => Ok(HttpResponse::ServiceUnavailable().finish()),
+    }
+}
+
+// --- Consistency checks
+
+/// Check the objects stored on this node, for a cluster-wide fsck started by another member.
+#[post("/fsck/local")]
+pub(crate) async fn fsck_local(
+    app: Data<RegistryApp>,
+    _caller: PeerOrAdmin,
+) -> actix_web::Result<impl Responder> {
+    let res: Result<FsckReport, Infallible> = Ok(fsck::check_local(&app).await);
+    Ok(Json(res))
 }
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use openraft::error::InstallSnapshotError;
 use openraft::error::NetworkError;
 use openraft::error::RPCError;
@@ -16,10 +18,20 @@ use openraft::RaftNetworkFactory;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
+use crate::app::issue_peer_token;
+use crate::token_server::TokenServer;
 use crate::RegistryNodeId;
 use crate::RegistryTypeConfig;
 
-pub struct RegistryNetwork {}
+#[derive(Clone)]
+pub struct RegistryNetwork {
+    pub id: RegistryNodeId,
+    pub client: reqwest::Client,
+    pub scheme: &'static str,
+    /// Signs the admin tokens that raft RPCs carry when members can't tell this node is a peer
+    /// from its certificate, see [`crate::app::RegistryApp::peer_token`].
+    pub token_server: Option<Arc<TokenServer>>,
+}
 
 impl RegistryNetwork {
     pub async fn send_rpc<Req, Resp, Err>(
@@ -36,17 +48,20 @@ impl RegistryNetwork {
     {
         let addr = &target_node.addr;
 
-        let url = format!("http://{}/{}", addr, uri);
+        let url = format!("{}://{}/{}", self.scheme, addr, uri);
 
         tracing::debug!("send_rpc to url: {}", url);
 
-        let client = reqwest::Client::new();
-
-        tracing::debug!("client is created for: {}", url);
+        let mut request = self.client.post(url).json(&req);
+        if let Some(token) = self
+            .token_server
+            .as_ref()
+            .and_then(|token_server| issue_peer_token(token_server, self.id))
+        {
+            request = request.bearer_auth(token);
+        }
 
-        let resp = client
-            .post(url)
-            .json(&req)
+        let resp = request
             .send()
             .await
             .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
@@ -62,14 +77,14 @@ impl RegistryNetwork {
     }
 }
 
-// NOTE: This could be implemented also on `Arc<RegistryNetwork>`, but since it's empty, implemented
-// directly.
+// NOTE: This could be implemented also on `Arc<RegistryNetwork>`, but since it's cheap to clone,
+// implemented directly.
 impl RaftNetworkFactory<RegistryTypeConfig> for RegistryNetwork {
     type Network = RegistryNetworkConnection;
 
     async fn new_client(&mut self, target: RegistryNodeId, node: &BasicNode) -> Self::Network {
         RegistryNetworkConnection {
-            owner: RegistryNetwork {},
+            owner: self.clone(),
             target,
             target_node: node.clone(),
         }
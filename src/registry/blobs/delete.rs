@@ -2,13 +2,16 @@ use crate::app::RegistryApp;
 use crate::extractors::Token;
 
 use crate::registry::errors::RegistryError;
+use crate::types::AuditRequest;
 use crate::types::Digest;
 use crate::types::RegistryAction;
 use crate::types::RepositoryName;
+use actix_request_identifier::RequestId;
 use actix_web::delete;
 use actix_web::http::StatusCode;
 use actix_web::web::Data;
 use actix_web::web::Path;
+use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::HttpResponseBuilder;
 use chrono::prelude::*;
@@ -23,16 +26,18 @@ pub struct BlobRequest {
 #[delete("/{repository:[^{}]+}/blobs/{digest}")]
 pub(crate) async fn delete(
     app: Data<RegistryApp>,
+    req: HttpRequest,
     path: Path<BlobRequest>,
     token: Token,
+    request_id: RequestId,
 ) -> Result<HttpResponse, RegistryError> {
-    if !token.validated_token {
-        return Err(RegistryError::MustAuthenticate {
-            challenge: token.get_push_challenge(&path.repository),
-        });
-    }
+    if !token.has_permission(&path.repository, "delete").await {
+        if !token.validated_token {
+            return Err(RegistryError::MustAuthenticate {
+                challenge: token.get_delete_challenge(&path.repository),
+            });
+        }
 
-    if !token.has_permission(&path.repository, "push") {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -51,7 +56,10 @@ pub(crate) async fn delete(
         user: token.sub.clone(),
     }];
 
-    if !app.consistent_write(actions).await {
+    if !app
+        .consistent_write_for(AuditRequest::new(&req, &request_id), actions)
+        .await
+    {
         // FIXME
         return Err(RegistryError::BlobNotFound {});
     }
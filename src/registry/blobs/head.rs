@@ -1,14 +1,14 @@
 use crate::app::RegistryApp;
 use crate::extractors::Token;
 use crate::registry::errors::RegistryError;
+use crate::registry::utils::stored_body;
+use crate::storage::ObjectKind;
 use crate::types::Digest;
 use crate::types::RepositoryName;
-use actix_files::NamedFile;
 use actix_web::head;
 use actix_web::http::StatusCode;
 use actix_web::web::Data;
 use actix_web::web::Path;
-use actix_web::HttpRequest;
 use actix_web::HttpResponseBuilder;
 use actix_web::Responder;
 use serde::Deserialize;
@@ -23,17 +23,16 @@ pub struct BlobRequest {
 #[head("/{repository:[^{}]+}/blobs/{digest}")]
 pub(crate) async fn head(
     app: Data<RegistryApp>,
-    req: HttpRequest,
     path: Path<BlobRequest>,
     token: Token,
 ) -> Result<impl Responder, RegistryError> {
-    if !token.validated_token {
-        return Err(RegistryError::MustAuthenticate {
-            challenge: token.get_pull_challenge(&path.repository),
-        });
-    }
+    if !token.has_permission(&path.repository, "pull").await {
+        if !token.validated_token {
+            return Err(RegistryError::MustAuthenticate {
+                challenge: token.get_pull_challenge(&path.repository),
+            });
+        }
 
-    if !token.has_permission(&path.repository, "pull") {
         debug!("Token does not have access to perform this action");
         return Err(RegistryError::AccessDenied {});
     }
@@ -65,19 +64,15 @@ pub(crate) async fn head(
         }
     };
 
-    let blob_path = app.get_blob_path(&path.digest);
-    if !blob_path.is_file() {
-        tracing::info!("Blob was not present on disk");
+    let Some((blob, stored_size)) = stored_body(&app, ObjectKind::Blob, &path.digest, true).await
+    else {
+        tracing::info!("Blob was not present in storage");
         return Err(RegistryError::BlobNotFound {});
-    }
-
-    let blob = NamedFile::open_async(blob_path)
-        .await
-        .unwrap()
-        .into_response(&req);
+    };
 
     Ok(HttpResponseBuilder::new(StatusCode::OK)
         .content_type(content_type)
         .append_header(("Docker-Content-Digest", path.digest.to_string()))
-        .body(blob.into_body()))
+        .no_chunking(stored_size)
+        .streaming(blob))
 }
@@ -22,13 +22,13 @@ pub(crate) async fn get(
     path: Path<BlobUploadRequest>,
     token: Token,
 ) -> Result<HttpResponse, RegistryError> {
-    if !token.validated_token {
-        return Err(RegistryError::MustAuthenticate {
-            challenge: token.get_push_challenge(&path.repository),
-        });
-    }
+    if !token.has_permission(&path.repository, "pull").await {
+        if !token.validated_token {
+            return Err(RegistryError::MustAuthenticate {
+                challenge: token.get_push_challenge(&path.repository),
+            });
+        }
 
-    if !token.has_permission(&path.repository, "pull") {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -48,13 +48,13 @@ pub(crate) async fn patch(
     body: Payload,
     token: Token,
 ) -> Result<impl Responder, RegistryError> {
-    if !token.validated_token {
-        return Err(RegistryError::MustAuthenticate {
-            challenge: token.get_push_challenge(&path.repository),
-        });
-    }
+    if !token.has_permission(&path.repository, "push").await {
+        if !token.validated_token {
+            return Err(RegistryError::MustAuthenticate {
+                challenge: token.get_push_challenge(&path.repository),
+            });
+        }
 
-    if !token.has_permission(&path.repository, "push") {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -4,14 +4,16 @@ use crate::extractors::token::Access;
 use crate::extractors::Token;
 use crate::registry::errors::RegistryError;
 use crate::registry::utils::{upload_part, validate_hash};
-use crate::types::{Digest, RegistryAction};
+use crate::storage::ObjectKind;
+use crate::types::{AuditRequest, Digest, RegistryAction};
 use crate::{app::RegistryApp, types::RepositoryName};
+use actix_request_identifier::RequestId;
 use actix_web::http::StatusCode;
 use actix_web::post;
 use actix_web::web::{Payload, Query};
 use actix_web::{
     web::{Data, Path},
-    HttpResponse, HttpResponseBuilder,
+    HttpRequest, HttpResponse, HttpResponseBuilder,
 };
 use chrono::Utc;
 use serde::Deserialize;
@@ -31,30 +33,32 @@ pub struct BlobUploadPostQuery {
 #[post("/{repository:[^{}]+}/blobs/uploads")]
 pub(crate) async fn post(
     app: Data<RegistryApp>,
+    req: HttpRequest,
     path: Path<BlobUploadRequest>,
     query: Query<BlobUploadPostQuery>,
     body: Payload,
     token: Token,
+    request_id: RequestId,
 ) -> Result<HttpResponse, RegistryError> {
-    if !token.validated_token {
-        let mut access = vec![Access {
-            repository: path.repository.clone(),
-            permissions: HashSet::from(["pull".to_string(), "push".to_string()]),
-        }];
-
-        if let Some(from) = &query.from {
-            access.push(Access {
-                repository: from.clone(),
-                permissions: HashSet::from(["pull".to_string()]),
+    if !token.has_permission(&path.repository, "push").await {
+        if !token.validated_token {
+            let mut access = vec![Access {
+                repository: path.repository.clone(),
+                permissions: HashSet::from(["pull".to_string(), "push".to_string()]),
+            }];
+
+            if let Some(from) = &query.from {
+                access.push(Access {
+                    repository: from.clone(),
+                    permissions: HashSet::from(["pull".to_string()]),
+                });
+            }
+
+            return Err(RegistryError::MustAuthenticate {
+                challenge: token.get_challenge(access),
             });
         }
 
-        return Err(RegistryError::MustAuthenticate {
-            challenge: token.get_challenge(access),
-        });
-    }
-
-    if !token.has_permission(&path.repository, "push") {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -63,7 +67,7 @@ pub(crate) async fn post(
             return Err(RegistryError::UploadInvalid {});
         }
 
-        if !token.has_permission(from, "pull") {
+        if !token.has_permission(from, "pull").await {
             return Err(RegistryError::UploadInvalid {});
         }
 
@@ -76,7 +80,10 @@ pub(crate) async fn post(
                     user: token.sub.clone(),
                 }];
 
-                if !app.consistent_write(actions).await {
+                if !app
+                    .consistent_write_for(AuditRequest::new(&req, &request_id), actions)
+                    .await
+                {
                     return Err(RegistryError::UploadInvalid {});
                 }
 
@@ -114,8 +121,6 @@ pub(crate) async fn post(
                 return Err(RegistryError::DigestInvalid {});
             }
 
-            let dest = app.get_blob_path(digest);
-
             let stat = match tokio::fs::metadata(&filename).await {
                 Ok(result) => result,
                 Err(_) => {
@@ -123,11 +128,9 @@ pub(crate) async fn post(
                 }
             };
 
-            match tokio::fs::rename(filename, dest).await {
-                Ok(_) => {}
-                Err(_) => {
-                    return Err(RegistryError::UploadInvalid {});
-                }
+            if let Err(err) = app.storage.put(ObjectKind::Blob, digest, &filename).await {
+                tracing::error!("Unable to store blob {digest}: {err:?}");
+                return Err(RegistryError::UploadInvalid {});
             }
 
             let actions = vec![
@@ -150,7 +153,10 @@ pub(crate) async fn post(
                 },
             ];
 
-            if !app.consistent_write(actions).await {
+            if !app
+                .consistent_write_for(AuditRequest::new(&req, &request_id), actions)
+                .await
+            {
                 return Err(RegistryError::UploadInvalid {});
             }
 
@@ -2,16 +2,20 @@ use crate::extractors::Token;
 use crate::registry::errors::RegistryError;
 use crate::registry::utils::upload_part;
 use crate::registry::utils::validate_hash;
+use crate::storage::ObjectKind;
+use crate::types::AuditRequest;
 use crate::types::Digest;
 use crate::types::RegistryAction;
 use crate::types::RepositoryName;
 use crate::RegistryApp;
+use actix_request_identifier::RequestId;
 use actix_web::http::StatusCode;
 use actix_web::put;
 use actix_web::web::Data;
 use actix_web::web::Path;
 use actix_web::web::Payload;
 use actix_web::web::Query;
+use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::HttpResponseBuilder;
 use chrono::Utc;
@@ -31,18 +35,20 @@ pub struct BlobUploadPutQuery {
 #[put("/{repository:[^{}]+}/blobs/uploads/{upload_id}")]
 pub(crate) async fn put(
     app: Data<RegistryApp>,
+    req: HttpRequest,
     path: Path<BlobUploadRequest>,
     query: Query<BlobUploadPutQuery>,
     body: Payload,
     token: Token,
+    request_id: RequestId,
 ) -> Result<HttpResponse, RegistryError> {
-    if !token.validated_token {
-        return Err(RegistryError::MustAuthenticate {
-            challenge: token.get_push_challenge(&path.repository),
-        });
-    }
+    if !token.has_permission(&path.repository, "push").await {
+        if !token.validated_token {
+            return Err(RegistryError::MustAuthenticate {
+                challenge: token.get_push_challenge(&path.repository),
+            });
+        }
 
-    if !token.has_permission(&path.repository, "push") {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -65,8 +71,6 @@ pub(crate) async fn put(
         return Err(RegistryError::DigestInvalid {});
     }
 
-    let dest = app.get_blob_path(&query.digest);
-
     let stat = match tokio::fs::metadata(&filename).await {
         Ok(result) => result,
         Err(_) => {
@@ -74,11 +78,13 @@ pub(crate) async fn put(
         }
     };
 
-    match tokio::fs::rename(filename.clone(), dest.clone()).await {
-        Ok(_) => {}
-        Err(_e) => {
-            return Err(RegistryError::UploadInvalid {});
-        }
+    if let Err(err) = app
+        .storage
+        .put(ObjectKind::Blob, &query.digest, &filename)
+        .await
+    {
+        tracing::error!("Unable to store blob {}: {err:?}", query.digest);
+        return Err(RegistryError::UploadInvalid {});
     }
 
     let actions = vec![
@@ -101,7 +107,10 @@ pub(crate) async fn put(
         },
     ];
 
-    if !app.consistent_write(actions).await {
+    if !app
+        .consistent_write_for(AuditRequest::new(&req, &request_id), actions)
+        .await
+    {
         return Err(RegistryError::UploadInvalid {});
     }
 
@@ -22,6 +22,8 @@ pub(crate) enum RegistryError {
         upload_id: String,
         size: u64,
     },
+    /// Something this node depends on, like its database or storage, failed.
+    Unavailable {},
 }
 
 impl std::fmt::Display for RegistryError {
@@ -86,6 +88,11 @@ impl ResponseError for RegistryError {
 
                 HttpResponseBuilder::new(StatusCode::BAD_REQUEST).body(body)
             }
+            Self::Unavailable {} => {
+                let body = simple_oci_error("UNAVAILABLE", "service unavailable");
+
+                HttpResponseBuilder::new(StatusCode::SERVICE_UNAVAILABLE).body(body)
+            }
             Self::RangeNotSatisfiable {
                 repository,
                 upload_id,
@@ -0,0 +1,299 @@
+//! A feed of changes to tags, manifests and blobs, streamed as they are applied to the state
+//! machine. It is read from the replicated audit history, so any node can serve it and a
+//! consumer can resume from where it got to after a disconnect.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::{ACCEPT, CACHE_CONTROL};
+use actix_web::web::{Bytes, Data, Query};
+use actix_web::{get, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures::stream;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::app::RegistryApp;
+use crate::extractors::Token;
+use crate::registry::errors::RegistryError;
+use crate::types::{AuditCursor, AuditEvent, Digest, RepositoryName};
+
+/// How many audit events are read from the history at once.
+const BATCH_SIZE: usize = 100;
+
+/// How often an idle event stream sends a comment, so proxies don't close it.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a permission decision is reused for. Decisions are also forgotten whenever entries
+/// are applied, but authorizers outside the log (a policy file or an HTTP service) can change
+/// their mind at any time.
+const PERMISSION_TTL: Duration = Duration::from_secs(60);
+
+/// The audited actions that change tags, manifests or blobs.
+const CHANGE_ACTIONS: &[&str] = &[
+    "blob-mounted",
+    "blob-unmounted",
+    "manifest-mounted",
+    "manifest-unmounted",
+    "hash-tagged",
+];
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    /// Only stream changes applied from log entries after this index.
+    since: Option<u64>,
+    /// Only stream changes to repositories whose name starts with this.
+    repository: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Change {
+    /// Where the change is in the history. Resuming from it with `Last-Event-ID` skips the
+    /// changes up to and including this one.
+    id: String,
+    /// The raft log index of the entry the change was applied from.
+    index: u64,
+    timestamp: DateTime<Utc>,
+    action: String,
+    repository: RepositoryName,
+    digest: Digest,
+    tag: Option<String>,
+    previous_digest: Option<Digest>,
+}
+
+impl Change {
+    fn from_audit(cursor: AuditCursor, event: AuditEvent) -> Option<Self> {
+        if !CHANGE_ACTIONS.contains(&event.action.as_str()) {
+            return None;
+        }
+
+        Some(Change {
+            id: format!("{}-{}", cursor.index, cursor.position),
+            index: event.index,
+            timestamp: event.timestamp,
+            action: event.action,
+            repository: event.repository?,
+            digest: event.digest?,
+            tag: event.tag,
+            previous_digest: event.previous_digest,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    EventStream,
+    Ndjson,
+}
+
+impl Format {
+    fn from_request(req: &HttpRequest) -> Self {
+        match req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+        {
+            Some(accept) if accept.contains("text/event-stream") => Format::EventStream,
+            _ => Format::Ndjson,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::EventStream => "text/event-stream",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn encode(self, change: &Change) -> Bytes {
+        let data = serde_json::to_string(change).unwrap();
+
+        match self {
+            Format::EventStream => format!(
+                "id: {}\nevent: {}\ndata: {data}\n\n",
+                change.id, change.action
+            )
+            .into(),
+            Format::Ndjson => format!("{data}\n").into(),
+        }
+    }
+}
+
+/// Parse the `Last-Event-ID` an event stream client sends when it reconnects.
+fn parse_event_id(id: &str) -> Option<AuditCursor> {
+    let (index, position) = id.split_once('-')?;
+
+    Some(AuditCursor {
+        index: index.parse().ok()?,
+        position: position.parse().ok()?,
+    })
+}
+
+struct Feed {
+    app: Data<RegistryApp>,
+    token: Token,
+    format: Format,
+    repository: Option<String>,
+    cursor: Option<AuditCursor>,
+    applied: tokio::sync::watch::Receiver<()>,
+    permitted: HashMap<RepositoryName, (bool, Instant)>,
+    pending: VecDeque<Bytes>,
+}
+
+impl Feed {
+    async fn is_permitted(&mut self, repository: &RepositoryName) -> bool {
+        if let Some((permitted, checked)) = self.permitted.get(repository) {
+            if checked.elapsed() < PERMISSION_TTL {
+                return *permitted;
+            }
+        }
+
+        let permitted = self.token.has_permission(repository, "pull").await;
+        self.permitted
+            .insert(repository.clone(), (permitted, Instant::now()));
+        permitted
+    }
+
+    /// Forget permission decisions if entries have been applied since they were made, as they
+    /// may have changed the ACL entries.
+    fn forget_permissions_if_applied(&mut self) {
+        if self.applied.has_changed().unwrap_or(false) {
+            self.applied.borrow_and_update();
+            self.permitted.clear();
+        }
+    }
+
+    /// How long until the caller's token expires, if it does.
+    fn expires_in(&self) -> Option<Duration> {
+        let expires = self.token.expires?;
+        Some((expires - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+    }
+
+    /// The next chunk of the response, waiting for more changes to be applied when the
+    /// consumer has seen everything so far. The stream ends when the caller's token expires.
+    async fn next(&mut self) -> Option<Bytes> {
+        loop {
+            if self.expires_in() == Some(Duration::ZERO) {
+                return None;
+            }
+
+            if let Some(chunk) = self.pending.pop_front() {
+                return Some(chunk);
+            }
+
+            self.forget_permissions_if_applied();
+
+            let batch = match self
+                .app
+                .store
+                .get_audit_events_after(self.cursor, BATCH_SIZE)
+            {
+                Ok(batch) => batch,
+                Err(err) => {
+                    error!("Change feed: Unable to read audit history: {err:?}");
+                    return None;
+                }
+            };
+
+            if batch.is_empty() {
+                let wait = match self.expires_in() {
+                    Some(expires_in) if expires_in < KEEPALIVE_INTERVAL => expires_in,
+                    _ => KEEPALIVE_INTERVAL,
+                };
+
+                match tokio::time::timeout(wait, self.applied.changed()).await {
+                    Ok(Ok(())) => self.permitted.clear(),
+                    Ok(Err(_)) => return None,
+                    Err(_) => {
+                        if self.format == Format::EventStream {
+                            self.pending
+                                .push_back(Bytes::from_static(b": keepalive\n\n"));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            for (cursor, event) in batch {
+                self.cursor = Some(cursor);
+
+                let Some(change) = Change::from_audit(cursor, event) else {
+                    continue;
+                };
+
+                if let Some(prefix) = &self.repository {
+                    if !change.repository.name.starts_with(prefix.as_str()) {
+                        continue;
+                    }
+                }
+
+                if !self.is_permitted(&change.repository).await {
+                    continue;
+                }
+
+                self.pending.push_back(self.format.encode(&change));
+            }
+        }
+    }
+}
+
+#[get("/_events")]
+pub(crate) async fn get(
+    app: Data<RegistryApp>,
+    req: HttpRequest,
+    query: Query<EventsQuery>,
+    token: Token,
+) -> Result<HttpResponse, RegistryError> {
+    if !token.validated_token {
+        return Err(RegistryError::MustAuthenticate {
+            challenge: token.get_general_challenge(),
+        });
+    }
+
+    let format = Format::from_request(&req);
+
+    // Subscribe before reading the history, so nothing applied in between is missed
+    let applied = app.store.watch_audit_events();
+
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(parse_event_id);
+
+    let cursor = match (last_event_id, query.since) {
+        (Some(cursor), _) => Some(cursor),
+        (None, Some(since)) => Some(AuditCursor {
+            index: since,
+            position: u32::MAX,
+        }),
+        // Without somewhere to resume from, only stream changes from now on
+        (None, None) => app.store.get_last_audit_cursor().map_err(|err| {
+            error!("Change feed: Unable to read audit history: {err:?}");
+            RegistryError::Unavailable {}
+        })?,
+    };
+
+    let feed = Feed {
+        app: app.clone(),
+        token,
+        format,
+        repository: query.into_inner().repository,
+        cursor,
+        applied,
+        permitted: HashMap::new(),
+        pending: VecDeque::new(),
+    };
+
+    let body = stream::unfold(feed, |mut feed| async move {
+        let chunk = feed.next().await?;
+        Some((Ok::<_, Infallible>(chunk), feed))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body))
+}
@@ -1,9 +1,13 @@
 use crate::app::RegistryApp;
 use crate::extractors::Token;
 use crate::registry::errors::RegistryError;
+use crate::registry::utils::stored_body;
+use crate::storage::ObjectKind;
+use crate::types::AuditRequest;
 use crate::types::Digest;
 use crate::types::RepositoryName;
-use actix_files::NamedFile;
+use crate::webhook::{Event, EventKind};
+use actix_request_identifier::RequestId;
 use actix_web::get;
 use actix_web::http::StatusCode;
 use actix_web::web::Data;
@@ -46,14 +50,15 @@ pub(crate) async fn get(
     req: HttpRequest,
     path: Path<ManifestGetRequestDigest>,
     token: Token,
+    request_id: RequestId,
 ) -> Result<HttpResponse, RegistryError> {
-    if !token.validated_token {
-        return Err(RegistryError::MustAuthenticate {
-            challenge: token.get_pull_challenge(&path.repository),
-        });
-    }
+    if !token.has_permission(&path.repository, "pull").await {
+        if !token.validated_token {
+            return Err(RegistryError::MustAuthenticate {
+                challenge: token.get_pull_challenge(&path.repository),
+            });
+        }
 
-    if !token.has_permission(&path.repository, "pull") {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -79,7 +84,7 @@ pub(crate) async fn get(
         }
     };
 
-    let _content_length = match manifest.size {
+    let content_length = match manifest.size {
         Some(content_length) => content_length,
         _ => {
             debug!("Could not extract content length from graph");
@@ -87,21 +92,25 @@ pub(crate) async fn get(
         }
     };
 
-    let manifest_path = app.get_manifest_path(&path.digest);
-    if !manifest_path.is_file() {
-        debug!("Expected manifest file does not exist");
+    let Some((manifest, stored_size)) =
+        stored_body(&app, ObjectKind::Manifest, &path.digest, false).await
+    else {
+        debug!("Expected manifest does not exist in storage");
         return Err(RegistryError::ManifestNotFound {});
-    }
+    };
 
-    let manifest = NamedFile::open_async(manifest_path)
-        .await
-        .unwrap()
-        .into_response(&req);
+    app.notify(Event {
+        content_type: Some(content_type.clone()),
+        size: Some(content_length),
+        request: Some(AuditRequest::new(&req, &request_id)),
+        ..Event::manifest(EventKind::Pull, &path.repository, &path.digest, &token.sub)
+    });
 
     Ok(HttpResponseBuilder::new(StatusCode::OK)
         .content_type(content_type)
         .append_header(("Docker-Content-Digest", path.digest.to_string()))
-        .body(manifest.into_body()))
+        .no_chunking(stored_size)
+        .streaming(manifest))
 }
 
 #[derive(Debug, Deserialize)]
@@ -116,14 +125,15 @@ pub(crate) async fn get_by_tag(
     req: HttpRequest,
     path: Path<ManifestGetRequestTag>,
     token: Token,
+    request_id: RequestId,
 ) -> Result<HttpResponse, RegistryError> {
-    if !token.validated_token {
-        return Err(RegistryError::MustAuthenticate {
-            challenge: token.get_pull_challenge(&path.repository),
-        });
-    }
+    if !token.has_permission(&path.repository, "pull").await {
+        if !token.validated_token {
+            return Err(RegistryError::MustAuthenticate {
+                challenge: token.get_pull_challenge(&path.repository),
+            });
+        }
 
-    if !token.has_permission(&path.repository, "pull") {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -157,7 +167,7 @@ pub(crate) async fn get_by_tag(
         }
     };
 
-    let _content_length = match manifest.size {
+    let content_length = match manifest.size {
         Some(content_length) => content_length,
         _ => {
             debug!("Could not extract content length from graph");
@@ -165,19 +175,24 @@ pub(crate) async fn get_by_tag(
         }
     };
 
-    let manifest_path = app.get_manifest_path(&digest);
-    if !manifest_path.is_file() {
-        debug!("Expected manifest file does not exist");
+    let Some((manifest, stored_size)) =
+        stored_body(&app, ObjectKind::Manifest, &digest, false).await
+    else {
+        debug!("Expected manifest does not exist in storage");
         return Err(RegistryError::ManifestNotFound {});
-    }
+    };
 
-    let manifest = NamedFile::open_async(manifest_path)
-        .await
-        .unwrap()
-        .into_response(&req);
+    app.notify(Event {
+        content_type: Some(content_type.clone()),
+        size: Some(content_length),
+        tag: Some(path.tag.clone()),
+        request: Some(AuditRequest::new(&req, &request_id)),
+        ..Event::manifest(EventKind::Pull, &path.repository, &digest, &token.sub)
+    });
 
     Ok(HttpResponseBuilder::new(StatusCode::OK)
         .content_type(content_type)
         .append_header(("Docker-Content-Digest", digest.to_string()))
-        .body(manifest.into_body()))
+        .no_chunking(stored_size)
+        .streaming(manifest))
 }
@@ -1,14 +1,14 @@
 use crate::app::RegistryApp;
 use crate::extractors::Token;
 use crate::registry::errors::RegistryError;
+use crate::registry::utils::stored_body;
+use crate::storage::ObjectKind;
 use crate::types::Digest;
 use crate::types::RepositoryName;
-use actix_files::NamedFile;
 use actix_web::head;
 use actix_web::http::StatusCode;
 use actix_web::web::Data;
 use actix_web::web::Path;
-use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::HttpResponseBuilder;
 use serde::Deserialize;
@@ -43,17 +43,16 @@ pub struct ManifestGetRequestDigest {
 #[head("/{repository:[^{}]+}/manifests/{digest:sha256:.*}")]
 pub(crate) async fn head(
     app: Data<RegistryApp>,
-    req: HttpRequest,
     path: Path<ManifestGetRequestDigest>,
     token: Token,
 ) -> Result<HttpResponse, RegistryError> {
-    if !token.validated_token {
+    if !token.validated_token && !token.is_public(&path.repository) {
         return Err(RegistryError::MustAuthenticate {
             challenge: token.get_pull_challenge(&path.repository),
         });
     }
 
-    if !token.has_permission(&path.repository, "pull") {
+    if !token.has_permission(&path.repository, "pull").await {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -87,21 +86,18 @@ pub(crate) async fn head(
         }
     };
 
-    let manifest_path = app.get_manifest_path(&path.digest);
-    if !manifest_path.is_file() {
-        debug!("Expected manifest file does not exist");
+    let Some((manifest, stored_size)) =
+        stored_body(&app, ObjectKind::Manifest, &path.digest, true).await
+    else {
+        debug!("Expected manifest does not exist in storage");
         return Err(RegistryError::ManifestNotFound {});
-    }
-
-    let manifest = NamedFile::open_async(manifest_path)
-        .await
-        .unwrap()
-        .into_response(&req);
+    };
 
     Ok(HttpResponseBuilder::new(StatusCode::OK)
         .content_type(content_type)
         .append_header(("Docker-Content-Digest", path.digest.to_string()))
-        .body(manifest.into_body()))
+        .no_chunking(stored_size)
+        .streaming(manifest))
 }
 
 #[derive(Debug, Deserialize)]
@@ -113,17 +109,16 @@ pub struct ManifestGetRequestTag {
 #[head("/{repository:[^{}]+}/manifests/{tag}")]
 pub(crate) async fn head_by_tag(
     app: Data<RegistryApp>,
-    req: HttpRequest,
     path: Path<ManifestGetRequestTag>,
     token: Token,
 ) -> Result<HttpResponse, RegistryError> {
-    if !token.validated_token {
+    if !token.validated_token && !token.is_public(&path.repository) {
         return Err(RegistryError::MustAuthenticate {
             challenge: token.get_pull_challenge(&path.repository),
         });
     }
 
-    if !token.has_permission(&path.repository, "pull") {
+    if !token.has_permission(&path.repository, "pull").await {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -165,19 +160,16 @@ pub(crate) async fn head_by_tag(
         }
     };
 
-    let manifest_path = app.get_manifest_path(&digest);
-    if !manifest_path.is_file() {
-        debug!("Expected manifest file does not exist");
+    let Some((manifest, stored_size)) =
+        stored_body(&app, ObjectKind::Manifest, &digest, true).await
+    else {
+        debug!("Expected manifest does not exist in storage");
         return Err(RegistryError::ManifestNotFound {});
-    }
-
-    let manifest = NamedFile::open_async(manifest_path)
-        .await
-        .unwrap()
-        .into_response(&req);
+    };
 
     Ok(HttpResponseBuilder::new(StatusCode::OK)
         .content_type(content_type)
         .append_header(("Docker-Content-Digest", digest.to_string()))
-        .body(manifest.into_body()))
+        .no_chunking(stored_size)
+        .streaming(manifest))
 }
@@ -2,9 +2,12 @@ use crate::app::RegistryApp;
 use crate::extractors::Token;
 use crate::registry::errors::RegistryError;
 use crate::registry::utils::get_hash;
+use crate::storage::ObjectKind;
+use crate::types::AuditRequest;
+use crate::types::Digest;
 use crate::types::RegistryAction;
 use crate::types::RepositoryName;
-use crate::webhook::Event;
+use actix_request_identifier::RequestId;
 use actix_web::http::StatusCode;
 use actix_web::put;
 use actix_web::web::Data;
@@ -29,16 +32,17 @@ pub(crate) async fn put(
     path: Path<ManifestPutRequest>,
     body: Payload,
     token: Token,
+    request_id: RequestId,
 ) -> Result<HttpResponse, RegistryError> {
     let extractor = &app.extractor;
 
-    if !token.validated_token {
-        return Err(RegistryError::MustAuthenticate {
-            challenge: token.get_push_challenge(&path.repository),
-        });
-    }
+    if !token.has_permission(&path.repository, "push").await {
+        if !token.validated_token {
+            return Err(RegistryError::MustAuthenticate {
+                challenge: token.get_push_challenge(&path.repository),
+            });
+        }
 
-    if !token.has_permission(&path.repository, "push") {
         return Err(RegistryError::AccessDenied {});
     }
 
@@ -62,6 +66,13 @@ pub(crate) async fn put(
         }
     };
 
+    // Pushing by digest (like the images in an index) mustn't create a tag
+    let by_digest = match path.tag.parse::<Digest>() {
+        Ok(expected) if expected == digest => true,
+        Ok(_) => return Err(RegistryError::DigestInvalid {}),
+        Err(_) => false,
+    };
+
     let content_type = req.headers().get("content-type").unwrap().to_str().unwrap();
 
     let extracted = extractor
@@ -96,40 +107,31 @@ pub(crate) async fn put(
         }
     };
     actions.append(&mut extracted.clone());
-    actions.append(&mut vec![RegistryAction::HashTagged {
-        timestamp: Utc::now(),
-        repository: path.repository.clone(),
-        digest: digest.clone(),
-        tag: path.tag.clone(),
-        user: token.sub.clone(),
-    }]);
-
-    let dest = app.get_manifest_path(&digest);
-
-    match tokio::fs::rename(upload_path, dest).await {
-        Ok(_) => {}
-        Err(_) => {
-            return Err(RegistryError::ManifestInvalid {});
-        }
+    if !by_digest {
+        actions.append(&mut vec![RegistryAction::HashTagged {
+            timestamp: Utc::now(),
+            repository: path.repository.clone(),
+            digest: digest.clone(),
+            tag: path.tag.clone(),
+            user: token.sub.clone(),
+        }]);
     }
 
-    if !app.consistent_write(actions).await {
-        tracing::error!("Raft storage failed");
+    if let Err(err) = app
+        .storage
+        .put(ObjectKind::Manifest, &digest, &upload_path)
+        .await
+    {
+        tracing::error!("Unable to store manifest {digest}: {err:?}");
         return Err(RegistryError::ManifestInvalid {});
     }
 
-    let resp = app
-        .webhooks
-        .send(Event {
-            repository: path.repository.clone(),
-            digest: digest.clone(),
-            tag: path.tag.to_owned(),
-            content_type: content_type.to_owned(),
-        })
-        .await;
-
-    if let Err(err) = resp {
-        tracing::error!("Error queueing webhook: {err}");
+    if !app
+        .consistent_write_for(AuditRequest::new(&req, &request_id), actions)
+        .await
+    {
+        tracing::error!("Raft storage failed");
+        return Err(RegistryError::ManifestInvalid {});
     }
 
     /*
@@ -1,5 +1,6 @@
 pub mod blobs;
 pub mod errors;
+pub mod events;
 pub mod get;
 pub mod head;
 pub mod manifests;
@@ -27,13 +27,13 @@ pub(crate) async fn get(
     query: actix_web::web::Query<TagQuery>,
     token: Token,
 ) -> Result<HttpResponse, RegistryError> {
-    if !token.validated_token {
+    if !token.validated_token && !token.is_public(&path.repository) {
         return Err(RegistryError::MustAuthenticate {
             challenge: token.get_pull_challenge(&path.repository),
         });
     }
 
-    if !token.has_permission(&path.repository, "pull") {
+    if !token.has_permission(&path.repository, "pull").await {
         return Err(RegistryError::AccessDenied {});
     }
 
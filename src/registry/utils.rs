@@ -1,5 +1,9 @@
+use crate::app::RegistryApp;
+use crate::storage::{ObjectKind, ObjectStream};
 use crate::types::Digest;
+use actix_web::http::header::RANGE;
 use actix_web::web::Payload;
+use actix_web::HttpRequest;
 use futures_util::StreamExt;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -73,3 +77,77 @@ pub(crate) fn simple_oci_error(code: &str, message: &str) -> String {
     })
     .to_string()
 }
+
+/// The body of a response that sends a stored object, and its size as stored. For a `head`
+/// request the object is only looked for, not read. `None` if this node doesn't have the object
+/// in storage.
+pub(crate) async fn stored_body(
+    app: &RegistryApp,
+    object: ObjectKind,
+    digest: &Digest,
+    head: bool,
+) -> Option<(ObjectStream, u64)> {
+    let result = async {
+        let Some(size) = app.storage.stat(object, digest).await? else {
+            return Ok(None);
+        };
+
+        if head {
+            return Ok(Some((futures_util::stream::empty().boxed(), size)));
+        }
+
+        let body = app.storage.open(object, digest, None).await?;
+        Ok::<_, anyhow::Error>(body.map(|body| (body, size)))
+    };
+
+    match result.await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!("Unable to read {} {digest}: {err:?}", object.as_str());
+            None
+        }
+    }
+}
+
+/// Like [`stored_body`], but starting `offset` bytes in, for a peer resuming a download. The
+/// size is that of the whole object. `None` as well if the object isn't longer than `offset`.
+pub(crate) async fn stored_body_from(
+    app: &RegistryApp,
+    object: ObjectKind,
+    digest: &Digest,
+    offset: u64,
+) -> Option<(ObjectStream, u64)> {
+    let result = async {
+        let Some(size) = app.storage.stat(object, digest).await? else {
+            return Ok(None);
+        };
+
+        if offset >= size {
+            return Ok(None);
+        }
+
+        let body = app.storage.open(object, digest, Some(offset..size)).await?;
+        Ok::<_, anyhow::Error>(body.map(|body| (body, size)))
+    };
+
+    match result.await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!("Unable to read {} {digest}: {err:?}", object.as_str());
+            None
+        }
+    }
+}
+
+/// Where a `Range: bytes=<offset>-` header asks for the body to start. This is the only kind of
+/// range mirroring asks for, and any other is ignored.
+pub(crate) fn range_offset(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes=")?
+        .strip_suffix('-')?
+        .parse()
+        .ok()
+}
@@ -0,0 +1,543 @@
+//! Background scrubbing of the blobs and manifests stored on this node.
+//!
+//! Files can rot on disk without anything noticing until a client pulls them and the digest
+//! doesn't match. The scrubber walks every object this node has a copy of, re-hashing it and
+//! checking its size against what was recorded when it was pushed. A corrupt object is moved
+//! to quarantine and, if another node has a copy, this node's copy is unstored so
+//! mirroring fetches a good one.
+
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::app::RegistryApp;
+use crate::config::ScrubberConfig;
+use crate::storage::{ObjectStream, Storage, Unavailable};
+use crate::types::{Digest, RegistryAction};
+use crate::RegistryNodeId;
+
+/// How many objects are read from the state machine at a time.
+const PAGE_SIZE: usize = 256;
+
+pub use crate::storage::ObjectKind;
+
+/// Why a stored object is considered corrupt.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "lowercase")]
+pub enum Problem {
+    /// The file is missing.
+    Missing,
+    /// The file couldn't be read. This might only be a passing error, like a timeout from an
+    /// S3 backend, so nothing is done about it and it is checked again on the next pass.
+    Unreadable,
+    /// The file isn't the size that was recorded when it was pushed.
+    Size { expected: u64, actual: u64 },
+    /// The file doesn't hash to its digest.
+    Hash { actual: Digest },
+    /// The file couldn't be found, but might be on a data directory that is offline. This
+    /// isn't corruption, and nothing is done about it.
+    Unavailable,
+}
+
+impl Problem {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Problem::Missing => "missing",
+            Problem::Unreadable => "unreadable",
+            Problem::Size { .. } => "size",
+            Problem::Hash { .. } => "hash",
+            Problem::Unavailable => "unavailable",
+        }
+    }
+
+    /// Whether the stored copy is known to be bad, rather than it couldn't be checked.
+    pub fn is_confirmed(&self) -> bool {
+        !matches!(self, Problem::Unreadable | Problem::Unavailable)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Corruption {
+    pub object: ObjectKind,
+    pub digest: Digest,
+    #[serde(flatten)]
+    pub problem: Problem,
+    /// Whether another node has a copy that mirroring can fetch.
+    pub recoverable: bool,
+}
+
+/// What a full pass over the objects stored on this node found.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrubPass {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub checked: u64,
+    pub bytes: u64,
+    pub corrupt: Vec<Corruption>,
+    /// Objects that couldn't be read, so couldn't be checked.
+    #[serde(default)]
+    pub unreadable: Vec<UnreadableObject>,
+    /// Why the pass stopped early, if it did.
+    pub error: Option<String>,
+}
+
+/// An object the scrubber couldn't read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnreadableObject {
+    pub object: ObjectKind,
+    pub digest: Digest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubStatus {
+    /// Whether scrubbing runs on a schedule.
+    pub enabled: bool,
+    pub running: bool,
+    pub last_pass: Option<ScrubPass>,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
+struct ObjectLabels {
+    object: String,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
+struct CorruptLabels {
+    object: String,
+    reason: String,
+}
+
+/// Scrubs this node's objects and keeps track of how it went.
+pub struct Scrubber {
+    config: ScrubberConfig,
+    running: tokio::sync::Mutex<()>,
+    last_pass: std::sync::Mutex<Option<ScrubPass>>,
+    checked: Family<ObjectLabels, Counter>,
+    checked_bytes: Counter,
+    corrupt: Family<CorruptLabels, Counter>,
+    unreadable: Family<ObjectLabels, Counter>,
+    progress: Gauge,
+    last_full_pass: Gauge,
+}
+
+impl Scrubber {
+    pub fn new(config: &ScrubberConfig, registry: &mut Registry) -> Self {
+        let scrubber = Scrubber {
+            config: config.clone(),
+            running: tokio::sync::Mutex::new(()),
+            last_pass: std::sync::Mutex::new(None),
+            checked: Family::default(),
+            checked_bytes: Counter::default(),
+            corrupt: Family::default(),
+            unreadable: Family::default(),
+            progress: Gauge::default(),
+            last_full_pass: Gauge::default(),
+        };
+
+        registry.register(
+            "distribd_scrubber_checked",
+            "Number of stored objects the scrubber has checked",
+            scrubber.checked.clone(),
+        );
+        registry.register(
+            "distribd_scrubber_checked_bytes",
+            "Bytes the scrubber has read",
+            scrubber.checked_bytes.clone(),
+        );
+        registry.register(
+            "distribd_scrubber_corrupt",
+            "Number of corrupt stored objects the scrubber has found",
+            scrubber.corrupt.clone(),
+        );
+        registry.register(
+            "distribd_scrubber_unreadable",
+            "Number of times the scrubber couldn't read a stored object",
+            scrubber.unreadable.clone(),
+        );
+        registry.register(
+            "distribd_scrubber_progress",
+            "Objects checked so far in the current pass",
+            scrubber.progress.clone(),
+        );
+        registry.register(
+            "distribd_scrubber_last_full_pass_timestamp_seconds",
+            "When the last full pass finished",
+            scrubber.last_full_pass.clone(),
+        );
+
+        scrubber
+    }
+
+    pub fn status(&self) -> ScrubStatus {
+        ScrubStatus {
+            enabled: self.config.enabled,
+            running: self.running.try_lock().is_err(),
+            last_pass: self.last_pass.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Keeps reads under the configured rate, by sleeping whenever the pass gets ahead of it.
+struct Throttle {
+    rate: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Throttle {
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    async fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if self.rate == 0 {
+            return;
+        }
+
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            tokio::time::sleep(due - elapsed).await;
+        }
+    }
+}
+
+/// Hash everything an object stream yields.
+async fn hash_stream(mut stream: ObjectStream) -> std::io::Result<Digest> {
+    let mut hasher = ring::digest::Context::new(&ring::digest::SHA256);
+    while let Some(chunk) = stream.next().await {
+        hasher.update(&chunk?);
+    }
+    Ok(Digest::from_sha256(&hasher.finish()))
+}
+
+/// Check a stored object against its digest and, if it was recorded, its size. Returns the
+/// problem with it, if any, and how many bytes were read.
+pub(crate) async fn check_stored(
+    storage: &dyn Storage,
+    object: ObjectKind,
+    digest: &Digest,
+    size: Option<u64>,
+) -> (Option<Problem>, u64) {
+    let actual_size = match storage.stat(object, digest).await {
+        Ok(Some(actual_size)) => actual_size,
+        Ok(None) => return (Some(Problem::Missing), 0),
+        Err(err) if err.downcast_ref::<Unavailable>().is_some() => {
+            return (Some(Problem::Unavailable), 0);
+        }
+        Err(err) => {
+            warn!(
+                "Scrubber: Unable to stat {} {digest}: {err:?}",
+                object.as_str()
+            );
+            return (Some(Problem::Unreadable), 0);
+        }
+    };
+
+    if let Some(expected) = size {
+        if expected != actual_size {
+            return (
+                Some(Problem::Size {
+                    expected,
+                    actual: actual_size,
+                }),
+                0,
+            );
+        }
+    }
+
+    let actual = match storage.open(object, digest, None).await {
+        Ok(Some(stream)) => hash_stream(stream).await,
+        Ok(None) => return (Some(Problem::Missing), 0),
+        Err(err) => Err(std::io::Error::other(err)),
+    };
+
+    match actual {
+        Ok(actual) if &actual == digest => (None, actual_size),
+        Ok(actual) => (Some(Problem::Hash { actual }), actual_size),
+        Err(err) => {
+            warn!(
+                "Scrubber: Unable to read {} {digest}: {err:?}",
+                object.as_str()
+            );
+            (Some(Problem::Unreadable), actual_size)
+        }
+    }
+}
+
+/// Whether this node still has `digest` stored, and whether any other node does too. The
+/// object may have been garbage collected or unstored while it was being checked.
+fn locations(app: &RegistryApp, object: ObjectKind, digest: &Digest) -> Option<bool> {
+    let locations = match object {
+        ObjectKind::Blob => app.get_blob(digest)?.locations,
+        ObjectKind::Manifest => app.get_manifest(digest)?.locations,
+    };
+
+    if !locations.contains(&app.id) {
+        return None;
+    }
+
+    Some(locations.iter().any(|location| location != &app.id))
+}
+
+fn unstore(object: ObjectKind, digest: &Digest, location: RegistryNodeId) -> RegistryAction {
+    match object {
+        ObjectKind::Blob => RegistryAction::BlobUnstored {
+            timestamp: Utc::now(),
+            digest: digest.clone(),
+            location,
+            user: "$scrubber".to_string(),
+        },
+        ObjectKind::Manifest => RegistryAction::ManifestUnstored {
+            timestamp: Utc::now(),
+            digest: digest.clone(),
+            location,
+            user: "$scrubber".to_string(),
+        },
+    }
+}
+
+/// Move a corrupt object out of the way and, if another node has a copy, unstore this node's
+/// copy so that mirroring replaces it.
+async fn quarantine(
+    app: &RegistryApp,
+    object: ObjectKind,
+    digest: &Digest,
+    problem: Problem,
+) -> anyhow::Result<Option<Corruption>> {
+    // Only act on objects that are still meant to be here
+    let Some(recoverable) = locations(app, object, digest) else {
+        return Ok(None);
+    };
+
+    if problem != Problem::Missing {
+        let destination = app.storage.quarantine(object, digest).await?;
+        warn!(
+            "Scrubber: Quarantined {} {digest} to {destination}",
+            object.as_str()
+        );
+    }
+
+    if recoverable {
+        if !app
+            .submit_write(vec![unstore(object, digest, app.id)])
+            .await
+        {
+            anyhow::bail!("Scrubber: Unable to unstore {digest}");
+        }
+    } else {
+        error!(
+            "Scrubber: {} {digest} is corrupt and no other node has a copy",
+            object.as_str()
+        );
+    }
+
+    app.scrubber
+        .corrupt
+        .get_or_create(&CorruptLabels {
+            object: object.as_str().to_string(),
+            reason: problem.as_str().to_string(),
+        })
+        .inc();
+
+    Ok(Some(Corruption {
+        object,
+        digest: digest.clone(),
+        problem,
+        recoverable,
+    }))
+}
+
+async fn check_object(
+    app: &RegistryApp,
+    throttle: &mut Throttle,
+    pass: &mut ScrubPass,
+    object: ObjectKind,
+    digest: &Digest,
+    size: Option<u64>,
+) -> anyhow::Result<()> {
+    let (problem, bytes) = check_stored(app.storage.as_ref(), object, digest, size).await;
+    throttle.consume(bytes).await;
+
+    pass.checked += 1;
+    pass.bytes += bytes;
+    app.scrubber.progress.inc();
+    app.scrubber.checked_bytes.inc_by(bytes);
+    app.scrubber
+        .checked
+        .get_or_create(&ObjectLabels {
+            object: object.as_str().to_string(),
+        })
+        .inc();
+
+    if problem == Some(Problem::Unavailable) {
+        warn!(
+            "Scrubber: {} {digest} might be on a data directory that is offline, skipping it",
+            object.as_str()
+        );
+        return Ok(());
+    }
+
+    if problem == Some(Problem::Unreadable) {
+        // Only a confirmed mismatch is worth moving a copy that may be fine out of the way for
+        warn!(
+            "Scrubber: Couldn't read {} {digest}, will check it again on the next pass",
+            object.as_str()
+        );
+        app.scrubber
+            .unreadable
+            .get_or_create(&ObjectLabels {
+                object: object.as_str().to_string(),
+            })
+            .inc();
+        pass.unreadable.push(UnreadableObject {
+            object,
+            digest: digest.clone(),
+        });
+        return Ok(());
+    }
+
+    if let Some(problem) = problem {
+        debug!(
+            "Scrubber: {} {digest} is corrupt: {problem:?}",
+            object.as_str()
+        );
+        if let Some(corruption) = quarantine(app, object, digest, problem).await? {
+            pass.corrupt.push(corruption);
+        }
+    }
+
+    Ok(())
+}
+
+async fn do_scrub(app: &RegistryApp, pass: &mut ScrubPass) -> anyhow::Result<()> {
+    let mut throttle = Throttle::new(app.scrubber.config.rate);
+
+    let mut after = None;
+    loop {
+        let page = app.store.get_manifests_after(after.as_ref(), PAGE_SIZE)?;
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        after = Some(last.clone());
+
+        for (digest, manifest) in page {
+            if manifest.locations.contains(&app.id) {
+                check_object(
+                    app,
+                    &mut throttle,
+                    pass,
+                    ObjectKind::Manifest,
+                    &digest,
+                    manifest.size,
+                )
+                .await?;
+            }
+        }
+    }
+
+    let mut after = None;
+    loop {
+        let page = app.store.get_blobs_after(after.as_ref(), PAGE_SIZE)?;
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        after = Some(last.clone());
+
+        for (digest, blob) in page {
+            if blob.locations.contains(&app.id) {
+                check_object(
+                    app,
+                    &mut throttle,
+                    pass,
+                    ObjectKind::Blob,
+                    &digest,
+                    blob.size,
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check every object stored on this node now. If a pass is already in progress, this waits
+/// for it to finish first.
+pub async fn scrub(app: &RegistryApp) -> ScrubPass {
+    let _running = app.scrubber.running.lock().await;
+
+    app.scrubber.progress.set(0);
+
+    let mut pass = ScrubPass {
+        started: Utc::now(),
+        finished: Utc::now(),
+        checked: 0,
+        bytes: 0,
+        corrupt: vec![],
+        unreadable: vec![],
+        error: None,
+    };
+
+    let result = do_scrub(app, &mut pass).await;
+
+    pass.finished = Utc::now();
+    match result {
+        Ok(()) => {
+            app.scrubber.last_full_pass.set(pass.finished.timestamp());
+            info!(
+                "Scrubber: Checked {} objects and found {} corrupt",
+                pass.checked,
+                pass.corrupt.len()
+            );
+        }
+        Err(err) => {
+            error!("Scrubber: Pass failed: {err:?}");
+            pass.error = Some(format!("{err:?}"));
+        }
+    }
+
+    *app.scrubber.last_pass.lock().unwrap() = Some(pass.clone());
+
+    pass
+}
+
+/// Start a pass every `interval` seconds, if scrubbing is enabled.
+pub fn start_scrubbing(app: Data<RegistryApp>) {
+    if !app.config.scrubber.enabled {
+        info!("Scrubber: Not enabled, it will only run when triggered");
+        return;
+    }
+
+    let interval = Duration::from_secs(app.config.scrubber.interval);
+
+    tokio::spawn(async move {
+        loop {
+            if matches!(
+                app.raft.metrics().borrow().state,
+                openraft::ServerState::Shutdown
+            ) {
+                break;
+            }
+
+            let started = Instant::now();
+            scrub(&app).await;
+
+            tokio::time::sleep(interval.saturating_sub(started.elapsed())).await;
+        }
+    });
+}
@@ -0,0 +1,236 @@
+use std::io::{ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::info;
+use uuid::Uuid;
+
+use super::{ObjectKind, ObjectStream, Storage, StoredObject};
+use crate::types::Digest;
+use crate::utils::get_quarantine_path;
+
+/// Objects kept as files under the `storage` directory, sharded by the first bytes of their
+/// hash.
+pub struct FilesystemStorage {
+    root: String,
+}
+
+impl FilesystemStorage {
+    pub fn new(root: &str) -> Self {
+        FilesystemStorage {
+            root: root.to_string(),
+        }
+    }
+
+    /// Where an object is kept, the same as [`crate::utils::get_blob_path`] and
+    /// [`crate::utils::get_manifest_path`] but without creating the directories.
+    pub fn path(&self, object: ObjectKind, digest: &Digest) -> PathBuf {
+        let hash = &digest.hash;
+        Path::new(&self.root)
+            .join(object.dir())
+            .join(&hash[0..2])
+            .join(&hash[2..4])
+            .join(&hash[4..6])
+            .join(&hash[6..])
+    }
+}
+
+#[async_trait]
+impl Storage for FilesystemStorage {
+    async fn put(&self, object: ObjectKind, digest: &Digest, source: &Path) -> Result<()> {
+        let path = self.path(object, digest);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+
+        match tokio::fs::rename(source, &path).await {
+            Ok(()) => Ok(()),
+            // Uploads are kept in `storage`, which can be on another disk to a data directory
+            Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                let partial = path.with_file_name(format!(
+                    ".{}-{}",
+                    digest.hash,
+                    Uuid::new_v4().as_hyphenated()
+                ));
+
+                let copied = async {
+                    tokio::fs::copy(source, &partial).await?;
+                    tokio::fs::rename(&partial, &path).await
+                };
+                if let Err(err) = copied.await {
+                    let _ = tokio::fs::remove_file(&partial).await;
+                    return Err(err).context(format!("Unable to copy {source:?} to {path:?}"));
+                }
+
+                tokio::fs::remove_file(source)
+                    .await
+                    .context(format!("Unable to remove {source:?}"))
+            }
+            Err(err) => Err(err).context(format!("Unable to move {source:?} to {path:?}")),
+        }
+    }
+
+    async fn open(
+        &self,
+        object: ObjectKind,
+        digest: &Digest,
+        range: Option<Range<u64>>,
+    ) -> Result<Option<ObjectStream>> {
+        let path = self.path(object, digest);
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(format!("Unable to open {path:?}")),
+        };
+
+        let Some(range) = range else {
+            return Ok(Some(ReaderStream::new(file).boxed()));
+        };
+
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let reader = file.take(range.end.saturating_sub(range.start));
+        Ok(Some(ReaderStream::new(reader).boxed()))
+    }
+
+    async fn stat(&self, object: ObjectKind, digest: &Digest) -> Result<Option<u64>> {
+        let path = self.path(object, digest);
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(format!("Unable to stat {path:?}")),
+        }
+    }
+
+    async fn delete(&self, object: ObjectKind, digest: &Digest) -> Result<()> {
+        let path = self.path(object, digest);
+
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => info!("Storage: Removed file {path:?}"),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err).context(format!("Error while removing {path:?}")),
+        }
+
+        // Tidy up the shard directories once they are empty
+        for path in path.parent().unwrap().ancestors().take(3) {
+            match path.read_dir() {
+                Ok(mut iter) => {
+                    if iter.next().is_some() {
+                        // We've hit a shared directory
+                        return Ok(());
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).context(format!("Error whilst reading contents of {path:?}"));
+                }
+            }
+
+            match tokio::fs::remove_dir(path).await {
+                Ok(_) => info!("Storage: Removed directory {path:?}"),
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).context(format!("Error whilst removing {path:?}")),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn quarantine(&self, object: ObjectKind, digest: &Digest) -> Result<String> {
+        let path = self.path(object, digest);
+        let destination = get_quarantine_path(&self.root, object.dir(), digest);
+        tokio::fs::rename(&path, &destination)
+            .await
+            .context(format!("Unable to move {path:?} to {destination:?}"))?;
+
+        Ok(destination.to_string_lossy().to_string())
+    }
+
+    async fn list(&self, object: ObjectKind) -> Result<Vec<StoredObject>> {
+        let base = Path::new(&self.root).join(object.dir());
+        let mut files = vec![];
+        let mut visiting = vec![base.clone()];
+
+        while let Some(dir) = visiting.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).context(format!("Unable to list {dir:?}")),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    visiting.push(path);
+                    continue;
+                }
+
+                let hash: String = path
+                    .strip_prefix(&base)?
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect();
+
+                files.push(StoredObject {
+                    location: path.to_string_lossy().to_string(),
+                    digest: format!("sha256:{hash}").parse().ok(),
+                });
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::TryStreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn put_open_and_delete() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path().to_string_lossy().to_string();
+        let storage = FilesystemStorage::new(&root);
+
+        let digest: Digest =
+            "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+                .parse()
+                .unwrap();
+        assert_eq!(storage.stat(ObjectKind::Blob, &digest).await.unwrap(), None);
+
+        let source = tempdir.path().join("upload");
+        std::fs::write(&source, "FOOBAR").unwrap();
+        storage
+            .put(ObjectKind::Blob, &digest, &source)
+            .await
+            .unwrap();
+        assert!(!source.exists());
+        assert_eq!(
+            storage.stat(ObjectKind::Blob, &digest).await.unwrap(),
+            Some(6)
+        );
+
+        let stream = storage
+            .open(ObjectKind::Blob, &digest, Some(2..5))
+            .await
+            .unwrap()
+            .unwrap();
+        let chunks: Vec<_> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"OBA");
+
+        let stored = storage.list(ObjectKind::Blob).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].digest, Some(digest.clone()));
+        assert!(storage.list(ObjectKind::Manifest).await.unwrap().is_empty());
+
+        storage.delete(ObjectKind::Blob, &digest).await.unwrap();
+        storage.delete(ObjectKind::Blob, &digest).await.unwrap();
+        assert_eq!(storage.read(ObjectKind::Blob, &digest).await.unwrap(), None);
+        assert!(!tempdir.path().join("blobs/24").exists());
+    }
+}
@@ -0,0 +1,531 @@
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use ring::digest::{digest, SHA256};
+use tracing::{debug, info, warn};
+
+use super::{FilesystemStorage, ObjectKind, ObjectStream, Storage, StoredObject, Unavailable};
+use crate::config::FilesystemConfig;
+use crate::types::Digest;
+
+const GIB: f64 = (1024 * 1024 * 1024) as f64;
+
+/// Kept in every data directory that is in use. An unmounted disk leaves an empty mountpoint
+/// behind, which can't otherwise be told apart from the disk once it's empty.
+pub const MARKER: &str = ".distribd-data-directory";
+
+struct DataDirectory {
+    root: PathBuf,
+    weight: f64,
+    storage: FilesystemStorage,
+}
+
+impl DataDirectory {
+    /// A directory without its marker is a disk that isn't mounted.
+    fn online(&self) -> bool {
+        self.root.join(MARKER).is_file()
+    }
+
+    /// Weighted rendezvous hashing: every directory scores every digest, and an object belongs
+    /// in the directory with the highest score. Adding a directory only moves the objects that
+    /// now score highest there.
+    fn score(&self, digest: &Digest) -> f64 {
+        let key = format!("{}\0{}", self.root.to_string_lossy(), digest.hash);
+        let hash = digest_u64(key.as_bytes());
+
+        // Somewhere in (0, 1), never either end
+        let unit = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+
+        -self.weight / unit.ln()
+    }
+}
+
+/// Mark a directory without a marker as a data directory, if it can only be one that is new or
+/// that was in use before there were markers. An empty directory on the same filesystem as its
+/// parent might be where a disk should be mounted, so it is left for the operator to mark.
+fn mark(root: &Path) {
+    let mut entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Storage: Unable to read data directory {root:?}, so it is offline: {err:?}");
+            return;
+        }
+    };
+    let in_use = entries.next().is_some();
+
+    let mountpoint = match (
+        std::fs::metadata(root),
+        root.parent().map(std::fs::metadata),
+    ) {
+        (Ok(metadata), Some(Ok(parent))) => metadata.dev() != parent.dev(),
+        _ => false,
+    };
+
+    if !in_use && !mountpoint {
+        warn!(
+            "Storage: Data directory {root:?} is empty and isn't a mountpoint, so it is \
+             treated as offline. Create {MARKER:?} in it to use it"
+        );
+        return;
+    }
+
+    info!("Storage: Using {root:?} as a data directory for the first time");
+    if let Err(err) = std::fs::write(root.join(MARKER), "") {
+        warn!(
+            "Storage: Unable to mark {root:?} as a data directory, so it will be offline: {err:?}"
+        );
+    }
+}
+
+/// The weight of a directory configured without one: its free space in GiB when it was first
+/// used, which is kept in its marker. Working it out again on every start would move where
+/// objects belong as the disks fill up.
+fn default_weight(root: &Path) -> f64 {
+    let marker = root.join(MARKER);
+
+    let recorded = std::fs::read_to_string(&marker)
+        .ok()
+        .and_then(|contents| contents.trim().parse::<f64>().ok())
+        .filter(|weight| *weight >= 0.0 && weight.is_finite());
+    if let Some(weight) = recorded {
+        return weight;
+    }
+
+    let weight = match fs4::available_space(root) {
+        Ok(available) => available as f64 / GIB,
+        Err(err) => {
+            warn!(
+                "Storage: Unable to find free space on {root:?}, so it won't get new objects: \
+                 {err:?}"
+            );
+            return 0.0;
+        }
+    };
+
+    // An offline directory's mountpoint isn't the disk, so only record the weight once it's back
+    if marker.is_file() {
+        if let Err(err) = std::fs::write(&marker, format!("{weight}\n")) {
+            warn!("Storage: Unable to record the weight of {root:?}: {err:?}");
+        }
+    }
+
+    weight
+}
+
+fn digest_u64(data: &[u8]) -> u64 {
+    let hash = digest(&SHA256, data);
+    u64::from_be_bytes(hash.as_ref()[..8].try_into().unwrap())
+}
+
+/// Objects spread over several data directories, each of which is a [`FilesystemStorage`].
+///
+/// Where an object goes depends only on its digest and the configured directories, so finding
+/// it needs no index: directories are tried in the order they'd be picked for that digest. An
+/// object only ends up further down that order if the directories ahead of it were full or
+/// offline when it was stored, or if a directory was added since.
+pub struct JbodStorage {
+    directories: Vec<DataDirectory>,
+    reserved_space: u64,
+}
+
+impl JbodStorage {
+    pub fn new(config: &FilesystemConfig) -> Result<Self> {
+        let mut directories = vec![];
+
+        for directory in &config.directories {
+            let root = PathBuf::from(&directory.path);
+
+            if !root.join(MARKER).exists() {
+                mark(&root);
+            }
+
+            let weight = match directory.weight {
+                Some(weight) if weight < 0.0 || !weight.is_finite() => {
+                    bail!("Data directory {root:?} has an invalid weight: {weight}");
+                }
+                Some(weight) => weight,
+                None => default_weight(&root),
+            };
+
+            info!("Storage: Using data directory {root:?} with weight {weight:.2}");
+
+            directories.push(DataDirectory {
+                storage: FilesystemStorage::new(&directory.path),
+                root,
+                weight,
+            });
+        }
+
+        if directories.is_empty() {
+            bail!("No data directories are configured");
+        }
+
+        Ok(JbodStorage {
+            directories,
+            reserved_space: config.reserved_space,
+        })
+    }
+
+    /// The directories in the order they'd be picked for `digest`.
+    fn candidates(&self, digest: &Digest) -> Vec<&DataDirectory> {
+        let mut candidates: Vec<(f64, &DataDirectory)> = self
+            .directories
+            .iter()
+            .map(|directory| (directory.score(digest), directory))
+            .collect();
+
+        candidates.sort_by(|(left, _), (right, _)| right.total_cmp(left));
+
+        candidates
+            .into_iter()
+            .map(|(_, directory)| directory)
+            .collect()
+    }
+
+    /// The directory an object is stored in and its size. If it isn't in any directory that
+    /// is online, but a directory is offline, it might be there.
+    async fn locate(
+        &self,
+        object: ObjectKind,
+        digest: &Digest,
+    ) -> Result<Option<(&DataDirectory, u64)>> {
+        let mut offline = None;
+
+        for directory in self.candidates(digest) {
+            if !directory.online() {
+                offline = Some(&directory.root);
+                continue;
+            }
+
+            if let Some(size) = directory.storage.stat(object, digest).await? {
+                return Ok(Some((directory, size)));
+            }
+        }
+
+        match offline {
+            Some(directory) => Err(Unavailable {
+                directory: directory.clone(),
+            }
+            .into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether an object of `size` bytes fits on `directory` and leaves the reserved space.
+    fn has_room(&self, directory: &DataDirectory, size: u64) -> bool {
+        match fs4::available_space(&directory.root) {
+            Ok(available) => available >= size.saturating_add(self.reserved_space),
+            Err(err) => {
+                warn!(
+                    "Storage: Unable to find free space on {:?}: {err:?}",
+                    directory.root
+                );
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for JbodStorage {
+    async fn put(&self, object: ObjectKind, digest: &Digest, source: &Path) -> Result<()> {
+        let size = tokio::fs::metadata(source)
+            .await
+            .context(format!("Unable to stat {source:?}"))?
+            .len();
+
+        for directory in self.candidates(digest) {
+            if directory.weight == 0.0 {
+                continue;
+            }
+
+            if !directory.online() {
+                warn!(
+                    "Storage: Data directory {:?} is offline, skipping it",
+                    directory.root
+                );
+                continue;
+            }
+
+            if !self.has_room(directory, size) {
+                debug!(
+                    "Storage: Data directory {:?} is full, skipping it",
+                    directory.root
+                );
+                continue;
+            }
+
+            match directory.storage.put(object, digest, source).await {
+                Ok(()) => return Ok(()),
+                Err(err) => warn!(
+                    "Storage: Unable to put {} {digest} in {:?}: {err:?}",
+                    object.as_str(),
+                    directory.root
+                ),
+            }
+        }
+
+        bail!(
+            "No data directory can take {} {digest} ({size} bytes)",
+            object.as_str()
+        )
+    }
+
+    async fn open(
+        &self,
+        object: ObjectKind,
+        digest: &Digest,
+        range: Option<Range<u64>>,
+    ) -> Result<Option<ObjectStream>> {
+        match self.locate(object, digest).await? {
+            Some((directory, _)) => directory.storage.open(object, digest, range).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn stat(&self, object: ObjectKind, digest: &Digest) -> Result<Option<u64>> {
+        Ok(self.locate(object, digest).await?.map(|(_, size)| size))
+    }
+
+    async fn delete(&self, object: ObjectKind, digest: &Digest) -> Result<()> {
+        let mut offline = None;
+
+        // An object can be in more than one directory if one was offline when it was stored
+        for directory in &self.directories {
+            if !directory.online() {
+                offline = Some(&directory.root);
+                continue;
+            }
+
+            directory.storage.delete(object, digest).await?;
+        }
+
+        // A copy left on an offline directory would come back when it does
+        match offline {
+            Some(directory) => Err(Unavailable {
+                directory: directory.clone(),
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    async fn quarantine(&self, object: ObjectKind, digest: &Digest) -> Result<String> {
+        match self.locate(object, digest).await? {
+            Some((directory, _)) => directory.storage.quarantine(object, digest).await,
+            None => bail!("{} {digest} isn't stored", object.as_str()),
+        }
+    }
+
+    async fn list(&self, object: ObjectKind) -> Result<Vec<StoredObject>> {
+        let mut objects = vec![];
+
+        for directory in &self.directories {
+            if !directory.online() {
+                warn!(
+                    "Storage: Data directory {:?} is offline, not listing it",
+                    directory.root
+                );
+                continue;
+            }
+
+            objects.extend(directory.storage.list(object).await?);
+        }
+
+        objects.sort();
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::DataDirectoryConfig;
+
+    use super::*;
+
+    fn digest(n: usize) -> Digest {
+        let hash = digest_u64(&n.to_be_bytes());
+        format!("sha256:{hash:016x}{hash:016x}{hash:016x}{hash:016x}")
+            .parse()
+            .unwrap()
+    }
+
+    fn config(root: &Path, weights: &[f64]) -> FilesystemConfig {
+        FilesystemConfig {
+            directories: weights
+                .iter()
+                .enumerate()
+                .map(|(idx, weight)| DataDirectoryConfig {
+                    path: root
+                        .join(format!("disk{idx}"))
+                        .to_string_lossy()
+                        .to_string(),
+                    weight: Some(*weight),
+                })
+                .collect(),
+            reserved_space: 0,
+        }
+    }
+
+    #[test]
+    fn placement_follows_weights() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = JbodStorage::new(&config(tempdir.path(), &[1.0, 3.0])).unwrap();
+
+        let mut counts = [0, 0];
+        for n in 0..4000 {
+            let first = storage.candidates(&digest(n))[0];
+            let idx = storage
+                .directories
+                .iter()
+                .position(|directory| std::ptr::eq(directory, first))
+                .unwrap();
+            counts[idx] += 1;
+
+            // The same digest always lands in the same place
+            assert!(std::ptr::eq(storage.candidates(&digest(n))[0], first));
+        }
+
+        assert!((800..1200).contains(&counts[0]), "{counts:?}");
+        assert!((2800..3200).contains(&counts[1]), "{counts:?}");
+    }
+
+    #[test]
+    fn adding_a_directory_only_moves_its_share() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let before = JbodStorage::new(&config(tempdir.path(), &[1.0, 1.0])).unwrap();
+        let after = JbodStorage::new(&config(tempdir.path(), &[1.0, 1.0, 1.0])).unwrap();
+
+        let mut moved = 0;
+        for n in 0..3000 {
+            let old = &before.candidates(&digest(n))[0].root;
+            let new = &after.candidates(&digest(n))[0].root;
+            if old != new {
+                assert_eq!(new, &after.directories[2].root);
+                moved += 1;
+            }
+        }
+
+        assert!((800..1200).contains(&moved), "{moved}");
+    }
+
+    #[test]
+    fn default_weight_is_recorded() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut config = config(tempdir.path(), &[1.0]);
+        config.directories[0].weight = None;
+        let root = PathBuf::from(&config.directories[0].path);
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("in-use"), "").unwrap();
+
+        // The free space when it was first used is kept
+        let storage = JbodStorage::new(&config).unwrap();
+        let recorded: f64 = std::fs::read_to_string(root.join(MARKER))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert_eq!(storage.directories[0].weight, recorded);
+
+        // And used from then on, however full the disk gets
+        std::fs::write(root.join(MARKER), "2.5\n").unwrap();
+        let storage = JbodStorage::new(&config).unwrap();
+        assert_eq!(storage.directories[0].weight, 2.5);
+    }
+
+    /// Mount a disk that has been used as a data directory before.
+    fn mount(root: &Path) {
+        std::fs::create_dir(root).unwrap();
+        std::fs::write(root.join(MARKER), "").unwrap();
+    }
+
+    #[tokio::test]
+    async fn offline_directories() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        // Only the first disk is mounted
+        mount(&tempdir.path().join("disk0"));
+        let storage = JbodStorage::new(&config(tempdir.path(), &[1.0, 1.0])).unwrap();
+
+        let online = &storage.directories[0].root;
+        let stored = (0..)
+            .map(digest)
+            .find(|digest| &storage.candidates(digest)[0].root != online)
+            .unwrap();
+        let missing = digest(1000);
+
+        let source = tempdir.path().join("upload");
+        std::fs::write(&source, "FOOBAR").unwrap();
+        storage
+            .put(ObjectKind::Blob, &stored, &source)
+            .await
+            .unwrap();
+
+        // It went to the next choice, where it can still be found
+        assert_eq!(
+            storage.stat(ObjectKind::Blob, &stored).await.unwrap(),
+            Some(6)
+        );
+        assert_eq!(storage.list(ObjectKind::Blob).await.unwrap().len(), 1);
+
+        // Anything else might be on the missing disk
+        let err = storage.stat(ObjectKind::Blob, &missing).await.unwrap_err();
+        assert!(err.downcast_ref::<Unavailable>().is_some());
+
+        // And so it can't be said to be deleted
+        assert!(storage.delete(ObjectKind::Blob, &stored).await.is_err());
+
+        mount(&storage.directories[1].root);
+        assert_eq!(
+            storage.stat(ObjectKind::Blob, &missing).await.unwrap(),
+            None
+        );
+        storage.delete(ObjectKind::Blob, &stored).await.unwrap();
+        assert!(storage.list(ObjectKind::Blob).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_mountpoints_are_offline() {
+        let tempdir = tempfile::tempdir().unwrap();
+        mount(&tempdir.path().join("disk0"));
+        mount(&tempdir.path().join("disk1"));
+        let storage = JbodStorage::new(&config(tempdir.path(), &[1.0, 1.0])).unwrap();
+
+        let second = &storage.directories[1].root;
+        let stored = (0..)
+            .map(digest)
+            .find(|digest| &storage.candidates(digest)[0].root == second)
+            .unwrap();
+
+        let source = tempdir.path().join("upload");
+        std::fs::write(&source, "FOOBAR").unwrap();
+        storage
+            .put(ObjectKind::Blob, &stored, &source)
+            .await
+            .unwrap();
+
+        // The disk is unmounted, leaving the directory it was mounted on
+        std::fs::remove_dir_all(second).unwrap();
+        std::fs::create_dir(second).unwrap();
+
+        let err = storage.stat(ObjectKind::Blob, &stored).await.unwrap_err();
+        assert!(err.downcast_ref::<Unavailable>().is_some());
+
+        // New objects don't end up on the filesystem underneath it
+        let source = tempdir.path().join("upload");
+        std::fs::write(&source, "FOOBAR").unwrap();
+        storage
+            .put(ObjectKind::Blob, &stored, &source)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_dir(second).unwrap().count(), 0);
+
+        // Restarting doesn't mistake it for a new disk either
+        let storage = JbodStorage::new(&config(tempdir.path(), &[1.0, 1.0])).unwrap();
+        assert!(!storage.directories[1].online());
+        assert!(storage.directories[0].online());
+    }
+}
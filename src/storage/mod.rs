@@ -0,0 +1,123 @@
+//! Where the contents of blobs and manifests are kept.
+//!
+//! Uploads are always written to local files under `storage/uploads`. Once an upload is
+//! complete and its digest has been checked, it is put into a [`Storage`], and everything that
+//! reads or deletes an object afterwards goes through the same [`Storage`]. Objects are kept
+//! on the local filesystem unless the configuration picks another backend, either in `storage`
+//! or spread over several data directories.
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::{Configuration, StorageBackendConfig};
+use crate::types::Digest;
+
+pub mod filesystem;
+pub mod jbod;
+pub mod s3;
+
+pub use filesystem::FilesystemStorage;
+pub use jbod::JbodStorage;
+pub use s3::S3Storage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectKind {
+    Blob,
+    Manifest,
+}
+
+impl ObjectKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ObjectKind::Blob => "blob",
+            ObjectKind::Manifest => "manifest",
+        }
+    }
+
+    /// The directory, or key prefix, objects of this kind are kept under.
+    pub(crate) fn dir(&self) -> &'static str {
+        match self {
+            ObjectKind::Blob => "blobs",
+            ObjectKind::Manifest => "manifests",
+        }
+    }
+}
+
+/// The contents of an object, a chunk at a time.
+pub type ObjectStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Something found in storage by [`Storage::list`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StoredObject {
+    /// Where it is, for people to read: a path or a URL.
+    pub location: String,
+    /// The digest it would hold, if its name is one.
+    pub digest: Option<Digest>,
+}
+
+/// An object couldn't be found, but it might be on a data directory that is offline. Nothing
+/// should conclude that it is missing until the directory is back, or has been removed from
+/// the configuration.
+#[derive(Debug, Error)]
+#[error("Data directory {directory:?} is offline")]
+pub struct Unavailable {
+    pub directory: PathBuf,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store the complete, verified local file at `source` as `digest`. The file is consumed.
+    async fn put(&self, object: ObjectKind, digest: &Digest, source: &Path) -> Result<()>;
+
+    /// Read an object, or just the bytes in `range`. `None` if it isn't stored.
+    async fn open(
+        &self,
+        object: ObjectKind,
+        digest: &Digest,
+        range: Option<Range<u64>>,
+    ) -> Result<Option<ObjectStream>>;
+
+    /// The size of an object, or `None` if it isn't stored.
+    async fn stat(&self, object: ObjectKind, digest: &Digest) -> Result<Option<u64>>;
+
+    /// Delete an object. Deleting an object that isn't stored isn't an error.
+    async fn delete(&self, object: ObjectKind, digest: &Digest) -> Result<()>;
+
+    /// Move a corrupt object out of the way, keeping it for someone to look at. Returns where
+    /// it was moved to.
+    async fn quarantine(&self, object: ObjectKind, digest: &Digest) -> Result<String>;
+
+    /// Everything stored under the prefix for `object`, including anything that isn't named
+    /// after a digest.
+    async fn list(&self, object: ObjectKind) -> Result<Vec<StoredObject>>;
+
+    /// Read a whole object into memory. `None` if it isn't stored.
+    async fn read(&self, object: ObjectKind, digest: &Digest) -> Result<Option<Vec<u8>>> {
+        let Some(stream) = self.open(object, digest, None).await? else {
+            return Ok(None);
+        };
+
+        let chunks: Vec<Bytes> = stream.try_collect().await?;
+        Ok(Some(chunks.concat()))
+    }
+}
+
+pub fn from_config(config: &Configuration) -> Result<Arc<dyn Storage>> {
+    Ok(match &config.storage_backend {
+        StorageBackendConfig::Filesystem(filesystem) if filesystem.directories.is_empty() => {
+            Arc::new(FilesystemStorage::new(&config.storage))
+        }
+        StorageBackendConfig::Filesystem(filesystem) => Arc::new(JbodStorage::new(filesystem)?),
+        StorageBackendConfig::S3(s3) => Arc::new(S3Storage::new(s3)?),
+    })
+}
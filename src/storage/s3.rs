@@ -0,0 +1,211 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use object_store::aws::AmazonS3Builder;
+use object_store::buffered::BufWriter;
+use object_store::path::Path as ObjectPath;
+use object_store::{GetOptions, GetRange, ObjectStore};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+use uuid::Uuid;
+
+use super::{ObjectKind, ObjectStream, Storage, StoredObject};
+use crate::config::S3Config;
+use crate::types::Digest;
+
+/// Objects kept in an S3 compatible bucket, as `{prefix}/blobs/{hash}` and
+/// `{prefix}/manifests/{hash}`.
+pub struct S3Storage {
+    store: Arc<dyn ObjectStore>,
+    bucket: String,
+    prefix: Vec<String>,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Result<Self> {
+        // Anything that isn't configured, like credentials, can come from the usual AWS_
+        // environment variables
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&config.bucket)
+            .with_region(&config.region)
+            .with_allow_http(config.allow_http);
+
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(access_key_id) = &config.access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+
+        let store = builder
+            .build()
+            .context(format!("Unable to configure bucket {}", config.bucket))?;
+
+        Ok(S3Storage {
+            store: Arc::new(store),
+            bucket: config.bucket.clone(),
+            prefix: config
+                .prefix
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .map(|part| part.to_string())
+                .collect(),
+        })
+    }
+
+    fn key(&self, parts: &[&str]) -> ObjectPath {
+        self.prefix
+            .iter()
+            .map(|part| part.as_str())
+            .chain(parts.iter().copied())
+            .collect()
+    }
+
+    fn path(&self, object: ObjectKind, digest: &Digest) -> ObjectPath {
+        self.key(&[object.dir(), &digest.hash])
+    }
+
+    fn url(&self, path: &ObjectPath) -> String {
+        format!("s3://{}/{path}", self.bucket)
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(
+        &self,
+        object: ObjectKind,
+        digest: &Digest,
+        source: &std::path::Path,
+    ) -> Result<()> {
+        let path = self.path(object, digest);
+
+        let mut file = tokio::fs::File::open(source)
+            .await
+            .context(format!("Unable to open {source:?}"))?;
+
+        // Small objects are sent in one request, large ones as a multipart upload
+        let mut writer = BufWriter::new(self.store.clone(), path.clone());
+        tokio::io::copy(&mut file, &mut writer)
+            .await
+            .context(format!("Unable to upload {}", self.url(&path)))?;
+        writer
+            .shutdown()
+            .await
+            .context(format!("Unable to upload {}", self.url(&path)))?;
+
+        tokio::fs::remove_file(source)
+            .await
+            .context(format!("Unable to remove {source:?}"))?;
+
+        Ok(())
+    }
+
+    async fn open(
+        &self,
+        object: ObjectKind,
+        digest: &Digest,
+        range: Option<Range<u64>>,
+    ) -> Result<Option<ObjectStream>> {
+        let path = self.path(object, digest);
+
+        let range = match range {
+            // S3 rejects empty ranges
+            Some(range) if range.is_empty() => {
+                return match self.stat(object, digest).await? {
+                    Some(_) => Ok(Some(futures::stream::empty().boxed())),
+                    None => Ok(None),
+                };
+            }
+            Some(range) => Some(GetRange::Bounded(range.start as usize..range.end as usize)),
+            None => None,
+        };
+
+        let options = GetOptions {
+            range,
+            ..Default::default()
+        };
+
+        match self.store.get_opts(&path, options).await {
+            Ok(result) => Ok(Some(
+                result.into_stream().map_err(std::io::Error::other).boxed(),
+            )),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(err).context(format!("Unable to read {}", self.url(&path))),
+        }
+    }
+
+    async fn stat(&self, object: ObjectKind, digest: &Digest) -> Result<Option<u64>> {
+        let path = self.path(object, digest);
+        match self.store.head(&path).await {
+            Ok(meta) => Ok(Some(meta.size as u64)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(err).context(format!("Unable to stat {}", self.url(&path))),
+        }
+    }
+
+    async fn delete(&self, object: ObjectKind, digest: &Digest) -> Result<()> {
+        let path = self.path(object, digest);
+        match self.store.delete(&path).await {
+            Ok(()) => {
+                info!("Storage: Removed {}", self.url(&path));
+                Ok(())
+            }
+            Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(err) => Err(err).context(format!("Error while removing {}", self.url(&path))),
+        }
+    }
+
+    async fn quarantine(&self, object: ObjectKind, digest: &Digest) -> Result<String> {
+        let path = self.path(object, digest);
+        let name = format!("{}-{}", digest.hash, Uuid::new_v4().as_hyphenated());
+        let destination = self.key(&["quarantine", object.dir(), &name]);
+
+        self.store
+            .rename(&path, &destination)
+            .await
+            .context(format!(
+                "Unable to move {} to {}",
+                self.url(&path),
+                self.url(&destination)
+            ))?;
+
+        Ok(self.url(&destination))
+    }
+
+    async fn list(&self, object: ObjectKind) -> Result<Vec<StoredObject>> {
+        let base = self.key(&[object.dir()]);
+
+        let metas: Vec<_> = self
+            .store
+            .list(Some(&base))
+            .try_collect()
+            .await
+            .context(format!("Unable to list {}", self.url(&base)))?;
+
+        let mut objects: Vec<StoredObject> = metas
+            .into_iter()
+            .map(|meta| {
+                let hash: String = meta
+                    .location
+                    .prefix_match(&base)
+                    .map(|parts| parts.map(|part| part.as_ref().to_string()).collect())
+                    .unwrap_or_default();
+
+                StoredObject {
+                    location: self.url(&meta.location),
+                    digest: format!("sha256:{hash}").parse().ok(),
+                }
+            })
+            .collect();
+
+        objects.sort();
+        Ok(objects)
+    }
+}
@@ -0,0 +1,121 @@
+//! Filling in the audit history after installing a snapshot.
+//!
+//! Snapshots leave out the audit history, as it only grows and would make every snapshot bigger
+//! than the last. A node that installs one instead fetches the events it is missing from its
+//! peers, a page at a time, in the background.
+
+use std::time::Duration;
+
+use actix_web::web::Data;
+use tracing::{debug, info, warn};
+
+use crate::app::RegistryApp;
+use crate::types::{AuditCursor, AuditEvent, AuditPageRequest};
+
+use super::AuditBackfill;
+
+/// How many audit events are asked for at a time.
+const PAGE_SIZE: usize = 1000;
+
+/// How long to wait before trying again when no peer could send a page.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Ask each of the other members for the next page of `backfill` until one of them sends it.
+async fn fetch_page(
+    app: &RegistryApp,
+    client: &reqwest::Client,
+    backfill: &AuditBackfill,
+) -> Option<Vec<(AuditCursor, AuditEvent)>> {
+    let membership = app
+        .store
+        .state_machine
+        .read()
+        .unwrap()
+        .get_last_membership()
+        .ok()?;
+
+    let scheme = app.config.raft.scheme();
+    let token = app.peer_token();
+    let request = AuditPageRequest {
+        after: backfill.after,
+        until: backfill.until,
+        limit: PAGE_SIZE,
+    };
+
+    for (id, node) in membership.nodes() {
+        if *id == app.id {
+            continue;
+        }
+
+        let result: anyhow::Result<Vec<(AuditCursor, AuditEvent)>> = async {
+            let mut req = client
+                .post(format!("{scheme}://{}/audit/page", node.addr))
+                .json(&request);
+            if let Some(token) = &token {
+                req = req.bearer_auth(token);
+            }
+            let resp = req.send().await?.error_for_status()?;
+            Ok(resp.json().await?)
+        }
+        .await;
+
+        match result {
+            Ok(events) => return Some(events),
+            Err(err) => debug!("Audit backfill: Node {id} couldn't send a page: {err:?}"),
+        }
+    }
+
+    None
+}
+
+/// Fetch the audit events this node is missing since it installed a snapshot, whenever there
+/// are any.
+pub(crate) fn start_backfilling_audit(app: Data<RegistryApp>) {
+    let mut applied = app.store.watch_audit_events();
+
+    tokio::spawn(async move {
+        let client = match app.cluster_client_builder().build() {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("Audit backfill: Unable to create client: {err:?}");
+                return;
+            }
+        };
+
+        loop {
+            let backfill = match app.store.get_audit_backfill() {
+                Ok(backfill) => backfill,
+                Err(err) => {
+                    warn!("Audit backfill: Unable to read progress: {err:?}");
+                    None
+                }
+            };
+
+            let Some(backfill) = backfill else {
+                // Installing a snapshot counts as applying
+                if applied.changed().await.is_err() {
+                    break;
+                }
+                continue;
+            };
+
+            let Some(events) = fetch_page(&app, &client, &backfill).await else {
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            };
+
+            if let Err(err) = app.store.backfill_audit(&events, PAGE_SIZE).await {
+                warn!("Audit backfill: Unable to store page: {err:?}");
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+
+            if events.len() < PAGE_SIZE {
+                info!(
+                    "Audit backfill: Fetched audit history up to log entry {}",
+                    backfill.until
+                );
+            }
+        }
+    });
+}
@@ -0,0 +1,540 @@
+//! Indexes over the manifests, blobs and tags, so garbage collection and finding what uses an
+//! object don't have to load every manifest and blob.
+//!
+//! They are updated in the same transaction as the changes they index, and can be rebuilt from
+//! the state machine at any time.
+//!
+//! * `dependents` has the reverse of each dependency: which manifests (or blobs) depend on an
+//!   object.
+//! * `subjects` has the subject of each referrer, the reverse of the `referrers` tree.
+//! * `tagged` has the tags that point at each manifest.
+//! * `garbage_candidates` has the mounts that may have become unreachable, because they were
+//!   pushed, a tag moved away from them or they were unmounted. Garbage collection only checks
+//!   these, and what they lead to.
+//! * `unmounted` has the objects that aren't in any repository, which each node deletes its
+//!   copies of.
+
+use std::collections::{BTreeSet, HashSet};
+
+use bincode::{options, Options};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sled::transaction::TransactionalTree;
+use sled::Tree;
+
+use crate::types::{Blob, Digest, Manifest, Mount, References, RepositoryName, TagKey};
+
+use super::metrics::StorageMetrics;
+use super::{
+    blobs, flush_async, manifests, referrers, s_w_err, sm_r_err, sm_w_err, tags, RegistryStore,
+    StorageResult,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum Object {
+    Manifest,
+    Blob,
+}
+
+/// What garbage collection found by checking the candidates.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GarbageScan {
+    /// The last log entry applied when the scan started.
+    pub index: u64,
+    /// Manifests that can't be reached from a tag in the repository.
+    pub manifests: Vec<Mount>,
+    /// Blobs that can't be reached from a tag in the repository.
+    pub blobs: Vec<Mount>,
+    /// Candidates that don't need checking again, unless they change.
+    pub checked: Vec<Mount>,
+}
+
+enum Reachability {
+    Tagged,
+    Recent,
+    Unreachable,
+}
+
+/// An object as it is mounted in a repository. A digest can be mounted as a manifest and as a
+/// blob.
+#[derive(Default)]
+struct Mounted {
+    manifest: bool,
+    blob: bool,
+    updated: Option<DateTime<Utc>>,
+}
+
+impl Mounted {
+    fn is_mounted(&self) -> bool {
+        self.manifest || self.blob
+    }
+}
+
+fn key<T: Serialize>(value: &T) -> Vec<u8> {
+    options().with_big_endian().serialize(value).unwrap()
+}
+
+fn prefix_keys<T: for<'de> Deserialize<'de>>(
+    tree: &Tree,
+    prefix: Vec<u8>,
+) -> StorageResult<Vec<T>> {
+    let opts = options().with_big_endian();
+    let mut keys = vec![];
+    for row in tree.scan_prefix(prefix) {
+        let (key, _) = row.map_err(sm_r_err)?;
+        keys.push(opts.deserialize(&key).unwrap());
+    }
+
+    Ok(keys)
+}
+
+pub(super) fn dependents(db: &sled::Db) -> Tree {
+    db.open_tree("dependents").expect("dependents open failed")
+}
+pub(super) fn subjects(db: &sled::Db) -> Tree {
+    db.open_tree("subjects").expect("subjects open failed")
+}
+pub(super) fn tagged(db: &sled::Db) -> Tree {
+    db.open_tree("tagged").expect("tagged open failed")
+}
+pub(super) fn garbage_candidates(db: &sled::Db) -> Tree {
+    db.open_tree("garbage_candidates")
+        .expect("garbage_candidates open failed")
+}
+pub(super) fn unmounted(db: &sled::Db) -> Tree {
+    db.open_tree("unmounted").expect("unmounted open failed")
+}
+
+/// Whether the indexes have been built in this database yet.
+pub(super) fn is_indexed(db: &sled::Db) -> bool {
+    db.tree_names()
+        .iter()
+        .any(|name| name.as_ref() == b"dependents")
+}
+
+/// The index trees, within a state machine transaction.
+pub(super) struct TxIndexes<'a> {
+    pub dependents: &'a TransactionalTree,
+    pub subjects: &'a TransactionalTree,
+    pub tagged: &'a TransactionalTree,
+    pub garbage_candidates: &'a TransactionalTree,
+    pub unmounted: &'a TransactionalTree,
+}
+
+impl TxIndexes<'_> {
+    /// Replace the dependencies of `digest`.
+    pub fn set_dependencies(
+        &self,
+        digest: &Digest,
+        previous: &[Digest],
+        dependencies: &[Digest],
+    ) -> StorageResult<()> {
+        for dependency in previous {
+            self.dependents
+                .remove(key(&(dependency, digest)))
+                .map_err(sm_w_err)?;
+        }
+        for dependency in dependencies {
+            self.dependents
+                .insert(key(&(dependency, digest)), vec![])
+                .map_err(sm_w_err)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_subject(&self, digest: &Digest, subject: &Digest) -> StorageResult<()> {
+        self.subjects
+            .insert(key(digest), key(subject))
+            .map_err(sm_w_err)?;
+        Ok(())
+    }
+
+    /// Move `tag` from `previous` to `digest`. The manifest it moved away from is checked by
+    /// the next garbage collection.
+    pub fn tag_moved(
+        &self,
+        index: u64,
+        repository: &RepositoryName,
+        tag: &str,
+        previous: Option<&Digest>,
+        digest: &Digest,
+    ) -> StorageResult<()> {
+        if let Some(previous) = previous {
+            self.tagged
+                .remove(key(&(previous, repository, tag)))
+                .map_err(sm_w_err)?;
+            self.add_candidate(index, repository, previous)?;
+        }
+        self.tagged
+            .insert(key(&(digest, repository, tag)), vec![])
+            .map_err(sm_w_err)?;
+        Ok(())
+    }
+
+    pub fn mounted(
+        &self,
+        index: u64,
+        object: Object,
+        repository: &RepositoryName,
+        digest: &Digest,
+    ) -> StorageResult<()> {
+        self.unmounted
+            .remove(key(&(object, digest)))
+            .map_err(sm_w_err)?;
+        self.add_candidate(index, repository, digest)
+    }
+
+    /// `digest` was unmounted from `repository`. The next garbage collection checks what it
+    /// depends on, which may only have been reachable through it.
+    pub fn unmounted(
+        &self,
+        index: u64,
+        object: Object,
+        repository: &RepositoryName,
+        digest: &Digest,
+        in_use: bool,
+    ) -> StorageResult<()> {
+        if !in_use {
+            self.unmounted
+                .insert(key(&(object, digest)), vec![])
+                .map_err(sm_w_err)?;
+        }
+        self.add_candidate(index, repository, digest)
+    }
+
+    /// `digest` isn't stored anywhere any more.
+    pub fn removed(
+        &self,
+        object: Object,
+        digest: &Digest,
+        dependencies: &[Digest],
+    ) -> StorageResult<()> {
+        self.unmounted
+            .remove(key(&(object, digest)))
+            .map_err(sm_w_err)?;
+        self.set_dependencies(digest, dependencies, &[])
+    }
+
+    /// Garbage collection checked `mounts` as of log entry `checked`. Mounts that changed since
+    /// then stay candidates.
+    pub fn garbage_checked(&self, checked: u64, mounts: &[Mount]) -> StorageResult<()> {
+        for mount in mounts {
+            let candidate = key(&(&mount.repository, &mount.digest));
+            let index = self
+                .garbage_candidates
+                .get(&candidate)
+                .map_err(sm_r_err)?
+                .map(|value| u64::from_be_bytes(value.as_ref().try_into().unwrap()));
+            if matches!(index, Some(index) if index <= checked) {
+                self.garbage_candidates
+                    .remove(candidate)
+                    .map_err(sm_w_err)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_candidate(
+        &self,
+        index: u64,
+        repository: &RepositoryName,
+        digest: &Digest,
+    ) -> StorageResult<()> {
+        self.garbage_candidates
+            .insert(key(&(repository, digest)), &index.to_be_bytes())
+            .map_err(sm_w_err)?;
+        Ok(())
+    }
+}
+
+/// Rebuild the indexes from the manifests, blobs, tags and referrers. Every mount becomes a
+/// garbage collection candidate, as nothing is known about what was checked before.
+pub(super) async fn rebuild(db: &sled::Db, metrics: &StorageMetrics) -> StorageResult<()> {
+    let opts = options().with_big_endian();
+
+    let mut dependents_batch = sled::Batch::default();
+    let mut subjects_batch = sled::Batch::default();
+    let mut tagged_batch = sled::Batch::default();
+    let mut candidates_batch = sled::Batch::default();
+    let mut unmounted_batch = sled::Batch::default();
+
+    let mut index_object = |object: Object,
+                            digest: &Digest,
+                            dependencies: &Option<Vec<Digest>>,
+                            repositories: &HashSet<RepositoryName>| {
+        for dependency in dependencies.iter().flatten() {
+            dependents_batch.insert(key(&(dependency, digest)), vec![]);
+        }
+        for repository in repositories {
+            candidates_batch.insert(key(&(repository, digest)), &0u64.to_be_bytes());
+        }
+        if repositories.is_empty() {
+            unmounted_batch.insert(key(&(object, digest)), vec![]);
+        }
+    };
+
+    for row in manifests(db).iter() {
+        let (digest, manifest) = row.map_err(sm_r_err)?;
+        let digest: Digest = opts.deserialize(&digest).unwrap();
+        let manifest: Manifest = opts.deserialize(&manifest).unwrap();
+        index_object(
+            Object::Manifest,
+            &digest,
+            &manifest.dependencies,
+            &manifest.repositories,
+        );
+    }
+
+    for row in blobs(db).iter() {
+        let (digest, blob) = row.map_err(sm_r_err)?;
+        let digest: Digest = opts.deserialize(&digest).unwrap();
+        let blob: Blob = opts.deserialize(&blob).unwrap();
+        index_object(
+            Object::Blob,
+            &digest,
+            &blob.dependencies,
+            &blob.repositories,
+        );
+    }
+
+    for row in referrers(db).iter() {
+        let (referrer, _) = row.map_err(sm_r_err)?;
+        let (subject, digest): (Digest, Digest) = opts.deserialize(&referrer).unwrap();
+        subjects_batch.insert(key(&digest), key(&subject));
+    }
+
+    for row in tags(db).iter() {
+        let (tag, digest) = row.map_err(sm_r_err)?;
+        let tag: TagKey = opts.deserialize(&tag).unwrap();
+        let digest: Digest = opts.deserialize(&digest).unwrap();
+        tagged_batch.insert(key(&(&digest, &tag.repository, &tag.tag)), vec![]);
+    }
+
+    for (tree, batch) in [
+        (dependents(db), dependents_batch),
+        (subjects(db), subjects_batch),
+        (tagged(db), tagged_batch),
+        (garbage_candidates(db), candidates_batch),
+        (unmounted(db), unmounted_batch),
+    ] {
+        tree.clear().map_err(sm_w_err)?;
+        tree.apply_batch(batch).map_err(sm_w_err)?;
+        let flushed = flush_async(&tree).await.map_err(s_w_err)?;
+        metrics.flushed_bytes.inc_by(flushed as u64);
+    }
+
+    Ok(())
+}
+
+impl RegistryStore {
+    /// The manifests (or blobs) that depend on `digest` directly.
+    fn get_dependents(&self, digest: &Digest) -> StorageResult<Vec<Digest>> {
+        let keys: Vec<(Digest, Digest)> = prefix_keys(&dependents(&self.db), key(digest))?;
+        Ok(keys.into_iter().map(|(_, dependent)| dependent).collect())
+    }
+
+    fn get_subject(&self, digest: &Digest) -> StorageResult<Option<Digest>> {
+        let subject = subjects(&self.db).get(key(digest)).map_err(sm_r_err)?;
+        Ok(subject.map(|subject| options().with_big_endian().deserialize(&subject).unwrap()))
+    }
+
+    fn get_referrers_of(&self, subject: &Digest) -> StorageResult<Vec<Digest>> {
+        let keys: Vec<(Digest, Digest)> = prefix_keys(&referrers(&self.db), key(subject))?;
+        Ok(keys.into_iter().map(|(_, digest)| digest).collect())
+    }
+
+    /// The tags that point at `digest`.
+    pub fn get_tags_of(&self, digest: &Digest) -> StorageResult<Vec<TagKey>> {
+        let keys: Vec<(Digest, RepositoryName, String)> =
+            prefix_keys(&tagged(&self.db), key(digest))?;
+        Ok(keys
+            .into_iter()
+            .map(|(_, repository, tag)| TagKey { repository, tag })
+            .collect())
+    }
+
+    fn is_tagged_in(&self, digest: &Digest, repository: &RepositoryName) -> StorageResult<bool> {
+        let mut rows = tagged(&self.db).scan_prefix(key(&(digest, repository)));
+        Ok(rows.next().transpose().map_err(sm_r_err)?.is_some())
+    }
+
+    fn get_mounted(&self, digest: &Digest, repository: &RepositoryName) -> StorageResult<Mounted> {
+        let mut mounted = Mounted::default();
+
+        if let Some(manifest) = self.get_manifest(digest)? {
+            if manifest.repositories.contains(repository) {
+                mounted.manifest = true;
+                mounted.updated = Some(manifest.updated);
+            }
+        }
+        if let Some(blob) = self.get_blob(digest)? {
+            if blob.repositories.contains(repository) {
+                mounted.blob = true;
+                mounted.updated = mounted.updated.max(Some(blob.updated));
+            }
+        }
+
+        Ok(mounted)
+    }
+
+    /// What `digest` depends on, as a manifest or a blob, and the manifests that refer to it.
+    fn get_children(&self, digest: &Digest) -> StorageResult<Vec<Digest>> {
+        let mut children = vec![];
+        if let Some(manifest) = self.get_manifest(digest)? {
+            children.extend(manifest.dependencies.into_iter().flatten());
+        }
+        if let Some(blob) = self.get_blob(digest)? {
+            children.extend(blob.dependencies.into_iter().flatten());
+        }
+        children.extend(self.get_referrers_of(digest)?);
+        Ok(children)
+    }
+
+    /// Whether `digest` can be reached from a tag in `repository`, or from something in the
+    /// repository that changed after `cutoff`. Only objects mounted in the repository are
+    /// followed.
+    fn get_reachability(
+        &self,
+        repository: &RepositoryName,
+        digest: &Digest,
+        cutoff: DateTime<Utc>,
+    ) -> StorageResult<Reachability> {
+        let mut visited = HashSet::new();
+        let mut visiting = vec![digest.clone()];
+
+        while let Some(digest) = visiting.pop() {
+            if !visited.insert(digest.clone()) {
+                continue;
+            }
+
+            let mounted = self.get_mounted(&digest, repository)?;
+            if !mounted.is_mounted() {
+                continue;
+            }
+            if self.is_tagged_in(&digest, repository)? {
+                return Ok(Reachability::Tagged);
+            }
+            if matches!(mounted.updated, Some(updated) if updated > cutoff) {
+                return Ok(Reachability::Recent);
+            }
+
+            visiting.extend(self.get_dependents(&digest)?);
+            visiting.extend(self.get_subject(&digest)?);
+        }
+
+        Ok(Reachability::Unreachable)
+    }
+
+    pub fn get_garbage_candidates(&self) -> StorageResult<Vec<Mount>> {
+        let keys: Vec<(RepositoryName, Digest)> =
+            prefix_keys(&garbage_candidates(&self.db), vec![])?;
+        Ok(keys
+            .into_iter()
+            .map(|(repository, digest)| Mount { digest, repository })
+            .collect())
+    }
+
+    /// Find the mounts that can't be reached from a tag in their repository and haven't
+    /// changed since `cutoff`, starting from the garbage collection candidates and following
+    /// what unreachable objects depend on.
+    pub fn find_garbage(&self, cutoff: DateTime<Utc>) -> StorageResult<GarbageScan> {
+        let index = self
+            .state_machine
+            .read()
+            .unwrap()
+            .get_last_applied_log()?
+            .map(|log_id| log_id.index)
+            .unwrap_or(0);
+
+        let mut scan = GarbageScan {
+            index,
+            ..Default::default()
+        };
+        let mut visited = HashSet::new();
+
+        for candidate in self.get_garbage_candidates()? {
+            // A candidate is checked once nothing it leads to is too recent to collect
+            let mut settled = true;
+            let mut visiting = vec![candidate.digest.clone()];
+
+            while let Some(digest) = visiting.pop() {
+                let mount = Mount {
+                    digest,
+                    repository: candidate.repository.clone(),
+                };
+                if !visited.insert(mount.clone()) {
+                    continue;
+                }
+
+                let mounted = self.get_mounted(&mount.digest, &mount.repository)?;
+                if mounted.is_mounted() {
+                    match self.get_reachability(&mount.repository, &mount.digest, cutoff)? {
+                        Reachability::Tagged => continue,
+                        Reachability::Recent => {
+                            settled = false;
+                            continue;
+                        }
+                        Reachability::Unreachable => {}
+                    }
+                    if mounted.manifest {
+                        scan.manifests.push(mount.clone());
+                    }
+                    if mounted.blob {
+                        scan.blobs.push(mount.clone());
+                    }
+                }
+
+                visiting.extend(self.get_children(&mount.digest)?);
+            }
+
+            if settled {
+                scan.checked.push(candidate);
+            }
+        }
+
+        Ok(scan)
+    }
+
+    /// Manifests that aren't in any repository.
+    pub fn get_unmounted_manifests(&self) -> StorageResult<Vec<Digest>> {
+        let keys: Vec<(Object, Digest)> =
+            prefix_keys(&unmounted(&self.db), key(&Object::Manifest))?;
+        Ok(keys.into_iter().map(|(_, digest)| digest).collect())
+    }
+
+    /// Blobs that aren't in any repository.
+    pub fn get_unmounted_blobs(&self) -> StorageResult<Vec<Digest>> {
+        let keys: Vec<(Object, Digest)> = prefix_keys(&unmounted(&self.db), key(&Object::Blob))?;
+        Ok(keys.into_iter().map(|(_, digest)| digest).collect())
+    }
+
+    /// The manifests, tags and repositories that use `digest`.
+    pub fn get_references(&self, digest: &Digest) -> StorageResult<References> {
+        let mut manifests = BTreeSet::new();
+        let mut visiting = self.get_dependents(digest)?;
+        while let Some(dependent) = visiting.pop() {
+            if manifests.insert(dependent.clone()) {
+                visiting.extend(self.get_dependents(&dependent)?);
+            }
+        }
+
+        let mut tags = BTreeSet::new();
+        for manifest in std::iter::once(digest).chain(manifests.iter()) {
+            tags.extend(self.get_tags_of(manifest)?);
+        }
+
+        let mut repositories = BTreeSet::new();
+        if let Some(manifest) = self.get_manifest(digest)? {
+            repositories.extend(manifest.repositories);
+        }
+        if let Some(blob) = self.get_blob(digest)? {
+            repositories.extend(blob.repositories);
+        }
+
+        Ok(References {
+            manifests: manifests.into_iter().collect(),
+            tags: tags.into_iter().collect(),
+            repositories: repositories.into_iter().collect(),
+        })
+    }
+}
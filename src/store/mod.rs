@@ -2,6 +2,7 @@
 mod test;
 
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
@@ -48,13 +49,21 @@ use crate::types::Digest;
 use crate::types::Manifest;
 use crate::types::RegistryAction;
 use crate::types::RepositoryName;
+use crate::types::RobotAccount;
 use crate::types::TagKey;
+use crate::types::{AclEntry, AclSubject, AuditCursor, AuditEvent, AuditQuery, AuditRequest};
 use crate::RegistryTypeConfig;
 
+use self::index::Object;
+use self::index::TxIndexes;
 use self::metrics::StorageMetrics;
 
+pub mod backfill;
+mod index;
 pub mod metrics;
 
+pub use self::index::GarbageScan;
+
 pub type RegistryNodeId = u64;
 
 /**
@@ -65,7 +74,12 @@ pub type RegistryNodeId = u64;
  */
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum RegistryRequest {
-    Transaction { actions: Vec<RegistryAction> },
+    Transaction {
+        actions: Vec<RegistryAction>,
+        /// The client request the actions were made for, recorded in their audit events.
+        #[serde(default)]
+        request: Option<AuditRequest>,
+    },
 }
 
 /**
@@ -105,6 +119,16 @@ pub struct SerializableRegistryStateMachine {
     pub manifests: BTreeMap<Digest, Manifest>,
     pub blobs: BTreeMap<Digest, Blob>,
     pub tags: BTreeMap<RepositoryName, BTreeMap<String, Digest>>,
+    #[serde(default)]
+    pub robots: BTreeMap<String, RobotAccount>,
+    #[serde(default)]
+    pub acls: Vec<AclEntry>,
+    /// How far each webhook has been notified, by its id.
+    #[serde(default)]
+    pub webhook_cursors: BTreeMap<String, AuditCursor>,
+    /// The manifests that refer to each subject.
+    #[serde(default)]
+    pub referrers: BTreeMap<Digest, BTreeSet<Digest>>,
 }
 
 #[derive(Debug)]
@@ -167,16 +191,42 @@ impl From<&RegistryStateMachine> for SerializableRegistryStateMachine {
             repo.insert(key.tag, value);
         }
 
+        let robot_tree = get_robots(&robots(&state.db)).expect("read db failed");
+        let acl_tree = get_acls(&acls(&state.db)).expect("read db failed");
+
         Self {
             last_applied_log: state.get_last_applied_log().expect("last_applied_log"),
             last_membership: state.get_last_membership().expect("last_membership"),
             manifests: manifest_tree,
             blobs: blob_tree,
             tags: tag_tree,
+            robots: robot_tree,
+            acls: acl_tree,
+            webhook_cursors: state.get_webhook_cursors().expect("webhook_cursors"),
+            referrers: get_referrers(&referrers(&state.db)).expect("read db failed"),
         }
     }
 }
 
+/// Audit events that a node is missing because it installed a snapshot rather than applying
+/// the log entries they were applied from. Snapshots don't include the audit history, which
+/// only grows, so it is fetched from a peer a page at a time instead: everything after `after`
+/// up to and including the log entry `until`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuditBackfill {
+    pub after: Option<AuditCursor>,
+    pub until: u64,
+}
+
+impl AuditBackfill {
+    /// Whether any of the events after `after` up to the log entry `until` are missing.
+    fn overlaps(&self, after: Option<AuditCursor>, until: u64) -> bool {
+        let missing_from = self.after.map(|cursor| cursor.index).unwrap_or(0);
+        let wanted_from = after.map(|cursor| cursor.index).unwrap_or(0);
+        missing_from < until && wanted_from < self.until
+    }
+}
+
 fn sm_r_err<E: Error + 'static>(e: E) -> StorageError<RegistryNodeId> {
     StorageIOError::new(
         ErrorSubject::StateMachine,
@@ -297,6 +347,36 @@ impl RegistryStateMachine {
             .map_err(ct_err)?;
         Ok(())
     }
+    pub fn get_webhook_cursors(&self) -> StorageResult<BTreeMap<String, AuditCursor>> {
+        let state_machine = state_machine(&self.db);
+        state_machine
+            .get(b"webhook_cursors")
+            .map_err(sm_r_err)
+            .and_then(|value| {
+                value
+                    .map(|v| serde_json::from_slice(&v).map_err(sm_r_err))
+                    .transpose()
+            })
+            .map(Option::unwrap_or_default)
+    }
+    fn set_webhook_cursor_tx(
+        &self,
+        tx_state_machine: &sled::transaction::TransactionalTree,
+        hook: &str,
+        cursor: &AuditCursor,
+    ) -> Result<(), sled::transaction::ConflictableTransactionError<AnyError>> {
+        let mut cursors: BTreeMap<String, AuditCursor> =
+            match tx_state_machine.get(b"webhook_cursors")? {
+                Some(value) => serde_json::from_slice(&value).map_err(ct_err)?,
+                None => BTreeMap::new(),
+            };
+        cursors.insert(hook.to_string(), *cursor);
+        let value = serde_json::to_vec(&cursors).map_err(ct_err)?;
+        tx_state_machine
+            .insert(b"webhook_cursors", value)
+            .map_err(ct_err)?;
+        Ok(())
+    }
     async fn from_serializable(
         id: RegistryNodeId,
         sm: SerializableRegistryStateMachine,
@@ -353,6 +433,47 @@ impl RegistryStateMachine {
         let flushed = flush_async(&tag_tree).await.map_err(s_w_err)?;
         metrics.flushed_bytes.inc_by(flushed as u64);
 
+        // Robots that were revoked since the snapshot was taken must not linger
+        let robot_tree = robots(&db);
+        robot_tree.clear().map_err(sm_w_err)?;
+        let mut batch = sled::Batch::default();
+        for (name, robot) in sm.robots {
+            batch.insert(
+                options().with_big_endian().serialize(&name).unwrap(),
+                options().with_big_endian().serialize(&robot).unwrap(),
+            );
+        }
+        robot_tree.apply_batch(batch).map_err(sm_w_err)?;
+        let flushed = flush_async(&robot_tree).await.map_err(s_w_err)?;
+        metrics.flushed_bytes.inc_by(flushed as u64);
+
+        let acl_tree = acls(&db);
+        acl_tree.clear().map_err(sm_w_err)?;
+        let mut batch = sled::Batch::default();
+        for entry in sm.acls {
+            batch.insert(
+                acl_key(&entry.subject, &entry.repository),
+                options().with_big_endian().serialize(&entry).unwrap(),
+            );
+        }
+        acl_tree.apply_batch(batch).map_err(sm_w_err)?;
+        let flushed = flush_async(&acl_tree).await.map_err(s_w_err)?;
+        metrics.flushed_bytes.inc_by(flushed as u64);
+
+        let referrer_tree = referrers(&db);
+        referrer_tree.clear().map_err(sm_w_err)?;
+        let mut batch = sled::Batch::default();
+        for (subject, digests) in sm.referrers {
+            for digest in digests {
+                batch.insert(referrer_key(&subject, &digest), vec![]);
+            }
+        }
+        referrer_tree.apply_batch(batch).map_err(sm_w_err)?;
+        let flushed = flush_async(&referrer_tree).await.map_err(s_w_err)?;
+        metrics.flushed_bytes.inc_by(flushed as u64);
+
+        index::rebuild(&db, &metrics).await?;
+
         let (pending_blobs, _) = channel(pblob);
         let (pending_manifests, _) = channel(pmanifest);
 
@@ -369,6 +490,14 @@ impl RegistryStateMachine {
         }
         r.set_last_membership(sm.last_membership).await?;
 
+        let state_machine = state_machine(&r.db);
+        state_machine
+            .insert(
+                b"webhook_cursors",
+                serde_json::to_vec(&sm.webhook_cursors).map_err(sm_w_err)?,
+            )
+            .map_err(sm_w_err)?;
+
         Ok(r)
     }
 
@@ -487,6 +616,33 @@ impl RegistryStateMachine {
         })
     }
 
+    fn tx_get_tag(
+        &self,
+        tags: &TransactionalTree,
+        repository: &RepositoryName,
+        tag: &str,
+    ) -> StorageResult<Option<Digest>> {
+        let key = options()
+            .with_big_endian()
+            .serialize(&TagKey {
+                repository: repository.clone(),
+                tag: tag.to_owned(),
+            })
+            .unwrap();
+        tags.get(key)
+            .map(|value| {
+                value.map(|value| {
+                    options()
+                        .with_big_endian()
+                        .deserialize(&value)
+                        .expect("invalid data")
+                })
+            })
+            .map_err(|e| {
+                StorageIOError::new(ErrorSubject::Store, ErrorVerb::Read, AnyError::new(&e)).into()
+            })
+    }
+
     fn tx_put_tag(
         &self,
         tags: &TransactionalTree,
@@ -513,6 +669,95 @@ impl RegistryStateMachine {
             StorageIOError::new(ErrorSubject::Store, ErrorVerb::Write, AnyError::new(&e)).into()
         })
     }
+
+    fn tx_put_robot(
+        &self,
+        robots: &TransactionalTree,
+        name: &str,
+        robot: &RobotAccount,
+    ) -> StorageResult<()> {
+        let key = options().with_big_endian().serialize(name).unwrap();
+        robots
+            .insert(
+                key,
+                options()
+                    .with_big_endian()
+                    .serialize(robot)
+                    .expect("invalid data"),
+            )
+            .map(|_value| ())
+            .map_err(|e| {
+                StorageIOError::new(ErrorSubject::Store, ErrorVerb::Write, AnyError::new(&e)).into()
+            })
+    }
+
+    fn tx_del_robot(&self, robots: &TransactionalTree, name: &str) -> StorageResult<()> {
+        let key = options().with_big_endian().serialize(name).unwrap();
+        robots.remove(key).map(|_value| ()).map_err(|e| {
+            StorageIOError::new(ErrorSubject::Store, ErrorVerb::Write, AnyError::new(&e)).into()
+        })
+    }
+
+    fn tx_put_acl(&self, acls: &TransactionalTree, entry: &AclEntry) -> StorageResult<()> {
+        acls.insert(
+            acl_key(&entry.subject, &entry.repository),
+            options()
+                .with_big_endian()
+                .serialize(entry)
+                .expect("invalid data"),
+        )
+        .map(|_value| ())
+        .map_err(|e| {
+            StorageIOError::new(ErrorSubject::Store, ErrorVerb::Write, AnyError::new(&e)).into()
+        })
+    }
+
+    fn tx_put_referrer(
+        &self,
+        referrers: &TransactionalTree,
+        subject: &Digest,
+        digest: &Digest,
+    ) -> StorageResult<()> {
+        referrers
+            .insert(referrer_key(subject, digest), vec![])
+            .map(|_value| ())
+            .map_err(|e| {
+                StorageIOError::new(ErrorSubject::Store, ErrorVerb::Write, AnyError::new(&e)).into()
+            })
+    }
+
+    fn tx_put_audit(
+        &self,
+        audit: &TransactionalTree,
+        position: u32,
+        event: &AuditEvent,
+    ) -> StorageResult<()> {
+        audit
+            .insert(
+                audit_key(event.index, position),
+                options()
+                    .with_big_endian()
+                    .serialize(event)
+                    .expect("invalid data"),
+            )
+            .map(|_value| ())
+            .map_err(|e| {
+                StorageIOError::new(ErrorSubject::Store, ErrorVerb::Write, AnyError::new(&e)).into()
+            })
+    }
+
+    fn tx_del_acl(
+        &self,
+        acls: &TransactionalTree,
+        subject: &AclSubject,
+        repository: &str,
+    ) -> StorageResult<()> {
+        acls.remove(acl_key(subject, repository))
+            .map(|_value| ())
+            .map_err(|e| {
+                StorageIOError::new(ErrorSubject::Store, ErrorVerb::Write, AnyError::new(&e)).into()
+            })
+    }
 }
 
 #[derive(Debug)]
@@ -523,6 +768,11 @@ pub struct RegistryStore {
     /// The Raft state machine.
     pub state_machine: RwLock<RegistryStateMachine>,
 
+    /// Marked as changed whenever entries or a snapshot have been applied, so readers of the
+    /// audit history know to look for new events. Unlike a sled subscriber, applying never
+    /// waits for anyone to read it.
+    applied: tokio::sync::watch::Sender<()>,
+
     // Metrics
     pub metrics: StorageMetrics,
 }
@@ -913,68 +1163,149 @@ impl RaftStorage<RegistryTypeConfig> for Arc<RegistryStore> {
         let blob_tree = blobs(&self.db);
         let manifest_tree = manifests(&self.db);
         let tag_tree = tags(&self.db);
+        let robot_tree = robots(&self.db);
+        let acl_tree = acls(&self.db);
+        let audit_tree = audit(&self.db);
+        let referrer_tree = referrers(&self.db);
+        let dependent_tree = index::dependents(&self.db);
+        let subject_tree = index::subjects(&self.db);
+        let tagged_tree = index::tagged(&self.db);
+        let garbage_candidate_tree = index::garbage_candidates(&self.db);
+        let unmounted_tree = index::unmounted(&self.db);
+
+        let trans_res = (
+            &state_machine,
+            &blob_tree,
+            &manifest_tree,
+            &tag_tree,
+            &robot_tree,
+            &acl_tree,
+            &audit_tree,
+            &referrer_tree,
+            &dependent_tree,
+            &subject_tree,
+            &tagged_tree,
+            &garbage_candidate_tree,
+            &unmounted_tree,
+        )
+            .transaction(
+                |(
+                    tx_state_machine,
+                    tx_blob_tree,
+                    tx_manifest_tree,
+                    tx_tag_tree,
+                    tx_robot_tree,
+                    tx_acl_tree,
+                    tx_audit_tree,
+                    tx_referrer_tree,
+                    tx_dependent_tree,
+                    tx_subject_tree,
+                    tx_tagged_tree,
+                    tx_garbage_candidate_tree,
+                    tx_unmounted_tree,
+                )| {
+                    let sm = self.state_machine.write().unwrap();
+                    let indexes = TxIndexes {
+                        dependents: tx_dependent_tree,
+                        subjects: tx_subject_tree,
+                        tagged: tx_tagged_tree,
+                        garbage_candidates: tx_garbage_candidate_tree,
+                        unmounted: tx_unmounted_tree,
+                    };
+
+                    let mut res = Vec::with_capacity(entries.len());
 
-        let trans_res = (&state_machine, &blob_tree, &manifest_tree, &tag_tree).transaction(
-            |(tx_state_machine, tx_blob_tree, tx_manifest_tree, tx_tag_tree)| {
-                let sm = self.state_machine.write().unwrap();
-
-                let mut res = Vec::with_capacity(entries.len());
-
-                for entry in entries {
-                    tracing::debug!(%entry.log_id, "replicate to sm");
-
-                    sm.set_last_applied_log_tx(tx_state_machine, entry.log_id)?;
-
-                    match entry.payload {
-                        EntryPayload::Blank => res.push(RegistryResponse {
-                            value: entry.log_id.index,
-                        }),
-                        EntryPayload::Normal(ref req) => match req {
-                            RegistryRequest::Transaction { actions } => {
-                                for action in actions {
-                                    match action {
-                                        RegistryAction::Empty => {}
-                                        RegistryAction::BlobStored {
-                                            timestamp,
-                                            digest,
-                                            location,
-                                            user: _,
-                                        } => {
-                                            let mut blob = sm
-                                                .tx_get_blob(tx_blob_tree, digest)
-                                                .unwrap()
+                    for entry in entries {
+                        tracing::debug!(%entry.log_id, "replicate to sm");
+
+                        sm.set_last_applied_log_tx(tx_state_machine, entry.log_id)?;
+
+                        match entry.payload {
+                            EntryPayload::Blank => res.push(RegistryResponse {
+                                value: entry.log_id.index,
+                            }),
+                            EntryPayload::Normal(ref req) => match req {
+                                RegistryRequest::Transaction { actions, request } => {
+                                    let mut audited = 0;
+                                    for action in actions {
+                                        if let Some(mut event) =
+                                            AuditEvent::from_action(entry.log_id.index, action)
+                                        {
+                                            if let RegistryAction::HashTagged {
+                                                digest,
+                                                repository,
+                                                tag,
+                                                ..
+                                            } = action
+                                            {
+                                                event.previous_digest = sm
+                                                    .tx_get_tag(tx_tag_tree, repository, tag)
+                                                    .unwrap()
+                                                    .filter(|previous| previous != digest);
+                                            }
+                                            event.request = request.clone();
+                                            sm.tx_put_audit(tx_audit_tree, audited, &event)
                                                 .unwrap();
-                                            blob.updated = *timestamp;
-                                            blob.locations.insert(*location);
-                                            sm.tx_put_blob(tx_blob_tree, digest, &blob).unwrap();
+                                            audited += 1;
                                         }
-                                        RegistryAction::BlobUnstored {
-                                            timestamp,
-                                            digest,
-                                            location,
-                                            user: _,
-                                        } => {
-                                            if let Some(mut blob) =
-                                                sm.tx_get_blob(tx_blob_tree, digest).unwrap()
-                                            {
-                                                blob.updated = *timestamp;
-                                                blob.locations.remove(location);
-                                                if blob.locations.is_empty() {
-                                                    sm.tx_del_blob(tx_blob_tree, digest).unwrap();
-                                                } else {
+
+                                        match action {
+                                            RegistryAction::Empty => {}
+                                            RegistryAction::BlobStored {
+                                                timestamp,
+                                                digest,
+                                                location,
+                                                user: _,
+                                            } => {
+                                                // It may have been garbage collected while
+                                                // it was being mirrored
+                                                if let Some(mut blob) =
+                                                    sm.tx_get_blob(tx_blob_tree, digest).unwrap()
+                                                {
+                                                    blob.updated = *timestamp;
+                                                    blob.locations.insert(*location);
                                                     sm.tx_put_blob(tx_blob_tree, digest, &blob)
                                                         .unwrap();
                                                 }
                                             }
-                                        }
-                                        RegistryAction::BlobMounted {
-                                            timestamp,
-                                            digest,
-                                            repository,
-                                            user: _,
-                                        } => {
-                                            let mut blob =
-                                                match sm.tx_get_blob(tx_blob_tree, digest).unwrap()
+                                            RegistryAction::BlobUnstored {
+                                                timestamp,
+                                                digest,
+                                                location,
+                                                user: _,
+                                            } => {
+                                                if let Some(mut blob) =
+                                                    sm.tx_get_blob(tx_blob_tree, digest).unwrap()
+                                                {
+                                                    blob.updated = *timestamp;
+                                                    blob.locations.remove(location);
+                                                    if blob.locations.is_empty() {
+                                                        sm.tx_del_blob(tx_blob_tree, digest)
+                                                            .unwrap();
+                                                        indexes
+                                                            .removed(
+                                                                Object::Blob,
+                                                                digest,
+                                                                blob.dependencies
+                                                                    .as_deref()
+                                                                    .unwrap_or_default(),
+                                                            )
+                                                            .unwrap();
+                                                    } else {
+                                                        sm.tx_put_blob(tx_blob_tree, digest, &blob)
+                                                            .unwrap();
+                                                    }
+                                                }
+                                            }
+                                            RegistryAction::BlobMounted {
+                                                timestamp,
+                                                digest,
+                                                repository,
+                                                user: _,
+                                            } => {
+                                                let mut blob = match sm
+                                                    .tx_get_blob(tx_blob_tree, digest)
+                                                    .unwrap()
                                                 {
                                                     Some(blob) => blob,
                                                     None => Blob {
@@ -987,86 +1318,96 @@ impl RaftStorage<RegistryTypeConfig> for Arc<RegistryStore> {
                                                         repositories: HashSet::new(),
                                                     },
                                                 };
-                                            blob.updated = *timestamp;
-                                            blob.repositories.insert(repository.clone());
-                                            sm.tx_put_blob(tx_blob_tree, digest, &blob).unwrap();
-                                        }
-                                        RegistryAction::BlobUnmounted {
-                                            timestamp,
-                                            digest,
-                                            repository,
-                                            user: _,
-                                        } => {
-                                            if let Some(mut blob) =
-                                                sm.tx_get_blob(tx_blob_tree, digest).unwrap()
-                                            {
                                                 blob.updated = *timestamp;
-                                                blob.repositories.remove(repository);
+                                                blob.repositories.insert(repository.clone());
                                                 sm.tx_put_blob(tx_blob_tree, digest, &blob)
                                                     .unwrap();
-                                            }
-                                        }
-                                        RegistryAction::BlobInfo {
-                                            timestamp,
-                                            digest,
-                                            dependencies,
-                                            content_type,
-                                        } => {
-                                            if let Some(mut blob) =
-                                                sm.tx_get_blob(tx_blob_tree, digest).unwrap()
-                                            {
-                                                blob.updated = *timestamp;
-                                                blob.dependencies = Some(dependencies.clone());
-                                                blob.content_type = Some(content_type.clone());
-                                                sm.tx_put_blob(tx_blob_tree, digest, &blob)
+                                                indexes
+                                                    .mounted(
+                                                        entry.log_id.index,
+                                                        Object::Blob,
+                                                        repository,
+                                                        digest,
+                                                    )
                                                     .unwrap();
                                             }
-                                        }
-                                        RegistryAction::BlobStat {
-                                            timestamp,
-                                            digest,
-                                            size,
-                                        } => {
-                                            if let Some(mut blob) =
-                                                sm.tx_get_blob(tx_blob_tree, digest).unwrap()
-                                            {
-                                                blob.updated = *timestamp;
-                                                blob.size = Some(*size);
-                                                sm.tx_put_blob(tx_blob_tree, digest, &blob)
-                                                    .unwrap();
+                                            RegistryAction::BlobUnmounted {
+                                                timestamp,
+                                                digest,
+                                                repository,
+                                                user: _,
+                                            } => {
+                                                if let Some(mut blob) =
+                                                    sm.tx_get_blob(tx_blob_tree, digest).unwrap()
+                                                {
+                                                    blob.updated = *timestamp;
+                                                    blob.repositories.remove(repository);
+                                                    sm.tx_put_blob(tx_blob_tree, digest, &blob)
+                                                        .unwrap();
+                                                    indexes
+                                                        .unmounted(
+                                                            entry.log_id.index,
+                                                            Object::Blob,
+                                                            repository,
+                                                            digest,
+                                                            !blob.repositories.is_empty(),
+                                                        )
+                                                        .unwrap();
+                                                }
                                             }
-                                        }
-                                        RegistryAction::ManifestStored {
-                                            timestamp,
-                                            digest,
-                                            location,
-                                            user: _,
-                                        } => {
-                                            let mut manifest = sm
-                                                .tx_get_manifest(tx_manifest_tree, digest)
-                                                .unwrap()
-                                                .unwrap();
-                                            manifest.updated = *timestamp;
-                                            manifest.locations.insert(*location);
-                                            sm.tx_put_manifest(tx_manifest_tree, digest, &manifest)
-                                                .unwrap();
-                                        }
-                                        RegistryAction::ManifestUnstored {
-                                            timestamp,
-                                            digest,
-                                            location,
-                                            user: _,
-                                        } => {
-                                            if let Some(mut manifest) = sm
-                                                .tx_get_manifest(tx_manifest_tree, digest)
-                                                .unwrap()
-                                            {
-                                                manifest.updated = *timestamp;
-                                                manifest.locations.remove(location);
-                                                if manifest.locations.is_empty() {
-                                                    sm.tx_del_manifest(tx_manifest_tree, digest)
+                                            RegistryAction::BlobInfo {
+                                                timestamp,
+                                                digest,
+                                                dependencies,
+                                                content_type,
+                                            } => {
+                                                if let Some(mut blob) =
+                                                    sm.tx_get_blob(tx_blob_tree, digest).unwrap()
+                                                {
+                                                    blob.updated = *timestamp;
+                                                    indexes
+                                                        .set_dependencies(
+                                                            digest,
+                                                            blob.dependencies
+                                                                .as_deref()
+                                                                .unwrap_or_default(),
+                                                            dependencies,
+                                                        )
+                                                        .unwrap();
+                                                    blob.dependencies = Some(dependencies.clone());
+                                                    blob.content_type = Some(content_type.clone());
+                                                    sm.tx_put_blob(tx_blob_tree, digest, &blob)
+                                                        .unwrap();
+                                                }
+                                            }
+                                            RegistryAction::BlobStat {
+                                                timestamp,
+                                                digest,
+                                                size,
+                                            } => {
+                                                if let Some(mut blob) =
+                                                    sm.tx_get_blob(tx_blob_tree, digest).unwrap()
+                                                {
+                                                    blob.updated = *timestamp;
+                                                    blob.size = Some(*size);
+                                                    sm.tx_put_blob(tx_blob_tree, digest, &blob)
                                                         .unwrap();
-                                                } else {
+                                                }
+                                            }
+                                            RegistryAction::ManifestStored {
+                                                timestamp,
+                                                digest,
+                                                location,
+                                                user: _,
+                                            } => {
+                                                // It may have been garbage collected while
+                                                // it was being mirrored
+                                                if let Some(mut manifest) = sm
+                                                    .tx_get_manifest(tx_manifest_tree, digest)
+                                                    .unwrap()
+                                                {
+                                                    manifest.updated = *timestamp;
+                                                    manifest.locations.insert(*location);
                                                     sm.tx_put_manifest(
                                                         tx_manifest_tree,
                                                         digest,
@@ -1075,123 +1416,283 @@ impl RaftStorage<RegistryTypeConfig> for Arc<RegistryStore> {
                                                     .unwrap();
                                                 }
                                             }
-                                        }
-                                        RegistryAction::ManifestMounted {
-                                            timestamp,
-                                            digest,
-                                            repository,
-                                            user: _,
-                                        } => {
-                                            let mut manifest = match sm
-                                                .tx_get_manifest(tx_manifest_tree, digest)
-                                                .unwrap()
-                                            {
-                                                Some(manifest) => manifest,
-                                                None => Manifest {
-                                                    created: *timestamp,
-                                                    updated: *timestamp,
-                                                    content_type: None,
-                                                    size: None,
-                                                    dependencies: Some(vec![]),
-                                                    locations: HashSet::new(),
-                                                    repositories: HashSet::new(),
-                                                },
-                                            };
-
-                                            manifest.updated = *timestamp;
-                                            manifest.repositories.insert(repository.clone());
-                                            sm.tx_put_manifest(tx_manifest_tree, digest, &manifest)
-                                                .unwrap();
-                                        }
-                                        RegistryAction::ManifestUnmounted {
-                                            timestamp,
-                                            digest,
-                                            repository,
-                                            user: _,
-                                        } => {
-                                            if let Some(mut manifest) = sm
-                                                .tx_get_manifest(tx_manifest_tree, digest)
-                                                .unwrap()
-                                            {
-                                                manifest.updated = *timestamp;
-                                                manifest.repositories.remove(repository);
-                                                sm.tx_put_manifest(
-                                                    tx_manifest_tree,
-                                                    digest,
-                                                    &manifest,
-                                                )
-                                                .unwrap();
+                                            RegistryAction::ManifestUnstored {
+                                                timestamp,
+                                                digest,
+                                                location,
+                                                user: _,
+                                            } => {
+                                                if let Some(mut manifest) = sm
+                                                    .tx_get_manifest(tx_manifest_tree, digest)
+                                                    .unwrap()
+                                                {
+                                                    manifest.updated = *timestamp;
+                                                    manifest.locations.remove(location);
+                                                    if manifest.locations.is_empty() {
+                                                        sm.tx_del_manifest(
+                                                            tx_manifest_tree,
+                                                            digest,
+                                                        )
+                                                        .unwrap();
+                                                        indexes
+                                                            .removed(
+                                                                Object::Manifest,
+                                                                digest,
+                                                                manifest
+                                                                    .dependencies
+                                                                    .as_deref()
+                                                                    .unwrap_or_default(),
+                                                            )
+                                                            .unwrap();
+                                                    } else {
+                                                        sm.tx_put_manifest(
+                                                            tx_manifest_tree,
+                                                            digest,
+                                                            &manifest,
+                                                        )
+                                                        .unwrap();
+                                                    }
+                                                }
                                             }
-                                        }
-                                        RegistryAction::ManifestInfo {
-                                            timestamp,
-                                            digest,
-                                            dependencies,
-                                            content_type,
-                                        } => {
-                                            if let Some(mut manifest) = sm
-                                                .tx_get_manifest(tx_manifest_tree, digest)
-                                                .unwrap()
-                                            {
+                                            RegistryAction::ManifestMounted {
+                                                timestamp,
+                                                digest,
+                                                repository,
+                                                user: _,
+                                            } => {
+                                                let mut manifest = match sm
+                                                    .tx_get_manifest(tx_manifest_tree, digest)
+                                                    .unwrap()
+                                                {
+                                                    Some(manifest) => manifest,
+                                                    None => Manifest {
+                                                        created: *timestamp,
+                                                        updated: *timestamp,
+                                                        content_type: None,
+                                                        size: None,
+                                                        dependencies: Some(vec![]),
+                                                        locations: HashSet::new(),
+                                                        repositories: HashSet::new(),
+                                                    },
+                                                };
+
                                                 manifest.updated = *timestamp;
-                                                manifest.dependencies = Some(dependencies.clone());
-                                                manifest.content_type = Some(content_type.clone());
+                                                manifest.repositories.insert(repository.clone());
                                                 sm.tx_put_manifest(
                                                     tx_manifest_tree,
                                                     digest,
                                                     &manifest,
                                                 )
                                                 .unwrap();
+                                                indexes
+                                                    .mounted(
+                                                        entry.log_id.index,
+                                                        Object::Manifest,
+                                                        repository,
+                                                        digest,
+                                                    )
+                                                    .unwrap();
                                             }
-                                        }
-                                        RegistryAction::ManifestStat {
-                                            timestamp,
-                                            digest,
-                                            size,
-                                        } => {
-                                            if let Some(mut manifest) = sm
-                                                .tx_get_manifest(tx_manifest_tree, digest)
-                                                .unwrap()
-                                            {
-                                                manifest.updated = *timestamp;
-                                                manifest.size = Some(*size);
-                                                sm.tx_put_manifest(
-                                                    tx_manifest_tree,
+                                            RegistryAction::ManifestUnmounted {
+                                                timestamp,
+                                                digest,
+                                                repository,
+                                                user: _,
+                                            } => {
+                                                if let Some(mut manifest) = sm
+                                                    .tx_get_manifest(tx_manifest_tree, digest)
+                                                    .unwrap()
+                                                {
+                                                    manifest.updated = *timestamp;
+                                                    manifest.repositories.remove(repository);
+                                                    sm.tx_put_manifest(
+                                                        tx_manifest_tree,
+                                                        digest,
+                                                        &manifest,
+                                                    )
+                                                    .unwrap();
+                                                    indexes
+                                                        .unmounted(
+                                                            entry.log_id.index,
+                                                            Object::Manifest,
+                                                            repository,
+                                                            digest,
+                                                            !manifest.repositories.is_empty(),
+                                                        )
+                                                        .unwrap();
+                                                }
+                                            }
+                                            RegistryAction::ManifestInfo {
+                                                timestamp,
+                                                digest,
+                                                dependencies,
+                                                content_type,
+                                            } => {
+                                                if let Some(mut manifest) = sm
+                                                    .tx_get_manifest(tx_manifest_tree, digest)
+                                                    .unwrap()
+                                                {
+                                                    manifest.updated = *timestamp;
+                                                    indexes
+                                                        .set_dependencies(
+                                                            digest,
+                                                            manifest
+                                                                .dependencies
+                                                                .as_deref()
+                                                                .unwrap_or_default(),
+                                                            dependencies,
+                                                        )
+                                                        .unwrap();
+                                                    manifest.dependencies =
+                                                        Some(dependencies.clone());
+                                                    manifest.content_type =
+                                                        Some(content_type.clone());
+                                                    sm.tx_put_manifest(
+                                                        tx_manifest_tree,
+                                                        digest,
+                                                        &manifest,
+                                                    )
+                                                    .unwrap();
+                                                }
+                                            }
+                                            RegistryAction::ManifestStat {
+                                                timestamp,
+                                                digest,
+                                                size,
+                                            } => {
+                                                if let Some(mut manifest) = sm
+                                                    .tx_get_manifest(tx_manifest_tree, digest)
+                                                    .unwrap()
+                                                {
+                                                    manifest.updated = *timestamp;
+                                                    manifest.size = Some(*size);
+                                                    sm.tx_put_manifest(
+                                                        tx_manifest_tree,
+                                                        digest,
+                                                        &manifest,
+                                                    )
+                                                    .unwrap();
+                                                }
+                                            }
+                                            RegistryAction::HashTagged {
+                                                timestamp: _,
+                                                digest,
+                                                repository,
+                                                tag,
+                                                user: _,
+                                            } => {
+                                                let previous = sm
+                                                    .tx_get_tag(tx_tag_tree, repository, tag)
+                                                    .unwrap();
+                                                sm.tx_put_tag(tx_tag_tree, repository, tag, digest)
+                                                    .unwrap();
+                                                indexes
+                                                    .tag_moved(
+                                                        entry.log_id.index,
+                                                        repository,
+                                                        tag,
+                                                        previous.as_ref(),
+                                                        digest,
+                                                    )
+                                                    .unwrap();
+                                            }
+                                            RegistryAction::RobotAccountCreated {
+                                                timestamp,
+                                                name,
+                                                secret_hash,
+                                                user,
+                                            } => {
+                                                let robot = RobotAccount {
+                                                    secret_hash: secret_hash.clone(),
+                                                    created: *timestamp,
+                                                    created_by: user.clone(),
+                                                };
+                                                sm.tx_put_robot(tx_robot_tree, name, &robot)
+                                                    .unwrap();
+                                            }
+                                            RegistryAction::RobotAccountRevoked {
+                                                timestamp: _,
+                                                name,
+                                                user: _,
+                                            } => {
+                                                sm.tx_del_robot(tx_robot_tree, name).unwrap();
+                                            }
+                                            RegistryAction::AclEntrySet {
+                                                timestamp,
+                                                subject,
+                                                repository,
+                                                actions,
+                                                user,
+                                            } => {
+                                                let entry = AclEntry {
+                                                    subject: subject.clone(),
+                                                    repository: repository.clone(),
+                                                    actions: actions.clone(),
+                                                    created: *timestamp,
+                                                    created_by: user.clone(),
+                                                };
+                                                sm.tx_put_acl(tx_acl_tree, &entry).unwrap();
+                                            }
+                                            RegistryAction::AclEntryRemoved {
+                                                timestamp: _,
+                                                subject,
+                                                repository,
+                                                user: _,
+                                            } => {
+                                                sm.tx_del_acl(tx_acl_tree, subject, repository)
+                                                    .unwrap();
+                                            }
+                                            RegistryAction::WebhookCursorMoved {
+                                                timestamp: _,
+                                                hook,
+                                                cursor,
+                                            } => {
+                                                sm.set_webhook_cursor_tx(
+                                                    tx_state_machine,
+                                                    hook,
+                                                    cursor,
+                                                )?;
+                                            }
+                                            RegistryAction::ManifestSubject {
+                                                timestamp: _,
+                                                digest,
+                                                subject,
+                                            } => {
+                                                sm.tx_put_referrer(
+                                                    tx_referrer_tree,
+                                                    subject,
                                                     digest,
-                                                    &manifest,
                                                 )
                                                 .unwrap();
+                                                indexes.set_subject(digest, subject).unwrap();
+                                            }
+                                            RegistryAction::GarbageChecked {
+                                                timestamp: _,
+                                                index,
+                                                mounts,
+                                            } => {
+                                                indexes.garbage_checked(*index, mounts).unwrap();
                                             }
-                                        }
-                                        RegistryAction::HashTagged {
-                                            timestamp: _,
-                                            digest,
-                                            repository,
-                                            tag,
-                                            user: _,
-                                        } => {
-                                            sm.tx_put_tag(tx_tag_tree, repository, tag, digest)
-                                                .unwrap();
                                         }
                                     }
+                                    res.push(RegistryResponse {
+                                        value: entry.log_id.index,
+                                    });
                                 }
+                            },
+                            EntryPayload::Membership(ref mem) => {
+                                let membership =
+                                    StoredMembership::new(Some(entry.log_id), mem.clone());
+                                sm.set_last_membership_tx(tx_state_machine, membership)?;
                                 res.push(RegistryResponse {
                                     value: entry.log_id.index,
-                                });
+                                })
                             }
-                        },
-                        EntryPayload::Membership(ref mem) => {
-                            let membership = StoredMembership::new(Some(entry.log_id), mem.clone());
-                            sm.set_last_membership_tx(tx_state_machine, membership)?;
-                            res.push(RegistryResponse {
-                                value: entry.log_id.index,
-                            })
-                        }
-                    };
-                }
-                Ok(res)
-            },
-        );
+                        };
+                    }
+                    Ok(res)
+                },
+            );
         let result_vec = trans_res.map_err(t_err)?;
 
         let flushed = self.flush_async().await.map_err(|e| {
@@ -1205,7 +1706,7 @@ impl RaftStorage<RegistryTypeConfig> for Arc<RegistryStore> {
         let mut pending_manifests = sm.pending_manifests.borrow().clone();
 
         for entry in entries {
-            if let EntryPayload::Normal(RegistryRequest::Transaction { ref actions }) =
+            if let EntryPayload::Normal(RegistryRequest::Transaction { ref actions, .. }) =
                 entry.payload
             {
                 for action in actions {
@@ -1279,6 +1780,8 @@ impl RaftStorage<RegistryTypeConfig> for Arc<RegistryStore> {
         sm.pending_blobs.send_replace(pending_blobs);
         sm.pending_manifests.send_replace(pending_manifests);
 
+        self.applied.send_replace(());
+
         Ok(result_vec)
     }
 
@@ -1322,6 +1825,12 @@ impl RaftStorage<RegistryTypeConfig> for Arc<RegistryStore> {
                         AnyError::new(&e),
                     )
                 })?;
+            // The audit events up to the snapshot aren't in it, so fetch any this node is
+            // missing from a peer
+            if let Some(until) = meta.last_log_id.map(|log_id| log_id.index) {
+                self.start_audit_backfill(until).await?;
+            }
+
             let new_sm = RegistryStateMachine::from_serializable(
                 self.id,
                 updated_state_machine,
@@ -1332,6 +1841,7 @@ impl RaftStorage<RegistryTypeConfig> for Arc<RegistryStore> {
             let mut state_machine = self.state_machine.write().unwrap();
             *state_machine = new_sm;
         }
+        self.applied.send_replace(());
 
         self.set_current_snapshot_(new_snapshot).await?;
         Ok(())
@@ -1383,6 +1893,161 @@ pub fn get_manifests(tree: &Tree) -> StorageResult<BTreeMap<Digest, Manifest>> {
 
     Ok(manifests)
 }
+/// Up to `limit` rows with keys after `after`, in key order, so a large tree can be walked a
+/// page at a time without holding it all in memory.
+fn get_page<T: for<'de> Deserialize<'de>>(
+    tree: &Tree,
+    after: Option<&Digest>,
+    limit: usize,
+) -> StorageResult<Vec<(Digest, T)>> {
+    let opts = options().with_big_endian();
+    let rows = match after {
+        Some(after) => {
+            let after = opts.serialize(after).unwrap();
+            tree.range((std::ops::Bound::Excluded(after), std::ops::Bound::Unbounded))
+        }
+        None => tree.range::<Vec<u8>, _>(..),
+    };
+
+    let mut page = vec![];
+    for row in rows.take(limit) {
+        let (key, value) = row.map_err(sm_r_err)?;
+        page.push((
+            opts.deserialize::<Digest>(&key).unwrap(),
+            opts.deserialize::<T>(&value).unwrap(),
+        ));
+    }
+
+    Ok(page)
+}
+pub fn get_robots(tree: &Tree) -> StorageResult<BTreeMap<String, RobotAccount>> {
+    let opts = options().with_big_endian();
+    let mut robots = BTreeMap::new();
+    for row in tree.iter() {
+        if let Ok((key, value)) = row {
+            let key = opts.deserialize::<String>(&key).unwrap();
+            let value = opts.deserialize::<RobotAccount>(&value).unwrap();
+            robots.insert(key, value);
+            continue;
+        }
+        break;
+    }
+
+    Ok(robots)
+}
+
+/// ACL entries are keyed by who they apply to and which repositories, so setting an entry
+/// again replaces its actions.
+fn acl_key(subject: &AclSubject, repository: &str) -> Vec<u8> {
+    options()
+        .with_big_endian()
+        .serialize(&(subject, repository))
+        .unwrap()
+}
+
+/// Referrers are keyed by their subject and then their own digest, so the referrers of a
+/// subject can be found with a prefix scan.
+fn referrer_key(subject: &Digest, digest: &Digest) -> Vec<u8> {
+    options()
+        .with_big_endian()
+        .serialize(&(subject, digest))
+        .unwrap()
+}
+
+pub fn get_referrers(tree: &Tree) -> StorageResult<BTreeMap<Digest, BTreeSet<Digest>>> {
+    let opts = options().with_big_endian();
+    let mut referrers: BTreeMap<Digest, BTreeSet<Digest>> = BTreeMap::new();
+    for row in tree.iter() {
+        let (key, _) = row.map_err(sm_r_err)?;
+        let (subject, digest) = opts.deserialize::<(Digest, Digest)>(&key).unwrap();
+        referrers.entry(subject).or_default().insert(digest);
+    }
+
+    Ok(referrers)
+}
+
+/// Audit events are keyed by the log entry they were applied from and their position among
+/// the audited actions of that entry, so they sort in the order they were applied and the same
+/// event always gets the same key on every node.
+fn audit_key(index: u64, position: u32) -> Vec<u8> {
+    let mut key = index.to_be_bytes().to_vec();
+    key.extend(position.to_be_bytes());
+    key
+}
+
+fn audit_cursor(key: &[u8]) -> AuditCursor {
+    AuditCursor {
+        index: bin_to_id(&key[0..8]),
+        position: u32::from_be_bytes(key[8..12].try_into().unwrap()),
+    }
+}
+
+/// Up to `limit` audit events after `after` (or from the start), oldest first.
+pub fn get_audit_events_after(
+    tree: &Tree,
+    after: Option<AuditCursor>,
+    limit: usize,
+) -> StorageResult<Vec<(AuditCursor, AuditEvent)>> {
+    let opts = options().with_big_endian();
+    let rows = match after {
+        Some(cursor) => tree.range((
+            std::ops::Bound::Excluded(audit_key(cursor.index, cursor.position)),
+            std::ops::Bound::Unbounded,
+        )),
+        None => tree.iter(),
+    };
+
+    let mut events = Vec::new();
+    for row in rows.take(limit) {
+        let (key, value) = row.map_err(sm_r_err)?;
+        events.push((
+            audit_cursor(&key),
+            opts.deserialize::<AuditEvent>(&value).unwrap(),
+        ));
+    }
+
+    Ok(events)
+}
+
+/// The most recent `limit` audit events that match `filter`, oldest first.
+pub fn get_audit_events(
+    tree: &Tree,
+    filter: &dyn Fn(&AuditEvent) -> bool,
+    limit: usize,
+) -> StorageResult<Vec<AuditEvent>> {
+    let opts = options().with_big_endian();
+    let mut events = Vec::new();
+    for row in tree.iter().rev() {
+        if events.len() >= limit {
+            break;
+        }
+        if let Ok((_key, value)) = row {
+            let event = opts.deserialize::<AuditEvent>(&value).unwrap();
+            if filter(&event) {
+                events.push(event);
+            }
+            continue;
+        }
+        break;
+    }
+    events.reverse();
+
+    Ok(events)
+}
+
+pub fn get_acls(tree: &Tree) -> StorageResult<Vec<AclEntry>> {
+    let opts = options().with_big_endian();
+    let mut acls = Vec::new();
+    for row in tree.iter() {
+        if let Ok((_key, value)) = row {
+            acls.push(opts.deserialize::<AclEntry>(&value).unwrap());
+            continue;
+        }
+        break;
+    }
+
+    Ok(acls)
+}
 impl RegistryStore {
     pub async fn new(
         db: Arc<sled::Db>,
@@ -1394,7 +2059,12 @@ impl RegistryStore {
         let blobs = blobs(&db);
         let manifests = manifests(&db);
         let _tags = tags(&db);
+        let _robots = robots(&db);
+        let _acls = acls(&db);
+        let _audit = audit(&db);
+        let _referrers = referrers(&db);
         let _logs = logs(&db);
+        let indexed = index::is_indexed(&db);
 
         let pblobs = get_blobs(&blobs)
             .unwrap()
@@ -1414,6 +2084,11 @@ impl RegistryStore {
 
         let metrics = StorageMetrics::new(registry);
 
+        // Databases from before the indexes existed need them building once
+        if !indexed {
+            index::rebuild(&db, &metrics).await.unwrap();
+        }
+
         let state_machine = RwLock::new(RegistryStateMachine::new(
             db.clone(),
             metrics.clone(),
@@ -1425,6 +2100,7 @@ impl RegistryStore {
             db,
             id,
             state_machine,
+            applied: tokio::sync::watch::Sender::new(()),
             metrics,
         })
     }
@@ -1449,6 +2125,14 @@ impl RegistryStore {
     pub fn get_blobs(&self) -> StorageResult<BTreeMap<Digest, Blob>> {
         get_blobs(&blobs(&self.db))
     }
+    /// Up to `limit` blobs with digests after `after`, in digest order.
+    pub fn get_blobs_after(
+        &self,
+        after: Option<&Digest>,
+        limit: usize,
+    ) -> StorageResult<Vec<(Digest, Blob)>> {
+        get_page(&blobs(&self.db), after, limit)
+    }
     pub fn get_manifest(&self, key: &Digest) -> StorageResult<Option<Manifest>> {
         let key = options().with_big_endian().serialize(key).unwrap();
         let manifest_tree = manifests(&self.db);
@@ -1469,6 +2153,14 @@ impl RegistryStore {
     pub fn get_manifests(&self) -> StorageResult<BTreeMap<Digest, Manifest>> {
         get_manifests(&manifests(&self.db))
     }
+    /// Up to `limit` manifests with digests after `after`, in digest order.
+    pub fn get_manifests_after(
+        &self,
+        after: Option<&Digest>,
+        limit: usize,
+    ) -> StorageResult<Vec<(Digest, Manifest)>> {
+        get_page(&manifests(&self.db), after, limit)
+    }
     pub fn get_tag(&self, repository: &RepositoryName, tag: &str) -> StorageResult<Option<Digest>> {
         let key = options()
             .with_big_endian()
@@ -1543,49 +2235,183 @@ impl RegistryStore {
         Ok(results)
     }
 
-    pub fn get_orphaned_blobs(&self) -> StorageResult<BTreeMap<Digest, Blob>> {
-        let mut blobs = self.get_blobs()?;
-        let mut visited: HashSet<Digest> = HashSet::new();
-        let mut visiting: HashSet<Digest> = HashSet::new();
+    pub fn get_robot(&self, name: &str) -> StorageResult<Option<RobotAccount>> {
+        let key = options().with_big_endian().serialize(name).unwrap();
+        let robot_tree = robots(&self.db);
+        robot_tree
+            .get(key)
+            .map(|value| {
+                value.map(|value| {
+                    options()
+                        .with_big_endian()
+                        .deserialize(&value)
+                        .expect("invalid data")
+                })
+            })
+            .map_err(|e| {
+                StorageIOError::new(ErrorSubject::Store, ErrorVerb::Read, AnyError::new(&e)).into()
+            })
+    }
 
-        for manifest in self.get_manifests()?.values() {
-            if let Some(dependencies) = &manifest.dependencies {
-                visiting.extend(dependencies.iter().cloned());
-            }
+    pub fn get_robots(&self) -> StorageResult<BTreeMap<String, RobotAccount>> {
+        get_robots(&robots(&self.db))
+    }
+
+    pub fn get_acl(
+        &self,
+        subject: &AclSubject,
+        repository: &str,
+    ) -> StorageResult<Option<AclEntry>> {
+        acls(&self.db)
+            .get(acl_key(subject, repository))
+            .map(|value| {
+                value.map(|value| {
+                    options()
+                        .with_big_endian()
+                        .deserialize(&value)
+                        .expect("invalid data")
+                })
+            })
+            .map_err(|e| {
+                StorageIOError::new(ErrorSubject::Store, ErrorVerb::Read, AnyError::new(&e)).into()
+            })
+    }
+
+    pub fn get_acls(&self) -> StorageResult<Vec<AclEntry>> {
+        get_acls(&acls(&self.db))
+    }
+
+    pub fn get_audit_events(&self, query: &AuditQuery) -> StorageResult<Vec<AuditEvent>> {
+        get_audit_events(&audit(&self.db), &|event| query.matches(event), query.limit)
+    }
+
+    pub fn get_audit_events_after(
+        &self,
+        after: Option<AuditCursor>,
+        limit: usize,
+    ) -> StorageResult<Vec<(AuditCursor, AuditEvent)>> {
+        get_audit_events_after(&audit(&self.db), after, limit)
+    }
+
+    /// Where the most recent audit event is in the history.
+    pub fn get_last_audit_cursor(&self) -> StorageResult<Option<AuditCursor>> {
+        let last = audit(&self.db).last().map_err(sm_r_err)?;
+        Ok(last.map(|(key, _)| audit_cursor(&key)))
+    }
+
+    /// The audit events this node is still missing since it installed a snapshot, if any.
+    pub fn get_audit_backfill(&self) -> StorageResult<Option<AuditBackfill>> {
+        store(&self.db)
+            .get(b"audit_backfill")
+            .map_err(s_r_err)?
+            .map(|value| serde_json::from_slice(&value).map_err(s_r_err))
+            .transpose()
+    }
+
+    async fn set_audit_backfill(&self, backfill: Option<AuditBackfill>) -> StorageResult<()> {
+        let store_tree = store(&self.db);
+        match backfill {
+            Some(backfill) => store_tree
+                .insert(
+                    b"audit_backfill",
+                    serde_json::to_vec(&backfill).map_err(s_w_err)?,
+                )
+                .map_err(s_w_err)?,
+            None => store_tree.remove(b"audit_backfill").map_err(s_w_err)?,
+        };
+        let flushed = flush_async(&store_tree).await.map_err(s_w_err)?;
+        self.metrics.flushed_bytes.inc_by(flushed as u64);
+        Ok(())
+    }
+
+    /// Record that the audit events up to the log entry `until` have to be fetched from a
+    /// peer, after any this node already has.
+    async fn start_audit_backfill(&self, until: u64) -> StorageResult<()> {
+        let after = self.get_last_audit_cursor()?;
+        let backfill = match self.get_audit_backfill()? {
+            // Still fetching for an earlier snapshot, so carry on from the same place. Fetching
+            // events this node already has again doesn't duplicate them.
+            Some(existing) => AuditBackfill {
+                after: existing.after.min(after),
+                until: existing.until.max(until),
+            },
+            None => AuditBackfill { after, until },
+        };
+
+        if backfill
+            .after
+            .is_some_and(|after| after.index >= backfill.until)
+        {
+            return Ok(());
         }
 
-        while let Some(digest) = visiting.iter().next().cloned() {
-            match blobs.get(&digest) {
-                Some(blob) => match &blob.dependencies {
-                    Some(dependencies) => {
-                        visiting.extend(
-                            dependencies
-                                .iter()
-                                .filter(|&digest| !visited.contains(digest))
-                                .cloned(),
-                        );
-                    }
-                    None => {}
-                },
-                _ => {
-                    tracing::debug!("Dangling dependency found: {digest} missing");
-                }
+        self.set_audit_backfill(Some(backfill)).await
+    }
+
+    /// Up to `limit` audit events after `after` (or from the start) up to and including the log
+    /// entry `until`, for a peer that is filling in its history. Returns `None` if this node is
+    /// missing some of them itself.
+    pub fn get_audit_page(
+        &self,
+        after: Option<AuditCursor>,
+        until: u64,
+        limit: usize,
+    ) -> StorageResult<Option<Vec<(AuditCursor, AuditEvent)>>> {
+        if let Some(backfill) = self.get_audit_backfill()? {
+            if backfill.overlaps(after, until) {
+                return Ok(None);
             }
+        }
+
+        let mut events = get_audit_events_after(&audit(&self.db), after, limit)?;
+        events.retain(|(cursor, _)| cursor.index <= until);
+        Ok(Some(events))
+    }
+
+    /// Store a page of audit events fetched from a peer, and record how far the backfill has
+    /// got. It is finished once a page has fewer than `limit` events.
+    pub async fn backfill_audit(
+        &self,
+        events: &[(AuditCursor, AuditEvent)],
+        limit: usize,
+    ) -> StorageResult<()> {
+        let Some(backfill) = self.get_audit_backfill()? else {
+            return Ok(());
+        };
 
-            visiting.remove(&digest);
-            visited.insert(digest);
+        let audit_tree = audit(&self.db);
+        let mut batch = sled::Batch::default();
+        for (cursor, event) in events {
+            batch.insert(
+                audit_key(cursor.index, cursor.position),
+                options().with_big_endian().serialize(event).unwrap(),
+            );
         }
+        audit_tree.apply_batch(batch).map_err(s_w_err)?;
+        let flushed = flush_async(&audit_tree).await.map_err(s_w_err)?;
+        self.metrics.flushed_bytes.inc_by(flushed as u64);
 
-        blobs.retain(|k, _| !visited.contains(k));
+        let after = events.last().map(|(cursor, _)| *cursor).or(backfill.after);
+        let finished = events.len() < limit;
+        self.set_audit_backfill((!finished).then_some(AuditBackfill { after, ..backfill }))
+            .await
+    }
 
-        Ok(blobs)
+    /// Changes whenever entries are applied, for following the audit history as it grows with
+    /// [`Self::get_audit_events_after`].
+    pub fn watch_audit_events(&self) -> tokio::sync::watch::Receiver<()> {
+        self.applied.subscribe()
     }
 
-    pub fn get_orphaned_manifests(&self) -> StorageResult<BTreeMap<Digest, Manifest>> {
-        let mut manifests = self.get_manifests()?;
-        let tags: HashSet<Digest> = self.get_all_tags()?.values().cloned().collect();
-        manifests.retain(|k, _| !tags.contains(k));
-        Ok(manifests)
+    /// How far notifications have been sent to the webhook with the id `hook`, replicated so a
+    /// new leader carries on where the last one stopped.
+    pub fn get_webhook_cursor(&self, hook: &str) -> StorageResult<Option<AuditCursor>> {
+        let cursors = self.state_machine.read().unwrap().get_webhook_cursors()?;
+        Ok(cursors.get(hook).copied())
+    }
+
+    pub fn get_referrers(&self) -> StorageResult<BTreeMap<Digest, BTreeSet<Digest>>> {
+        get_referrers(&referrers(&self.db))
     }
 }
 
@@ -1604,6 +2430,18 @@ fn manifests(db: &sled::Db) -> sled::Tree {
 fn tags(db: &sled::Db) -> sled::Tree {
     db.open_tree("tags").expect("tags open failed")
 }
+fn robots(db: &sled::Db) -> sled::Tree {
+    db.open_tree("robots").expect("robots open failed")
+}
+fn referrers(db: &sled::Db) -> sled::Tree {
+    db.open_tree("referrers").expect("referrers open failed")
+}
+fn acls(db: &sled::Db) -> sled::Tree {
+    db.open_tree("acls").expect("acls open failed")
+}
+fn audit(db: &sled::Db) -> sled::Tree {
+    db.open_tree("audit").expect("audit open failed")
+}
 fn state_machine(db: &sled::Db) -> sled::Tree {
     db.open_tree("state_machine")
         .expect("state_machine open failed")
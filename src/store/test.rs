@@ -10,19 +10,29 @@ use openraft::Entry;
 use openraft::EntryPayload;
 use openraft::LeaderId;
 use openraft::LogId;
+use openraft::RaftSnapshotBuilder;
 use openraft::RaftStorage;
 use openraft::StorageError;
 use tempfile::TempDir;
 use tracing_test::traced_test;
 
+use crate::config::WebhookConfig;
+use crate::types::AclSubject;
+use crate::types::AuditCursor;
+use crate::types::AuditQuery;
+use crate::types::AuditRequest;
 use crate::types::Digest;
+use crate::types::Mount;
 use crate::types::RegistryAction;
 use crate::types::RepositoryName;
 use crate::RegistryNodeId;
 use crate::RegistryStore;
 use crate::RegistryTypeConfig;
 
+use super::AuditBackfill;
 use super::RegistryRequest;
+use super::RegistryStateMachine;
+use super::SerializableRegistryStateMachine;
 
 static GLOBAL_TEST_COUNT: AtomicUsize = AtomicUsize::new(0);
 
@@ -66,6 +76,7 @@ impl StoreBuilder<RegistryTypeConfig, LogStore, StateMachine, TempDir> for SledB
 
 struct TestStorage {
     store: Arc<RegistryStore>,
+    index: u64,
     _tempdir: TempDir,
 }
 
@@ -81,21 +92,30 @@ async fn setup_state() -> TestStorage {
 
     TestStorage {
         store,
+        index: 0,
         _tempdir: tempdir,
     }
 }
 
 impl TestStorage {
     async fn dispatch_actions(&mut self, actions: Vec<RegistryAction>) {
+        self.dispatch(RegistryRequest::Transaction {
+            actions,
+            request: None,
+        })
+        .await;
+    }
+
+    async fn dispatch(&mut self, request: RegistryRequest) {
+        self.index += 1;
         let log_id = LogId {
             leader_id: LeaderId {
                 term: 1,
                 node_id: 1,
             },
-            index: 1,
+            index: self.index,
         };
-        let payload =
-            EntryPayload::<RegistryTypeConfig>::Normal(RegistryRequest::Transaction { actions });
+        let payload = EntryPayload::<RegistryTypeConfig>::Normal(request);
         let entry = Entry::<RegistryTypeConfig> { log_id, payload };
         self.store.apply_to_state_machine(&[entry]).await.unwrap();
     }
@@ -295,6 +315,34 @@ async fn blob_becomes_available_again() {
 
 // MANIFEST TESTS
 
+#[tokio::test]
+#[traced_test]
+async fn stored_after_being_collected() {
+    let mut state = setup_state().await;
+
+    // A copy mirrored after the object was garbage collected is recorded against nothing
+    state
+        .dispatch_actions(vec![
+            RegistryAction::BlobStored {
+                timestamp: Utc::now(),
+                digest: "sha256:abcdefg".parse().unwrap(),
+                location: 1,
+                user: "test".to_string(),
+            },
+            RegistryAction::ManifestStored {
+                timestamp: Utc::now(),
+                digest: "sha256:abcdefg".parse().unwrap(),
+                location: 1,
+                user: "test".to_string(),
+            },
+        ])
+        .await;
+
+    let digest = "sha256:abcdefg".parse().unwrap();
+    assert!(state.store.get_blob(&digest).unwrap().is_none());
+    assert!(state.store.get_manifest(&digest).unwrap().is_none());
+}
+
 #[tokio::test]
 #[traced_test]
 async fn manifest_not_available_initially() {
@@ -561,19 +609,22 @@ async fn can_collect_orphaned_manifests() {
             RegistryAction::HashTagged {
                 timestamp: Utc::now(),
                 user: "test".to_string(),
-                repository,
+                repository: repository.clone(),
                 digest: digest2,
                 tag: "latest".to_string(),
             },
         ])
         .await;
 
-    let collected = state.store.get_orphaned_manifests().unwrap();
-    assert_eq!(collected.len(), 1);
-
-    let entry = collected.iter().next().unwrap();
-    assert_eq!(entry.0, &digest1);
-    assert!(entry.1.locations.contains(&0));
+    // The tag moved, so nothing in the repository refers to the first manifest any more
+    let collected = state.store.find_garbage(Utc::now()).unwrap().manifests;
+    assert_eq!(
+        collected,
+        vec![Mount {
+            digest: digest1,
+            repository
+        }]
+    );
 }
 
 #[tokio::test]
@@ -672,18 +723,21 @@ async fn can_collect_orphaned_blobs() {
                 content_type: "foo".to_string(),
                 dependencies: vec![digest4],
             },
+            RegistryAction::HashTagged {
+                timestamp: Utc::now(),
+                user: "test".to_string(),
+                repository: repository.clone(),
+                digest: manifest_digest.clone(),
+                tag: "latest".to_string(),
+            },
         ])
         .await;
 
-    let collected = state.store.get_orphaned_blobs().unwrap();
+    let collected = state.store.find_garbage(Utc::now()).unwrap().blobs;
     assert_eq!(collected.len(), 2);
 
-    for (digest, blob) in collected.iter() {
-        if digest == &digest1 {
-            assert_eq!(blob.dependencies.as_ref().unwrap().len(), 0);
-        } else if digest == &digest2 {
-            assert_eq!(blob.dependencies.as_ref().unwrap().len(), 1);
-        } else {
+    for Mount { digest, .. } in collected.iter() {
+        if digest != &digest1 && digest != &digest2 {
             panic!("Unexpected digest was collected")
         }
     }
@@ -707,6 +761,961 @@ async fn can_collect_orphaned_blobs() {
         ])
         .await;
 
-    let collected = state.store.get_orphaned_blobs().unwrap();
+    let collected = state.store.find_garbage(Utc::now()).unwrap().blobs;
     assert_eq!(collected.len(), 4);
 }
+
+#[tokio::test]
+#[traced_test]
+async fn image_index_children_are_reachable() {
+    let mut state = setup_state().await;
+
+    let repository: RepositoryName = "myrepo".parse().unwrap();
+    let index: Digest = "sha256:1111111".parse().unwrap();
+    let amd64: Digest = "sha256:2222222".parse().unwrap();
+    let arm64: Digest = "sha256:3333333".parse().unwrap();
+    let layer: Digest = "sha256:4444444".parse().unwrap();
+    let signature: Digest = "sha256:5555555".parse().unwrap();
+    let untagged: Digest = "sha256:6666666".parse().unwrap();
+
+    let mut actions = vec![];
+    for digest in [&index, &amd64, &arm64, &signature, &untagged] {
+        actions.push(RegistryAction::ManifestMounted {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: repository.clone(),
+            digest: digest.clone(),
+        });
+    }
+    actions.extend([
+        RegistryAction::BlobMounted {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: repository.clone(),
+            digest: layer.clone(),
+        },
+        RegistryAction::ManifestInfo {
+            timestamp: Utc::now(),
+            digest: amd64.clone(),
+            content_type: "foo".to_string(),
+            dependencies: vec![layer.clone()],
+        },
+        RegistryAction::ManifestInfo {
+            timestamp: Utc::now(),
+            digest: index.clone(),
+            content_type: "foo".to_string(),
+            dependencies: vec![amd64, arm64],
+        },
+        RegistryAction::ManifestSubject {
+            timestamp: Utc::now(),
+            digest: signature.clone(),
+            subject: index.clone(),
+        },
+        RegistryAction::HashTagged {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: repository.clone(),
+            digest: index.clone(),
+            tag: "latest".to_string(),
+        },
+    ]);
+    state.dispatch_actions(actions).await;
+
+    let unreachable = state.store.find_garbage(Utc::now()).unwrap();
+    assert_eq!(
+        unreachable.manifests,
+        vec![Mount {
+            digest: untagged,
+            repository
+        }]
+    );
+    assert!(unreachable.blobs.is_empty());
+
+    assert_eq!(
+        state.store.get_referrers().unwrap()[&index],
+        [signature].into_iter().collect()
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn reachability_is_per_repository() {
+    let mut state = setup_state().await;
+
+    let tagged: RepositoryName = "tagged".parse().unwrap();
+    let untagged: RepositoryName = "untagged".parse().unwrap();
+    let manifest: Digest = "sha256:1111111".parse().unwrap();
+    let layer: Digest = "sha256:2222222".parse().unwrap();
+
+    let mut actions = vec![];
+    for repository in [&tagged, &untagged] {
+        actions.extend([
+            RegistryAction::ManifestMounted {
+                timestamp: Utc::now(),
+                user: "test".to_string(),
+                repository: repository.clone(),
+                digest: manifest.clone(),
+            },
+            RegistryAction::BlobMounted {
+                timestamp: Utc::now(),
+                user: "test".to_string(),
+                repository: repository.clone(),
+                digest: layer.clone(),
+            },
+        ]);
+    }
+    actions.extend([
+        RegistryAction::ManifestInfo {
+            timestamp: Utc::now(),
+            digest: manifest.clone(),
+            content_type: "foo".to_string(),
+            dependencies: vec![layer.clone()],
+        },
+        RegistryAction::HashTagged {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: tagged,
+            digest: manifest.clone(),
+            tag: "latest".to_string(),
+        },
+    ]);
+    state.dispatch_actions(actions).await;
+
+    let unreachable = state.store.find_garbage(Utc::now()).unwrap();
+    assert_eq!(
+        unreachable.manifests,
+        vec![Mount {
+            digest: manifest,
+            repository: untagged.clone()
+        }]
+    );
+    assert_eq!(
+        unreachable.blobs,
+        vec![Mount {
+            digest: layer,
+            repository: untagged
+        }]
+    );
+}
+
+/// Mount a manifest that depends on `dependencies` and tag it.
+fn push_manifest(
+    repository: &RepositoryName,
+    digest: &Digest,
+    dependencies: &[Digest],
+    tag: &str,
+) -> Vec<RegistryAction> {
+    let mut actions = vec![];
+    for dependency in dependencies {
+        actions.push(RegistryAction::BlobMounted {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: repository.clone(),
+            digest: dependency.clone(),
+        });
+    }
+    actions.extend([
+        RegistryAction::ManifestMounted {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: repository.clone(),
+            digest: digest.clone(),
+        },
+        RegistryAction::ManifestInfo {
+            timestamp: Utc::now(),
+            digest: digest.clone(),
+            content_type: "foo".to_string(),
+            dependencies: dependencies.to_vec(),
+        },
+        RegistryAction::HashTagged {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: repository.clone(),
+            digest: digest.clone(),
+            tag: tag.to_string(),
+        },
+    ]);
+    actions
+}
+
+#[tokio::test]
+#[traced_test]
+async fn garbage_candidates_are_checked_once() {
+    let mut state = setup_state().await;
+
+    let repository: RepositoryName = "myrepo".parse().unwrap();
+    let manifest1: Digest = "sha256:1111111".parse().unwrap();
+    let manifest2: Digest = "sha256:2222222".parse().unwrap();
+    let layer1: Digest = "sha256:3333333".parse().unwrap();
+    let layer2: Digest = "sha256:4444444".parse().unwrap();
+
+    state
+        .dispatch_actions(push_manifest(
+            &repository,
+            &manifest1,
+            &[layer1.clone()],
+            "latest",
+        ))
+        .await;
+
+    // Everything that was pushed is checked, and is reachable
+    let scan = state.store.find_garbage(Utc::now()).unwrap();
+    assert!(scan.manifests.is_empty());
+    assert!(scan.blobs.is_empty());
+    assert_eq!(scan.checked.len(), 2);
+
+    state
+        .dispatch_actions(vec![RegistryAction::GarbageChecked {
+            timestamp: Utc::now(),
+            index: scan.index,
+            mounts: scan.checked,
+        }])
+        .await;
+    assert!(state.store.get_garbage_candidates().unwrap().is_empty());
+
+    // Moving the tag makes the old manifest a candidate, and its layer is found through it
+    state
+        .dispatch_actions(push_manifest(
+            &repository,
+            &manifest2,
+            &[layer2.clone()],
+            "latest",
+        ))
+        .await;
+    let scan = state.store.find_garbage(Utc::now()).unwrap();
+    assert_eq!(
+        scan.manifests,
+        vec![Mount {
+            digest: manifest1.clone(),
+            repository: repository.clone()
+        }]
+    );
+    assert_eq!(
+        scan.blobs,
+        vec![Mount {
+            digest: layer1,
+            repository: repository.clone()
+        }]
+    );
+
+    // A candidate that changed after the scan isn't cleared by it
+    state
+        .dispatch_actions(vec![RegistryAction::ManifestMounted {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: repository.clone(),
+            digest: manifest1.clone(),
+        }])
+        .await;
+    state
+        .dispatch_actions(vec![RegistryAction::GarbageChecked {
+            timestamp: Utc::now(),
+            index: scan.index,
+            mounts: scan.checked,
+        }])
+        .await;
+    assert_eq!(
+        state.store.get_garbage_candidates().unwrap(),
+        vec![Mount {
+            digest: manifest1,
+            repository
+        }]
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn recent_garbage_is_kept() {
+    let mut state = setup_state().await;
+
+    let repository: RepositoryName = "myrepo".parse().unwrap();
+    let manifest: Digest = "sha256:1111111".parse().unwrap();
+    let layer: Digest = "sha256:2222222".parse().unwrap();
+
+    let cutoff = Utc::now();
+    state
+        .dispatch_actions(vec![
+            RegistryAction::BlobMounted {
+                timestamp: Utc::now(),
+                user: "test".to_string(),
+                repository: repository.clone(),
+                digest: layer.clone(),
+            },
+            RegistryAction::ManifestMounted {
+                timestamp: Utc::now(),
+                user: "test".to_string(),
+                repository: repository.clone(),
+                digest: manifest.clone(),
+            },
+            RegistryAction::ManifestInfo {
+                timestamp: Utc::now(),
+                digest: manifest.clone(),
+                content_type: "foo".to_string(),
+                dependencies: vec![layer.clone()],
+            },
+        ])
+        .await;
+
+    // Nothing changed before the cutoff, so it all stays to be checked again
+    let scan = state.store.find_garbage(cutoff).unwrap();
+    assert!(scan.manifests.is_empty());
+    assert!(scan.blobs.is_empty());
+    assert!(scan.checked.is_empty());
+
+    let scan = state.store.find_garbage(Utc::now()).unwrap();
+    assert_eq!(scan.manifests.len(), 1);
+    assert_eq!(scan.blobs.len(), 1);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn references() {
+    let mut state = setup_state().await;
+
+    let repository: RepositoryName = "myrepo".parse().unwrap();
+    let index: Digest = "sha256:1111111".parse().unwrap();
+    let image: Digest = "sha256:2222222".parse().unwrap();
+    let layer: Digest = "sha256:3333333".parse().unwrap();
+
+    let mut actions = push_manifest(&repository, &image, &[layer.clone()], "amd64");
+    actions.extend(push_manifest(
+        &repository,
+        &index,
+        &[image.clone()],
+        "latest",
+    ));
+    state.dispatch_actions(actions).await;
+
+    let references = state.store.get_references(&layer).unwrap();
+    assert_eq!(references.manifests, vec![index.clone(), image.clone()]);
+    assert_eq!(
+        references
+            .tags
+            .iter()
+            .map(|tag| tag.tag.as_str())
+            .collect::<Vec<_>>(),
+        vec!["amd64", "latest"]
+    );
+    assert_eq!(references.repositories, vec![repository.clone()]);
+
+    // Moving a tag away stops it referring to the layer
+    state
+        .dispatch_actions(vec![RegistryAction::HashTagged {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: repository.clone(),
+            digest: index.clone(),
+            tag: "amd64".to_string(),
+        }])
+        .await;
+    let references = state.store.get_references(&image).unwrap();
+    assert_eq!(references.manifests, vec![index]);
+    assert_eq!(
+        references
+            .tags
+            .iter()
+            .map(|tag| tag.tag.as_str())
+            .collect::<Vec<_>>(),
+        vec!["amd64", "latest"]
+    );
+    assert!(state.store.get_tags_of(&image).unwrap().is_empty());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn unmounted_objects() {
+    let mut state = setup_state().await;
+
+    let repository: RepositoryName = "myrepo".parse().unwrap();
+    let digest: Digest = "sha256:1111111".parse().unwrap();
+
+    state
+        .dispatch_actions(vec![
+            RegistryAction::BlobMounted {
+                timestamp: Utc::now(),
+                user: "test".to_string(),
+                repository: repository.clone(),
+                digest: digest.clone(),
+            },
+            RegistryAction::BlobStored {
+                timestamp: Utc::now(),
+                user: "test".to_string(),
+                location: 0,
+                digest: digest.clone(),
+            },
+        ])
+        .await;
+    assert!(state.store.get_unmounted_blobs().unwrap().is_empty());
+
+    state
+        .dispatch_actions(vec![RegistryAction::BlobUnmounted {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            repository: repository.clone(),
+            digest: digest.clone(),
+        }])
+        .await;
+    assert_eq!(
+        state.store.get_unmounted_blobs().unwrap(),
+        vec![digest.clone()]
+    );
+    assert!(state.store.get_unmounted_manifests().unwrap().is_empty());
+
+    state
+        .dispatch_actions(vec![RegistryAction::BlobUnstored {
+            timestamp: Utc::now(),
+            user: "test".to_string(),
+            location: 0,
+            digest,
+        }])
+        .await;
+    assert!(state.store.get_unmounted_blobs().unwrap().is_empty());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn blobs_can_be_paged() {
+    let mut state = setup_state().await;
+
+    let repository: RepositoryName = "myrepo".parse().unwrap();
+    let digests: Vec<Digest> = ["sha256:1111111", "sha256:2222222", "sha256:3333333"]
+        .iter()
+        .map(|digest| digest.parse().unwrap())
+        .collect();
+
+    state
+        .dispatch_actions(
+            digests
+                .iter()
+                .map(|digest| RegistryAction::BlobMounted {
+                    timestamp: Utc::now(),
+                    user: "test".to_string(),
+                    repository: repository.clone(),
+                    digest: digest.clone(),
+                })
+                .collect(),
+        )
+        .await;
+
+    let page = state.store.get_blobs_after(None, 2).unwrap();
+    assert_eq!(
+        page.iter().map(|(digest, _)| digest).collect::<Vec<_>>(),
+        vec![&digests[0], &digests[1]]
+    );
+
+    let page = state.store.get_blobs_after(Some(&digests[1]), 2).unwrap();
+    assert_eq!(
+        page.iter().map(|(digest, _)| digest).collect::<Vec<_>>(),
+        vec![&digests[2]]
+    );
+
+    assert!(state
+        .store
+        .get_blobs_after(Some(&digests[2]), 2)
+        .unwrap()
+        .is_empty());
+}
+
+// ROBOT ACCOUNT TESTS
+
+#[tokio::test]
+#[traced_test]
+async fn robot_account_lifecycle() {
+    let mut state = setup_state().await;
+
+    assert!(state.store.get_robot("ci").unwrap().is_none());
+
+    state
+        .dispatch_actions(vec![RegistryAction::RobotAccountCreated {
+            timestamp: Utc::now(),
+            name: "ci".to_string(),
+            secret_hash: "sha256:abcdefg".to_string(),
+            user: "test".to_string(),
+        }])
+        .await;
+
+    let robot = state.store.get_robot("ci").unwrap().unwrap();
+    assert_eq!(robot.secret_hash, "sha256:abcdefg");
+    assert_eq!(robot.created_by, "test");
+    assert!(state.store.get_robots().unwrap().contains_key("ci"));
+
+    state
+        .dispatch_actions(vec![RegistryAction::RobotAccountRevoked {
+            timestamp: Utc::now(),
+            name: "ci".to_string(),
+            user: "test".to_string(),
+        }])
+        .await;
+
+    assert!(state.store.get_robot("ci").unwrap().is_none());
+    assert!(state.store.get_robots().unwrap().is_empty());
+}
+
+// ACL TESTS
+
+#[tokio::test]
+#[traced_test]
+async fn acl_entry_lifecycle() {
+    let mut state = setup_state().await;
+
+    let developers = AclSubject::Group("developers".to_string());
+    assert!(state
+        .store
+        .get_acl(&developers, "team/*")
+        .unwrap()
+        .is_none());
+
+    for actions in [vec!["pull"], vec!["pull", "push"]] {
+        state
+            .dispatch_actions(vec![RegistryAction::AclEntrySet {
+                timestamp: Utc::now(),
+                subject: developers.clone(),
+                repository: "team/*".to_string(),
+                actions: actions.into_iter().map(String::from).collect(),
+                user: "test".to_string(),
+            }])
+            .await;
+    }
+
+    // Setting an entry again replaces it
+    let entry = state.store.get_acl(&developers, "team/*").unwrap().unwrap();
+    assert_eq!(entry.actions.len(), 2);
+    assert_eq!(entry.created_by, "test");
+    assert_eq!(state.store.get_acls().unwrap(), vec![entry]);
+
+    state
+        .dispatch_actions(vec![RegistryAction::AclEntryRemoved {
+            timestamp: Utc::now(),
+            subject: developers.clone(),
+            repository: "team/*".to_string(),
+            user: "test".to_string(),
+        }])
+        .await;
+
+    assert!(state
+        .store
+        .get_acl(&developers, "team/*")
+        .unwrap()
+        .is_none());
+    assert!(state.store.get_acls().unwrap().is_empty());
+}
+
+// AUDIT TESTS
+
+#[tokio::test]
+#[traced_test]
+async fn audit_history() {
+    let mut state = setup_state().await;
+
+    let digest: Digest = "sha256:abcdefg".parse().unwrap();
+    let repository: RepositoryName = "team/app".parse().unwrap();
+
+    state
+        .dispatch_actions(vec![
+            RegistryAction::ManifestMounted {
+                timestamp: Utc::now(),
+                digest: digest.clone(),
+                repository: repository.clone(),
+                user: "alice".to_string(),
+            },
+            RegistryAction::ManifestStored {
+                timestamp: Utc::now(),
+                digest: digest.clone(),
+                location: 1,
+                user: "$internal".to_string(),
+            },
+            RegistryAction::HashTagged {
+                timestamp: Utc::now(),
+                digest: digest.clone(),
+                repository: repository.clone(),
+                tag: "latest".to_string(),
+                user: "alice".to_string(),
+            },
+        ])
+        .await;
+
+    let deleted = Utc::now();
+
+    state
+        .dispatch_actions(vec![RegistryAction::ManifestUnmounted {
+            timestamp: deleted,
+            digest: digest.clone(),
+            repository: "other/app".parse().unwrap(),
+            user: "bob".to_string(),
+        }])
+        .await;
+
+    // Replication bookkeeping isn't audited
+    let events = state
+        .store
+        .get_audit_events(&AuditQuery::default())
+        .unwrap();
+    let actions: Vec<_> = events.iter().map(|event| event.action.as_str()).collect();
+    assert_eq!(
+        actions,
+        vec!["manifest-mounted", "hash-tagged", "manifest-unmounted"]
+    );
+    assert_eq!(events[1].tag.as_deref(), Some("latest"));
+    assert_eq!(events[2].index, 2);
+
+    let query = |query: AuditQuery| state.store.get_audit_events(&query).unwrap().len();
+
+    assert_eq!(
+        query(AuditQuery {
+            repository: Some("team/*".to_string()),
+            ..Default::default()
+        }),
+        2
+    );
+    assert_eq!(
+        query(AuditQuery {
+            user: Some("bob".to_string()),
+            ..Default::default()
+        }),
+        1
+    );
+    assert_eq!(
+        query(AuditQuery {
+            action: Some("hash-tagged".to_string()),
+            ..Default::default()
+        }),
+        1
+    );
+    assert_eq!(
+        query(AuditQuery {
+            since: Some(deleted),
+            ..Default::default()
+        }),
+        1
+    );
+    assert_eq!(
+        query(AuditQuery {
+            until: Some(deleted),
+            ..Default::default()
+        }),
+        2
+    );
+
+    // The most recent events are returned when there are too many
+    let events = state
+        .store
+        .get_audit_events(&AuditQuery {
+            limit: 1,
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(events[0].user, "bob");
+}
+
+#[tokio::test]
+#[traced_test]
+async fn audit_records_the_request() {
+    let mut state = setup_state().await;
+
+    let request = AuditRequest {
+        id: "request-1".to_string(),
+        addr: "10.0.0.1:42961".to_string(),
+        host: "registry.example.com".to_string(),
+        method: "DELETE".to_string(),
+        useragent: "docker/24.0".to_string(),
+    };
+
+    state
+        .dispatch(RegistryRequest::Transaction {
+            actions: vec![RegistryAction::ManifestUnmounted {
+                timestamp: Utc::now(),
+                digest: "sha256:abcdefg".parse().unwrap(),
+                repository: "team/app".parse().unwrap(),
+                user: "alice".to_string(),
+            }],
+            request: Some(request.clone()),
+        })
+        .await;
+    state
+        .dispatch_actions(vec![RegistryAction::RobotAccountRevoked {
+            timestamp: Utc::now(),
+            name: "ci".to_string(),
+            user: "$system".to_string(),
+        }])
+        .await;
+
+    let events = state
+        .store
+        .get_audit_events(&AuditQuery::default())
+        .unwrap();
+    assert_eq!(events[0].request, Some(request));
+    assert_eq!(events[1].request, None);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn audit_history_survives_snapshots() {
+    let mut state = setup_state().await;
+
+    state
+        .dispatch_actions(vec![
+            RegistryAction::RobotAccountCreated {
+                timestamp: Utc::now(),
+                name: "ci".to_string(),
+                secret_hash: "sha256:abcdefg".to_string(),
+                user: "admin".to_string(),
+            },
+            RegistryAction::RobotAccountRevoked {
+                timestamp: Utc::now(),
+                name: "ci".to_string(),
+                user: "admin".to_string(),
+            },
+        ])
+        .await;
+
+    let snapshot = state.store.clone().build_snapshot().await.unwrap();
+    let until = snapshot.meta.last_log_id.unwrap().index;
+
+    // The history isn't in the snapshot, so the node that installs it fetches it separately
+    let mut restored = setup_state().await;
+    restored
+        .store
+        .clone()
+        .install_snapshot(&snapshot.meta, snapshot.snapshot)
+        .await
+        .unwrap();
+    assert_eq!(
+        restored.store.get_audit_backfill().unwrap(),
+        Some(AuditBackfill { after: None, until })
+    );
+
+    // It can't send pages of history it doesn't have yet
+    assert_eq!(
+        restored.store.get_audit_page(None, until, 10).unwrap(),
+        None
+    );
+
+    let mut pages = 0;
+    while let Some(backfill) = restored.store.get_audit_backfill().unwrap() {
+        let page = state
+            .store
+            .get_audit_page(backfill.after, backfill.until, 1)
+            .unwrap()
+            .unwrap();
+        restored.store.backfill_audit(&page, 1).await.unwrap();
+        pages += 1;
+    }
+    assert_eq!(pages, 3);
+
+    let events = state
+        .store
+        .get_audit_events(&AuditQuery::default())
+        .unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(
+        restored
+            .store
+            .get_audit_events(&AuditQuery::default())
+            .unwrap(),
+        events
+    );
+
+    // Later entries are applied as usual
+    restored.index = until;
+    restored
+        .dispatch_actions(vec![RegistryAction::RobotAccountCreated {
+            timestamp: Utc::now(),
+            name: "deploy".to_string(),
+            secret_hash: "sha256:hijklmn".to_string(),
+            user: "admin".to_string(),
+        }])
+        .await;
+    assert_eq!(
+        restored
+            .store
+            .get_audit_events(&AuditQuery::default())
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_eq!(
+        restored
+            .store
+            .get_audit_page(None, until, 10)
+            .unwrap()
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+/// Move a tag back and forth `times` times, returning the size of a snapshot taken afterwards.
+async fn retag_and_snapshot(state: &mut TestStorage, times: usize) -> usize {
+    let repository: RepositoryName = "team/app".parse().unwrap();
+    let digests: Vec<Digest> = vec![
+        "sha256:abcdefg".parse().unwrap(),
+        "sha256:hijklmn".parse().unwrap(),
+    ];
+
+    for i in 0..times {
+        state
+            .dispatch_actions(vec![RegistryAction::HashTagged {
+                timestamp: Utc::now(),
+                digest: digests[i % 2].clone(),
+                repository: repository.clone(),
+                tag: "latest".to_string(),
+                user: "alice".to_string(),
+            }])
+            .await;
+    }
+
+    state
+        .store
+        .clone()
+        .build_snapshot()
+        .await
+        .unwrap()
+        .snapshot
+        .into_inner()
+        .len()
+}
+
+#[tokio::test]
+#[traced_test]
+async fn snapshot_size_is_bounded_by_state() {
+    let mut state = setup_state().await;
+
+    // The history keeps growing, but the state it leaves behind is the same size
+    let small = retag_and_snapshot(&mut state, 10).await;
+    let large = retag_and_snapshot(&mut state, 500).await;
+    assert_eq!(
+        state
+            .store
+            .get_audit_events(&AuditQuery {
+                limit: 1000,
+                ..Default::default()
+            })
+            .unwrap()
+            .len(),
+        510
+    );
+    assert!(large < small + 32, "{small} bytes grew to {large} bytes");
+}
+
+#[tokio::test]
+#[traced_test]
+async fn webhook_cursor() {
+    let mut state = setup_state().await;
+
+    let first: Digest = "sha256:abcdefg".parse().unwrap();
+    let second: Digest = "sha256:hijklmn".parse().unwrap();
+    let repository: RepositoryName = "team/app".parse().unwrap();
+
+    for digest in [&first, &first, &second] {
+        state
+            .dispatch_actions(vec![RegistryAction::HashTagged {
+                timestamp: Utc::now(),
+                digest: digest.clone(),
+                repository: repository.clone(),
+                tag: "latest".to_string(),
+                user: "alice".to_string(),
+            }])
+            .await;
+    }
+
+    // Retagging the same manifest doesn't count as moving the tag
+    let events = state.store.get_audit_events_after(None, 10).unwrap();
+    let previous: Vec<_> = events
+        .iter()
+        .map(|(_, event)| event.previous_digest.clone())
+        .collect();
+    assert_eq!(previous, vec![None, None, Some(first.clone())]);
+    assert_eq!(
+        state.store.get_last_audit_cursor().unwrap(),
+        Some(events[2].0)
+    );
+
+    assert_eq!(state.store.get_webhook_cursor("http://one").unwrap(), None);
+
+    // Each webhook has its own cursor
+    let cursor = events[0].0;
+    state
+        .dispatch_actions(vec![
+            RegistryAction::WebhookCursorMoved {
+                timestamp: Utc::now(),
+                hook: "http://one".to_string(),
+                cursor,
+            },
+            RegistryAction::WebhookCursorMoved {
+                timestamp: Utc::now(),
+                hook: "audit".to_string(),
+                cursor: events[2].0,
+            },
+        ])
+        .await;
+    assert_eq!(
+        state.store.get_webhook_cursor("http://one").unwrap(),
+        Some(cursor)
+    );
+    assert_eq!(
+        state.store.get_webhook_cursor("audit").unwrap(),
+        Some(events[2].0)
+    );
+    assert_eq!(state.store.get_webhook_cursor("http://two").unwrap(), None);
+
+    // Moving the cursor isn't audited
+    let after = state
+        .store
+        .get_audit_events_after(Some(cursor), 10)
+        .unwrap();
+    assert_eq!(after.len(), 2);
+    assert_eq!(after[0].0, events[1].0);
+
+    let snapshot =
+        SerializableRegistryStateMachine::from(&*state.store.state_machine.read().unwrap());
+    let restored = setup_state().await;
+    RegistryStateMachine::from_serializable(
+        0,
+        snapshot,
+        restored.store.db.clone(),
+        restored.store.metrics.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        restored.store.get_webhook_cursor("http://one").unwrap(),
+        Some(cursor)
+    );
+    assert_eq!(
+        restored.store.get_webhook_cursor("audit").unwrap(),
+        Some(events[2].0)
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn webhook_cursor_survives_inserting_a_webhook() {
+    let mut state = setup_state().await;
+
+    let hook = |data: &str| -> WebhookConfig { serde_json::from_str(data).unwrap() };
+    let existing = hook(r#"{"url": "http://existing", "matcher": ".*"}"#);
+    let before = [existing.clone()];
+    let after = [
+        hook(r#"{"id": "new", "url": "http://new", "matcher": ".*"}"#),
+        existing,
+    ];
+
+    let cursor = AuditCursor {
+        index: 5,
+        position: 1,
+    };
+    state
+        .dispatch_actions(vec![RegistryAction::WebhookCursorMoved {
+            timestamp: Utc::now(),
+            hook: before[0].id().to_string(),
+            cursor,
+        }])
+        .await;
+
+    // The new webhook starts from scratch rather than taking over the existing one's cursor
+    assert_eq!(state.store.get_webhook_cursor(after[0].id()).unwrap(), None);
+    assert_eq!(
+        state.store.get_webhook_cursor(after[1].id()).unwrap(),
+        Some(cursor)
+    );
+}
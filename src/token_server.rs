@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use actix_web::get;
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use actix_web::web::Query;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use actix_web::HttpResponseBuilder;
+use actix_web::ResponseError;
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use data_encoding::{BASE64, BASE64URL_NOPAD, HEXLOWER};
+use jwt_simple::prelude::*;
+use rand::RngCore;
+use ring::digest::{digest, SHA256};
+use rustls_pki_types::PrivateKeyDer;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+use x509_parser::der_parser::parse_der;
+
+use crate::app::RegistryApp;
+use crate::authorizer::policy::Policy;
+use crate::config::{config_path, BuiltinTokenServerConfig, TokenConfig};
+use crate::extractors::admin::AdminClaims;
+use crate::keys::{TokenKey, VerificationKey};
+use crate::types::RepositoryName;
+
+/// Robot accounts log in with this prefix so they can't be confused with htpasswd users.
+pub const ROBOT_PREFIX: &str = "robot$";
+
+/// Parse an htpasswd file. Only bcrypt hashes are supported, like distribution.
+pub fn parse_htpasswd(data: &str) -> HashMap<String, String> {
+    let mut users = HashMap::new();
+
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once(':') {
+            Some((user, hash)) if hash.starts_with("$2") => {
+                users.insert(user.to_string(), hash.to_string());
+            }
+            Some((user, _)) => warn!("htpasswd: Ignoring {user}, only bcrypt is supported"),
+            None => warn!("htpasswd: Ignoring malformed line"),
+        }
+    }
+
+    users
+}
+
+/// Read and parse an htpasswd file.
+pub fn load_htpasswd(path: &Path) -> Result<HashMap<String, String>> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    Ok(parse_htpasswd(&data))
+}
+
+/// Check a username and password against an htpasswd file or, for `robot$` accounts, the
+/// replicated robot accounts. Returns the account name if they are valid.
+pub(crate) async fn verify_password(
+    app: &RegistryApp,
+    htpasswd: &HashMap<String, String>,
+    username: &str,
+    password: &str,
+) -> Option<String> {
+    if let Some(name) = username.strip_prefix(ROBOT_PREFIX) {
+        let robot = app.store.get_robot(name).ok()??;
+        let hash = hash_secret(password);
+
+        #[allow(deprecated)]
+        return ring::constant_time::verify_slices_are_equal(
+            hash.as_bytes(),
+            robot.secret_hash.as_bytes(),
+        )
+        .ok()
+        .map(|_| username.to_string());
+    }
+
+    let hash = htpasswd.get(username)?.clone();
+    let password = password.to_string();
+
+    // bcrypt is deliberately slow, so keep it off the executor
+    match actix_web::web::block(move || bcrypt::verify(password, &hash)).await {
+        Ok(Ok(true)) => Some(username.to_string()),
+        _ => None,
+    }
+}
+
+/// Decode the username and password from an `Authorization: Basic` header value.
+pub(crate) fn parse_basic_credentials(header: &str) -> Option<(String, String)> {
+    let (kind, encoded) = header.split_once(' ')?;
+    if !kind.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = BASE64.decode(encoded.trim().as_bytes()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE64URL_NOPAD.encode(&secret)
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    format!(
+        "sha256:{}",
+        HEXLOWER.encode(digest(&SHA256, secret.as_bytes()).as_ref())
+    )
+}
+
+/// Load an ES256 signing key from a PKCS#8 or SEC1 PEM file.
+fn load_signing_key(pem: &str) -> Result<ES256KeyPair> {
+    let key = rustls_pemfile::private_key(&mut pem.as_bytes())?.context("No private key found")?;
+
+    let key_pair = match key {
+        PrivateKeyDer::Pkcs8(key) => ES256KeyPair::from_der(key.secret_pkcs8_der()),
+        PrivateKeyDer::Sec1(key) => {
+            // ECPrivateKey ::= SEQUENCE { version, privateKey OCTET STRING, ... }
+            let (_, key) = parse_der(key.secret_sec1_der()).context("Invalid SEC1 key")?;
+            let scalar = key
+                .as_sequence()
+                .ok()
+                .and_then(|fields| fields.get(1))
+                .and_then(|scalar| scalar.as_slice().ok())
+                .context("Invalid SEC1 key")?;
+            if scalar.len() != 32 {
+                bail!("Token signing key must be an ECDSA P-256 key");
+            }
+            ES256KeyPair::from_bytes(scalar)
+        }
+        _ => bail!("Token signing key must be an ECDSA P-256 key"),
+    };
+
+    key_pair.map_err(|err| anyhow::anyhow!("Token signing key must be an ECDSA P-256 key: {err}"))
+}
+
+#[derive(Serialize, Deserialize)]
+struct IssuedAccess {
+    #[serde(rename = "type")]
+    kind: String,
+    name: RepositoryName,
+    actions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IssuedClaims {
+    access: Vec<IssuedAccess>,
+}
+
+/// Parse a scope like `repository:foo/bar:pull,push`. Other resource types are ignored.
+fn parse_scope(scope: &str) -> Option<(RepositoryName, Vec<String>)> {
+    let (resource, actions) = scope.rsplit_once(':')?;
+    let (kind, name) = resource.split_once(':')?;
+
+    if kind != "repository" {
+        return None;
+    }
+
+    let repository = name.parse().ok()?;
+    let actions = actions
+        .split(',')
+        .map(|action| action.to_string())
+        .collect();
+
+    Some((repository, actions))
+}
+
+pub struct TokenServer {
+    issuer: String,
+    service: String,
+    lifetime: u64,
+    key_pair: ES256KeyPair,
+    htpasswd: HashMap<String, String>,
+    policy: Policy,
+}
+
+impl TokenServer {
+    pub fn new(token_config: &TokenConfig, config: &BuiltinTokenServerConfig) -> Result<Self> {
+        let key_path = config_path(&config.key);
+        let pem = std::fs::read_to_string(&key_path)
+            .with_context(|| format!("Unable to read {}", key_path.display()))?;
+        let key_pair = load_signing_key(&pem)
+            .with_context(|| format!("Unable to load {}", key_path.display()))?;
+
+        let htpasswd = match &config.htpasswd {
+            Some(path) => load_htpasswd(&config_path(path))?,
+            None => HashMap::new(),
+        };
+
+        let policy = Policy::load(&config_path(&config.policy))?;
+
+        Ok(TokenServer {
+            issuer: token_config.issuer.clone(),
+            service: token_config.service.clone(),
+            lifetime: config.lifetime,
+            key_pair,
+            htpasswd,
+            policy,
+        })
+    }
+
+    /// The key that tokens issued by this server are verified with.
+    pub fn token_key(&self) -> TokenKey {
+        TokenKey {
+            kid: None,
+            alg: Some("ES256".to_string()),
+            key: VerificationKey::Es256(self.key_pair.public_key()),
+        }
+    }
+
+    /// Check a username and password, returning the account name if they are valid.
+    async fn authenticate(
+        &self,
+        app: &RegistryApp,
+        username: &str,
+        password: &str,
+    ) -> Option<String> {
+        verify_password(app, &self.htpasswd, username, password).await
+    }
+
+    fn issue(&self, account: Option<&str>, scopes: &[String]) -> Result<String> {
+        let mut access = vec![];
+
+        for scope in scopes.iter().flat_map(|scope| scope.split(' ')) {
+            let Some((repository, requested)) = parse_scope(scope) else {
+                debug!("Ignoring scope {scope}");
+                continue;
+            };
+
+            let granted = self.policy.granted(account, &repository);
+            let actions: Vec<String> = requested
+                .into_iter()
+                .filter(|action| granted.contains(action) || granted.contains("*"))
+                .collect();
+
+            if !actions.is_empty() {
+                access.push(IssuedAccess {
+                    kind: "repository".to_string(),
+                    name: repository,
+                    actions,
+                });
+            }
+        }
+
+        let mut claims =
+            Claims::with_custom_claims(IssuedClaims { access }, Duration::from_secs(self.lifetime))
+                .with_issuer(&self.issuer)
+                .with_audience(&self.service)
+                .with_jwt_id(uuid::Uuid::new_v4().to_string());
+
+        // Tokens for callers that didn't log in have no subject, so they can't be taken for an
+        // account, not even one called "anonymous"
+        if let Some(account) = account {
+            claims = claims.with_subject(account);
+        }
+
+        self.key_pair
+            .sign(claims)
+            .map_err(|err| anyhow::anyhow!("Unable to sign token: {err}"))
+    }
+
+    /// Issue a token for the management API. These are never handed out by `/token`, only
+    /// minted by tools that can read the signing key, like the `distribd` CLI.
+    pub fn issue_admin(&self, subject: &str) -> Result<String> {
+        let claims = Claims::with_custom_claims(
+            AdminClaims { admin: true },
+            Duration::from_secs(self.lifetime),
+        )
+        .with_issuer(&self.issuer)
+        .with_audience(&self.service)
+        .with_subject(subject)
+        .with_jwt_id(uuid::Uuid::new_v4().to_string());
+
+        self.key_pair
+            .sign(claims)
+            .map_err(|err| anyhow::anyhow!("Unable to sign token: {err}"))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TokenServerError {
+    #[error("The built-in token server is not enabled")]
+    NotEnabled,
+    #[error("Invalid credentials")]
+    Unauthorized,
+    #[error("Invalid request: {0}")]
+    Invalid(String),
+    #[error("Unable to issue token")]
+    Failed,
+}
+
+impl ResponseError for TokenServerError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotEnabled => StatusCode::NOT_FOUND,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut builder = HttpResponseBuilder::new(self.status_code());
+        if let Self::Unauthorized = self {
+            builder.append_header(("WWW-Authenticate", "Basic realm=\"distribd\""));
+        }
+        builder.json(serde_json::json!({ "details": self.to_string() }))
+    }
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+    access_token: String,
+    expires_in: u64,
+    issued_at: String,
+}
+
+fn basic_credentials(req: &HttpRequest) -> Result<Option<(String, String)>, TokenServerError> {
+    let header = match req.headers().get("authorization") {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    header
+        .to_str()
+        .ok()
+        .and_then(parse_basic_credentials)
+        .map(Some)
+        .ok_or_else(|| TokenServerError::Invalid("Malformed authorization header".to_string()))
+}
+
+/// Issue a token using the Docker token authentication flow.
+#[get("/token")]
+pub(crate) async fn token(
+    app: Data<RegistryApp>,
+    req: HttpRequest,
+    query: Query<Vec<(String, String)>>,
+) -> Result<HttpResponse, TokenServerError> {
+    let server = match &app.token_server {
+        Some(server) => server,
+        None => return Err(TokenServerError::NotEnabled),
+    };
+
+    let mut scopes = vec![];
+    for (key, value) in query.iter() {
+        match key.as_str() {
+            "service" if value != &server.service => {
+                return Err(TokenServerError::Invalid(format!(
+                    "Unknown service {value}"
+                )));
+            }
+            "scope" => scopes.push(value.clone()),
+            _ => {}
+        }
+    }
+
+    let account = match basic_credentials(&req)? {
+        Some((username, password)) => match server.authenticate(&app, &username, &password).await {
+            Some(account) => Some(account),
+            None => {
+                info!("Token server: Rejected credentials for {username}");
+                return Err(TokenServerError::Unauthorized);
+            }
+        },
+        None => None,
+    };
+
+    let token = server.issue(account.as_deref(), &scopes).map_err(|err| {
+        warn!("Token server: {err:?}");
+        TokenServerError::Failed
+    })?;
+
+    Ok(HttpResponse::Ok().json(TokenResponse {
+        token: token.clone(),
+        access_token: token,
+        expires_in: server.lifetime,
+        issued_at: Utc::now().to_rfc3339(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::authorizer::PolicyAuthorizer;
+    use crate::config::TokenConfig;
+    use crate::extractors::token::{verification_options, AdditionalClaims, Token};
+    use crate::keys::KeySet;
+    use crate::utils::glob_match;
+
+    fn policy() -> Policy {
+        serde_json::from_value(serde_json::json!({
+            "rules": [
+                {"accounts": ["alice"], "repositories": ["team-a/*"], "actions": ["pull", "push"]},
+                {"accounts": ["robot$ci-*"], "repositories": ["team-a/app"], "actions": ["pull"]},
+                {"accounts": ["*"], "repositories": ["${account}/*"], "actions": ["*"]},
+                {"anonymous": true, "repositories": ["public/*"], "actions": ["pull"]},
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn server() -> TokenServer {
+        TokenServer {
+            issuer: "issuer".to_string(),
+            service: "service".to_string(),
+            lifetime: 300,
+            key_pair: ES256KeyPair::generate(),
+            htpasswd: HashMap::new(),
+            policy: policy(),
+        }
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("team-a/*", "team-a/app"));
+        assert!(glob_match("team-a/*", "team-a/nested/app"));
+        assert!(glob_match("*/app", "team-a/app"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("team-a/*", "team-b/app"));
+        assert!(!glob_match("team-a", "team-a/app"));
+    }
+
+    #[test]
+    fn scopes() {
+        let (repository, actions) = parse_scope("repository:foo/bar:pull,push").unwrap();
+        assert_eq!(repository.to_string(), "foo/bar");
+        assert_eq!(actions, vec!["pull", "push"]);
+
+        assert!(parse_scope("registry:catalog:*").is_none());
+        assert!(parse_scope("repository").is_none());
+    }
+
+    #[test]
+    fn htpasswd() {
+        let hash = bcrypt::hash("password", 4).unwrap();
+        let users = parse_htpasswd(&format!(
+            "# users\nalice:{hash}\nbob:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n\nbroken\n"
+        ));
+
+        assert_eq!(users.len(), 1);
+        assert!(bcrypt::verify("password", users.get("alice").unwrap()).unwrap());
+    }
+
+    #[test]
+    fn secrets() {
+        let secret = generate_secret();
+        assert_ne!(secret, generate_secret());
+        assert_eq!(hash_secret(&secret), hash_secret(&secret));
+        assert!(hash_secret(&secret).starts_with("sha256:"));
+    }
+
+    #[test]
+    fn sec1_signing_key() {
+        let pem = std::fs::read_to_string("fixtures/tls/ecdsa-p256-sec1.key").unwrap();
+        let sec1 = load_signing_key(&pem).unwrap();
+
+        let pem = std::fs::read_to_string("fixtures/tls/ecdsa-p256-pkcs8.key").unwrap();
+        let pkcs8 = load_signing_key(&pem).unwrap();
+
+        assert_eq!(sec1.to_bytes(), pkcs8.to_bytes());
+
+        let pem = std::fs::read_to_string("fixtures/tls/ecdsa-p384-sec1.key").unwrap();
+        assert!(load_signing_key(&pem).is_err());
+    }
+
+    #[test]
+    fn issued_tokens_are_trusted() {
+        let server = server();
+
+        let mut keys = KeySet::default();
+        keys.add(server.token_key());
+
+        let issued = server
+            .issue(
+                Some("alice"),
+                &["repository:team-a/app:pull,push,delete repository:team-b/app:pull".to_string()],
+            )
+            .unwrap();
+
+        let options = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&["issuer"])),
+            allowed_audiences: Some(HashSet::from_strings(&["service"])),
+            ..Default::default()
+        };
+        let claims = keys.verify::<IssuedClaims>(&issued, options).unwrap();
+
+        assert_eq!(claims.subject, Some("alice".to_string()));
+        assert_eq!(claims.custom.access.len(), 1);
+        assert_eq!(claims.custom.access[0].kind, "repository");
+        assert_eq!(claims.custom.access[0].name.to_string(), "team-a/app");
+        assert_eq!(claims.custom.access[0].actions, vec!["pull", "push"]);
+    }
+
+    #[tokio::test]
+    async fn anonymous_tokens_are_not_an_account() {
+        let server = server();
+
+        let mut keys = KeySet::default();
+        keys.add(server.token_key());
+
+        let issued = server
+            .issue(
+                None,
+                &["repository:anonymous/app:pull,push repository:public/app:pull".to_string()],
+            )
+            .unwrap();
+
+        let config: TokenConfig = serde_json::from_value(serde_json::json!({
+            "issuer": "issuer",
+            "service": "service",
+            "realm": "realm",
+        }))
+        .unwrap();
+        let claims = keys
+            .verify::<AdditionalClaims>(&issued, verification_options(&config))
+            .unwrap();
+        assert_eq!(claims.subject, None);
+
+        let caller = Token::bearer(claims, &config, Arc::new(PolicyAuthorizer::new(policy())));
+
+        // Anonymous rules apply, but not the `${account}/*` rule for every account
+        let public = "public/app".parse().unwrap();
+        let anonymous = "anonymous/app".parse().unwrap();
+        assert!(caller.has_permission(&public, "pull").await);
+        assert!(!caller.has_permission(&anonymous, "pull").await);
+        assert!(!caller.has_permission(&anonymous, "push").await);
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Who an ACL entry applies to. Written as `alice` or `user:alice` for a single account and
+/// `group:developers` for every member of a group.
+#[derive(Debug, Clone, Hash, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum AclSubject {
+    User(String),
+    Group(String),
+}
+
+impl FromStr for AclSubject {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let subject = match s.split_once(':') {
+            Some(("user", name)) => AclSubject::User(name.to_string()),
+            Some(("group", name)) => AclSubject::Group(name.to_string()),
+            _ => AclSubject::User(s.to_string()),
+        };
+
+        match &subject {
+            AclSubject::User(name) | AclSubject::Group(name) if name.is_empty() => {
+                Err("ACL subjects can't be empty")
+            }
+            _ => Ok(subject),
+        }
+    }
+}
+
+impl fmt::Display for AclSubject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AclSubject::User(name) => write!(f, "user:{name}"),
+            AclSubject::Group(name) => write!(f, "group:{name}"),
+        }
+    }
+}
+
+/// Grants `actions` on any repository matching `repository` (which can use `*`) to `subject`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AclEntry {
+    pub subject: AclSubject,
+    pub repository: String,
+    pub actions: BTreeSet<String>,
+    pub created: DateTime<Utc>,
+    pub created_by: String,
+}
+
+impl AclEntry {
+    /// Whether the entry applies to `account`, a member of `groups`.
+    pub fn applies_to(&self, account: &str, groups: &[String]) -> bool {
+        match &self.subject {
+            AclSubject::User(name) => name == account,
+            AclSubject::Group(name) => groups.contains(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subjects() {
+        let subject: AclSubject = "alice".parse().unwrap();
+        assert_eq!(subject, AclSubject::User("alice".to_string()));
+        assert_eq!(subject.to_string(), "user:alice");
+
+        let subject: AclSubject = "user:robot$ci".parse().unwrap();
+        assert_eq!(subject, AclSubject::User("robot$ci".to_string()));
+
+        let subject: AclSubject = "group:developers".parse().unwrap();
+        assert_eq!(subject, AclSubject::Group("developers".to_string()));
+        assert_eq!(subject.to_string(), "group:developers");
+
+        assert!("group:".parse::<AclSubject>().is_err());
+        assert!("".parse::<AclSubject>().is_err());
+    }
+}
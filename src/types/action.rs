@@ -1,9 +1,14 @@
+use std::collections::BTreeSet;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::RegistryNodeId;
 
+use super::acl::AclSubject;
+use super::audit::AuditCursor;
 use super::digest::Digest;
+use super::mount::Mount;
 use super::RepositoryName;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -125,4 +130,59 @@ pub enum RegistryAction {
         tag: String,
         user: String,
     },
+
+    // A robot account was created, or its secret was replaced
+    RobotAccountCreated {
+        timestamp: DateTime<Utc>,
+        name: String,
+        secret_hash: String,
+        user: String,
+    },
+
+    // A robot account was revoked and can no longer get tokens
+    RobotAccountRevoked {
+        timestamp: DateTime<Utc>,
+        name: String,
+        user: String,
+    },
+
+    // An ACL entry was added, or the actions of an existing one were replaced
+    AclEntrySet {
+        timestamp: DateTime<Utc>,
+        subject: AclSubject,
+        repository: String,
+        actions: BTreeSet<String>,
+        user: String,
+    },
+
+    // An ACL entry was removed
+    AclEntryRemoved {
+        timestamp: DateTime<Utc>,
+        subject: AclSubject,
+        repository: String,
+        user: String,
+    },
+
+    // The webhook with the id `hook` was notified of the audit history up to and including
+    // `cursor`
+    WebhookCursorMoved {
+        timestamp: DateTime<Utc>,
+        hook: String,
+        cursor: AuditCursor,
+    },
+
+    // A manifest refers to `subject`, like a signature or SBOM for an image
+    ManifestSubject {
+        timestamp: DateTime<Utc>,
+        digest: Digest,
+        subject: Digest,
+    },
+
+    // Garbage collection has checked these mounts using the state as of log entry `index`,
+    // so they don't need checking again unless they changed after it
+    GarbageChecked {
+        timestamp: DateTime<Utc>,
+        index: u64,
+        mounts: Vec<Mount>,
+    },
 }
@@ -0,0 +1,262 @@
+use actix_request_identifier::RequestId;
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::glob_match;
+
+use super::{Digest, RegistryAction, RepositoryName};
+
+/// The HTTP request that made a change.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuditRequest {
+    pub id: String,
+    pub addr: String,
+    pub host: String,
+    pub method: String,
+    pub useragent: String,
+}
+
+impl AuditRequest {
+    pub fn new(req: &HttpRequest, id: &RequestId) -> Self {
+        AuditRequest {
+            id: id.as_str().to_string(),
+            addr: req
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            host: req.connection_info().host().to_string(),
+            method: req.method().to_string(),
+            useragent: req
+                .headers()
+                .get("user-agent")
+                .and_then(|agent| agent.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+/// A record of who changed what, kept in an append-only index as actions are applied.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuditEvent {
+    /// The raft log index of the entry the action was applied from.
+    pub index: u64,
+    pub timestamp: DateTime<Utc>,
+    pub user: String,
+    /// The kind of action, like `manifest-mounted` or `hash-tagged`.
+    pub action: String,
+    /// The repository, or for ACL entries the repository pattern.
+    pub repository: Option<RepositoryName>,
+    pub digest: Option<Digest>,
+    pub tag: Option<String>,
+    /// For `hash-tagged`, the manifest the tag pointed at before it was moved.
+    pub previous_digest: Option<Digest>,
+    /// The robot account or ACL subject that was changed.
+    pub subject: Option<String>,
+    /// The request that made the change. Background tasks like garbage collection don't have one.
+    #[serde(default)]
+    pub request: Option<AuditRequest>,
+}
+
+impl AuditEvent {
+    fn new(index: u64, timestamp: &DateTime<Utc>, user: &str, action: &str) -> Self {
+        AuditEvent {
+            index,
+            timestamp: *timestamp,
+            user: user.to_string(),
+            action: action.to_string(),
+            repository: None,
+            digest: None,
+            tag: None,
+            previous_digest: None,
+            subject: None,
+            request: None,
+        }
+    }
+
+    /// The audit record for `action`, applied from the log entry at `index`. Replication
+    /// bookkeeping (where objects are stored and their metadata) isn't audited.
+    pub fn from_action(index: u64, action: &RegistryAction) -> Option<Self> {
+        let event = match action {
+            RegistryAction::BlobMounted {
+                timestamp,
+                digest,
+                repository,
+                user,
+            } => AuditEvent {
+                repository: Some(repository.clone()),
+                digest: Some(digest.clone()),
+                ..Self::new(index, timestamp, user, "blob-mounted")
+            },
+            RegistryAction::BlobUnmounted {
+                timestamp,
+                digest,
+                repository,
+                user,
+            } => AuditEvent {
+                repository: Some(repository.clone()),
+                digest: Some(digest.clone()),
+                ..Self::new(index, timestamp, user, "blob-unmounted")
+            },
+            RegistryAction::ManifestMounted {
+                timestamp,
+                digest,
+                repository,
+                user,
+            } => AuditEvent {
+                repository: Some(repository.clone()),
+                digest: Some(digest.clone()),
+                ..Self::new(index, timestamp, user, "manifest-mounted")
+            },
+            RegistryAction::ManifestUnmounted {
+                timestamp,
+                digest,
+                repository,
+                user,
+            } => AuditEvent {
+                repository: Some(repository.clone()),
+                digest: Some(digest.clone()),
+                ..Self::new(index, timestamp, user, "manifest-unmounted")
+            },
+            RegistryAction::HashTagged {
+                timestamp,
+                digest,
+                repository,
+                tag,
+                user,
+            } => AuditEvent {
+                repository: Some(repository.clone()),
+                digest: Some(digest.clone()),
+                tag: Some(tag.clone()),
+                ..Self::new(index, timestamp, user, "hash-tagged")
+            },
+            RegistryAction::RobotAccountCreated {
+                timestamp,
+                name,
+                secret_hash: _,
+                user,
+            } => AuditEvent {
+                subject: Some(name.clone()),
+                ..Self::new(index, timestamp, user, "robot-account-created")
+            },
+            RegistryAction::RobotAccountRevoked {
+                timestamp,
+                name,
+                user,
+            } => AuditEvent {
+                subject: Some(name.clone()),
+                ..Self::new(index, timestamp, user, "robot-account-revoked")
+            },
+            RegistryAction::AclEntrySet {
+                timestamp,
+                subject,
+                repository,
+                actions: _,
+                user,
+            } => AuditEvent {
+                repository: Some(repository.parse().unwrap()),
+                subject: Some(subject.to_string()),
+                ..Self::new(index, timestamp, user, "acl-entry-set")
+            },
+            RegistryAction::AclEntryRemoved {
+                timestamp,
+                subject,
+                repository,
+                user,
+            } => AuditEvent {
+                repository: Some(repository.parse().unwrap()),
+                subject: Some(subject.to_string()),
+                ..Self::new(index, timestamp, user, "acl-entry-removed")
+            },
+            _ => return None,
+        };
+
+        Some(event)
+    }
+}
+
+/// Where an event is in the audit history: the log entry it was applied from, and its position
+/// among the audited actions of that entry.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AuditCursor {
+    pub index: u64,
+    pub position: u32,
+}
+
+/// A page of the audit history that a node which installed a snapshot asks a peer for: events
+/// after `after` (or from the start) up to and including the log entry `until`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditPageRequest {
+    pub after: Option<AuditCursor>,
+    pub until: u64,
+    pub limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    1000
+}
+
+/// Which audit events to return. Every filter that is set must match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditQuery {
+    /// A repository name, or a pattern using `*`.
+    pub repository: Option<String>,
+    pub user: Option<String>,
+    pub action: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Return at most this many of the most recent matching events.
+    #[serde(default = "default_audit_limit")]
+    pub limit: usize,
+}
+
+impl Default for AuditQuery {
+    fn default() -> Self {
+        AuditQuery {
+            repository: None,
+            user: None,
+            action: None,
+            since: None,
+            until: None,
+            limit: default_audit_limit(),
+        }
+    }
+}
+
+impl AuditQuery {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        if let Some(pattern) = &self.repository {
+            match &event.repository {
+                Some(repository) if glob_match(pattern, &repository.name) => {}
+                _ => return false,
+            }
+        }
+
+        if let Some(user) = &self.user {
+            if &event.user != user {
+                return false;
+            }
+        }
+
+        if let Some(action) = &self.action {
+            if &event.action != action {
+                return false;
+            }
+        }
+
+        if let Some(since) = &self.since {
+            if &event.timestamp < since {
+                return false;
+            }
+        }
+
+        if let Some(until) = &self.until {
+            if &event.timestamp >= until {
+                return false;
+            }
+        }
+
+        true
+    }
+}
@@ -1,13 +1,23 @@
+pub mod acl;
 pub mod action;
+pub mod audit;
 pub mod blob;
 pub mod digest;
 pub mod manifest;
+pub mod mount;
+pub mod references;
 pub mod repository_name;
+pub mod robot_account;
 pub mod tag_key;
 
+pub use acl::{AclEntry, AclSubject};
 pub use action::RegistryAction;
+pub use audit::{AuditCursor, AuditEvent, AuditPageRequest, AuditQuery, AuditRequest};
 pub use blob::Blob;
 pub use digest::Digest;
 pub use manifest::Manifest;
+pub use mount::Mount;
+pub use references::References;
 pub use repository_name::RepositoryName;
+pub use robot_account::RobotAccount;
 pub use tag_key::TagKey;
@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Digest, RepositoryName};
+
+/// A manifest or blob in a repository.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Mount {
+    pub digest: Digest,
+    pub repository: RepositoryName,
+}
@@ -0,0 +1,14 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Digest, RepositoryName, TagKey};
+
+/// Everything that uses a manifest or blob.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct References {
+    /// The manifests that depend on it, directly or through an index.
+    pub manifests: Vec<Digest>,
+    /// The tags that point at it, or at a manifest that depends on it.
+    pub tags: Vec<TagKey>,
+    /// The repositories it is mounted in.
+    pub repositories: Vec<RepositoryName>,
+}
@@ -0,0 +1,10 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RobotAccount {
+    /// SHA-256 of the robot's secret. Secrets are random so a slow hash isn't needed.
+    pub secret_hash: String,
+    pub created: DateTime<Utc>,
+    pub created_by: String,
+}
@@ -52,12 +52,58 @@ pub fn get_temp_path(root: &str) -> std::path::PathBuf {
     path
 }
 
-pub fn get_temp_mirror_path(root: &str) -> std::path::PathBuf {
-    let upload_id = Uuid::new_v4().as_hyphenated().to_string();
-
+/// Where a mirror download is written until it is complete. It is named after the object, so a
+/// download that was cut off can be picked up again.
+pub fn get_partial_mirror_path(root: &str, object: &str, digest: &Digest) -> std::path::PathBuf {
     let mut path = std::path::Path::new(root).to_path_buf();
     path.push("uploads");
-    path.push(format!("mirror-{upload_id}"));
+    path.push(format!("mirror-{object}-{}", digest.hash));
+
+    path
+}
+
+/// Where a corrupt blob or manifest is moved to, out of the way of the registry. `kind` is
+/// `blobs` or `manifests`.
+pub fn get_quarantine_path(root: &str, kind: &str, digest: &Digest) -> std::path::PathBuf {
+    let mut path = std::path::Path::new(root).to_path_buf();
+    path.push("quarantine");
+    path.push(kind);
+
+    std::fs::create_dir_all(path.clone()).unwrap();
+
+    path.push(format!(
+        "{}-{}",
+        digest.hash,
+        Uuid::new_v4().as_hyphenated()
+    ));
 
     path
 }
+
+/// Match `value` against a pattern where `*` matches any run of characters, including `/`.
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let value = value.as_bytes();
+
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack = None;
+
+    while v < value.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == value[v] {
+            p += 1;
+            v += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character and try again
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
@@ -0,0 +1,493 @@
+//! Durable webhook delivery.
+//!
+//! Notifications are written to a local sled tree before they are sent, so they survive a
+//! restart. Failed deliveries are retried with exponential backoff and moved to a dead letter
+//! tree once they run out of attempts, where an admin can inspect or requeue them.
+//!
+//! Deliveries made by the leader from the audit history are dropped if it stops being the
+//! leader before they are sent, as the next leader queues them again from the replicated
+//! cursor.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use bincode::{options, Options};
+use chrono::{DateTime, Utc};
+use data_encoding::HEXLOWER;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+
+/// The longest we wait between two attempts at the same delivery.
+const MAXIMUM_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// The header a delivery's HMAC-SHA256 signature is sent in, when its webhook has a secret.
+pub const SIGNATURE_HEADER: &str = "X-Distribd-Signature";
+
+/// A notification waiting to be sent to one webhook.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Delivery {
+    pub id: u64,
+    /// The id of the event in the payload.
+    pub event: String,
+    /// The id of the webhook. Several webhooks can share a URL, but not their secret or retry
+    /// settings.
+    pub hook: String,
+    pub url: String,
+    /// Whether the leader queued it from the audit history, rather than it being a pull
+    /// notified by this node or a dead letter requeued by an admin.
+    pub from_history: bool,
+    pub payload: String,
+    pub attempts: u32,
+    pub created: DateTime<Utc>,
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+impl Delivery {
+    /// The value of the signature header for this delivery: `sha256=` and the hex HMAC of the
+    /// payload.
+    pub fn signature(&self, secret: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let tag = hmac::sign(&key, self.payload.as_bytes());
+        format!("sha256={}", HEXLOWER.encode(tag.as_ref()))
+    }
+}
+
+/// How long to wait before the next attempt, after `attempts` failures. It doubles each time,
+/// starting at `initial`.
+pub fn backoff(initial: Duration, attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    initial.saturating_mul(factor).min(MAXIMUM_BACKOFF)
+}
+
+fn delivery_key(id: u64) -> [u8; 8] {
+    id.to_be_bytes()
+}
+
+fn to_bytes(delivery: &Delivery) -> Result<Vec<u8>> {
+    options()
+        .with_big_endian()
+        .serialize(delivery)
+        .context("Failed to serialize webhook delivery")
+}
+
+fn from_bytes(value: &[u8]) -> Result<Delivery> {
+    options()
+        .with_big_endian()
+        .deserialize(value)
+        .context("Failed to deserialize webhook delivery")
+}
+
+/// Where a delivery due at `at` is in the schedule: ordered by time, then by id.
+fn schedule_key(at: DateTime<Utc>, id: u64) -> [u8; 16] {
+    // Flipping the sign bit keeps times before 1970 in order too
+    let at = (at.timestamp_micros() as u64) ^ (1 << 63);
+
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&at.to_be_bytes());
+    key[8..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn schedule_id(key: &[u8]) -> u64 {
+    u64::from_be_bytes(key[8..16].try_into().unwrap())
+}
+
+/// Where the delivery of `event` to the webhook with the id `hook` is in the index used to spot
+/// a notification that is already queued.
+fn event_key(event: &str, hook: &str) -> Vec<u8> {
+    let mut key = (hook.len() as u64).to_be_bytes().to_vec();
+    key.extend_from_slice(hook.as_bytes());
+    key.extend_from_slice(event.as_bytes());
+    key
+}
+
+fn tx_err(err: TransactionError<String>) -> anyhow::Error {
+    anyhow!("Failed to update webhook deliveries: {err:?}")
+}
+
+/// The deliveries this node still has to send. They aren't replicated: each node delivers the
+/// pulls it served and, while it is the leader, the changes in the audit history.
+///
+/// Alongside the deliveries themselves, keyed by id, `schedule` orders them by when they are
+/// next due and `events` finds one from its event and webhook, so neither queueing nor
+/// finding what is due has to read the whole queue.
+pub struct DeliveryQueue {
+    db: sled::Db,
+    pending: sled::Tree,
+    schedule: sled::Tree,
+    events: sled::Tree,
+    dead_letters: sled::Tree,
+}
+
+impl DeliveryQueue {
+    pub fn new(db: &sled::Db) -> Result<Self> {
+        Ok(DeliveryQueue {
+            db: db.clone(),
+            pending: db.open_tree("webhook_deliveries")?,
+            schedule: db.open_tree("webhook_delivery_schedule")?,
+            events: db.open_tree("webhook_delivery_events")?,
+            dead_letters: db.open_tree("webhook_dead_letters")?,
+        })
+    }
+
+    /// Queue `payload`, the notification of `event`, for delivery to the webhook with the id
+    /// `hook`, whose URL is `url`, straight away. If it is already queued, because
+    /// the same events are being notified again, the queued delivery is returned instead.
+    pub fn push(
+        &self,
+        event: &str,
+        hook: &str,
+        url: &str,
+        payload: &str,
+        from_history: bool,
+    ) -> Result<Delivery> {
+        let now = Utc::now();
+        let delivery = Delivery {
+            id: self.db.generate_id()?,
+            event: event.to_string(),
+            hook: hook.to_string(),
+            url: url.to_string(),
+            from_history,
+            payload: payload.to_string(),
+            attempts: 0,
+            created: now,
+            next_attempt: now,
+            last_error: None,
+        };
+        let value = to_bytes(&delivery)?;
+
+        let queued = (&self.pending, &self.schedule, &self.events)
+            .transaction(|(pending, schedule, events)| {
+                let key = event_key(event, hook);
+                if let Some(id) = events.get(&key)? {
+                    if let Some(queued) = pending.get(id)? {
+                        return Ok(Some(queued));
+                    }
+                }
+
+                pending.insert(&delivery_key(delivery.id), value.as_slice())?;
+                schedule.insert(&schedule_key(delivery.next_attempt, delivery.id), &[])?;
+                events.insert(key, &delivery_key(delivery.id))?;
+                Ok(None)
+            })
+            .map_err(tx_err)?;
+
+        match queued {
+            Some(queued) => from_bytes(&queued),
+            None => Ok(delivery),
+        }
+    }
+
+    fn all(tree: &sled::Tree) -> Result<Vec<Delivery>> {
+        tree.iter()
+            .values()
+            .map(|value| from_bytes(&value?))
+            .collect()
+    }
+
+    pub fn pending(&self) -> Result<Vec<Delivery>> {
+        Self::all(&self.pending)
+    }
+
+    /// Whether the delivery `id` is still waiting to be sent.
+    pub fn is_pending(&self, id: u64) -> Result<bool> {
+        Ok(self.pending.contains_key(delivery_key(id))?)
+    }
+
+    /// Deliveries that should be attempted at or before `now`, oldest first.
+    pub fn due(&self, now: DateTime<Utc>) -> Result<Vec<Delivery>> {
+        let mut due = vec![];
+
+        for key in self.schedule.range(..=schedule_key(now, u64::MAX)).keys() {
+            // It may have been sent or dropped since the schedule was read
+            if let Some(value) = self.pending.get(delivery_key(schedule_id(&key?)))? {
+                due.push(from_bytes(&value)?);
+            }
+        }
+
+        Ok(due)
+    }
+
+    /// When the next pending delivery should be attempted.
+    pub fn next_attempt(&self) -> Result<Option<DateTime<Utc>>> {
+        let Some((key, _)) = self.schedule.first()? else {
+            return Ok(None);
+        };
+
+        match self.pending.get(delivery_key(schedule_id(&key)))? {
+            Some(value) => Ok(Some(from_bytes(&value)?.next_attempt)),
+            None => Ok(None),
+        }
+    }
+
+    /// Take the delivery `id` out of the queue, returning it if it was still there.
+    fn remove(&self, id: u64) -> Result<Option<Delivery>> {
+        (&self.pending, &self.schedule, &self.events)
+            .transaction(|(pending, schedule, events)| {
+                let Some(value) = pending.remove(&delivery_key(id))? else {
+                    return Ok(None);
+                };
+                let queued = from_bytes(&value)
+                    .map_err(|err| ConflictableTransactionError::Abort(err.to_string()))?;
+
+                schedule.remove(&schedule_key(queued.next_attempt, id))?;
+                let key = event_key(&queued.event, &queued.hook);
+                if events.get(&key)?.as_deref() == Some(&delivery_key(id)[..]) {
+                    events.remove(key)?;
+                }
+                Ok(Some(queued))
+            })
+            .map_err(tx_err)
+    }
+
+    pub fn delivered(&self, delivery: &Delivery) -> Result<()> {
+        self.remove(delivery.id)?;
+        Ok(())
+    }
+
+    /// Record a failed attempt, to be tried again at its `next_attempt`. Nothing happens if it
+    /// was dropped while it was being attempted.
+    pub fn reschedule(&self, delivery: &Delivery) -> Result<()> {
+        let value = to_bytes(delivery)?;
+
+        (&self.pending, &self.schedule)
+            .transaction(|(pending, schedule)| {
+                let Some(old) = pending.get(delivery_key(delivery.id))? else {
+                    return Ok(());
+                };
+                let old = from_bytes(&old)
+                    .map_err(|err| ConflictableTransactionError::Abort(err.to_string()))?;
+
+                pending.insert(&delivery_key(delivery.id), value.as_slice())?;
+                schedule.remove(&schedule_key(old.next_attempt, delivery.id))?;
+                schedule.insert(&schedule_key(delivery.next_attempt, delivery.id), &[])?;
+                Ok(())
+            })
+            .map_err(tx_err)
+    }
+
+    /// Give up on a delivery and keep it in the dead letter list, unless it was dropped while
+    /// it was being attempted.
+    pub fn dead_letter(&self, delivery: &Delivery) -> Result<()> {
+        if self.remove(delivery.id)?.is_some() {
+            self.dead_letters
+                .insert(delivery_key(delivery.id), to_bytes(delivery)?)?;
+        }
+        Ok(())
+    }
+
+    /// Drop the deliveries queued from the audit history, because this node isn't the leader
+    /// any more. Only `ids` are dropped, unless it is `None`. Returns how many were dropped.
+    pub fn drop_from_history(&self, ids: Option<&[u64]>) -> Result<usize> {
+        let candidates = match ids {
+            Some(ids) => ids.to_vec(),
+            None => self
+                .pending()?
+                .into_iter()
+                .filter(|delivery| delivery.from_history)
+                .map(|delivery| delivery.id)
+                .collect(),
+        };
+
+        let mut dropped = 0;
+        for id in candidates {
+            let Some(value) = self.pending.get(delivery_key(id))? else {
+                continue;
+            };
+            if from_bytes(&value)?.from_history && self.remove(id)?.is_some() {
+                dropped += 1;
+            }
+        }
+
+        Ok(dropped)
+    }
+
+    pub fn dead_letters(&self) -> Result<Vec<Delivery>> {
+        Self::all(&self.dead_letters)
+    }
+
+    /// Queue dead letters to be attempted again from scratch, all of them if `ids` is empty.
+    /// Returns how many were requeued.
+    pub fn retry_dead_letters(&self, ids: &[u64]) -> Result<usize> {
+        let mut retried = 0;
+
+        for delivery in self.dead_letters()? {
+            if !ids.is_empty() && !ids.contains(&delivery.id) {
+                continue;
+            }
+
+            // Sent whether or not this node is the leader, as an admin asked for it
+            let delivery = Delivery {
+                attempts: 0,
+                next_attempt: Utc::now(),
+                from_history: false,
+                ..delivery
+            };
+            let value = to_bytes(&delivery)?;
+
+            (
+                &self.pending,
+                &self.schedule,
+                &self.events,
+                &self.dead_letters,
+            )
+                .transaction(|(pending, schedule, events, dead_letters)| {
+                    pending.insert(&delivery_key(delivery.id), value.as_slice())?;
+                    schedule.insert(&schedule_key(delivery.next_attempt, delivery.id), &[])?;
+                    events.insert(
+                        event_key(&delivery.event, &delivery.hook),
+                        &delivery_key(delivery.id),
+                    )?;
+                    dead_letters.remove(&delivery_key(delivery.id))?;
+                    Ok(())
+                })
+                .map_err(tx_err)?;
+            retried += 1;
+        }
+
+        Ok(retried)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue() -> DeliveryQueue {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        DeliveryQueue::new(&db).unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let initial = Duration::from_secs(5);
+        assert_eq!(backoff(initial, 1), Duration::from_secs(5));
+        assert_eq!(backoff(initial, 2), Duration::from_secs(10));
+        assert_eq!(backoff(initial, 4), Duration::from_secs(40));
+        assert_eq!(backoff(initial, 100), MAXIMUM_BACKOFF);
+    }
+
+    #[test]
+    fn signature() {
+        let delivery = Delivery {
+            id: 1,
+            event: "1-0".to_string(),
+            hook: "one".to_string(),
+            url: "http://localhost".to_string(),
+            from_history: true,
+            payload: "{\"events\":[]}".to_string(),
+            attempts: 0,
+            created: Utc::now(),
+            next_attempt: Utc::now(),
+            last_error: None,
+        };
+
+        assert_eq!(
+            delivery.signature("secret"),
+            "sha256=a642b59553c93e227ec0f2f38910fbf71231a2197c00899833c00478cec86f34"
+        );
+    }
+
+    #[test]
+    fn retries_and_dead_letters() {
+        let queue = queue();
+
+        let first = queue.push("1-0", "one", "http://one", "{}", true).unwrap();
+        let second = queue.push("1-0", "two", "http://two", "{}", true).unwrap();
+
+        // Notifying the same event again doesn't queue it twice
+        assert_eq!(
+            queue.push("1-0", "one", "http://one", "{}", true).unwrap(),
+            first
+        );
+        assert_eq!(queue.pending().unwrap().len(), 2);
+        assert_eq!(
+            queue.due(Utc::now()).unwrap(),
+            vec![first.clone(), second.clone()]
+        );
+
+        let later = Utc::now() + chrono::Duration::try_seconds(60).unwrap();
+        let failed = Delivery {
+            attempts: 1,
+            next_attempt: later,
+            last_error: Some("500 Internal Server Error".to_string()),
+            ..first.clone()
+        };
+        queue.reschedule(&failed).unwrap();
+        assert_eq!(queue.due(Utc::now()).unwrap(), vec![second.clone()]);
+        assert_eq!(queue.next_attempt().unwrap(), Some(second.next_attempt));
+
+        queue.delivered(&second).unwrap();
+        assert_eq!(queue.next_attempt().unwrap(), Some(later));
+
+        queue.dead_letter(&failed).unwrap();
+        assert!(queue.pending().unwrap().is_empty());
+        assert_eq!(queue.dead_letters().unwrap()[0].attempts, 1);
+
+        assert_eq!(queue.retry_dead_letters(&[first.id + 100]).unwrap(), 0);
+        assert_eq!(queue.retry_dead_letters(&[]).unwrap(), 1);
+        assert!(queue.dead_letters().unwrap().is_empty());
+
+        let retried = &queue.due(Utc::now()).unwrap()[0];
+        assert_eq!(retried.id, first.id);
+        assert_eq!(retried.attempts, 0);
+        assert!(!retried.from_history);
+    }
+
+    #[test]
+    fn due_in_order_of_next_attempt() {
+        let queue = queue();
+
+        let first = queue.push("1-0", "one", "http://one", "{}", true).unwrap();
+        let second = queue.push("2-0", "one", "http://one", "{}", true).unwrap();
+
+        let now = Utc::now();
+        let later = Delivery {
+            attempts: 1,
+            next_attempt: now - chrono::Duration::try_seconds(1).unwrap(),
+            ..first.clone()
+        };
+        let sooner = Delivery {
+            attempts: 1,
+            next_attempt: now - chrono::Duration::try_seconds(2).unwrap(),
+            ..second.clone()
+        };
+        queue.reschedule(&later).unwrap();
+        queue.reschedule(&sooner).unwrap();
+
+        assert_eq!(queue.due(now).unwrap(), vec![sooner.clone(), later.clone()]);
+        assert_eq!(queue.next_attempt().unwrap(), Some(sooner.next_attempt));
+
+        // Once it has been sent, the same event can be queued again
+        queue.delivered(&sooner).unwrap();
+        assert_eq!(queue.due(now).unwrap(), vec![later]);
+        let again = queue.push("2-0", "one", "http://one", "{}", true).unwrap();
+        assert_ne!(again.id, second.id);
+    }
+
+    #[test]
+    fn dropped_when_no_longer_leader() {
+        let queue = queue();
+
+        let first = queue.push("1-0", "one", "http://one", "{}", true).unwrap();
+        let second = queue.push("2-0", "one", "http://one", "{}", true).unwrap();
+        let pull = queue
+            .push("pull", "one", "http://one", "{}", false)
+            .unwrap();
+
+        assert_eq!(queue.drop_from_history(Some(&[first.id])).unwrap(), 1);
+        assert_eq!(queue.pending().unwrap(), vec![second.clone(), pull.clone()]);
+
+        // A failure recorded after it was dropped doesn't bring it back
+        queue.reschedule(&first).unwrap();
+        queue.dead_letter(&first).unwrap();
+        assert_eq!(queue.pending().unwrap(), vec![second, pull.clone()]);
+        assert!(queue.dead_letters().unwrap().is_empty());
+
+        // Pulls are still sent
+        assert_eq!(queue.drop_from_history(None).unwrap(), 1);
+        assert_eq!(queue.pending().unwrap(), vec![pull]);
+    }
+}
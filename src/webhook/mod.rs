@@ -0,0 +1,754 @@
+pub mod delivery;
+pub mod notifier;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{Configuration, WebhookConfig};
+use crate::types::{AuditRequest, Digest, RepositoryName};
+use chrono::{DateTime, Utc};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::registry::Registry;
+
+use futures::StreamExt;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::Notify;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use delivery::{backoff, Delivery, DeliveryQueue, SIGNATURE_HEADER};
+
+pub const EVENTS_CONTENT_TYPE: &str = "application/vnd.docker.distribution.events.v1+json";
+
+/// The kinds of event a webhook can subscribe to.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventAction {
+    Push,
+    Pull,
+    Mount,
+    Delete,
+    Tag,
+}
+
+impl EventAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventAction::Push => "push",
+            EventAction::Pull => "pull",
+            EventAction::Mount => "mount",
+            EventAction::Delete => "delete",
+            EventAction::Tag => "tag",
+        }
+    }
+}
+
+pub enum EventKind {
+    /// A manifest was pushed and tagged.
+    Push,
+    /// A manifest was pulled.
+    Pull,
+    /// A blob was added to a repository, by an upload or mounted from another repository.
+    Mount,
+    /// A manifest or blob was deleted, by a client or by garbage collection.
+    Delete,
+    /// A tag that pointed at `previous` was moved.
+    TagMoved { previous: Digest },
+}
+
+impl EventKind {
+    pub fn action(&self) -> EventAction {
+        match self {
+            EventKind::Push => EventAction::Push,
+            EventKind::Pull => EventAction::Pull,
+            EventKind::Mount => EventAction::Mount,
+            EventKind::Delete => EventAction::Delete,
+            EventKind::TagMoved { .. } => EventAction::Tag,
+        }
+    }
+}
+
+/// Whether an event is about a manifest or a blob.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventTarget {
+    Manifest,
+    Blob,
+}
+
+pub struct Event {
+    pub kind: EventKind,
+    pub target: EventTarget,
+    pub timestamp: DateTime<Utc>,
+    pub repository: RepositoryName,
+    pub digest: Digest,
+    pub content_type: Option<String>,
+    pub size: Option<u64>,
+    pub tag: Option<String>,
+    /// Who made the change, the subject of their token or `$system`.
+    pub actor: String,
+    /// The request that caused the event. Background tasks like garbage collection don't have one.
+    pub request: Option<AuditRequest>,
+}
+
+impl Event {
+    pub fn manifest(
+        kind: EventKind,
+        repository: &RepositoryName,
+        digest: &Digest,
+        actor: &str,
+    ) -> Self {
+        Self::new(kind, EventTarget::Manifest, repository, digest, actor)
+    }
+
+    pub fn blob(
+        kind: EventKind,
+        repository: &RepositoryName,
+        digest: &Digest,
+        actor: &str,
+    ) -> Self {
+        Self::new(kind, EventTarget::Blob, repository, digest, actor)
+    }
+
+    fn new(
+        kind: EventKind,
+        target: EventTarget,
+        repository: &RepositoryName,
+        digest: &Digest,
+        actor: &str,
+    ) -> Self {
+        Event {
+            kind,
+            target,
+            timestamp: Utc::now(),
+            repository: repository.clone(),
+            digest: digest.clone(),
+            content_type: None,
+            size: None,
+            tag: None,
+            actor: actor.to_string(),
+            request: None,
+        }
+    }
+
+    /// What webhook matchers are tested against: `repository:tag`, or `repository@digest` for
+    /// events that aren't about a tag.
+    pub fn match_target(&self) -> String {
+        match &self.tag {
+            Some(tag) => format!("{}:{}", self.repository, tag),
+            None => format!("{}@{}", self.repository, self.digest),
+        }
+    }
+
+    /// A notification in the format distribution sends. `source` identifies this node.
+    fn notification(&self, id: &str, source: &serde_json::Value) -> serde_json::Value {
+        let Event {
+            kind,
+            target,
+            timestamp,
+            repository,
+            digest,
+            content_type,
+            size,
+            tag,
+            actor,
+            request,
+        } = self;
+
+        let kind_of_object = match target {
+            EventTarget::Manifest => "manifests",
+            EventTarget::Blob => "blobs",
+        };
+
+        let mut target = json!({
+            "mediaType": content_type,
+            "size": size,
+            "digest": digest,
+            "length": size,
+            "repository": repository,
+            "url": format!("/v2/{repository}/{kind_of_object}/{digest}"),
+        });
+
+        if let Some(tag) = tag {
+            target["tag"] = json!(tag);
+        }
+
+        if let EventKind::TagMoved { previous } = kind {
+            target["previousDigest"] = json!(previous);
+        }
+
+        let mut notification = json!({
+            "id": id,
+            "timestamp": timestamp.to_rfc3339(),
+            "action": kind.action().as_str(),
+            "target": target,
+            "actor": {
+                "name": actor,
+            },
+            "source": source,
+        });
+
+        if let Some(request) = request {
+            notification["request"] = json!({
+                "id": request.id,
+                "addr": request.addr,
+                "host": request.host,
+                "method": request.method,
+                "useragent": request.useragent,
+            });
+        }
+
+        notification
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, EncodeLabelSet, Debug)]
+struct WebhookMetricLabels {
+    status: String,
+}
+
+#[derive(Clone, Default)]
+struct WebhookMetrics {
+    posts: Family<WebhookMetricLabels, Counter>,
+    dead_letters: Counter,
+}
+
+/// How many deliveries are attempted at once.
+const CONCURRENT_DELIVERIES: usize = 16;
+
+/// How long to wait for new events when no retries are due sooner.
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+async fn deliver(
+    client: &reqwest::Client,
+    webhooks: &[WebhookConfig],
+    queue: &DeliveryQueue,
+    metrics: &WebhookMetrics,
+    delivery: Delivery,
+) {
+    let hook = webhooks.iter().find(|hook| hook.id() == delivery.hook);
+    let Some(hook) = hook else {
+        warn!(
+            "Dropping webhook delivery {} to {} as it is no longer configured",
+            delivery.id, delivery.url
+        );
+        if let Err(err) = queue.delivered(&delivery) {
+            error!("Unable to remove webhook delivery {}: {err:?}", delivery.id);
+        }
+        return;
+    };
+
+    let mut request = client
+        .post(&hook.url)
+        .timeout(Duration::from_secs(hook.timeout))
+        .header(reqwest::header::CONTENT_TYPE, EVENTS_CONTENT_TYPE);
+    if let Some(secret) = &hook.secret {
+        request = request.header(SIGNATURE_HEADER, delivery.signature(secret));
+    }
+
+    let failure = match request.body(delivery.payload.clone()).send().await {
+        Ok(resp) => {
+            let labels = WebhookMetricLabels {
+                status: resp.status().to_string(),
+            };
+            metrics.posts.get_or_create(&labels).inc();
+
+            match resp.status().is_success() {
+                true => None,
+                false => Some(format!("Webhook returned {}", resp.status())),
+            }
+        }
+        Err(err) => {
+            let labels = WebhookMetricLabels {
+                status: String::from("000"),
+            };
+            metrics.posts.get_or_create(&labels).inc();
+
+            Some(err.to_string())
+        }
+    };
+
+    let result = match failure {
+        None => queue.delivered(&delivery),
+        Some(failure) => {
+            warn!(
+                "Webhook delivery {} to {} failed: {failure}",
+                delivery.id, hook.url
+            );
+
+            let attempts = delivery.attempts + 1;
+            let wait = backoff(Duration::from_secs(hook.retry_backoff), attempts);
+            let delivery = Delivery {
+                attempts,
+                next_attempt: Utc::now() + chrono::Duration::from_std(wait).unwrap(),
+                last_error: Some(failure),
+                ..delivery
+            };
+
+            if attempts >= hook.max_attempts {
+                error!(
+                    "Giving up on webhook delivery {} to {} after {attempts} attempts",
+                    delivery.id, hook.url
+                );
+                metrics.dead_letters.inc();
+                queue.dead_letter(&delivery)
+            } else {
+                queue.reschedule(&delivery)
+            }
+        }
+    };
+
+    if let Err(err) = result {
+        error!("Unable to update webhook delivery: {err:?}");
+    }
+}
+
+/// Turns events into notifications for the configured webhooks and queues them for delivery.
+pub struct Webhooks {
+    webhooks: Vec<WebhookConfig>,
+    source: serde_json::Value,
+    queue: Arc<DeliveryQueue>,
+    queued: Arc<Notify>,
+}
+
+impl Webhooks {
+    /// Whether any webhook is interested in events like this.
+    pub fn wants(&self, action: EventAction) -> bool {
+        self.webhooks.iter().any(|hook| hook.wants(action))
+    }
+
+    /// The ids of the configured webhooks.
+    pub fn ids(&self) -> Vec<String> {
+        self.webhooks
+            .iter()
+            .map(|hook| hook.id().to_string())
+            .collect()
+    }
+
+    /// Queue a notification of `event`, which isn't from the audit history, for every webhook
+    /// that wants it. `id` is sent as the id of the event, so receivers can tell if they have
+    /// seen it before.
+    pub fn enqueue(&self, id: &str, event: &Event) -> anyhow::Result<()> {
+        for hook in &self.webhooks {
+            self.queue_for(hook, id, event, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queue a notification of `event`, from the audit history, for the webhook with the id
+    /// `hook` if it wants it. Returns the id of the delivery.
+    pub fn enqueue_from_history(
+        &self,
+        hook: &str,
+        id: &str,
+        event: &Event,
+    ) -> anyhow::Result<Option<u64>> {
+        let Some(hook) = self.webhooks.iter().find(|config| config.id() == hook) else {
+            return Ok(None);
+        };
+
+        self.queue_for(hook, id, event, true)
+    }
+
+    fn queue_for(
+        &self,
+        hook: &WebhookConfig,
+        id: &str,
+        event: &Event,
+        from_history: bool,
+    ) -> anyhow::Result<Option<u64>> {
+        let action = event.kind.action();
+        if !hook.wants(action) || !hook.matcher.is_match(&event.match_target()) {
+            return Ok(None);
+        }
+        if action == EventAction::Pull && rand::random::<f64>() >= hook.pull_sample_rate {
+            return Ok(None);
+        }
+
+        let payload = json!({
+            "events": [event.notification(id, &self.source)],
+        })
+        .to_string();
+
+        let delivery = self
+            .queue
+            .push(id, hook.id(), &hook.url, &payload, from_history)?;
+
+        self.queued.notify_one();
+
+        Ok(Some(delivery.id))
+    }
+}
+
+/// Start delivering notifications for the configured webhooks. They are queued in `queue` and
+/// delivered in the background, so they are retried after failures and restarts.
+pub fn start_webhook_worker(
+    config: &Configuration,
+    queue: Arc<DeliveryQueue>,
+    registry: &mut Registry,
+) -> Arc<Webhooks> {
+    let metrics = WebhookMetrics::default();
+    registry.register(
+        "distribd_webhooks_post",
+        "Number of webhooks sent",
+        metrics.posts.clone(),
+    );
+    registry.register(
+        "distribd_webhooks_dead_letters",
+        "Number of webhook deliveries that ran out of attempts",
+        metrics.dead_letters.clone(),
+    );
+
+    let webhooks = Arc::new(Webhooks {
+        webhooks: config.webhooks.clone(),
+        // Like distribution, each run of the registry is a new instance
+        source: json!({
+            "addr": format!("{}:{}", config.identifier, config.registry.port),
+            "instanceID": Uuid::new_v4().to_string(),
+        }),
+        queue: queue.clone(),
+        queued: Arc::new(Notify::new()),
+    });
+
+    let queued = webhooks.queued.clone();
+    let hooks = config.webhooks.clone();
+
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+
+        loop {
+            let due = queue.due(Utc::now()).unwrap_or_else(|err| {
+                error!("Unable to read webhook deliveries: {err:?}");
+                vec![]
+            });
+
+            futures::stream::iter(due)
+                .for_each_concurrent(CONCURRENT_DELIVERIES, |delivery| {
+                    deliver(&client, &hooks, &queue, &metrics, delivery)
+                })
+                .await;
+
+            let wait = match queue.next_attempt() {
+                Ok(Some(next_attempt)) => (next_attempt - Utc::now())
+                    .to_std()
+                    .unwrap_or(Duration::ZERO)
+                    .min(IDLE_INTERVAL),
+                _ => IDLE_INTERVAL,
+            };
+
+            // Either way, check what is due again
+            let _ = tokio::time::timeout(wait, queued.notified()).await;
+        }
+    });
+
+    webhooks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use super::*;
+
+    fn digest() -> Digest {
+        "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+            .parse()
+            .unwrap()
+    }
+
+    fn push() -> Event {
+        Event {
+            timestamp: "2024-03-09T14:44:26Z".parse().unwrap(),
+            content_type: Some("application/vnd.oci.image.manifest.v1+json".to_string()),
+            size: Some(1234),
+            tag: Some("latest".to_string()),
+            request: Some(AuditRequest {
+                id: "request-1".to_string(),
+                addr: "10.0.0.1:42961".to_string(),
+                host: "registry.example.com".to_string(),
+                method: "PUT".to_string(),
+                useragent: "docker/24.0".to_string(),
+            }),
+            ..Event::manifest(
+                EventKind::Push,
+                &"team/app".parse().unwrap(),
+                &digest(),
+                "alice",
+            )
+        }
+    }
+
+    #[test]
+    fn notification() {
+        let source = json!({"addr": "registry-0:8000", "instanceID": "instance"});
+        let notification = push().notification("42-0", &source);
+
+        assert_eq!(notification["id"], "42-0");
+        assert_eq!(notification["action"], "push");
+        assert_eq!(notification["timestamp"], "2024-03-09T14:44:26+00:00");
+        assert_eq!(notification["target"]["size"], 1234);
+        assert_eq!(notification["target"]["length"], 1234);
+        assert_eq!(notification["target"]["tag"], "latest");
+        assert_eq!(
+            notification["target"]["url"],
+            format!("/v2/team/app/manifests/{}", digest())
+        );
+        assert_eq!(notification["request"]["id"], "request-1");
+        assert_eq!(notification["request"]["addr"], "10.0.0.1:42961");
+        assert_eq!(notification["request"]["useragent"], "docker/24.0");
+        assert_eq!(notification["actor"]["name"], "alice");
+        assert_eq!(notification["source"], source);
+    }
+
+    #[test]
+    fn mount_delete_and_tag_notifications() {
+        let source = json!({"addr": "registry-0:8000", "instanceID": "instance"});
+
+        let mount = Event::blob(
+            EventKind::Mount,
+            &"team/app".parse().unwrap(),
+            &digest(),
+            "alice",
+        );
+        assert_eq!(mount.match_target(), format!("team/app@{}", digest()));
+
+        let notification = mount.notification("1-0", &source);
+        assert_eq!(notification["action"], "mount");
+        assert_eq!(
+            notification["target"]["url"],
+            format!("/v2/team/app/blobs/{}", digest())
+        );
+
+        let delete = Event::manifest(
+            EventKind::Delete,
+            &"team/app".parse().unwrap(),
+            &digest(),
+            "$system",
+        );
+        let notification = delete.notification("2-0", &source);
+        assert_eq!(notification["action"], "delete");
+        assert_eq!(notification["actor"]["name"], "$system");
+        assert!(notification.get("request").is_none());
+
+        let previous: Digest =
+            "sha256:0000000000000000000000000000000000000000000000000000000000000000"
+                .parse()
+                .unwrap();
+        let moved = Event {
+            tag: Some("latest".to_string()),
+            ..Event::manifest(
+                EventKind::TagMoved {
+                    previous: previous.clone(),
+                },
+                &"team/app".parse().unwrap(),
+                &digest(),
+                "alice",
+            )
+        };
+        let notification = moved.notification("3-0-tag", &source);
+        assert_eq!(notification["action"], "tag");
+        assert_eq!(
+            notification["target"]["previousDigest"],
+            previous.to_string()
+        );
+    }
+
+    #[actix_web::test]
+    async fn delivers_signed_retries_and_dead_letters() {
+        let received = Arc::new(Mutex::new(vec![]));
+
+        let log = received.clone();
+        let server = HttpServer::new(move || {
+            let log = log.clone();
+            App::new().route(
+                "/{hook}",
+                web::post().to(move |req: actix_web::HttpRequest, body: String| {
+                    let hook = req.match_info().get("hook").unwrap().to_string();
+                    let signature = req
+                        .headers()
+                        .get(SIGNATURE_HEADER)
+                        .map(|value| value.to_str().unwrap().to_string());
+                    let content_type = req.headers().get("content-type").cloned().unwrap();
+
+                    let mut log = log.lock().unwrap();
+                    log.push((hook.clone(), signature, content_type, body));
+                    let attempts = log.iter().filter(|(name, ..)| name == &hook).count();
+
+                    async move {
+                        match (hook.as_str(), attempts) {
+                            ("flaky", 1) | ("broken", _) => HttpResponse::InternalServerError(),
+                            _ => HttpResponse::Ok(),
+                        }
+                        .finish()
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let queue = Arc::new(DeliveryQueue::new(&db).unwrap());
+
+        let config = Configuration {
+            webhooks: vec![
+                serde_json::from_value(json!({
+                    "url": format!("http://{address}/flaky"),
+                    "matcher": ".*",
+                    "secret": "secret",
+                    "retry_backoff": 0,
+                }))
+                .unwrap(),
+                serde_json::from_value(json!({
+                    "url": format!("http://{address}/broken"),
+                    "matcher": ".*",
+                    "max_attempts": 2,
+                    "retry_backoff": 0,
+                }))
+                .unwrap(),
+            ],
+            ..Default::default()
+        };
+
+        let webhooks = start_webhook_worker(&config, queue.clone(), &mut Registry::default());
+        webhooks.enqueue("1-0", &push()).unwrap();
+
+        for _ in 0..100 {
+            if queue.pending().unwrap().is_empty() && !queue.dead_letters().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert!(queue.pending().unwrap().is_empty());
+
+        let dead_letters = queue.dead_letters().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].url, format!("http://{address}/broken"));
+        assert_eq!(dead_letters[0].attempts, 2);
+
+        let received = received.lock().unwrap().clone();
+        let flaky = received
+            .iter()
+            .filter(|(hook, ..)| hook == "flaky")
+            .collect::<Vec<_>>();
+        assert_eq!(flaky.len(), 2);
+
+        let (_, signature, content_type, body) = flaky[1];
+        assert_eq!(content_type, EVENTS_CONTENT_TYPE);
+        let delivery = Delivery {
+            payload: body.clone(),
+            ..dead_letters[0].clone()
+        };
+        assert_eq!(
+            signature.as_deref(),
+            Some(delivery.signature("secret").as_str())
+        );
+
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["events"][0]["action"], "push");
+
+        assert!(received
+            .iter()
+            .filter(|(hook, ..)| hook == "broken")
+            .all(|(_, signature, ..)| signature.is_none()));
+
+        handle.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn hooks_sharing_a_url_use_their_own_secret() {
+        let received = Arc::new(Mutex::new(vec![]));
+
+        let log = received.clone();
+        let server = HttpServer::new(move || {
+            let log = log.clone();
+            App::new().route(
+                "/shared",
+                web::post().to(move |req: actix_web::HttpRequest, body: String| {
+                    let signature = req
+                        .headers()
+                        .get(SIGNATURE_HEADER)
+                        .map(|value| value.to_str().unwrap().to_string());
+                    log.lock().unwrap().push((signature, body));
+                    async { HttpResponse::Ok().finish() }
+                }),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let queue = Arc::new(DeliveryQueue::new(&db).unwrap());
+
+        // Sharing a URL, they need ids of their own
+        let config = Configuration {
+            webhooks: ["first", "second"]
+                .iter()
+                .map(|secret| {
+                    serde_json::from_value(json!({
+                        "id": secret,
+                        "url": format!("http://{address}/shared"),
+                        "matcher": ".*",
+                        "secret": secret,
+                    }))
+                    .unwrap()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        let webhooks = start_webhook_worker(&config, queue.clone(), &mut Registry::default());
+        webhooks.enqueue("1-0", &push()).unwrap();
+
+        for _ in 0..100 {
+            if received.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let received = received.lock().unwrap().clone();
+        let mut signatures = received
+            .iter()
+            .map(|(signature, body)| {
+                let delivery = Delivery {
+                    id: 0,
+                    event: "1-0".to_string(),
+                    hook: String::new(),
+                    url: String::new(),
+                    from_history: false,
+                    payload: body.clone(),
+                    attempts: 0,
+                    created: Utc::now(),
+                    next_attempt: Utc::now(),
+                    last_error: None,
+                };
+                let secret = ["first", "second"]
+                    .into_iter()
+                    .find(|secret| signature.as_deref() == Some(&delivery.signature(secret)));
+                secret.unwrap()
+            })
+            .collect::<Vec<_>>();
+        signatures.sort();
+        assert_eq!(signatures, vec!["first", "second"]);
+
+        handle.stop(true).await;
+    }
+}
@@ -0,0 +1,374 @@
+//! Webhook notifications for changes to the registry.
+//!
+//! Changes are notified from the replicated audit history rather than by the node that handled
+//! the request, so changes made through any node, imports and garbage collection are notified,
+//! and a node crashing after a write doesn't lose them. Only the leader sends them. Each webhook
+//! has its own replicated cursor, which the leader moves once a batch has been delivered to it
+//! (or has become dead letters), so a webhook that is down doesn't hold up the others.
+//!
+//! A node that stops being the leader drops the notifications it hasn't sent yet, and the next
+//! leader carries on from the cursor. Anything sent after the cursor was last moved is sent
+//! again, with the same event id, so notifications are delivered at least once.
+
+use std::time::Duration;
+
+use actix_web::web::Data;
+use chrono::Utc;
+use tracing::{debug, error};
+
+use crate::app::RegistryApp;
+use crate::types::{AuditCursor, AuditEvent, RegistryAction};
+
+use super::{Event, EventKind, EventTarget};
+
+/// How many audit events are notified before the cursor is moved.
+const BATCH_SIZE: usize = 100;
+
+/// How often to check for new events if nothing wakes us up sooner.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The id of the notification for the audit event at `cursor`. It is the same whichever node
+/// sends it, so a notification that is sent again after a leader change has the same id.
+fn notification_id(cursor: &AuditCursor) -> String {
+    format!("{}-{}", cursor.index, cursor.position)
+}
+
+/// The webhook events for a run of audit events, with their ids. A manifest mounted and tagged
+/// by the same log entry is a single push.
+pub fn events_from_audit(batch: &[(AuditCursor, AuditEvent)]) -> Vec<(String, Event)> {
+    let mut events = vec![];
+
+    for (cursor, audit) in batch {
+        let (Some(repository), Some(digest)) = (&audit.repository, &audit.digest) else {
+            continue;
+        };
+
+        let id = notification_id(cursor);
+
+        let event = match audit.action.as_str() {
+            "hash-tagged" => {
+                if let Some(previous) = &audit.previous_digest {
+                    events.push((
+                        format!("{id}-tag"),
+                        Event {
+                            timestamp: audit.timestamp,
+                            tag: audit.tag.clone(),
+                            request: audit.request.clone(),
+                            ..Event::manifest(
+                                EventKind::TagMoved {
+                                    previous: previous.clone(),
+                                },
+                                repository,
+                                digest,
+                                &audit.user,
+                            )
+                        },
+                    ));
+                }
+
+                Event {
+                    tag: audit.tag.clone(),
+                    ..Event::manifest(EventKind::Push, repository, digest, &audit.user)
+                }
+            }
+            "manifest-mounted" => {
+                let tagged = batch.iter().any(|(other, tagged)| {
+                    other.index == cursor.index
+                        && tagged.action == "hash-tagged"
+                        && tagged.repository == audit.repository
+                        && tagged.digest == audit.digest
+                });
+                if tagged {
+                    continue;
+                }
+                Event::manifest(EventKind::Push, repository, digest, &audit.user)
+            }
+            "manifest-unmounted" => {
+                Event::manifest(EventKind::Delete, repository, digest, &audit.user)
+            }
+            "blob-mounted" => Event::blob(EventKind::Mount, repository, digest, &audit.user),
+            "blob-unmounted" => Event::blob(EventKind::Delete, repository, digest, &audit.user),
+            _ => continue,
+        };
+
+        events.push((
+            id,
+            Event {
+                timestamp: audit.timestamp,
+                request: audit.request.clone(),
+                ..event
+            },
+        ));
+    }
+
+    events
+}
+
+/// Wait until none of `deliveries` are queued any more, because they were delivered or ran out
+/// of attempts. If this node stops being the leader first, they are dropped, as the next
+/// leader will queue them again, and false is returned.
+async fn wait_for_deliveries(app: &RegistryApp, deliveries: &[u64]) -> anyhow::Result<bool> {
+    let mut receiver = app.raft.metrics();
+
+    loop {
+        let mut pending = false;
+        for id in deliveries {
+            if app.webhook_deliveries.is_pending(*id)? {
+                pending = true;
+                break;
+            }
+        }
+        if !pending {
+            return Ok(true);
+        }
+
+        let leader = matches!(receiver.borrow().state, openraft::ServerState::Leader);
+        if !leader
+            || matches!(
+                tokio::time::timeout(POLL_INTERVAL, receiver.changed()).await,
+                Ok(Err(_))
+            )
+        {
+            app.webhook_deliveries.drop_from_history(Some(deliveries))?;
+            return Ok(false);
+        }
+    }
+}
+
+/// Move the replicated cursor of the webhook with the id `hook`, returning whether the write
+/// succeeded.
+async fn move_cursor(app: &RegistryApp, hook: &str, cursor: AuditCursor) -> bool {
+    app.submit_write(vec![RegistryAction::WebhookCursorMoved {
+        timestamp: Utc::now(),
+        hook: hook.to_string(),
+        cursor,
+    }])
+    .await
+}
+
+/// Notify the webhook with the id `hook` of the next batch of audit events, returning whether
+/// there may be more waiting.
+async fn notify_batch(app: &RegistryApp, hook: &str) -> anyhow::Result<bool> {
+    let cursor = match app.store.get_webhook_cursor(hook)? {
+        Some(cursor) => cursor,
+        None => {
+            // Start from the end of the history, rather than notifying everything that
+            // happened before the webhook was configured
+            let cursor = app.store.get_last_audit_cursor()?.unwrap_or(AuditCursor {
+                index: 0,
+                position: 0,
+            });
+            move_cursor(app, hook, cursor).await;
+            return Ok(false);
+        }
+    };
+
+    // A leader that joined from a snapshot may not have all the history yet
+    if app.store.get_audit_backfill()?.is_some() {
+        return Ok(false);
+    }
+
+    let mut batch = app.store.get_audit_events_after(Some(cursor), BATCH_SIZE)?;
+
+    // Don't split a log entry between two batches, so pushes are always recognised
+    if batch.len() >= BATCH_SIZE {
+        while let Some((last, _)) = batch.last() {
+            let more = app.store.get_audit_events_after(Some(*last), 1)?;
+            match more.first() {
+                Some((next, _)) if next.index == last.index => batch.extend(more),
+                _ => break,
+            }
+        }
+    }
+
+    let Some((last, _)) = batch.last() else {
+        return Ok(false);
+    };
+    let last = *last;
+
+    let mut deliveries = vec![];
+    for (id, event) in events_from_audit(&batch) {
+        let (content_type, size) = match event.target {
+            EventTarget::Manifest => app
+                .get_manifest(&event.digest)
+                .map(|manifest| (manifest.content_type, manifest.size))
+                .unwrap_or_default(),
+            EventTarget::Blob => app
+                .get_blob(&event.digest)
+                .map(|blob| (blob.content_type, blob.size))
+                .unwrap_or_default(),
+        };
+
+        deliveries.extend(app.webhooks.enqueue_from_history(
+            hook,
+            &id,
+            &Event {
+                content_type,
+                size,
+                ..event
+            },
+        )?);
+    }
+
+    // Until the cursor moves, the next leader would notify the batch again with the same ids,
+    // so it only moves once this webhook has nothing left to send
+    if !wait_for_deliveries(app, &deliveries).await? {
+        return Ok(false);
+    }
+
+    if !move_cursor(app, hook, last).await {
+        return Ok(false);
+    }
+
+    debug!("Webhooks: Notified webhook {hook} of audit events up to {last:?}");
+
+    Ok(true)
+}
+
+/// Notify webhooks of changes to the registry whenever this node is the leader. Each webhook is
+/// notified separately.
+pub(crate) fn start_notifying(app: Data<RegistryApp>) {
+    // Anything queued while this node was last the leader is queued again by whichever node
+    // is the leader now
+    match app.webhook_deliveries.drop_from_history(None) {
+        Ok(0) => {}
+        Ok(dropped) => debug!("Webhooks: Dropped {dropped} deliveries from an earlier term"),
+        Err(err) => error!("Webhooks: Unable to drop old deliveries: {err:?}"),
+    }
+
+    for hook in app.webhooks.ids() {
+        let app = app.clone();
+        let mut receiver = app.raft.metrics();
+
+        tokio::spawn(async move {
+            loop {
+                let state = receiver.borrow().state;
+
+                if matches!(state, openraft::ServerState::Shutdown) {
+                    break;
+                }
+
+                if matches!(state, openraft::ServerState::Leader) {
+                    match notify_batch(&app, &hook).await {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(err) => error!("Webhooks: Unable to notify changes: {err:?}"),
+                    }
+                }
+
+                if let Ok(Err(_)) = tokio::time::timeout(POLL_INTERVAL, receiver.changed()).await {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{AuditRequest, Digest, RepositoryName};
+
+    fn digest(n: u8) -> Digest {
+        format!("sha256:{}", format!("{n:02x}").repeat(32))
+            .parse()
+            .unwrap()
+    }
+
+    fn audit(
+        index: u64,
+        position: u32,
+        action: &str,
+        tag: Option<&str>,
+    ) -> (AuditCursor, AuditEvent) {
+        let repository: RepositoryName = "team/app".parse().unwrap();
+        (
+            AuditCursor { index, position },
+            AuditEvent {
+                index,
+                timestamp: Utc::now(),
+                user: "alice".to_string(),
+                action: action.to_string(),
+                repository: Some(repository),
+                digest: Some(digest(1)),
+                tag: tag.map(str::to_string),
+                previous_digest: None,
+                subject: None,
+                request: None,
+            },
+        )
+    }
+
+    #[test]
+    fn tagged_push_is_one_event() {
+        let request = AuditRequest {
+            id: "request-1".to_string(),
+            addr: "10.0.0.1:42961".to_string(),
+            host: "registry.example.com".to_string(),
+            method: "PUT".to_string(),
+            useragent: "docker/24.0".to_string(),
+        };
+
+        let mut batch = vec![
+            audit(5, 0, "blob-mounted", None),
+            audit(5, 1, "manifest-mounted", None),
+            audit(5, 2, "hash-tagged", Some("latest")),
+            audit(6, 0, "manifest-mounted", None),
+            audit(7, 0, "acl-entry-set", None),
+        ];
+        for (_, event) in &mut batch[..3] {
+            event.request = Some(request.clone());
+        }
+
+        let events = events_from_audit(&batch);
+        let summary = events
+            .iter()
+            .map(|(id, event)| (id.as_str(), event.kind.action().as_str(), event.tag.clone()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("5-0", "mount", None),
+                ("5-2", "push", Some("latest".to_string())),
+                ("6-0", "push", None),
+            ]
+        );
+
+        // The request that made the change is passed on
+        assert_eq!(events[0].1.request.as_ref(), Some(&request));
+        assert_eq!(events[1].1.request.as_ref(), Some(&request));
+        assert_eq!(events[2].1.request, None);
+
+        let source = serde_json::json!({"addr": "registry-0:8000", "instanceID": "instance"});
+        let notification = events[1].1.notification(&events[1].0, &source);
+        assert_eq!(notification["request"]["id"], "request-1");
+        assert_eq!(notification["request"]["addr"], "10.0.0.1:42961");
+        assert_eq!(notification["request"]["method"], "PUT");
+        assert_eq!(notification["request"]["useragent"], "docker/24.0");
+    }
+
+    #[test]
+    fn moved_tags_and_deletes() {
+        let (cursor, mut tagged) = audit(3, 0, "hash-tagged", Some("latest"));
+        tagged.previous_digest = Some(digest(2));
+
+        let batch = vec![
+            (cursor, tagged),
+            audit(4, 0, "manifest-unmounted", None),
+            audit(4, 1, "blob-unmounted", None),
+        ];
+
+        let events = events_from_audit(&batch);
+        assert_eq!(events.len(), 4);
+
+        let (id, moved) = &events[0];
+        assert_eq!(id, "3-0-tag");
+        assert!(matches!(&moved.kind, EventKind::TagMoved { previous } if previous == &digest(2)));
+
+        assert_eq!(events[1].0, "3-0");
+        assert_eq!(events[2].1.kind.action().as_str(), "delete");
+        assert_eq!(events[2].1.target, EventTarget::Manifest);
+        assert_eq!(events[3].1.target, EventTarget::Blob);
+    }
+}
@@ -0,0 +1,193 @@
+//! An in-process stand-in for S3, with just enough of the API for the S3 storage backend:
+//! putting, copying, reading (including ranges), stating, deleting and listing objects in a
+//! single bucket. Requests aren't authenticated.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+
+use actix_web::dev::ServerHandle;
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::{Bytes, Data, Query};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
+use chrono::Utc;
+
+type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+pub struct FakeS3 {
+    pub endpoint: String,
+    pub bucket: String,
+    objects: Objects,
+    handle: ServerHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeS3 {
+    pub fn start() -> FakeS3 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let objects: Objects = Default::default();
+
+        let data = Data::new(objects.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .default_service(actix_web::web::to(handle))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+
+        let thread = thread::spawn(move || {
+            actix_web::rt::System::new().block_on(server).unwrap();
+        });
+
+        FakeS3 {
+            endpoint,
+            bucket: "registry".to_string(),
+            objects,
+            handle,
+            thread: Some(thread),
+        }
+    }
+
+    /// The keys of every object in the bucket.
+    pub fn keys(&self) -> Vec<String> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn put(&self, key: &str, data: &[u8]) {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), data.to_vec());
+    }
+}
+
+impl Drop for FakeS3 {
+    fn drop(&mut self) {
+        futures::executor::block_on(self.handle.stop(false));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn last_modified() -> String {
+    Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn etag(data: &[u8]) -> String {
+    format!("\"{}\"", data.len())
+}
+
+/// `bytes=start-end`, inclusive, as a range of the object.
+fn parse_range(header: &str, size: usize) -> Option<(usize, usize)> {
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+    let start: usize = start.parse().ok()?;
+    let end: usize = match end {
+        "" => size.checked_sub(1)?,
+        end => end.parse::<usize>().ok()?.min(size.checked_sub(1)?),
+    };
+    (start <= end).then_some((start, end))
+}
+
+async fn handle(
+    req: HttpRequest,
+    objects: Data<Objects>,
+    query: Query<HashMap<String, String>>,
+    body: Bytes,
+) -> HttpResponse {
+    let path = req.path().trim_start_matches('/');
+    let (_bucket, key) = path.split_once('/').unwrap_or((path, ""));
+
+    if key.is_empty() {
+        if req.method() == Method::GET && query.get("list-type").map(|t| t.as_str()) == Some("2") {
+            return list(&objects, query.get("prefix").cloned().unwrap_or_default());
+        }
+        return HttpResponse::NotImplemented().finish();
+    }
+
+    let mut objects = objects.lock().unwrap();
+
+    match *req.method() {
+        Method::PUT => {
+            let data = match req.headers().get("x-amz-copy-source") {
+                Some(source) => {
+                    let source = source.to_str().unwrap();
+                    let (_bucket, source) = source.split_once('/').unwrap();
+                    match objects.get(source) {
+                        Some(data) => data.clone(),
+                        None => return HttpResponse::NotFound().finish(),
+                    }
+                }
+                None => body.to_vec(),
+            };
+
+            let etag = etag(&data);
+            objects.insert(key.to_string(), data);
+            HttpResponse::Ok().insert_header(("ETag", etag)).finish()
+        }
+        Method::GET | Method::HEAD => {
+            let Some(data) = objects.get(key) else {
+                return HttpResponse::NotFound().finish();
+            };
+
+            let range = req
+                .headers()
+                .get("range")
+                .and_then(|range| parse_range(range.to_str().ok()?, data.len()));
+
+            let (mut resp, body) = match range {
+                Some((start, end)) => {
+                    let mut resp = HttpResponse::build(StatusCode::PARTIAL_CONTENT);
+                    resp.insert_header((
+                        "Content-Range",
+                        format!("bytes {start}-{end}/{}", data.len()),
+                    ));
+                    (resp, data[start..=end].to_vec())
+                }
+                None => (HttpResponse::Ok(), data.clone()),
+            };
+
+            resp.insert_header(("ETag", etag(data)))
+                .insert_header(("Last-Modified", last_modified()))
+                .body(body)
+        }
+        Method::DELETE => {
+            objects.remove(key);
+            HttpResponse::NoContent().finish()
+        }
+        _ => HttpResponse::NotImplemented().finish(),
+    }
+}
+
+fn list(objects: &Objects, prefix: String) -> HttpResponse {
+    let objects = objects.lock().unwrap();
+
+    let contents: String = objects
+        .iter()
+        .filter(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, data)| {
+            format!(
+                "<Contents><Key>{key}</Key><LastModified>{}</LastModified><Size>{}</Size><ETag>{}</ETag></Contents>",
+                Utc::now().to_rfc3339(),
+                data.len(),
+                etag(data).replace('"', "&quot;"),
+            )
+        })
+        .collect();
+
+    HttpResponse::Ok().content_type("application/xml").body(format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Prefix>{prefix}</Prefix><KeyCount>{}</KeyCount>{contents}</ListBucketResult>",
+        objects.len()
+    ))
+}
@@ -1,3 +1,4 @@
 #![allow(clippy::uninlined_format_args)]
 
+mod fake_s3;
 mod test_cluster;
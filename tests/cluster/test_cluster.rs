@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::thread;
@@ -5,18 +6,39 @@ use std::thread::JoinHandle;
 use std::time::Duration;
 
 use distribd::client::RegistryClient;
+use distribd::config::BasicAuthConfig;
+use distribd::config::BuiltinTokenServerConfig;
 use distribd::config::Configuration;
+use distribd::config::DataDirectoryConfig;
+use distribd::config::FilesystemConfig;
+use distribd::config::JwksConfig;
 use distribd::config::PrometheusConfig;
 use distribd::config::RaftConfig;
 use distribd::config::RegistryConfig;
+use distribd::config::S3Config;
+use distribd::config::StorageBackendConfig;
+use distribd::config::TlsConfig;
+use distribd::config::TokenConfig;
+use distribd::fsck;
+use distribd::fsck::ClusterFsckRequest;
+use distribd::fsck::Issue;
+use distribd::network::management::AclRule;
+use distribd::scrubber::ObjectKind;
+use distribd::scrubber::Problem;
 use distribd::start_raft_node;
+use distribd::storage::FilesystemStorage;
+use distribd::token_server::TokenServer;
+use distribd::types::AuditQuery;
 use distribd::types::Digest;
+use distribd::types::RegistryAction;
+use distribd::utils::get_blob_path;
+use distribd::utils::get_partial_mirror_path;
 use lazy_static::lazy_static;
 use maplit::btreeset;
 use reqwest::Response;
 use reqwest::StatusCode;
 use reqwest::{
-    header::{HeaderMap, CONTENT_TYPE},
+    header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE},
     Url,
 };
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
@@ -29,6 +51,8 @@ use tokio::runtime::Runtime;
 use tokio::sync::Notify;
 use tracing_test::traced_test;
 
+use crate::fake_s3::FakeS3;
+
 lazy_static! {
     static ref IP_ADDRESSES: ResourcePool<String> = {
         let r = ResourcePool::new();
@@ -360,6 +384,44 @@ impl Drop for TestCluster {
 }
 
 async fn configure() -> anyhow::Result<TestCluster> {
+    configure_cluster(|_| {}).await
+}
+
+fn fixture(name: &str) -> String {
+    std::env::current_dir()
+        .unwrap()
+        .join("fixtures/tls")
+        .join(name)
+        .to_string_lossy()
+        .to_string()
+}
+
+fn tls_config() -> TlsConfig {
+    TlsConfig {
+        key: fixture("node.key"),
+        chain: fixture("node.crt"),
+        ca: Some(fixture("ca.crt")),
+        admins: vec![],
+    }
+}
+
+/// A client for the raft port that trusts the test CA, optionally presenting a certificate.
+fn tls_client(identity: Option<(&str, &str)>) -> reqwest::Client {
+    let ca = std::fs::read(fixture("ca.crt")).unwrap();
+    let mut builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(reqwest::Certificate::from_pem(&ca).unwrap());
+
+    if let Some((key, chain)) = identity {
+        let mut pem = std::fs::read(fixture(key)).unwrap();
+        pem.extend(std::fs::read(fixture(chain)).unwrap());
+        builder = builder.identity(reqwest::Identity::from_pem(&pem).unwrap());
+    }
+
+    builder.build().unwrap()
+}
+
+async fn configure_cluster<F: Fn(&mut Configuration)>(customize: F) -> anyhow::Result<TestCluster> {
     /*std::panic::set_hook(Box::new(|panic| {
         log_panic(panic);
     }));*/
@@ -379,6 +441,7 @@ async fn configure() -> anyhow::Result<TestCluster> {
     let mut peers = vec![];
     for id in 1..4 {
         let mut config = test_config(id, address.clone());
+        customize(&mut config);
 
         let tempdir = tempfile::tempdir().unwrap();
         config.storage = tempdir.path().to_owned().to_string_lossy().to_string();
@@ -400,11 +463,33 @@ async fn configure() -> anyhow::Result<TestCluster> {
         });
 
         let retry_policy = Some(ExponentialBackoff::builder().build_with_max_retries(3));
-        let backend = RegistryClient::new(
-            id,
-            format!("{}:{}", address.clone(), config.raft.port),
-            retry_policy,
-        );
+        let backend = match config.raft.tls {
+            Some(_) => RegistryClient::with_client(
+                id,
+                format!("{}:{}", address.clone(), config.raft.port),
+                "https",
+                tls_client(Some(("node.key", "node.crt"))),
+                retry_policy,
+            ),
+            None => RegistryClient::new(
+                id,
+                format!("{}:{}", address.clone(), config.raft.port),
+                retry_policy,
+            ),
+        };
+
+        let backend = match &config.token_server {
+            Some(
+                token_config @ TokenConfig {
+                    builtin: Some(builtin),
+                    ..
+                },
+            ) => {
+                let server = TokenServer::new(token_config, builtin).unwrap();
+                backend.with_token(server.issue_admin("test").unwrap())
+            }
+            _ => backend,
+        };
 
         let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
         let client = ClientBuilder::new(reqwest::Client::new())
@@ -1316,3 +1401,1654 @@ async fn import_old_snapshot() {
         })
         .await;
 }
+
+#[tokio::test]
+#[traced_test]
+async fn mutual_tls() {
+    // The nodes' certificate is also allowed to use the management API
+    let cluster = configure_cluster(|config| {
+        config.raft.tls = Some(TlsConfig {
+            admins: vec!["distribd test node".to_string()],
+            ..tls_config()
+        })
+    })
+    .await
+    .unwrap();
+    let client = &cluster.peers.first().unwrap().client;
+    let url = cluster.peers.first().unwrap().url.clone();
+
+    // The blob can only reach the other nodes if mirroring works over mutual TLS
+    let url = url
+        .join("foo/bar/blobs/uploads?digest=sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5")
+        .unwrap();
+    let resp = client.post(url).body("FOOBAR").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    cluster
+        .head_all(
+            "foo/bar/blobs/sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5",
+            |resp| {
+                if resp.status() == StatusCode::NOT_FOUND {
+                    return true;
+                }
+                assert_eq!(resp.status(), StatusCode::OK);
+                false
+            },
+        )
+        .await;
+
+    let metrics = format!("https://{}/metrics", cluster.peers.first().unwrap().address);
+
+    let resp = tls_client(Some(("node.key", "node.crt")))
+        .get(&metrics)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Callers without a certificate are turned away during the handshake
+    assert!(tls_client(None).get(&metrics).send().await.is_err());
+
+    // As are callers with a certificate from some other CA
+    assert!(tls_client(Some(("rogue.key", "rogue.crt")))
+        .get(&metrics)
+        .send()
+        .await
+        .is_err());
+}
+
+/// Configure the built-in token server with a policy and htpasswd file written to `etc`.
+fn builtin_token_config(etc: &std::path::Path) -> TokenConfig {
+    std::fs::write(
+        etc.join("htpasswd"),
+        format!("alice:{}\n", bcrypt::hash("password", 4).unwrap()),
+    )
+    .unwrap();
+
+    std::fs::write(
+        etc.join("policy.yaml"),
+        r#"
+rules:
+  - accounts: ["robot$ci"]
+    repositories: ["team/*"]
+    actions: ["pull", "push"]
+  - accounts: ["alice"]
+    repositories: ["${account}/*"]
+    actions: ["pull", "push", "delete"]
+  - accounts: ["alice"]
+    repositories: ["public/*"]
+    actions: ["pull", "push"]
+  - anonymous: true
+    repositories: ["public/*"]
+    actions: ["pull"]
+"#,
+    )
+    .unwrap();
+
+    TokenConfig {
+        issuer: "distribd".to_string(),
+        service: "registry".to_string(),
+        realm: "http://localhost/token".to_string(),
+        public_key: None,
+        public_keys: vec![],
+        jwks: None,
+        public_repositories: vec!["public/*".to_string()],
+        builtin: Some(BuiltinTokenServerConfig {
+            key: std::env::current_dir()
+                .unwrap()
+                .join("fixtures/etc/distribd/token.key")
+                .to_string_lossy()
+                .to_string(),
+            htpasswd: Some(etc.join("htpasswd").to_string_lossy().to_string()),
+            policy: etc.join("policy.yaml").to_string_lossy().to_string(),
+            lifetime: 300,
+        }),
+    }
+}
+
+async fn get_token(
+    node: &TestNode,
+    credentials: Option<(&str, &str)>,
+    scope: &str,
+) -> Result<String, StatusCode> {
+    let url = node
+        .url
+        .join(&format!("/token?service=registry&scope={scope}"))
+        .unwrap();
+    let mut req = node.client.get(url);
+    if let Some((username, password)) = credentials {
+        req = req.basic_auth(username, Some(password));
+    }
+
+    let resp = req.send().await.unwrap();
+    if resp.status() != StatusCode::OK {
+        return Err(resp.status());
+    }
+
+    let body: Value = resp.json().await.unwrap();
+    Ok(body["token"].as_str().unwrap().to_string())
+}
+
+#[tokio::test]
+#[traced_test]
+async fn builtin_token_server() {
+    let etc = tempfile::tempdir().unwrap();
+    let token_config = builtin_token_config(etc.path());
+
+    let cluster = configure_cluster(move |config| config.token_server = Some(token_config.clone()))
+        .await
+        .unwrap();
+
+    let node = cluster.peers.first().unwrap();
+    let upload = "blobs/uploads?digest=sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5";
+    let blob = "blobs/sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5";
+
+    // Without a token the client is told where to get one
+    let url = node.url.join(&format!("team/app/{upload}")).unwrap();
+    let resp = node.client.post(url).body("FOOBAR").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let robot = node.backend.create_robot("ci").await.unwrap();
+    assert_eq!(robot.username, "robot$ci");
+
+    let robots = node.backend.robots().await.unwrap();
+    assert_eq!(robots.len(), 1);
+    assert_eq!(robots[0].username, "robot$ci");
+
+    // The robot can push to the repositories the policy grants it
+    let token = get_token(
+        node,
+        Some((&robot.username, &robot.secret)),
+        "repository:team/app:pull,push",
+    )
+    .await
+    .unwrap();
+
+    let url = node.url.join(&format!("team/app/{upload}")).unwrap();
+    let resp = node
+        .client
+        .post(url)
+        .bearer_auth(&token)
+        .body("FOOBAR")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let url = node.url.join(&format!("team/app/{blob}")).unwrap();
+    let resp = node
+        .client
+        .get(url)
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Registry tokens can't be used for the management API
+    let export = format!("http://{}/export", node.address);
+    let resp = reqwest::Client::new().get(&export).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = reqwest::Client::new()
+        .get(&export)
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // htpasswd users get their own namespace
+    assert_eq!(
+        get_token(
+            node,
+            Some(("alice", "wrong")),
+            "repository:alice/app:pull,push"
+        )
+        .await,
+        Err(StatusCode::UNAUTHORIZED)
+    );
+    let token = get_token(
+        node,
+        Some(("alice", "password")),
+        "repository:alice/app:pull,push",
+    )
+    .await
+    .unwrap();
+
+    let url = node.url.join(&format!("alice/app/{upload}")).unwrap();
+    let resp = node
+        .client
+        .post(url)
+        .bearer_auth(&token)
+        .body("FOOBAR")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    // But the token doesn't cover anything else
+    let url = node.url.join(&format!("team/app/{blob}")).unwrap();
+    let resp = node
+        .client
+        .get(url)
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Anonymous clients can only pull public repositories
+    let token = get_token(node, None, "repository:public/app:pull,push")
+        .await
+        .unwrap();
+
+    let url = node.url.join(&format!("public/app/{upload}")).unwrap();
+    let resp = node
+        .client
+        .post(url)
+        .bearer_auth(&token)
+        .body("FOOBAR")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Once revoked the robot can't get any more tokens
+    node.backend.revoke_robot(&robot.username).await.unwrap();
+    assert!(node.backend.robots().await.unwrap().is_empty());
+
+    assert_eq!(
+        get_token(
+            node,
+            Some((&robot.username, &robot.secret)),
+            "repository:team/app:pull"
+        )
+        .await,
+        Err(StatusCode::UNAUTHORIZED)
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn raft_writes_need_a_peer_or_admin() {
+    let etc = tempfile::tempdir().unwrap();
+    let token_config = builtin_token_config(etc.path());
+
+    let cluster = configure_cluster(move |config| config.token_server = Some(token_config.clone()))
+        .await
+        .unwrap();
+
+    let leader = cluster.peers.first().unwrap();
+    let write = format!("http://{}/write", leader.address);
+    let body = json!({"Transaction": {"actions": []}});
+
+    let resp = reqwest::Client::new()
+        .post(&write)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let follower = cluster.peers.get(1).unwrap();
+    let token = get_token(
+        follower,
+        Some(("alice", "password")),
+        "repository:alice/app:pull,push",
+    )
+    .await
+    .unwrap();
+
+    let resp = reqwest::Client::new()
+        .post(&write)
+        .bearer_auth(&token)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Raft RPCs could overwrite the state just as well, so they are guarded too
+    for rpc in ["raft-vote", "raft-append", "raft-snapshot"] {
+        let resp = reqwest::Client::new()
+            .post(format!("http://{}/{rpc}", leader.address))
+            .json(&json!({}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{rpc}");
+    }
+
+    // Members forward writes to the leader with a token of their own
+    let url = follower
+        .url
+        .join("alice/app/blobs/uploads?digest=sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5")
+        .unwrap();
+    let resp = follower
+        .client
+        .post(url)
+        .bearer_auth(&token)
+        .body("FOOBAR")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn admin_auth_needs_members_to_authenticate() {
+    let etc = tempfile::tempdir().unwrap();
+
+    // Members could neither present a certificate nor sign a token of their own
+    let mut token_config = builtin_token_config(etc.path());
+    token_config.builtin = None;
+    token_config.jwks = Some(JwksConfig {
+        url: None,
+        path: Some(etc.path().join("jwks.json").to_string_lossy().to_string()),
+        refresh_interval: 300,
+    });
+
+    let mut config = test_config(1, "127.0.0.1".to_string());
+    config.storage = etc.path().join("storage").to_string_lossy().to_string();
+    config.token_server = Some(token_config);
+
+    let err = start_raft_node(config).await.unwrap_err();
+    assert!(err.to_string().contains("raft.tls.ca"), "{err}");
+}
+
+#[tokio::test]
+#[traced_test]
+async fn wildcard_scopes_and_public_repositories() {
+    let etc = tempfile::tempdir().unwrap();
+    let token_config = builtin_token_config(etc.path());
+
+    let cluster = configure_cluster(move |config| config.token_server = Some(token_config.clone()))
+        .await
+        .unwrap();
+
+    let node = cluster.peers.first().unwrap();
+    let upload = "blobs/uploads?digest=sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5";
+    let blob = "blobs/sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5";
+
+    // One token covers every repository matching the scope
+    let token = get_token(
+        node,
+        Some(("alice", "password")),
+        "repository:alice/*:pull,push repository:public/*:pull,push",
+    )
+    .await
+    .unwrap();
+
+    for repository in ["alice/app", "alice/other", "public/app"] {
+        let url = node.url.join(&format!("{repository}/{upload}")).unwrap();
+        let resp = node
+            .client
+            .post(url)
+            .bearer_auth(&token)
+            .body("FOOBAR")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    // Pushing doesn't imply deleting
+    let url = node.url.join(&format!("alice/app/{blob}")).unwrap();
+    let resp = node
+        .client
+        .delete(url.clone())
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let delete_token = get_token(
+        node,
+        Some(("alice", "password")),
+        "repository:alice/app:delete",
+    )
+    .await
+    .unwrap();
+    let resp = node
+        .client
+        .delete(url)
+        .bearer_auth(&delete_token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+    // Public repositories can be pulled without a token, private ones can't
+    let url = node.url.join(&format!("public/app/{blob}")).unwrap();
+    let resp = node.client.get(url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let url = node.url.join(&format!("alice/other/{blob}")).unwrap();
+    let resp = node.client.get(url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn management_api_with_mutual_tls() {
+    let etc = tempfile::tempdir().unwrap();
+    let token_config = builtin_token_config(etc.path());
+
+    // Only an admin token will do, the node certificate is only good for peer RPC
+    let cluster = configure_cluster(move |config| {
+        config.raft.tls = Some(TlsConfig {
+            admins: vec!["distribd admin".to_string()],
+            ..tls_config()
+        });
+        config.token_server = Some(token_config.clone());
+    })
+    .await
+    .unwrap();
+
+    let node = cluster.peers.first().unwrap();
+    let export = format!("https://{}/export", node.address);
+
+    let resp = tls_client(Some(("node.key", "node.crt")))
+        .get(&export)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    assert!(node.backend.export().await.is_ok());
+
+    // Peers can still replicate and mirror
+    let token = get_token(
+        node,
+        Some(("alice", "password")),
+        "repository:alice/app:pull,push",
+    )
+    .await
+    .unwrap();
+    let url = node
+        .url
+        .join("alice/app/blobs/uploads?digest=sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5")
+        .unwrap();
+    let resp = node
+        .client
+        .post(url)
+        .bearer_auth(&token)
+        .body("FOOBAR")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let blob =
+        "alice/app/blobs/sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5";
+    for peer in cluster.peers.iter() {
+        let url = peer.url.join(blob).unwrap();
+        let mut status = StatusCode::NOT_FOUND;
+        for _ in 0..10 {
+            let resp = peer
+                .client
+                .head(url.clone())
+                .bearer_auth(&token)
+                .send()
+                .await
+                .unwrap();
+            status = resp.status();
+            if status != StatusCode::NOT_FOUND {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        assert_eq!(status, StatusCode::OK);
+    }
+}
+
+/// Push `FOOBAR` to `repository` with a username and password, returning the status code.
+async fn basic_push(
+    node: &TestNode,
+    repository: &str,
+    credentials: Option<(&str, &str)>,
+) -> StatusCode {
+    let url = node
+        .url
+        .join(&format!("{repository}/blobs/uploads?digest=sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"))
+        .unwrap();
+    let mut req = node.client.post(url).body("FOOBAR");
+    if let Some((username, password)) = credentials {
+        req = req.basic_auth(username, Some(password));
+    }
+    req.send().await.unwrap().status()
+}
+
+#[tokio::test]
+#[traced_test]
+async fn basic_auth_with_acls() {
+    let etc = tempfile::tempdir().unwrap();
+    std::fs::write(
+        etc.path().join("htpasswd"),
+        format!(
+            "alice:{}\nbob:{}\n",
+            bcrypt::hash("password", 4).unwrap(),
+            bcrypt::hash("hunter2", 4).unwrap()
+        ),
+    )
+    .unwrap();
+
+    let htpasswd = etc.path().join("htpasswd").to_string_lossy().to_string();
+    let cluster = configure_cluster(move |config| {
+        config.basic_auth = Some(BasicAuthConfig {
+            htpasswd: htpasswd.clone(),
+            groups: BTreeMap::from([("developers".to_string(), vec!["bob".to_string()])]),
+            realm: "distribd".to_string(),
+        });
+    })
+    .await
+    .unwrap();
+
+    let node = cluster.peers.first().unwrap();
+
+    // Clients are asked for a username and password
+    let url = node.url.join("alice/app/blobs/uploads").unwrap();
+    let resp = node.client.post(url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        resp.headers().get("www-authenticate").unwrap(),
+        "Basic realm=\"distribd\""
+    );
+
+    assert_eq!(
+        basic_push(node, "alice/app", Some(("alice", "wrong"))).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // Without an ACL entry a valid login can't do anything
+    assert_eq!(
+        basic_push(node, "alice/app", Some(("alice", "password"))).await,
+        StatusCode::FORBIDDEN
+    );
+
+    node.backend
+        .set_acl(&AclRule {
+            subject: "alice".parse().unwrap(),
+            repository: "alice/*".to_string(),
+            actions: btreeset! {"pull".to_string(), "push".to_string()},
+        })
+        .await
+        .unwrap();
+    node.backend
+        .set_acl(&AclRule {
+            subject: "group:developers".parse().unwrap(),
+            repository: "team/*".to_string(),
+            actions: btreeset! {"*".to_string()},
+        })
+        .await
+        .unwrap();
+    assert_eq!(node.backend.acls().await.unwrap().len(), 2);
+
+    // Entries apply on every node once they have been replicated
+    for peer in cluster.peers.iter() {
+        let mut status = StatusCode::FORBIDDEN;
+        for _ in 0..10 {
+            status = basic_push(peer, "team/app", Some(("bob", "hunter2"))).await;
+            if status != StatusCode::FORBIDDEN {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    assert_eq!(
+        basic_push(node, "alice/app", Some(("alice", "password"))).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        basic_push(node, "team/app", Some(("alice", "password"))).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        basic_push(node, "alice/app", Some(("bob", "hunter2"))).await,
+        StatusCode::FORBIDDEN
+    );
+
+    // Removing an entry takes the access away again
+    node.backend
+        .remove_acl(&AclRule {
+            subject: "alice".parse().unwrap(),
+            repository: "alice/*".to_string(),
+            actions: Default::default(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        basic_push(node, "alice/app", Some(("alice", "password"))).await,
+        StatusCode::FORBIDDEN
+    );
+
+    // The audit history records who did what
+    let events = node
+        .backend
+        .audit(&AuditQuery {
+            user: Some("bob".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert!(events.iter().all(|event| event.action == "blob-mounted"
+        && event.repository == Some("team/app".parse().unwrap())));
+    assert!(!events.is_empty());
+
+    let events = node
+        .backend
+        .audit(&AuditQuery {
+            action: Some("acl-entry-removed".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].subject.as_deref(), Some("user:alice"));
+}
+
+/// Tag an empty manifest list as `repository:tag`.
+async fn put_manifest_list(node: &TestNode, repository: &str, tag: &str) {
+    let url = node
+        .url
+        .join(&format!("{repository}/manifests/{tag}"))
+        .unwrap();
+
+    let resp = node
+        .client
+        .put(url)
+        .header(
+            CONTENT_TYPE,
+            "application/vnd.docker.distribution.manifest.list.v2+json",
+        )
+        .json(&json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+            "manifests": []
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), StatusCode::CREATED);
+}
+
+/// Read `count` lines from a streaming response.
+async fn read_lines(resp: &mut Response, count: usize) -> Vec<String> {
+    let mut buffer = String::new();
+
+    while buffer.matches('\n').count() < count {
+        let chunk = tokio::time::timeout(Duration::from_secs(10), resp.chunk())
+            .await
+            .expect("Timed out waiting for the change feed")
+            .unwrap()
+            .expect("The change feed ended");
+        buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+
+    buffer.lines().take(count).map(str::to_string).collect()
+}
+
+#[tokio::test]
+#[traced_test]
+async fn change_feed() {
+    let cluster = configure().await.unwrap();
+    let leader = cluster.peers.first().unwrap();
+    let follower = cluster.peers.get(1).unwrap();
+
+    // Any node can serve the feed, and by default it starts from now
+    let mut resp = follower
+        .client
+        .get(follower.url.join("_events?repository=foo/").unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-ndjson"
+    );
+
+    put_manifest_list(leader, "other/app", "latest").await;
+    put_manifest_list(leader, "foo/bar", "latest").await;
+
+    let changes: Vec<Value> = read_lines(&mut resp, 2)
+        .await
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(changes[0]["action"], "manifest-mounted");
+    assert_eq!(changes[0]["repository"], "foo/bar");
+    assert_eq!(changes[1]["action"], "hash-tagged");
+    assert_eq!(changes[1]["tag"], "latest");
+
+    // Resuming from an index replays what happened after it, as server-sent events
+    let since = changes[0]["index"].as_u64().unwrap() - 1;
+    let mut resp = follower
+        .client
+        .get(
+            follower
+                .url
+                .join(&format!("_events?since={since}&repository=foo/"))
+                .unwrap(),
+        )
+        .header("Accept", "text/event-stream")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.headers().get(CONTENT_TYPE).unwrap(),
+        "text/event-stream"
+    );
+
+    let lines = read_lines(&mut resp, 3).await;
+    assert_eq!(
+        lines[0],
+        format!("id: {}", changes[0]["id"].as_str().unwrap())
+    );
+    assert_eq!(lines[1], "event: manifest-mounted");
+    assert!(lines[2].starts_with("data: {"));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn change_feed_ends_when_token_expires() {
+    let etc = tempfile::tempdir().unwrap();
+    let mut token_config = builtin_token_config(etc.path());
+    token_config.builtin.as_mut().unwrap().lifetime = 2;
+
+    let cluster = configure_cluster(move |config| config.token_server = Some(token_config.clone()))
+        .await
+        .unwrap();
+
+    let node = cluster.peers.first().unwrap();
+    let token = get_token(
+        node,
+        Some(("alice", "password")),
+        "repository:alice/app:pull",
+    )
+    .await
+    .unwrap();
+
+    let mut resp = node
+        .client
+        .get(node.url.join("_events").unwrap())
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let chunk = tokio::time::timeout(Duration::from_secs(10), resp.chunk())
+        .await
+        .expect("The change feed outlived the token")
+        .unwrap();
+    assert_eq!(chunk, None);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn garbage_collection() {
+    let cluster = configure_cluster(|config| {
+        config.garbage_collection.grace_period = 0;
+    })
+    .await
+    .unwrap();
+    let leader = cluster.peers.first().unwrap();
+
+    let digest: Digest = "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+        .parse()
+        .unwrap();
+
+    // A blob that no manifest refers to is garbage
+    assert_eq!(
+        basic_push(leader, "foo/bar", None).await,
+        StatusCode::CREATED
+    );
+
+    let status = leader.backend.garbage_collect_status().await.unwrap();
+    assert!(!status.enabled);
+    assert!(status.last_run.is_none());
+
+    let report = leader.backend.garbage_collect_dry_run().await.unwrap();
+    assert_eq!(report.blob_mounts.len(), 1);
+    assert_eq!(report.blob_mounts[0].digest, digest);
+    assert_eq!(report.blob_mounts[0].repository.name, "foo/bar");
+    assert!(report.blobs.is_empty());
+
+    // A dry run doesn't change anything
+    let url = leader.url.join(&format!("foo/bar/blobs/{digest}")).unwrap();
+    let resp = leader.client.head(url.clone()).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The leader unmounts the blob and then deletes its own copy in the same run
+    let run = leader.backend.garbage_collect().await.unwrap();
+    assert_eq!(run.error, None);
+    assert_eq!(run.report.blob_mounts, report.blob_mounts);
+    assert_eq!(run.report.blobs, vec![digest.clone()]);
+
+    let resp = leader.client.head(url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let status = leader.backend.garbage_collect_status().await.unwrap();
+    assert!(!status.running);
+    assert_eq!(status.last_run, Some(run));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn scrubbing_replaces_corrupt_blobs() {
+    let cluster = configure().await.unwrap();
+    let leader = cluster.peers.first().unwrap();
+
+    let digest: Digest = "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+        .parse()
+        .unwrap();
+
+    assert_eq!(
+        basic_push(leader, "foo/bar", None).await,
+        StatusCode::CREATED
+    );
+
+    // Wait for the other nodes to have a copy
+    let path = get_blob_path(&leader._tempdir.path().to_string_lossy(), &digest);
+    for peer in cluster.peers.iter().skip(1) {
+        let peer_path = get_blob_path(&peer._tempdir.path().to_string_lossy(), &digest);
+        for _ in 0..20 {
+            if peer_path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        assert!(peer_path.exists());
+    }
+
+    let pass = leader.backend.scrub().await.unwrap();
+    assert_eq!(pass.error, None);
+    assert_eq!(pass.checked, 1);
+    assert!(pass.corrupt.is_empty());
+
+    // The same size, but the wrong content
+    std::fs::write(&path, "FOOBAZ").unwrap();
+
+    let pass = leader.backend.scrub().await.unwrap();
+    assert_eq!(pass.corrupt.len(), 1);
+    assert_eq!(pass.corrupt[0].digest, digest);
+    assert!(matches!(pass.corrupt[0].problem, Problem::Hash { .. }));
+    assert!(pass.corrupt[0].recoverable);
+
+    // Mirroring fetches a good copy from another node
+    for _ in 0..20 {
+        if std::fs::read_to_string(&path).ok().as_deref() == Some("FOOBAR") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "FOOBAR");
+
+    let status = leader.backend.scrub_status().await.unwrap();
+    assert!(!status.enabled);
+    assert_eq!(status.last_pass, Some(pass));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn fsck_finds_corrupt_blobs() {
+    let cluster = configure().await.unwrap();
+    let leader = cluster.peers.first().unwrap();
+    let storage = leader._tempdir.path().to_string_lossy().to_string();
+
+    let digest: Digest = "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+        .parse()
+        .unwrap();
+
+    assert_eq!(
+        basic_push(leader, "foo/bar", None).await,
+        StatusCode::CREATED
+    );
+
+    let state = leader.backend.export().await.unwrap();
+    let report = fsck::check(&state, &FilesystemStorage::new(&storage), 1).await;
+    assert_eq!(report.blobs, 1);
+    assert_eq!(report.issues, vec![]);
+    assert!(report.fixes.is_empty());
+
+    std::fs::write(get_blob_path(&storage, &digest), "FOOBAZ").unwrap();
+
+    let report = fsck::check(&state, &FilesystemStorage::new(&storage), 1).await;
+    assert_eq!(
+        report.issues,
+        vec![Issue::Stored {
+            object: ObjectKind::Blob,
+            digest: digest.clone(),
+            problem: Problem::Hash {
+                actual: Digest::from_sha256(&ring::digest::digest(
+                    &ring::digest::SHA256,
+                    b"FOOBAZ"
+                )),
+            },
+        }]
+    );
+    assert!(matches!(
+        &report.fixes[..],
+        [RegistryAction::BlobUnstored { digest: fixed, location: 1, .. }] if fixed == &digest
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn fsck_cluster_with_token_server() {
+    let etc = tempfile::tempdir().unwrap();
+    let token_config = builtin_token_config(etc.path());
+
+    let cluster = configure_cluster(move |config| config.token_server = Some(token_config.clone()))
+        .await
+        .unwrap();
+    let leader = cluster.peers.first().unwrap();
+
+    // Nobody else can make a node check its storage
+    let resp = reqwest::Client::new()
+        .post(format!("http://{}/fsck/local", leader.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // But members can ask each other
+    let report = leader
+        .backend
+        .fsck(&ClusterFsckRequest::default())
+        .await
+        .unwrap();
+    assert_eq!(report.nodes.len(), 3);
+    assert!(
+        report.nodes.values().all(|node| node.error.is_none()),
+        "{:?}",
+        report.nodes
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn fsck_cluster_finds_under_replicated_blobs() {
+    let cluster = configure().await.unwrap();
+    let leader = cluster.peers.first().unwrap();
+    let follower = cluster.peers.get(1).unwrap();
+
+    let digest: Digest = "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+        .parse()
+        .unwrap();
+
+    assert_eq!(
+        basic_push(leader, "foo/bar", None).await,
+        StatusCode::CREATED
+    );
+
+    // Wait for the other nodes to have a copy
+    for peer in cluster.peers.iter().skip(1) {
+        let peer_path = get_blob_path(&peer._tempdir.path().to_string_lossy(), &digest);
+        for _ in 0..20 {
+            if peer_path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        assert!(peer_path.exists());
+    }
+
+    let report = leader
+        .backend
+        .fsck(&ClusterFsckRequest::default())
+        .await
+        .unwrap();
+    assert_eq!(report.replicas, 3);
+    assert_eq!(report.nodes.len(), 3);
+    assert!(report.nodes.values().all(|node| node.error.is_none()));
+    assert!(report.unavailable.is_empty());
+    assert!(report.under_replicated.is_empty());
+
+    let path = get_blob_path(&follower._tempdir.path().to_string_lossy(), &digest);
+    std::fs::write(&path, "FOOBAZ").unwrap();
+
+    let report = leader
+        .backend
+        .fsck(&ClusterFsckRequest {
+            repair: true,
+            replicas: None,
+        })
+        .await
+        .unwrap();
+    assert!(report.unavailable.is_empty());
+    assert_eq!(report.under_replicated.len(), 1);
+    assert_eq!(report.under_replicated[0].digest, digest);
+    assert_eq!(report.under_replicated[0].healthy, btreeset! {1, 3});
+    assert_eq!(report.under_replicated[0].corrupt, btreeset! {2});
+    assert!(report.repaired);
+
+    // Once the corrupt copy is unstored, mirroring fetches a good one
+    for _ in 0..20 {
+        if std::fs::read_to_string(&path).ok().as_deref() == Some("FOOBAR") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "FOOBAR");
+}
+
+#[tokio::test]
+#[traced_test]
+async fn s3_storage_backend() {
+    let s3 = FakeS3::start();
+
+    let endpoint = s3.endpoint.clone();
+    let bucket = s3.bucket.clone();
+    let cluster = configure_cluster(move |config| {
+        config.storage_backend = StorageBackendConfig::S3(S3Config {
+            bucket: bucket.clone(),
+            region: "us-east-1".to_string(),
+            endpoint: Some(endpoint.clone()),
+            access_key_id: Some("distribd".to_string()),
+            secret_access_key: Some("distribd".to_string()),
+            prefix: config.identifier.clone(),
+            allow_http: true,
+        });
+    })
+    .await
+    .unwrap();
+    let leader = cluster.peers.first().unwrap();
+
+    let digest: Digest = "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+        .parse()
+        .unwrap();
+    let key = |node: usize| format!("registry-{node}/blobs/{}", digest.hash);
+
+    assert_eq!(
+        basic_push(leader, "foo/bar", None).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(s3.get(&key(0)), Some(b"FOOBAR".to_vec()));
+
+    let url = leader.url.join(&format!("foo/bar/blobs/{digest}")).unwrap();
+    let resp = leader.client.head(url.clone()).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get(CONTENT_LENGTH).unwrap(), "6");
+
+    let resp = leader.client.get(url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await.unwrap(), "FOOBAR");
+
+    // Every node mirrors the blob into its own prefix
+    for node in 1..3 {
+        for _ in 0..20 {
+            if s3.get(&key(node)).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        assert_eq!(s3.get(&key(node)), Some(b"FOOBAR".to_vec()));
+    }
+
+    // And has recorded that it has
+    for _ in 0..20 {
+        let state = leader.backend.export().await.unwrap();
+        if state.blobs[&digest].locations.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    let report = leader
+        .backend
+        .fsck(&ClusterFsckRequest::default())
+        .await
+        .unwrap();
+    for node in report.nodes.values() {
+        assert_eq!(node.report.as_ref().unwrap().issues, vec![]);
+    }
+
+    // A corrupt copy is quarantined and replaced from another node
+    s3.put(&key(0), b"FOOBAZ");
+
+    let pass = leader.backend.scrub().await.unwrap();
+    assert_eq!(pass.corrupt.len(), 1);
+    assert!(matches!(pass.corrupt[0].problem, Problem::Hash { .. }));
+    assert!(s3
+        .keys()
+        .iter()
+        .any(|key| key.starts_with(&format!("registry-0/quarantine/blobs/{}-", digest.hash))));
+
+    for _ in 0..20 {
+        if s3.get(&key(0)).as_deref() == Some(b"FOOBAR") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    assert_eq!(s3.get(&key(0)), Some(b"FOOBAR".to_vec()));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn webhooks_are_notified_by_the_leader() {
+    // Fails the first delivery, so it has to be retried
+    let received: Arc<std::sync::Mutex<Vec<Value>>> = Default::default();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let hook = format!("http://{}/hook", listener.local_addr().unwrap());
+    let log = received.clone();
+    let server = actix_web::HttpServer::new(move || {
+        let log = log.clone();
+        actix_web::App::new().route(
+            "/hook",
+            actix_web::web::post().to(move |body: String| {
+                let mut log = log.lock().unwrap();
+                log.push(serde_json::from_str::<Value>(&body).unwrap());
+                let first = log.len() == 1;
+                async move {
+                    match first {
+                        true => actix_web::HttpResponse::InternalServerError(),
+                        false => actix_web::HttpResponse::Ok(),
+                    }
+                    .finish()
+                }
+            }),
+        )
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    let handle = server.handle();
+    let thread = thread::spawn(move || {
+        actix_web::rt::System::new().block_on(server).unwrap();
+    });
+
+    // Nothing listens here, so its deliveries keep failing for a long time
+    let unreachable = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let down = format!("http://{}/hook", unreachable.local_addr().unwrap());
+    drop(unreachable);
+
+    let cluster = configure_cluster(|config| {
+        config.webhooks = vec![
+            serde_json::from_value(json!({
+                "url": down,
+                "matcher": ".*",
+                "actions": ["mount"],
+                "retry_backoff": 60,
+            }))
+            .unwrap(),
+            serde_json::from_value(json!({
+                "url": hook,
+                "matcher": ".*",
+                "actions": ["mount"],
+                "retry_backoff": 0,
+            }))
+            .unwrap(),
+        ];
+    })
+    .await
+    .unwrap();
+
+    // Give the leader a moment to start notifying from the end of the history
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    // Pushed through a follower, but notified by the leader
+    assert_eq!(
+        basic_push(&cluster.peers[1], "foo/bar", None).await,
+        StatusCode::CREATED
+    );
+
+    for _ in 0..40 {
+        if received.lock().unwrap().len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    // Nothing else turns up once the retry has been delivered
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(received.lock().unwrap().len(), 2);
+
+    // The next change is notified without waiting for the webhook that is down to give up
+    assert_eq!(
+        basic_push(&cluster.peers[2], "foo/baz", None).await,
+        StatusCode::CREATED
+    );
+
+    for _ in 0..40 {
+        if received.lock().unwrap().len() >= 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    let received = received.lock().unwrap().clone();
+    assert_eq!(received.len(), 3);
+    assert_eq!(received[2]["events"][0]["target"]["repository"], "foo/baz");
+
+    let first = &received[0]["events"][0];
+    assert_eq!(first["action"], "mount");
+    assert_eq!(first["target"]["repository"], "foo/bar");
+    assert_eq!(first["request"]["method"], "POST");
+    assert!(!first["request"]["id"].as_str().unwrap().is_empty());
+    assert_eq!(received[1]["events"][0]["id"], first["id"]);
+
+    handle.stop(false).await;
+    thread.join().unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn data_directories() {
+    let disks = tempfile::tempdir().unwrap();
+
+    let root = disks.path().to_owned();
+    let cluster = configure_cluster(move |config| {
+        let directories = (0..2)
+            .map(|disk| {
+                let path = root.join(&config.identifier).join(format!("disk{disk}"));
+                std::fs::create_dir_all(&path).unwrap();
+                std::fs::write(path.join(distribd::storage::jbod::MARKER), "").unwrap();
+                DataDirectoryConfig {
+                    path: path.to_string_lossy().to_string(),
+                    weight: Some(1.0),
+                }
+            })
+            .collect();
+
+        config.storage_backend = StorageBackendConfig::Filesystem(FilesystemConfig {
+            directories,
+            reserved_space: 0,
+        });
+    })
+    .await
+    .unwrap();
+    let leader = cluster.peers.first().unwrap();
+
+    let digest: Digest = "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+        .parse()
+        .unwrap();
+    let disk = |node: usize, disk: usize| disks.path().join(format!("registry-{node}/disk{disk}"));
+    let blob_path = |node: usize, idx: usize| {
+        FilesystemStorage::new(&disk(node, idx).to_string_lossy()).path(ObjectKind::Blob, &digest)
+    };
+    let stored_on = |node: usize| {
+        (0..2)
+            .filter(|idx| blob_path(node, *idx).exists())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        basic_push(leader, "foo/bar", None).await,
+        StatusCode::CREATED
+    );
+
+    // The blob is in exactly one of the leader's data directories, and not in `storage`
+    let on = stored_on(0);
+    assert_eq!(on.len(), 1);
+    assert!(!leader._tempdir.path().join("blobs/24").exists());
+
+    let url = leader.url.join(&format!("foo/bar/blobs/{digest}")).unwrap();
+    let resp = leader.client.get(url.clone()).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await.unwrap(), "FOOBAR");
+
+    // Wait for every node to have mirrored it
+    for _ in 0..20 {
+        let state = leader.backend.export().await.unwrap();
+        if state.blobs[&digest].locations.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    for node in 1..3 {
+        assert_eq!(stored_on(node).len(), 1);
+    }
+
+    let report = leader
+        .backend
+        .fsck(&ClusterFsckRequest::default())
+        .await
+        .unwrap();
+    for node in report.nodes.values() {
+        assert_eq!(node.report.as_ref().unwrap().issues, vec![]);
+    }
+
+    // A copy in the other directory is reported
+    let (used, other) = (on[0], 1 - on[0]);
+    let copy = blob_path(0, other);
+    std::fs::create_dir_all(copy.parent().unwrap()).unwrap();
+    std::fs::copy(blob_path(0, used), &copy).unwrap();
+
+    let report = leader
+        .backend
+        .fsck(&ClusterFsckRequest::default())
+        .await
+        .unwrap();
+    let issues = &report.nodes[&1].report.as_ref().unwrap().issues;
+    assert_eq!(issues.len(), 1);
+    assert!(matches!(&issues[0], Issue::DuplicateFile { .. }));
+    std::fs::remove_file(&copy).unwrap();
+
+    // With the disk holding the blob gone, it isn't treated as missing
+    let unmounted = disks.path().join("unmounted");
+    std::fs::rename(disk(0, used), &unmounted).unwrap();
+
+    let report = leader
+        .backend
+        .fsck(&ClusterFsckRequest {
+            repair: true,
+            replicas: None,
+        })
+        .await
+        .unwrap();
+    let node = report.nodes[&1].report.as_ref().unwrap();
+    assert_eq!(
+        node.issues,
+        vec![Issue::Stored {
+            object: ObjectKind::Blob,
+            digest: digest.clone(),
+            problem: Problem::Unavailable,
+        }]
+    );
+    assert!(node.fixes.is_empty());
+
+    let pass = leader.backend.scrub().await.unwrap();
+    assert!(pass.corrupt.is_empty());
+
+    std::fs::rename(&unmounted, disk(0, used)).unwrap();
+    let resp = leader.client.get(url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await.unwrap(), "FOOBAR");
+}
+
+#[tokio::test]
+#[traced_test]
+async fn concurrent_mirroring() {
+    let cluster = configure_cluster(|config| {
+        config.mirroring.concurrency = 3;
+        config.mirroring.batch_size = 4;
+    })
+    .await
+    .unwrap();
+    let leader = cluster.peers.first().unwrap();
+
+    let mut digests = vec![];
+    for i in 0..20 {
+        let body = format!("blob-{i}");
+        let digest = Digest::from_sha256(&ring::digest::digest(
+            &ring::digest::SHA256,
+            body.as_bytes(),
+        ));
+
+        let url = leader
+            .url
+            .join(&format!("foo/bar/blobs/uploads?digest={digest}"))
+            .unwrap();
+        let resp = leader.client.post(url).body(body).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        digests.push(digest);
+    }
+
+    // Every node fetches and records a copy of every blob
+    let mut mirrored = false;
+    for _ in 0..40 {
+        let state = leader.backend.export().await.unwrap();
+        mirrored = digests
+            .iter()
+            .all(|digest| state.blobs[digest].locations.len() == 3);
+        if mirrored {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    assert!(mirrored);
+
+    for peer in cluster.peers.iter() {
+        let storage = peer._tempdir.path().to_string_lossy().to_string();
+        for (i, digest) in digests.iter().enumerate() {
+            assert_eq!(
+                std::fs::read_to_string(get_blob_path(&storage, digest)).unwrap(),
+                format!("blob-{i}")
+            );
+        }
+    }
+
+    // Each follower fetched every blob from somewhere, and nothing kept failing
+    for peer in cluster.peers.iter().skip(1) {
+        let status = peer.backend.mirror_status().await.unwrap();
+        let successes: u64 = status.peers.values().map(|peer| peer.successes).sum();
+        assert!(successes >= 20, "{status:?}");
+        assert!(status.failing.is_empty(), "{status:?}");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn resuming_mirror_downloads() {
+    let cluster = configure().await.unwrap();
+    let leader = cluster.peers.first().unwrap();
+
+    let body: String = (0..20000).map(|i| format!("{i:08}")).collect();
+    let digest = Digest::from_sha256(&ring::digest::digest(
+        &ring::digest::SHA256,
+        body.as_bytes(),
+    ));
+
+    // The followers had got halfway through fetching it before being cut off
+    for peer in cluster.peers.iter().skip(1) {
+        let storage = peer._tempdir.path().to_string_lossy().to_string();
+        std::fs::write(
+            get_partial_mirror_path(&storage, "blobs", &digest),
+            &body[..body.len() / 2],
+        )
+        .unwrap();
+    }
+
+    let url = leader
+        .url
+        .join(&format!("foo/bar/blobs/uploads?digest={digest}"))
+        .unwrap();
+    let resp = leader
+        .client
+        .post(url)
+        .body(body.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    // Peers can ask for the rest of a blob
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/blobs/{digest}", leader.address))
+        .header("Range", "bytes=100-")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        resp.headers().get("Content-Range").unwrap(),
+        &format!("bytes 100-{}/{}", body.len() - 1, body.len())
+    );
+    assert_eq!(resp.text().await.unwrap(), &body[100..]);
+
+    let mut mirrored = false;
+    for _ in 0..20 {
+        let state = leader.backend.export().await.unwrap();
+        mirrored = state.blobs[&digest].locations.len() == 3;
+        if mirrored {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    assert!(mirrored);
+
+    for peer in cluster.peers.iter().skip(1) {
+        let storage = peer._tempdir.path().to_string_lossy().to_string();
+        assert_eq!(
+            std::fs::read_to_string(get_blob_path(&storage, &digest)).unwrap(),
+            body
+        );
+        assert!(!get_partial_mirror_path(&storage, "blobs", &digest).exists());
+    }
+}
+
+/// Push a manifest by its digest, returning the digest.
+async fn put_manifest_by_digest(
+    node: &TestNode,
+    repository: &str,
+    content_type: &str,
+    manifest: &Value,
+) -> Digest {
+    let body = serde_json::to_vec(manifest).unwrap();
+    let digest = Digest::from_sha256(&ring::digest::digest(&ring::digest::SHA256, &body));
+
+    let url = node
+        .url
+        .join(&format!("{repository}/manifests/{digest}"))
+        .unwrap();
+    let resp = node
+        .client
+        .put(url)
+        .header(CONTENT_TYPE, content_type)
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    digest
+}
+
+#[tokio::test]
+#[traced_test]
+async fn garbage_collection_keeps_image_indexes() {
+    let cluster = configure_cluster(|config| {
+        config.garbage_collection.grace_period = 0;
+    })
+    .await
+    .unwrap();
+    let leader = cluster.peers.first().unwrap();
+
+    assert_eq!(
+        basic_push(leader, "foo/bar", None).await,
+        StatusCode::CREATED
+    );
+
+    let config = json!({
+        "mediaType": "application/vnd.oci.image.config.v1+json",
+        "size": 6,
+        "digest": "sha256:24c422e681f1c1bd08286c7aaf5d23a5f088dcdb0b219806b3a9e579244f00c5"
+    });
+
+    // The image is pushed by digest, so it isn't tagged
+    let image = json!({
+        "schemaVersion": 2,
+        "config": config,
+        "layers": []
+    });
+    let image_digest = put_manifest_by_digest(
+        leader,
+        "foo/bar",
+        "application/vnd.oci.image.manifest.v1+json",
+        &image,
+    )
+    .await;
+
+    // A digest that doesn't match the manifest is rejected
+    let url = leader
+        .url
+        .join("foo/bar/manifests/sha256:0000000000000000000000000000000000000000000000000000000000000000")
+        .unwrap();
+    let resp = leader
+        .client
+        .put(url)
+        .header(CONTENT_TYPE, "application/vnd.oci.image.manifest.v1+json")
+        .json(&image)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let index = json!({
+        "schemaVersion": 2,
+        "manifests": [{
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "size": serde_json::to_vec(&image).unwrap().len(),
+            "digest": image_digest,
+            "platform": {"architecture": "amd64", "os": "linux"}
+        }]
+    });
+    let resp = leader
+        .client
+        .put(leader.url.join("foo/bar/manifests/latest").unwrap())
+        .header(CONTENT_TYPE, "application/vnd.oci.image.index.v1+json")
+        .json(&index)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let index_digest = resp
+        .headers()
+        .get("Docker-Content-Digest")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // A signature refers to the index rather than the index referring to it
+    let signature = json!({
+        "schemaVersion": 2,
+        "config": config,
+        "layers": [],
+        "subject": {
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "size": serde_json::to_vec(&index).unwrap().len(),
+            "digest": index_digest
+        }
+    });
+    put_manifest_by_digest(
+        leader,
+        "foo/bar",
+        "application/vnd.oci.image.manifest.v1+json",
+        &signature,
+    )
+    .await;
+
+    // Pushing by digest didn't create any tags
+    let resp = leader
+        .client
+        .get(leader.url.join("foo/bar/tags/list").unwrap())
+        .send()
+        .await
+        .unwrap();
+    let tags: Value = resp.json().await.unwrap();
+    assert_eq!(tags["tags"], json!(["latest"]));
+
+    // Everything is reachable from the tag
+    let report = leader.backend.garbage_collect_dry_run().await.unwrap();
+    assert!(report.manifest_mounts.is_empty());
+    assert!(report.blob_mounts.is_empty());
+
+    let run = leader.backend.garbage_collect().await.unwrap();
+    assert_eq!(run.error, None);
+    assert!(run.report.manifests.is_empty());
+    assert!(run.report.blobs.is_empty());
+
+    let url = leader
+        .url
+        .join(&format!("foo/bar/manifests/{image_digest}"))
+        .unwrap();
+    let resp = leader
+        .client
+        .head(url)
+        .header("Accept", "application/vnd.oci.image.manifest.v1+json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}